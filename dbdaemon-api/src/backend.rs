@@ -5,6 +5,7 @@
 use std::{
     collections::{HashMap, HashSet},
     fmt::Display,
+    str::FromStr,
 };
 
 // use chrono::{DateTime, Utc};
@@ -17,8 +18,8 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use dbschema::{
-    DbTable, DbTableId, DualVersionedValue, Filter, ObjectId, SingleVersionedValue, TimeRange,
-    Timeline,
+    DbTable, DbTableId, DualVersionedValue, Filter, Identified, ObjectId, SingleVersionedValue,
+    TimeRange, Timeline,
 };
 use rpc::rpc;
 
@@ -32,7 +33,6 @@ pub type DbClient = BackendDbServiceStub<
 >;
 
 #[rpc(service, stub(javascript, python), log_errors)]
-// TODO: prevent race conditions with locks (experation of 5-10min ==> error)
 // TODO: add update schema
 pub trait BackendDbService {
     /* Setup and configuration. */
@@ -44,29 +44,165 @@ pub trait BackendDbService {
 
     async fn register_table(&self, id: DbTableId, definition: DbTable);
 
+    /// Report what registering the definition would do, without
+    /// applying it. Existing values are validated against the new
+    /// definition, so this reads the whole table. If migration steps
+    /// are given, the report is for `migrate_table`, and values are
+    /// validated after migration.
+    async fn check_table_registration(
+        &self,
+        id: DbTableId,
+        definition: DbTable,
+        migration: Option<Vec<MigrationStep>>,
+    ) -> RegistrationReport;
+
+    /// Register a new definition for an existing table. The table is
+    /// reindexed, applying the migration to the value of every
+    /// version of every object. Migrated values must be valid for
+    /// the new definition.
+    async fn migrate_table(
+        &self,
+        id: DbTableId,
+        definition: DbTable,
+        migration: Vec<MigrationStep>,
+    );
+
     async fn unregister_table(&self, id: DbTableId);
 
+    /// Rebuild the in-memory state of a table from the database. The
+    /// table is not available while it is reloading.
+    async fn reload_table(&self, id: DbTableId);
+
     async fn get_table_ids(&self) -> HashSet<DbTableId>;
 
     async fn get_table_definitions(&self) -> HashMap<DbTableId, DbTable>;
 
     async fn get_table_definition(&self, id: DbTableId) -> DbTable;
 
+    /// Get the definition of a table as registered at the given time.
+    async fn get_table_definition_at(
+        &self,
+        id: DbTableId,
+        timestamp: DateTime<Utc>,
+    ) -> Option<DbTable>;
+
+    /// List all registered definitions of a table, oldest first.
+    async fn get_table_history(&self, id: DbTableId) -> Vec<TableRevision>;
+
+    /// Compare two definitions of a table, field by field.
+    async fn diff_table_definitions(&self, old: DbTable, new: DbTable) -> SchemaDiff;
+
     async fn verify_table_data_start(
         &self,
         table_id: DbTableId,
         range: Option<TimeRange>,
     ) -> VerificationId;
 
+    /// Verify a table and resolve overlapping versions by closing
+    /// the earlier version at the start of the later one. In a dry
+    /// run, the intended repairs are reported but not applied. Only
+    /// single-versioned tables can be repaired.
+    async fn repair_table_data_start(
+        &self,
+        table_id: DbTableId,
+        range: Option<TimeRange>,
+        dry_run: bool,
+    ) -> VerificationId;
+
     async fn verify_table_data_next(
         &self,
         verification_id: VerificationId,
     ) -> Option<Vec<VerificationMsg>>;
 
+    /// List the running and finished verification jobs. Jobs expire
+    /// when their messages are not retrieved for an hour.
+    async fn list_verifications(&self) -> Vec<VerificationJob>;
+
+    /// Stop a running verification job. The job stays listed as
+    /// cancelled until it expires.
+    async fn cancel_verification(&self, verification_id: VerificationId);
+
+    /// Write a table, including all versions, document ids and
+    /// versions and its definition, to an archive. Archives are named
+    /// files in the archive directory of the daemon.
+    async fn export_table(
+        &self,
+        id: DbTableId,
+        name: String,
+        format: ArchiveFormat,
+    ) -> ArchiveSummary;
+
+    /// Restore a table from an archive in the archive directory of
+    /// the daemon. The table is created if it does not exist;
+    /// otherwise, it must be empty and have the same definition. An
+    /// import that fails may leave a partially restored table behind.
+    async fn import_table(&self, name: String, format: ArchiveFormat) -> ArchiveSummary;
+
+    /// Compare the active (and current) versions held in memory for
+    /// a table with those stored in the database. With `fix`, the
+    /// in-memory state is replaced by the stored state.
+    async fn check_table_drift(&self, table_id: DbTableId, fix: bool) -> DriftReport;
+
+    /// Read the state of multiple discovery and config tables as of
+    /// a single instant. Without a timestamp, the current state is
+    /// read with all tables locked together, so that no transaction
+    /// is partially visible.
+    async fn read_snapshot(
+        &self,
+        tables: Vec<SnapshotQuery>,
+        timestamp: Option<DateTime<Utc>>,
+    ) -> HashMap<DbTableId, SnapshotTable>;
+
+    /* Change feeds. */
+
+    /// Start watching a table for changes. When `resume` is given,
+    /// events following the identified event are delivered first.
+    async fn watch_table_start(&self, table_id: DbTableId, resume: Option<ChangeToken>) -> WatchId;
+
+    /// Wait for the next changes. Returns an empty list if nothing
+    /// changed for a while. Watches that are not polled for ten
+    /// minutes expire.
+    async fn watch_table_next(&self, watch_id: WatchId) -> Vec<ChangeEvent>;
+
+    async fn watch_table_stop(&self, watch_id: WatchId);
+
+    /* Transactions. */
+
+    /// Apply operations on multiple tables, all or nothing.
+    async fn run_transaction(&self, transaction: Vec<TableTransaction>);
+
     /* Metric object (timestamped) manipulation. */
 
     async fn bulk_insert_timestamped_objects(&self, table_id: DbTableId, values: Vec<Value>);
 
+    /// Query a timestamped table for documents matching the filter,
+    /// with their timestamp in the given range. Results are sorted on
+    /// the given fields, or on the timestamp if no sort fields are
+    /// given. Further pages are retrieved with
+    /// `read_timestamped_objects_page`.
+    async fn query_timestamped_objects(
+        &self,
+        table_id: DbTableId,
+        filter: Filter,
+        range: TimeRange,
+        sort: Vec<SortField>,
+        options: PageOptions,
+    ) -> Page<Value>;
+
+    /// Retrieve the next page of a timestamped table query.
+    async fn read_timestamped_objects_page(&self, cursor: CursorId) -> Page<Value>;
+
+    /// Aggregate numeric fields of a timestamped table over time
+    /// buckets and, optionally, key fields. Only documents matching
+    /// the filter and with their timestamp in the range are included.
+    async fn aggregate_timestamped_objects(
+        &self,
+        table_id: DbTableId,
+        filter: Filter,
+        range: TimeRange,
+        aggregation: Aggregation,
+    ) -> Vec<AggregationBucket>;
+
     // async fn create_metric(
     //     &self,
     //     table_id: DbTableId,
@@ -127,6 +263,25 @@ pub trait BackendDbService {
 
     async fn remove_discovery_object(&self, table_id: DbTableId, object_id: ObjectId);
 
+    /* Compare-and-set variants: fail if the object was modified
+     * since `version` was read with `read_discovery_object_version`.
+     * Versions only change when a write reaches the database. */
+
+    async fn update_discovery_object_if(
+        &self,
+        table_id: DbTableId,
+        object_id: ObjectId,
+        value: Value,
+        version: VersionKey,
+    );
+
+    async fn remove_discovery_object_if(
+        &self,
+        table_id: DbTableId,
+        object_id: ObjectId,
+        version: VersionKey,
+    );
+
     async fn bulk_update_discovery_objects(
         &self,
         table_id: DbTableId,
@@ -145,6 +300,12 @@ pub trait BackendDbService {
         object_id: ObjectId,
     ) -> Option<SingleVersionedValue>;
 
+    async fn read_discovery_object_version(
+        &self,
+        table_id: DbTableId,
+        object_id: ObjectId,
+    ) -> VersionKey;
+
     async fn read_discovery_objects(
         &self,
         table_id: DbTableId,
@@ -192,6 +353,32 @@ pub trait BackendDbService {
         range: TimeRange,
     ) -> HashMap<ObjectId, Vec<SingleVersionedValue>>;
 
+    /// Paginated variant of `read_discovery_objects_history`. Returns
+    /// the first page; further pages are retrieved with
+    /// `read_discovery_objects_page`.
+    async fn read_discovery_objects_history_paged(
+        &self,
+        table_id: DbTableId,
+        object_ids: HashSet<ObjectId>,
+        range: TimeRange,
+        options: PageOptions,
+    ) -> Page<Identified<SingleVersionedValue>>;
+
+    /// Paginated variant of `query_discovery_objects_history`.
+    async fn query_discovery_objects_history_paged(
+        &self,
+        table_id: DbTableId,
+        filter: Filter,
+        range: TimeRange,
+        options: PageOptions,
+    ) -> Page<Identified<SingleVersionedValue>>;
+
+    /// Retrieve the next page of a paginated discovery object query.
+    async fn read_discovery_objects_page(
+        &self,
+        cursor: CursorId,
+    ) -> Page<Identified<SingleVersionedValue>>;
+
     async fn query_discovery_objects_at(
         &self,
         table_id: DbTableId,
@@ -234,8 +421,37 @@ pub trait BackendDbService {
 
     async fn remove_config_object(&self, table_id: DbTableId, object_id: ObjectId);
 
+    /* Compare-and-set variants: fail if the current version of the
+     * object was modified since `version` was read with
+     * `read_config_object_version`. Versions only change when a
+     * write reaches the database. */
+
+    async fn update_config_object_if(
+        &self,
+        table_id: DbTableId,
+        object_id: ObjectId,
+        value: Value,
+        commit: bool,
+        version: VersionKey,
+    );
+
+    async fn remove_config_object_if(
+        &self,
+        table_id: DbTableId,
+        object_id: ObjectId,
+        version: VersionKey,
+    );
+
     async fn activate_config_object(&self, table_id: DbTableId, object_id: ObjectId);
 
+    async fn bulk_update_config_objects(
+        &self,
+        table_id: DbTableId,
+        updates: HashMap<ObjectId, Operation>,
+        commit: bool,
+        activate: bool,
+    );
+
     async fn read_config_object(
         &self,
         table_id: DbTableId,
@@ -250,6 +466,12 @@ pub trait BackendDbService {
         timeline: Timeline,
     ) -> Option<DualVersionedValue>;
 
+    async fn read_config_object_version(
+        &self,
+        table_id: DbTableId,
+        object_id: ObjectId,
+    ) -> VersionKey;
+
     async fn read_config_objects(
         &self,
         table_id: DbTableId,
@@ -304,6 +526,37 @@ pub trait BackendDbService {
         range: TimeRange,
     ) -> HashMap<ObjectId, Vec<DualVersionedValue>>;
 
+    /// Paginated variant of `read_config_objects_history`. Returns
+    /// the first page; further pages are retrieved with
+    /// `read_config_objects_page`.
+    async fn read_config_objects_history_paged(
+        &self,
+        table_id: DbTableId,
+        object_ids: HashSet<ObjectId>,
+        timeline: Timeline,
+        range: TimeRange,
+        options: PageOptions,
+    ) -> Page<Identified<DualVersionedValue>>;
+
+    /// Paginated variant of `query_config_objects_history`.
+    async fn query_config_objects_history_paged(
+        &self,
+        table_id: DbTableId,
+        filter: Filter,
+        timeline: Timeline,
+        range: TimeRange,
+        options: PageOptions,
+    ) -> Page<Identified<DualVersionedValue>>;
+
+    /// Retrieve the next page of a paginated config object query.
+    async fn read_config_objects_page(
+        &self,
+        cursor: CursorId,
+    ) -> Page<Identified<DualVersionedValue>>;
+
+    /// Release a cursor before all pages were retrieved.
+    async fn close_cursor(&self, cursor: CursorId);
+
     async fn query_config_objects_at(
         &self,
         table_id: DbTableId,
@@ -356,15 +609,351 @@ impl Display for VerificationId {
     }
 }
 
+impl FromStr for VerificationId {
+    type Err = uuid::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub enum VerificationMsg {
     Overlap(Box<VersionProblem>),
     Gap(Box<VersionProblem>),
+    Repair(Box<VersionRepair>),
+    UncommittedActivation(Box<VersionRef>),
+    MultipleOpen(Box<OpenVersions>),
     Progress(u64),
     Error(String),
 }
 
+/// What a verification job does with the problems it finds.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum VerificationMode {
+    /// Only report problems.
+    Verify,
+    /// Report problems and the repairs that would resolve them.
+    DryRun,
+    /// Report and repair problems.
+    Repair,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum VerificationState {
+    Running,
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+/// The status of a verification job.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VerificationJob {
+    pub verification_id: VerificationId,
+    pub table_id: DbTableId,
+    pub range: Option<TimeRange>,
+    pub mode: VerificationMode,
+    pub state: VerificationState,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    /// The number of processed documents.
+    pub processed: u64,
+    /// The number of reported problems.
+    pub problems: u64,
+    /// The number of reported (or, in a dry run, intended) repairs.
+    pub repairs: u64,
+    pub error: Option<String>,
+}
+
+/// The outcome of a dry-run table registration.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegistrationReport {
+    pub verdict: RegistrationVerdict,
+    /// The reason the definition cannot be registered, if it cannot.
+    pub error: Option<String>,
+    /// The changed fields that require a reindex.
+    pub fields: Vec<FieldDiff>,
+    /// The number of stored documents, which a reindex would copy.
+    pub documents: u64,
+    /// The number of stored values that are invalid for the new
+    /// definition. Values are only checked when the table would need
+    /// a reindex or cannot be updated, after the migration if any.
+    pub invalid: u64,
+    /// Details on the first invalid values.
+    pub invalid_values: Vec<InvalidValue>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationVerdict {
+    /// The table does not exist and would be created.
+    New,
+    /// The definition is already registered.
+    Unchanged,
+    /// The table would be updated in place.
+    Compatible,
+    /// The table would be reindexed.
+    NeedsReindex,
+    /// The definition cannot be registered over the current one.
+    Incompatible,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InvalidValue {
+    /// The object the value belongs to, for versioned tables.
+    pub object_id: Option<ObjectId>,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    /// One JSON record per line.
+    Ndjson,
+    /// A sequence of CBOR records.
+    Cbor,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchiveSummary {
+    pub table_id: DbTableId,
+    /// The number of exported or imported documents.
+    pub documents: u64,
+}
+
+/// A registered definition of a table.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TableRevision {
+    pub from: DateTime<Utc>,
+    /// The time the definition was replaced, if it was.
+    pub to: Option<DateTime<Utc>>,
+    pub definition: DbTable,
+    /// Whether the table was reindexed when this definition was
+    /// registered. Not known for definitions registered before this
+    /// was recorded.
+    #[serde(default)]
+    pub reindexed: Option<bool>,
+    /// The migration applied when this definition was registered.
+    #[serde(default)]
+    pub migration: Option<Vec<MigrationStep>>,
+}
+
+/// The differences between two definitions of a table. Fields are
+/// given as dotted paths in the value.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct SchemaDiff {
+    pub added: Vec<FieldDiff>,
+    pub removed: Vec<FieldDiff>,
+    pub changed: Vec<FieldDiff>,
+    /// Whether the new definition can only be registered by
+    /// reindexing the table.
+    pub reindex: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FieldDiff {
+    pub field: String,
+    /// The type of the field in the old definition, if any.
+    pub old: Option<String>,
+    /// The type of the field in the new definition, if any.
+    pub new: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+pub struct WatchId(Uuid);
+
+impl WatchId {
+    // New definition involves randomness; not adding a `Default` instance!
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl Display for WatchId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+pub struct CursorId(Uuid);
+
+impl CursorId {
+    // New definition involves randomness; not adding a `Default` instance!
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl Display for CursorId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Pagination options for history queries.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct PageOptions {
+    /// The maximum number of items per page (at most 10000).
+    pub page_size: usize,
+    /// The number of seconds the cursor is kept open while waiting
+    /// for the next page to be requested (1 to 3600).
+    pub keep_alive: u64,
+}
+
+/// A page of query results. The cursor is set if more pages follow.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub cursor: Option<CursorId>,
+}
+
+/// A sort key for query results.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SortField {
+    /// The dotted path of the field in the document.
+    pub field: String,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// A time-bucketed aggregation on a timestamped table.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Aggregation {
+    /// The width of the time buckets, in seconds.
+    pub interval: u64,
+    /// Key fields to group on within each time bucket.
+    #[serde(default)]
+    pub group_by: Vec<String>,
+    /// The values to calculate for each bucket.
+    pub metrics: Vec<Metric>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Metric {
+    /// The dotted path of the field in the document.
+    pub field: String,
+    pub function: MetricFunction,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricFunction {
+    Avg,
+    Min,
+    Max,
+    Sum,
+    Count,
+    /// The given percentile (0-100).
+    Percentile(f64),
+}
+
+/// The result of an aggregation for one time bucket and group.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AggregationBucket {
+    /// The start of the time bucket.
+    pub timestamp: DateTime<Utc>,
+    /// The values of the group-by fields.
+    pub key: Vec<Value>,
+    /// The number of documents in the bucket.
+    pub count: u64,
+    /// The calculated values, in the order of the requested metrics.
+    /// Values are missing if the bucket has no values for the field.
+    pub values: Vec<Option<f64>>,
+}
+
+/// Identifies an event in the change feed of a table.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ChangeToken {
+    pub epoch: Uuid,
+    pub seq: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChangeEvent {
+    pub token: ChangeToken,
+    pub object_id: ObjectId,
+    pub kind: ChangeKind,
+    pub timestamp: DateTime<Utc>,
+    /// The new (current or active) value, if any.
+    pub value: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Removed,
+    Activated,
+}
+
+/// A table to include in a snapshot.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotQuery {
+    pub table_id: DbTableId,
+    /// Only include objects matching the filter.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+    /// The timeline to read for config tables. Defaults to the
+    /// active timeline.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeline: Option<Timeline>,
+}
+
+/// The state of a table in a snapshot.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotTable {
+    Discovery(HashMap<ObjectId, SingleVersionedValue>),
+    Config(HashMap<ObjectId, DualVersionedValue>),
+}
+
+/// Operations on a single table, as part of a multi-table transaction.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TableTransaction {
+    /// Operations on a discovery (single-versioned) table.
+    Discovery {
+        table_id: DbTableId,
+        updates: HashMap<ObjectId, Operation>,
+    },
+    /// Operations on a config (dual-versioned) table.
+    Config {
+        table_id: DbTableId,
+        updates: HashMap<ObjectId, Operation>,
+        commit: bool,
+        activate: bool,
+    },
+}
+
+impl TableTransaction {
+    pub fn table_id(&self) -> &DbTableId {
+        match self {
+            Self::Discovery { table_id, .. } | Self::Config { table_id, .. } => table_id,
+        }
+    }
+
+    pub fn updates(&self) -> &HashMap<ObjectId, Operation> {
+        match self {
+            Self::Discovery { updates, .. } | Self::Config { updates, .. } => updates,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct VersionProblem {
     pub object_id: ObjectId,
@@ -372,4 +961,101 @@ pub struct VersionProblem {
     pub cur_version_id: String,
     pub prev_to: Option<DateTime<Utc>>,
     pub cur_from: DateTime<Utc>,
+    /// The timeline on which the problem was found. Not set for
+    /// single-versioned tables.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeline: Option<Timeline>,
+}
+
+/// A step of a schema migration. Paths are lists of field names,
+/// starting from the root of the value. Steps on missing fields
+/// have no effect, since older versions may not have them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationStep {
+    /// Rename a field, keeping it in the same object.
+    Rename { path: Vec<String>, to: String },
+    /// Move a field to another path. Missing objects on the new path
+    /// are created.
+    Move { from: Vec<String>, to: Vec<String> },
+    /// Set a field that is missing or null.
+    Default { path: Vec<String>, value: Value },
+    /// Convert the value of a field to another type.
+    Convert { path: Vec<String>, to: Conversion },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Conversion {
+    String,
+    Integer,
+    Float,
+    Boolean,
+}
+
+/// The differences found between the in-memory state of a table and
+/// the database.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DriftReport {
+    pub differences: Vec<Drift>,
+    /// Whether the in-memory state was replaced.
+    pub fixed: bool,
+}
+
+/// An object whose version in memory differs from the database.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Drift {
+    pub object_id: ObjectId,
+    /// The timeline on which the versions differ. Not set for
+    /// single-versioned tables.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeline: Option<Timeline>,
+    pub memory: Option<VersionKey>,
+    pub database: Option<VersionKey>,
+}
+
+/// A version id with its document version. Also used as token for
+/// compare-and-set operations: the version is bumped by every write
+/// to the document.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct VersionKey {
+    pub version_id: String,
+    pub version: u64,
+}
+
+impl Display for VersionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.version_id, self.version)
+    }
+}
+
+/// A version of an object, e.g. an activated version that was never
+/// committed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VersionRef {
+    pub object_id: ObjectId,
+    pub version_id: String,
+}
+
+/// An object with more than one open version on a timeline.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpenVersions {
+    pub object_id: ObjectId,
+    pub timeline: Timeline,
+    pub version_ids: Vec<String>,
+}
+
+/// A version closed to resolve an overlap. Repairs reported by a dry
+/// run are not applied.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VersionRepair {
+    pub object_id: ObjectId,
+    pub version_id: String,
+    pub prev_to: Option<DateTime<Utc>>,
+    pub new_to: DateTime<Utc>,
+    pub applied: bool,
+    /// The versions were modified after they were read, so the
+    /// repair was not applied.
+    #[serde(default)]
+    pub conflict: bool,
 }
@@ -5,7 +5,13 @@
 mod backend;
 
 pub use backend::{
-    js_backend_db_service_stub, py_backend_db_service_stub, BackendDbHandler, BackendDbProto,
-    BackendDbRequest, BackendDbService, BackendDbServiceStub, DbClient, DbServer, VerificationId,
-    VerificationMsg, VersionProblem,
+    js_backend_db_service_stub, py_backend_db_service_stub, Aggregation, AggregationBucket,
+    ArchiveFormat, ArchiveSummary, BackendDbHandler, BackendDbProto, BackendDbRequest,
+    BackendDbService, BackendDbServiceStub, ChangeEvent, ChangeKind, ChangeToken, Conversion,
+    CursorId, DbClient, DbServer, Drift, DriftReport, FieldDiff, InvalidValue, Metric,
+    MetricFunction, MigrationStep, OpenVersions, Page, PageOptions, RegistrationReport,
+    RegistrationVerdict, SchemaDiff, SnapshotQuery, SnapshotTable, SortField, SortOrder,
+    TableRevision, TableTransaction, VerificationId, VerificationJob, VerificationMode,
+    VerificationMsg, VerificationState, VersionKey, VersionProblem, VersionRef, VersionRepair,
+    WatchId,
 };
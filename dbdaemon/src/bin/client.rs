@@ -2,18 +2,24 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::{convert::TryFrom, net::SocketAddr, path::PathBuf, sync::Arc, time::Instant};
+use std::{
+    collections::HashMap, convert::TryFrom, net::SocketAddr, path::PathBuf, sync::Arc,
+    time::Instant,
+};
 
 use chrono::{DateTime, Utc};
 use clap::Parser;
-use dbschema::{DbTableId, DualVersioned, HasSchema, HasTableDef, Identified, TimeRange, Timeline};
+use dbschema::{
+    DbTableId, DualVersioned, HasSchema, HasTableDef, Identified, ObjectId, TimeRange, Timeline,
+};
 use futures::{StreamExt, TryStreamExt};
 use rpc::GenericValue;
 use rustls::pki_types::ServerName;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use dbdaemon_api::{BackendDbProto, BackendDbServiceStub};
+use dbdaemon_api::{ArchiveFormat, BackendDbProto, BackendDbServiceStub, VerificationId};
+use dbdaemon_types::Operation;
 
 #[derive(Parser)]
 struct Args {
@@ -42,6 +48,22 @@ struct Args {
 #[derive(clap::Subcommand)]
 enum Command {
     VerifyTableData(VerifyArgs),
+    ListVerifications,
+    CancelVerification {
+        verification_id: VerificationId,
+    },
+    ReloadTable {
+        table_id: String,
+    },
+    CheckDrift {
+        table_id: String,
+        /// Replace the in-memory state by the stored state.
+        #[clap(long)]
+        fix: bool,
+    },
+    TableHistory {
+        table_id: String,
+    },
     Benchmark,
 }
 
@@ -52,6 +74,12 @@ struct VerifyArgs {
     from: Option<DateTime<Utc>>,
     #[clap(long)]
     to: Option<DateTime<Utc>>,
+    /// Resolve overlapping versions.
+    #[clap(long)]
+    repair: bool,
+    /// Report the repairs without applying them.
+    #[clap(long, requires = "repair")]
+    dry_run: bool,
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -101,6 +129,47 @@ async fn run(args: &Args) -> Result<()> {
 
     match &args.command {
         Command::VerifyTableData(verify_args) => verify_table_data(&client, verify_args).await,
+        Command::ListVerifications => list_verifications(&client).await,
+        Command::CancelVerification { verification_id } => client
+            .cancel_verification(*verification_id)
+            .await
+            .map_err(Error::DbDaemon),
+        Command::ReloadTable { table_id } => client
+            .reload_table(DbTableId::from_string(table_id.clone()))
+            .await
+            .map_err(Error::DbDaemon),
+        Command::CheckDrift { table_id, fix } => check_drift(&client, table_id, *fix).await,
+        Command::TableHistory { table_id } => table_history(&client, table_id).await,
+        Command::ExportTable {
+            table_id,
+            name,
+            cbor,
+        } => {
+            let summary = client
+                .export_table(
+                    DbTableId::from_string(table_id.clone()),
+                    name.clone(),
+                    archive_format(*cbor),
+                )
+                .await
+                .map_err(Error::DbDaemon)?;
+            eprintln!(
+                "Exported {} document(s) from table \"{}\"",
+                summary.documents, summary.table_id
+            );
+            Ok(())
+        }
+        Command::ImportTable { name, cbor } => {
+            let summary = client
+                .import_table(name.clone(), archive_format(*cbor))
+                .await
+                .map_err(Error::DbDaemon)?;
+            eprintln!(
+                "Imported {} document(s) into table \"{}\"",
+                summary.documents, summary.table_id
+            );
+            Ok(())
+        }
         Command::Benchmark => benchmark(&client).await,
     }
 }
@@ -112,18 +181,25 @@ async fn verify_table_data<
     client: &BackendDbServiceStub<C, V>,
     args: &VerifyArgs,
 ) -> Result<()> {
-    let proc_id = client
-        .verify_table_data_start(
-            DbTableId::from_string(args.table_id.clone()),
-            Some(TimeRange::new(args.from, args.to)),
-        )
-        .await
-        .map_err(Error::DbDaemon)?;
+    let table_id = DbTableId::from_string(args.table_id.clone());
+    let range = Some(TimeRange::new(args.from, args.to));
+    let proc_id = match args.repair {
+        true => {
+            client
+                .repair_table_data_start(table_id, range, args.dry_run)
+                .await
+        }
+        false => client.verify_table_data_start(table_id, range).await,
+    }
+    .map_err(Error::DbDaemon)?;
 
     eprintln!("Started verification of table \"{}\"", &args.table_id);
 
     let mut overlaps = 0;
     let mut reuses = 0;
+    let mut repairs = 0;
+    let mut uncommitted = 0;
+    let mut open = 0;
 
     while let Some(msgs) = client
         .verify_table_data_next(proc_id)
@@ -150,6 +226,39 @@ async fn verify_table_data<
                         p.object_id, p.prev_version_id, p.prev_to, p.cur_version_id, p.cur_from
                     );
                 }
+                dbdaemon_api::VerificationMsg::Repair(r) => {
+                    if !r.conflict {
+                        repairs += 1;
+                    }
+                    println!(
+                        "{} version {} of object {}: valid till {:?} instead of {:?}",
+                        match (r.applied, r.conflict) {
+                            (true, _) => "Repaired",
+                            (false, true) => "Skipped (modified concurrently)",
+                            (false, false) => "Would repair",
+                        },
+                        r.version_id,
+                        r.object_id,
+                        r.new_to,
+                        r.prev_to
+                    );
+                }
+                dbdaemon_api::VerificationMsg::UncommittedActivation(v) => {
+                    uncommitted += 1;
+                    println!(
+                        "Found activated version {} of object {} that was never committed",
+                        v.version_id, v.object_id
+                    );
+                }
+                dbdaemon_api::VerificationMsg::MultipleOpen(o) => {
+                    open += 1;
+                    println!(
+                        "Found multiple open versions of object {} on the {:?} timeline: {}",
+                        o.object_id,
+                        o.timeline,
+                        o.version_ids.join(", ")
+                    );
+                }
                 dbdaemon_api::VerificationMsg::Progress(n) => {
                     eprintln!("Processed {n} docs...");
                 }
@@ -162,7 +271,144 @@ async fn verify_table_data<
 
     eprintln!("Finished verification of table \"{}\"", &args.table_id);
     eprintln!("Found {overlaps} overlapping version(s), {reuses} reused object id(s)");
+    if uncommitted > 0 || open > 0 {
+        eprintln!(
+            "Found {uncommitted} uncommitted activation(s), \
+             {open} object(s) with multiple open versions"
+        );
+    }
+    if args.repair {
+        eprintln!("Repairs: {repairs}");
+    }
+
+    Ok(())
+}
+
+async fn list_verifications<
+    C: rpc::RequestHandler<BackendDbProto, V, ExtraArgs = ()>,
+    V: GenericValue,
+>(
+    client: &BackendDbServiceStub<C, V>,
+) -> Result<()> {
+    for job in client.list_verifications().await.map_err(Error::DbDaemon)? {
+        println!(
+            "{} {} ({:?}, from {:?} to {:?}): {:?} since {}, \
+             {} doc(s), {} problem(s), {} repair(s)",
+            job.verification_id,
+            job.table_id,
+            job.mode,
+            job.range.as_ref().and_then(|range| range.from),
+            job.range.as_ref().and_then(|range| range.to),
+            job.state,
+            job.finished.unwrap_or(job.started),
+            job.processed,
+            job.problems,
+            job.repairs
+        );
+        if let Some(e) = &job.error {
+            println!("\tError: {e}");
+        }
+    }
+    Ok(())
+}
+
+async fn check_drift<C: rpc::RequestHandler<BackendDbProto, V, ExtraArgs = ()>, V: GenericValue>(
+    client: &BackendDbServiceStub<C, V>,
+    table_id: &str,
+    fix: bool,
+) -> Result<()> {
+    let report = client
+        .check_table_drift(DbTableId::from_string(table_id.to_string()), fix)
+        .await
+        .map_err(Error::DbDaemon)?;
+    for drift in &report.differences {
+        println!(
+            "Object {}{}: {} in memory, {} in the database",
+            drift.object_id,
+            drift
+                .timeline
+                .map_or_else(String::new, |timeline| format!(" ({timeline:?})")),
+            drift.memory.as_ref().map_or_else(
+                || "none".to_string(),
+                |v| format!("{} (version {})", v.version_id, v.version)
+            ),
+            drift.database.as_ref().map_or_else(
+                || "none".to_string(),
+                |v| format!("{} (version {})", v.version_id, v.version)
+            ),
+        );
+    }
+    eprintln!(
+        "Found {} difference(s) in table \"{table_id}\"{}",
+        report.differences.len(),
+        if report.fixed { "; reloaded" } else { "" }
+    );
+    Ok(())
+}
+
+fn archive_format(cbor: bool) -> ArchiveFormat {
+    match cbor {
+        true => ArchiveFormat::Cbor,
+        false => ArchiveFormat::Ndjson,
+    }
+}
 
+async fn table_history<
+    C: rpc::RequestHandler<BackendDbProto, V, ExtraArgs = ()>,
+    V: GenericValue,
+>(
+    client: &BackendDbServiceStub<C, V>,
+    table_id: &str,
+) -> Result<()> {
+    let revisions = client
+        .get_table_history(DbTableId::from_string(table_id.to_string()))
+        .await
+        .map_err(Error::DbDaemon)?;
+    let mut previous = None;
+    for revision in revisions {
+        println!(
+            "Revision from {}{}{}",
+            revision.from,
+            revision
+                .to
+                .map_or_else(String::new, |to| format!(" to {to}")),
+            match (revision.reindexed, &revision.migration) {
+                (_, Some(_)) => " (migrated)",
+                (Some(true), None) => " (reindexed)",
+                (Some(false), None) => "",
+                (None, None) => " (reindex not recorded)",
+            }
+        );
+        if let Some(old) = previous.take() {
+            let diff = client
+                .diff_table_definitions(old, revision.definition.clone())
+                .await
+                .map_err(Error::DbDaemon)?;
+            for field in &diff.added {
+                println!(
+                    "  + {}: {}",
+                    field.field,
+                    field.new.as_deref().unwrap_or("?")
+                );
+            }
+            for field in &diff.removed {
+                println!(
+                    "  - {}: {}",
+                    field.field,
+                    field.old.as_deref().unwrap_or("?")
+                );
+            }
+            for field in &diff.changed {
+                println!(
+                    "  ~ {}: {} -> {}",
+                    field.field,
+                    field.old.as_deref().unwrap_or("?"),
+                    field.new.as_deref().unwrap_or("?")
+                );
+            }
+        }
+        previous = Some(revision.definition);
+    }
     Ok(())
 }
 
@@ -311,23 +557,54 @@ async fn benchmark<C: rpc::RequestHandler<BackendDbProto, V, ExtraArgs = ()>, V:
         start.elapsed().as_millis() as f64 / 1000.
     );
 
-    // let start = Instant::now();
-    // eprintln!("Removing objects (bulk request)...");
-
-    // client
-    //     .bulk_update_discovery_objects(
-    //         test_table.clone(),
-    //         objs.into_keys()
-    //             .map(|object_id| (object_id, Operation::Remove))
-    //             .collect(),
-    //     )
-    //     .await
-    //     .map_err(Error::DbDaemon)?;
-
-    // eprintln!(
-    //     "Took {:.3} seconds",
-    //     start.elapsed().as_millis() as f64 / 1000.
-    // );
+    /* Create and activate objects (bulk request). */
+
+    let start = Instant::now();
+    eprintln!("Creating {NOBJS} objects (bulk request)...");
+
+    let objs = (0..NOBJS)
+        .map(|i| {
+            let value = serde_json::to_value(Test {
+                test: format!("{i}"),
+            })
+            .unwrap();
+            (ObjectId::new(), Operation::Create(value))
+        })
+        .collect::<HashMap<_, _>>();
+    let object_ids = objs.keys().cloned().collect::<Vec<_>>();
+
+    client
+        .bulk_update_config_objects(test_table.clone(), objs, true, true)
+        .await
+        .map_err(Error::DbDaemon)?;
+
+    eprintln!(
+        "Took {:.3} seconds",
+        start.elapsed().as_millis() as f64 / 1000.
+    );
+
+    /* Remove objects (bulk request). */
+
+    let start = Instant::now();
+    eprintln!("Removing objects (bulk request)...");
+
+    client
+        .bulk_update_config_objects(
+            test_table.clone(),
+            object_ids
+                .into_iter()
+                .map(|object_id| (object_id, Operation::Remove))
+                .collect(),
+            true,
+            true,
+        )
+        .await
+        .map_err(Error::DbDaemon)?;
+
+    eprintln!(
+        "Took {:.3} seconds",
+        start.elapsed().as_millis() as f64 / 1000.
+    );
 
     Ok(())
 }
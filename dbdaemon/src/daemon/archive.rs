@@ -0,0 +1,248 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    io::BufReader,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{fs::File, io::AsyncWriteExt, sync::mpsc};
+
+use dbdaemon_api::{ArchiveFormat, ArchiveSummary};
+use dbschema::{DbTable, DbTableId, Filter};
+
+use crate::database::{elastic::ElasticId, Database};
+
+use super::error::{Error, Result};
+use super::state::State;
+
+/// The number of records read ahead of the import.
+const READ_AHEAD: usize = 1000;
+
+/// A table archive starts with a header, followed by the stored
+/// documents. Documents keep their id and external version, so that
+/// timelines are restored identically.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveEntry {
+    Header(ArchiveHeader),
+    Doc {
+        id: ElasticId,
+        version: u64,
+        doc: Value,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchiveHeader {
+    pub table_id: DbTableId,
+    pub definition: DbTable,
+}
+
+/// Reads the documents of an archive in batches. The file is decoded
+/// by a blocking task, which stops when the reader is dropped.
+pub struct ArchiveReader {
+    path: PathBuf,
+    receiver: mpsc::Receiver<std::result::Result<ArchiveEntry, String>>,
+}
+
+/// Resolve an archive name to a path in the archive directory. Only
+/// plain relative paths are accepted, so that clients cannot read or
+/// write files outside of the directory.
+pub(super) fn resolve(dir: Option<&Path>, name: &str) -> Result<PathBuf> {
+    let dir = dir.ok_or(Error::ArchivesDisabled)?;
+    let path = Path::new(name);
+    match path.components().all(|c| matches!(c, Component::Normal(_)))
+        && path.components().next().is_some()
+    {
+        true => Ok(dir.join(path)),
+        false => Err(Error::InvalidArchiveName(name.to_string())),
+    }
+}
+
+/// Write a table to an archive.
+pub(super) async fn export<D: Database<Id = ElasticId>>(
+    database: &D,
+    state: &State,
+    table_id: &DbTableId,
+    path: &Path,
+    format: ArchiveFormat,
+) -> Result<ArchiveSummary> {
+    /* Writes hold a shared lock until they are written to the
+     * database, so none are in flight under the exclusive lock. The
+     * point in time taken under it is a consistent snapshot of the
+     * table. The lock is then downgraded, so that the table can be
+     * written to, but not reindexed or unregistered, while the
+     * snapshot is exported. */
+    let table = state.lock_table_state(table_id).await?;
+    let mapping = &table.read(table_id)?.mapping;
+    let definition = mapping.table.clone();
+    let schema = mapping.table_schema.clone();
+    let sort = mapping.sort_fields.clone();
+    let filter = Filter::All(vec![]);
+
+    database.refresh_table(table_id).await?;
+    let (mut docs, mut next) = database
+        .query_objects_first::<Value>(
+            table_id,
+            &schema,
+            &filter,
+            &sort,
+            Duration::from_secs(60),
+            None,
+        )
+        .await?;
+    let _table = table.downgrade();
+
+    let header = ArchiveEntry::Header(ArchiveHeader {
+        table_id: table_id.clone(),
+        definition,
+    });
+    let mut file = match create(path, format, &header).await {
+        Ok(file) => file,
+        Err(e) => return Err(close(database, next, e).await),
+    };
+
+    let mut documents = 0;
+    loop {
+        documents += docs.len() as u64;
+        if let Err(e) = write(&mut file, path, format, docs).await {
+            return Err(close(database, next, e).await);
+        }
+        match next {
+            Some(query_state) => (docs, next) = database.query_objects_next(query_state).await?,
+            None => break,
+        }
+    }
+
+    file.sync_all()
+        .await
+        .map_err(|e| Error::WriteArchive(path.to_path_buf(), e))?;
+    Ok(ArchiveSummary {
+        table_id: table_id.clone(),
+        documents,
+    })
+}
+
+async fn create(path: &Path, format: ArchiveFormat, header: &ArchiveEntry) -> Result<File> {
+    let mut file = File::create(path)
+        .await
+        .map_err(|e| Error::WriteArchive(path.to_path_buf(), e))?;
+    file.write_all(&encode(format, header)?)
+        .await
+        .map_err(|e| Error::WriteArchive(path.to_path_buf(), e))?;
+    Ok(file)
+}
+
+async fn write(
+    file: &mut File,
+    path: &Path,
+    format: ArchiveFormat,
+    docs: Vec<(ElasticId, u64, Value)>,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    for (id, version, doc) in docs {
+        buf.extend(encode(format, &ArchiveEntry::Doc { id, version, doc })?);
+    }
+    file.write_all(&buf)
+        .await
+        .map_err(|e| Error::WriteArchive(path.to_path_buf(), e))
+}
+
+/// Close an unfinished query after an error.
+async fn close<D: Database>(
+    database: &D,
+    query_state: Option<D::QueryState<'_>>,
+    error: Error,
+) -> Error {
+    if let Some(query_state) = query_state {
+        if let Err(e) = database.query_objects_close(query_state).await {
+            log::warn!("failed to close query: {e}");
+        }
+    }
+    error
+}
+
+impl ArchiveReader {
+    /// Open an archive and read its header.
+    pub async fn open(path: &Path, format: ArchiveFormat) -> Result<(ArchiveHeader, Self)> {
+        let file = File::open(path)
+            .await
+            .map_err(|e| Error::ReadArchive(path.to_path_buf(), e))?
+            .into_std()
+            .await;
+        let (sender, receiver) = mpsc::channel(READ_AHEAD);
+
+        tokio::task::spawn_blocking(move || {
+            let reader = BufReader::new(file);
+            match format {
+                ArchiveFormat::Ndjson => {
+                    for entry in serde_json::Deserializer::from_reader(reader).into_iter() {
+                        if sender
+                            .blocking_send(entry.map_err(|e| e.to_string()))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                }
+                ArchiveFormat::Cbor => {
+                    for entry in serde_cbor::Deserializer::from_reader(reader).into_iter() {
+                        if sender
+                            .blocking_send(entry.map_err(|e| e.to_string()))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        let mut reader = Self {
+            path: path.to_path_buf(),
+            receiver,
+        };
+        match reader.receiver.recv().await {
+            Some(Ok(ArchiveEntry::Header(header))) => Ok((header, reader)),
+            Some(Err(e)) => Err(reader.error(e)),
+            _ => Err(reader.error(String::from("missing header"))),
+        }
+    }
+
+    /// Read the next batch of documents. Returns an empty batch at
+    /// the end of the archive.
+    pub async fn next_batch(&mut self, size: usize) -> Result<Vec<(ElasticId, u64, Value)>> {
+        let mut batch = Vec::with_capacity(size);
+        while batch.len() < size {
+            match self.receiver.recv().await {
+                Some(Ok(ArchiveEntry::Doc { id, version, doc })) => batch.push((id, version, doc)),
+                Some(Ok(ArchiveEntry::Header(_))) => {
+                    return Err(self.error(String::from("unexpected header")))
+                }
+                Some(Err(e)) => return Err(self.error(e)),
+                None => break,
+            }
+        }
+        Ok(batch)
+    }
+
+    fn error(&self, msg: String) -> Error {
+        Error::ArchiveFormat(self.path.clone(), msg)
+    }
+}
+
+fn encode(format: ArchiveFormat, entry: &ArchiveEntry) -> Result<Vec<u8>> {
+    match format {
+        ArchiveFormat::Ndjson => {
+            let mut data = serde_json::to_vec(entry)?;
+            data.push(b'\n');
+            Ok(data)
+        }
+        ArchiveFormat::Cbor => Ok(serde_cbor::to_vec(entry)?),
+    }
+}
@@ -0,0 +1,286 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use dbschema::{DbTableId, ObjectId};
+use parking_lot::Mutex;
+use serde_json::Value;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use dbdaemon_api::{ChangeEvent, ChangeKind, ChangeToken};
+
+use super::error::{Error, Result};
+
+/// In-memory log of the most recent changes to a table. Events are
+/// numbered sequentially within an epoch, which is chosen randomly
+/// when the table is loaded, so that tokens from a previous run are
+/// recognized as expired.
+///
+/// Sequence numbers are reserved when a change is committed to the
+/// in-memory state, under the data lock, but the change is only
+/// published after it is written to the database. Events are
+/// delivered in the order of their sequence numbers, so that clients
+/// see changes in the order they were committed. Reserved numbers of
+/// changes that are rolled back are skipped.
+#[derive(Debug)]
+pub struct ChangeLog {
+    epoch: Uuid,
+    events: Mutex<ChangeLogEvents>,
+    notify: Notify,
+}
+
+#[derive(Debug)]
+struct ChangeLogEvents {
+    /// The next sequence number to reserve.
+    next_seq: u64,
+    /// All sequence numbers before this one are published or
+    /// cancelled.
+    published_seq: u64,
+    /// The first sequence number that is still in the log.
+    first_seq: u64,
+    events: VecDeque<ChangeEvent>,
+    /// Changes waiting for changes with an earlier sequence number.
+    /// Cancelled changes are `None`.
+    pending: BTreeMap<u64, Option<ChangeEvent>>,
+    /// Set when the log is replaced, after changes that were not
+    /// recorded as events.
+    expired: bool,
+}
+
+/// A change, before it is published.
+#[derive(Debug)]
+pub struct Change {
+    pub object_id: ObjectId,
+    pub kind: ChangeKind,
+    pub timestamp: DateTime<Utc>,
+    pub value: Option<Value>,
+}
+
+/// Changes committed to the in-memory state, with their reserved
+/// sequence numbers. Changes that are dropped without being
+/// published are cancelled, so that they do not hold back later
+/// events.
+#[derive(Debug)]
+pub struct PendingChanges {
+    changes: Arc<ChangeLog>,
+    pending: Vec<(u64, Change)>,
+}
+
+/// A client's position in the change log of a table.
+pub struct Watch {
+    pub table_id: DbTableId,
+    changes: Arc<ChangeLog>,
+    next_seq: u64,
+    last_access: Instant,
+}
+
+impl ChangeLog {
+    /// The number of events kept for resuming clients.
+    const CAPACITY: usize = 10000;
+
+    // New definition involves randomness; not adding a `Default` instance!
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            epoch: Uuid::new_v4(),
+            events: Mutex::new(ChangeLogEvents {
+                next_seq: 0,
+                published_seq: 0,
+                first_seq: 0,
+                events: VecDeque::new(),
+                pending: BTreeMap::new(),
+                expired: false,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Publish changes that were not reserved in advance. The
+    /// caller must hold a lock that excludes concurrent commits.
+    pub fn publish(self: &Arc<Self>, changes: Vec<Change>) {
+        let mut pending = PendingChanges::new(self.clone());
+        changes.into_iter().for_each(|change| pending.push(change));
+        pending.publish();
+    }
+
+    fn reserve(&self) -> u64 {
+        let mut log = self.events.lock();
+        let seq = log.next_seq;
+        log.next_seq += 1;
+        seq
+    }
+
+    /// Resolve reserved sequence numbers, with the event to publish
+    /// or `None` for cancelled changes.
+    fn resolve<I>(&self, changes: I)
+    where
+        I: IntoIterator<Item = (u64, Option<Change>)>,
+    {
+        let published = {
+            let mut log = self.events.lock();
+            for (seq, change) in changes {
+                let event = change.map(|change| ChangeEvent {
+                    token: ChangeToken {
+                        epoch: self.epoch,
+                        seq,
+                    },
+                    object_id: change.object_id,
+                    kind: change.kind,
+                    timestamp: change.timestamp,
+                    value: change.value,
+                });
+                log.pending.insert(seq, event);
+            }
+
+            let published = log.published_seq;
+            loop {
+                let seq = log.published_seq;
+                let Some(event) = log.pending.remove(&seq) else {
+                    break;
+                };
+                log.published_seq += 1;
+                log.events.extend(event);
+            }
+            while log.events.len() > Self::CAPACITY {
+                if let Some(event) = log.events.pop_front() {
+                    log.first_seq = event.token.seq + 1;
+                }
+            }
+            log.published_seq > published
+        };
+
+        if published {
+            self.notify.notify_waiters();
+        }
+    }
+
+    /// Expire all tokens and watches on this log. Used when the
+    /// table is replaced in a way that is not reported as changes,
+    /// so that clients resynchronize.
+    pub fn expire(&self) {
+        self.events.lock().expired = true;
+        self.notify.notify_waiters();
+    }
+
+    /// Whether all events following `token` are still available.
+    fn resumable(&self, token: &ChangeToken) -> bool {
+        let log = self.events.lock();
+        !log.expired
+            && token.epoch == self.epoch
+            && token.seq < log.published_seq
+            && token.seq + 1 >= log.first_seq
+    }
+
+    /// The published events from sequence number `seq` on.
+    fn since(&self, seq: u64, limit: usize) -> Option<Vec<ChangeEvent>> {
+        let log = self.events.lock();
+        (!log.expired && seq >= log.first_seq).then(|| {
+            let start = log.events.partition_point(|event| event.token.seq < seq);
+            log.events.range(start..).take(limit).cloned().collect()
+        })
+    }
+}
+
+impl PendingChanges {
+    pub fn new(changes: Arc<ChangeLog>) -> Self {
+        Self {
+            changes,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Add a change and reserve its sequence number. Must be called
+    /// while the change is committed, with the data lock held.
+    pub fn push(&mut self, change: Change) {
+        self.pending.push((self.changes.reserve(), change));
+    }
+
+    pub fn publish(mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        self.changes
+            .resolve(pending.into_iter().map(|(seq, change)| (seq, Some(change))));
+    }
+
+    /// Cancel the changes, after a rollback.
+    pub fn cancel(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let pending = std::mem::take(&mut self.pending);
+        self.changes
+            .resolve(pending.into_iter().map(|(seq, _)| (seq, None)));
+    }
+}
+
+impl Drop for PendingChanges {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+impl Watch {
+    /// Watches that are not polled for this long are considered
+    /// abandoned by their client.
+    pub const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+    /// Start watching a table, optionally resuming after the event
+    /// identified by `resume`.
+    pub fn new(
+        table_id: DbTableId,
+        changes: Arc<ChangeLog>,
+        resume: Option<ChangeToken>,
+    ) -> Result<Self> {
+        let next_seq = match resume {
+            Some(token) if changes.resumable(&token) => token.seq + 1,
+            Some(_) => return Err(Error::ChangeTokenExpired(table_id)),
+            None => changes.events.lock().published_seq,
+        };
+        Ok(Self {
+            table_id,
+            changes,
+            next_seq,
+            last_access: Instant::now(),
+        })
+    }
+
+    /// Wait for the next events. Returns an empty list if no events
+    /// arrived within `timeout`.
+    pub async fn next(&mut self, limit: usize, timeout: Duration) -> Result<Vec<ChangeEvent>> {
+        let changes = self.changes.clone();
+        let notified = changes.notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let mut events = self.poll(limit)?;
+        if events.is_empty() && tokio::time::timeout(timeout, notified).await.is_ok() {
+            events = self.poll(limit)?;
+        }
+        self.last_access = Instant::now();
+        Ok(events)
+    }
+
+    /// Returns true if the watch was not polled within the idle
+    /// timeout.
+    pub fn is_idle(&self) -> bool {
+        self.last_access.elapsed() > Self::IDLE_TIMEOUT
+    }
+
+    fn poll(&mut self, limit: usize) -> Result<Vec<ChangeEvent>> {
+        let events = self
+            .changes
+            .since(self.next_seq, limit)
+            .ok_or_else(|| Error::ChangeTokenExpired(self.table_id.clone()))?;
+        if let Some(event) = events.last() {
+            self.next_seq = event.token.seq + 1;
+        }
+        Ok(events)
+    }
+}
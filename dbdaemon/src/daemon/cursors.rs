@@ -0,0 +1,199 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+use dbdaemon_api::PageOptions;
+use dbschema::{DbSchema, DbTableId, DualVersionedValue, Filter, Identified, SingleVersionedValue};
+
+use crate::database::{elastic::ElasticId, Database};
+
+use super::error::Result;
+
+/// The maximum number of items per page.
+const MAX_PAGE_SIZE: usize = 10000;
+/// The keep-alive time of a cursor is clamped to this range.
+const MIN_KEEP_ALIVE: Duration = Duration::from_secs(1);
+const MAX_KEEP_ALIVE: Duration = Duration::from_secs(3600);
+
+/// A page of results and whether more pages follow.
+type PageMsg<T> = Result<(Vec<T>, bool)>;
+
+/// An open cursor over the results of a database query. The query is
+/// run by a background task, which prepares the next page while the
+/// client processes the current one. The task stops when the client
+/// does not request a page within the keep-alive time, or when the
+/// cursor is dropped.
+pub struct Cursor<T> {
+    receiver: mpsc::Receiver<PageMsg<T>>,
+    keep_alive: Duration,
+    last_access: Instant,
+}
+
+/// A cursor for a table of any versioning type.
+pub enum AnyCursor {
+    SingleVersioned(Cursor<Identified<SingleVersionedValue>>),
+    DualVersioned(Cursor<Identified<DualVersionedValue>>),
+    Timestamped(Cursor<Value>),
+}
+
+/// Items that can be returned by a cursor.
+pub trait CursorItem: DeserializeOwned + Send + Sync + Sized + 'static {
+    fn wrap(cursor: Cursor<Self>) -> AnyCursor;
+    fn unwrap(cursor: &mut AnyCursor) -> Option<&mut Cursor<Self>>;
+}
+
+impl<T: CursorItem> Cursor<T> {
+    pub fn start<D: Database<Id = ElasticId> + 'static>(
+        database: Arc<D>,
+        table_id: DbTableId,
+        schema: DbSchema,
+        filter: Filter,
+        sort: Value,
+        options: PageOptions,
+    ) -> Self {
+        let page_size = options.page_size.clamp(1, MAX_PAGE_SIZE);
+        let keep_alive =
+            Duration::from_secs(options.keep_alive).clamp(MIN_KEEP_ALIVE, MAX_KEEP_ALIVE);
+        let (sender, receiver) = mpsc::channel(1);
+
+        tokio::spawn(async move {
+            /* The point in time must outlive the wait for the client
+             * and the prefetch of the next page. */
+            let pit_keep_alive = keep_alive.saturating_mul(2);
+            let mut buffer = VecDeque::new();
+
+            let mut next = match database
+                .query_objects_first::<T>(&table_id, &schema, &filter, &sort, pit_keep_alive, None)
+                .await
+            {
+                Ok((docs, next)) => {
+                    buffer.extend(docs.into_iter().map(|(_, _, doc)| doc));
+                    next
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e.into())).await;
+                    return;
+                }
+            };
+
+            loop {
+                while buffer.len() < page_size {
+                    let Some(state) = next.take() else {
+                        break;
+                    };
+                    match database.query_objects_next::<T>(state).await {
+                        Ok((docs, state)) => {
+                            buffer.extend(docs.into_iter().map(|(_, _, doc)| doc));
+                            next = state;
+                        }
+                        Err(e) => {
+                            let _ = sender.send(Err(e.into())).await;
+                            return;
+                        }
+                    }
+                }
+
+                let page = buffer
+                    .drain(..page_size.min(buffer.len()))
+                    .collect::<Vec<_>>();
+                let more = !buffer.is_empty() || next.is_some();
+                match tokio::time::timeout(keep_alive, sender.send(Ok((page, more)))).await {
+                    Ok(Ok(())) if more => continue,
+                    _ => break,
+                }
+            }
+
+            /* Release the point in time of an abandoned cursor. */
+            if let Some(state) = next {
+                if let Err(e) = database.query_objects_close(state).await {
+                    log::warn!("failed to close cursor query: {e}");
+                }
+            }
+        });
+
+        Self {
+            receiver,
+            keep_alive,
+            last_access: Instant::now(),
+        }
+    }
+
+    /// Wait for the next page. Returns `None` if the cursor has
+    /// expired.
+    pub async fn next(&mut self) -> Option<PageMsg<T>> {
+        if self.last_access.elapsed() > self.keep_alive {
+            return None;
+        }
+        self.last_access = Instant::now();
+        self.receiver.recv().await
+    }
+}
+
+impl<T> Cursor<T> {
+    /// Returns true if the cursor has no more pages to deliver, or
+    /// if the client did not request a page within the keep-alive
+    /// time. Pages prefetched for abandoned cursors are never read.
+    pub fn is_finished(&self) -> bool {
+        (self.receiver.is_closed() && self.receiver.is_empty())
+            || self.last_access.elapsed() > self.keep_alive
+    }
+}
+
+impl AnyCursor {
+    pub fn is_finished(&self) -> bool {
+        match self {
+            Self::SingleVersioned(cursor) => cursor.is_finished(),
+            Self::DualVersioned(cursor) => cursor.is_finished(),
+            Self::Timestamped(cursor) => cursor.is_finished(),
+        }
+    }
+}
+
+impl CursorItem for Identified<SingleVersionedValue> {
+    fn wrap(cursor: Cursor<Self>) -> AnyCursor {
+        AnyCursor::SingleVersioned(cursor)
+    }
+
+    fn unwrap(cursor: &mut AnyCursor) -> Option<&mut Cursor<Self>> {
+        match cursor {
+            AnyCursor::SingleVersioned(cursor) => Some(cursor),
+            _ => None,
+        }
+    }
+}
+
+impl CursorItem for Identified<DualVersionedValue> {
+    fn wrap(cursor: Cursor<Self>) -> AnyCursor {
+        AnyCursor::DualVersioned(cursor)
+    }
+
+    fn unwrap(cursor: &mut AnyCursor) -> Option<&mut Cursor<Self>> {
+        match cursor {
+            AnyCursor::DualVersioned(cursor) => Some(cursor),
+            _ => None,
+        }
+    }
+}
+
+impl CursorItem for Value {
+    fn wrap(cursor: Cursor<Self>) -> AnyCursor {
+        AnyCursor::Timestamped(cursor)
+    }
+
+    fn unwrap(cursor: &mut AnyCursor) -> Option<&mut Cursor<Self>> {
+        match cursor {
+            AnyCursor::Timestamped(cursor) => Some(cursor),
+            _ => None,
+        }
+    }
+}
@@ -2,12 +2,23 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::ops::{Deref, DerefMut};
+use std::{
+    collections::HashSet,
+    ops::{Deref, DerefMut},
+};
 
 use chrono::{DateTime, Utc};
 use dbschema::DbSchema;
 
-use super::{table_read::TableReadGuard, updates::UpdateGuard};
+use crate::database::elastic::ElasticId;
+
+use super::{
+    dual_versioned_data::DualVersionedTransaction,
+    single_versioned_data::SingleVersionedTransaction,
+    table_data::TableData,
+    table_read::TableReadGuard,
+    updates::{AnyUpdateGuard, UpdateGuard},
+};
 
 pub struct DataWriteGuard<'a, T> {
     state: &'a TableReadGuard<'a>,
@@ -15,15 +26,35 @@ pub struct DataWriteGuard<'a, T> {
     transaction: T,
 }
 
+/// Pending transaction on a table of any versioning type.
+pub enum AnyDataWriteGuard<'a> {
+    SingleVersioned(DataWriteGuard<'a, SingleVersionedTransaction<'a>>),
+    DualVersioned(DataWriteGuard<'a, DualVersionedTransaction<'a>>),
+}
+
 pub trait Transaction<'a>: Sized {
     type Value;
+    type Staged: Staged + Send + Sync + 'static;
     fn commit(
         self,
         now: DateTime<Utc>,
         force_update: bool,
         value_schema: &DbSchema,
         updates: &mut UpdateGuard<'a, Self::Value>,
-    );
+    ) -> Self::Staged;
+}
+
+/// Changes to the in-memory state of a table made by a committed
+/// transaction. They are only applied once the transaction has been
+/// written to the database, so that readers never see unwritten
+/// changes.
+pub trait Staged {
+    /// Install the new state of the modified objects.
+    fn apply(self: Box<Self>, data: &mut TableData);
+    /// Keep the previous state of the modified objects after the
+    /// write failed. Documents in `touched` were (possibly) written
+    /// to the database and get a version past the compensating write.
+    fn revert(self: Box<Self>, data: &mut TableData, touched: &HashSet<ElasticId>);
 }
 
 impl<'a, T: Transaction<'a>> DataWriteGuard<'a, T> {
@@ -37,16 +68,26 @@ impl<'a, T: Transaction<'a>> DataWriteGuard<'a, T> {
 
     pub fn commit(self) -> UpdateGuard<'a, T::Value> {
         let mut updates = UpdateGuard::new(self.state);
-        self.transaction.commit(
+        let staged = self.transaction.commit(
             self.now,
             self.state.mapping.table.force_update,
             &self.state.mapping.value_schema,
             &mut updates,
         );
+        updates.stage(staged);
         updates
     }
 }
 
+impl<'a> AnyDataWriteGuard<'a> {
+    pub fn commit(self) -> AnyUpdateGuard<'a> {
+        match self {
+            Self::SingleVersioned(data) => AnyUpdateGuard::SingleVersioned(data.commit()),
+            Self::DualVersioned(data) => AnyUpdateGuard::DualVersioned(data.commit()),
+        }
+    }
+}
+
 impl<T> Deref for DataWriteGuard<'_, T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
@@ -3,8 +3,10 @@
  ******************************************************************************/
 
 use std::{
-    collections::{hash_map::Entry, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
 use chrono::{DateTime, Utc};
@@ -15,129 +17,438 @@ use tokio::sync::Mutex as AsyncMutex;
 use tracing::instrument;
 
 use dbschema::{
-    Anchor, Compatibility, DbTableId, DualVersionedValue, Filter, FilterPath, Identified, ObjectId,
-    SingleVersionedValue, TimeRange, Timeline,
+    Compatibility, DbTable, DbTableId, DualVersionedValue, Filter, FilterPath, Identified,
+    ObjectId, SingleVersionedValue, TimeRange, Timeline, VersioningType,
 };
 
-use crate::database::{backend::Database, elastic::ElasticId};
-use dbdaemon_api::{BackendDbService, VerificationId, VerificationMsg, VersionProblem};
+use crate::database::{elastic::ElasticId, Database, Transform, TIMESTAMP_FIELD};
+use dbdaemon_api::{
+    Aggregation, AggregationBucket, ArchiveFormat, ArchiveSummary, BackendDbService, ChangeEvent,
+    ChangeToken, CursorId, DriftReport, MigrationStep, Page, PageOptions, RegistrationReport,
+    SchemaDiff, SnapshotQuery, SnapshotTable, SortField, TableRevision, TableTransaction,
+    VerificationId, VerificationJob, VerificationMode, VerificationMsg, VersionKey, WatchId,
+};
 use dbdaemon_types::Operation;
 
-#[cfg(feature = "elastic")]
-use crate::database::elastic;
-// #[cfg(feature = "mariadb")]
-// use crate::database::mariadb;
-
 use super::{
-    filters::range_filter,
-    schema_table::TableInfo,
+    archive::{self, ArchiveReader},
+    changes::Watch,
+    cursors::{AnyCursor, Cursor, CursorItem},
+    data_write::AnyDataWriteGuard,
+    drift,
+    filters::{history_filter_dual, history_filter_single, range_filter, timestamp_filter},
+    journal::Journal,
+    migration::Migration,
+    registration,
+    retention::{self, RetentionConfig},
+    schema_diff,
+    schema_table::{SchemaChange, SchemaDocument, TableInfo, SCHEMA_TABLE},
+    snapshot,
     state::State,
     table_mapping::TableMapping,
     table_state::{TableNonOperationalState, TableOperationalState},
+    updates::MultiUpdateGuard,
+    verification::{self, VerificationWorker},
     Error,
 };
 
-pub struct DbDaemon {
-    elastic: Arc<elastic::Database>,
-    state: State,
-    verification: RwLock<
-        HashMap<VerificationId, Arc<AsyncMutex<tokio::sync::mpsc::Receiver<VerificationMsg>>>>,
-    >,
+/// The database daemon. The daemon core is generic over the database
+/// backend, so that it can be embedded with any `Database`
+/// implementation.
+pub struct DbDaemon<D> {
+    database: Arc<D>,
+    journal: Arc<Journal>,
+    state: Arc<State>,
+    verification: RwLock<HashMap<VerificationId, Arc<VerificationWorker>>>,
+    watches: RwLock<HashMap<WatchId, Arc<AsyncMutex<Watch>>>>,
+    cursors: RwLock<HashMap<CursorId, Arc<AsyncMutex<AnyCursor>>>>,
+    archive_dir: Option<PathBuf>,
 }
 
-impl DbDaemon {
-    pub async fn new(config: elastic::DatabaseConfig) -> Result<DbDaemon, Error> {
-        let elastic = Arc::new(elastic::Database::new(config).await?);
-        let state = State::load(&elastic).await?;
-        Ok(DbDaemon {
-            elastic,
-            state,
+impl<D: Database<Id = ElasticId> + 'static> DbDaemon<D> {
+    pub async fn new(database: D, journal: Option<&Path>) -> Result<Self, Error> {
+        let journal = match journal {
+            Some(path) => Journal::open(path).await?,
+            None => Journal::disabled(),
+        };
+        let state = State::load(&database, &journal).await?;
+        Ok(Self {
+            database: Arc::new(database),
+            journal: Arc::new(journal),
+            state: Arc::new(state),
             verification: RwLock::new(HashMap::new()),
+            watches: RwLock::new(HashMap::new()),
+            cursors: RwLock::new(HashMap::new()),
+            archive_dir: None,
         })
     }
-}
 
-impl BackendDbService for DbDaemon {
-    type Error = Error;
-
-    /* Setup and configuration. */
+    /// Enable table export and import, with archives stored in the
+    /// given directory.
+    pub fn set_archive_dir(&mut self, dir: PathBuf) {
+        self.archive_dir = Some(dir);
+    }
 
-    #[instrument(skip(self))]
-    async fn wait_for_databases(&self) -> Result<(), Error> {
-        self.elastic.wait_for_database().await?;
-        Ok(())
+    /// Start a background task enforcing the retention policies at
+    /// the given interval.
+    pub fn start_retention(&self, config: RetentionConfig, interval: Duration) {
+        tokio::spawn(retention::run(
+            self.database.clone(),
+            self.state.clone(),
+            config,
+            interval,
+        ));
     }
 
-    #[instrument(skip(self))]
-    async fn verify_databases(&self) -> Result<(), Error> {
-        self.elastic.verify_database().await?;
-        Ok(())
+    /// Start a background task checking all tables for drift between
+    /// the in-memory state and the database at the given interval.
+    pub fn start_drift_check(&self, interval: Duration, fix: bool) {
+        tokio::spawn(drift::run(
+            self.database.clone(),
+            self.state.clone(),
+            interval,
+            fix,
+        ));
     }
 
-    /* Schema manipulation. */
+    /// Start a verification worker on a table.
+    async fn start_verification(
+        &self,
+        table_id: DbTableId,
+        range: Option<TimeRange>,
+        mode: VerificationMode,
+    ) -> Result<VerificationId, Error> {
+        /* Forget about jobs that were abandoned by their clients. */
+        self.verification
+            .write()
+            .retain(|_, worker| !worker.expire());
 
-    #[instrument(skip(self))]
-    async fn register_table(
+        let verification_id = VerificationId::new();
+        let table = self
+            .state
+            .read_table_owned(table_id.clone(), "verify_table_data")
+            .await?;
+        let job_range = range
+            .as_ref()
+            .map(|range| TimeRange::new(range.from, range.to));
+
+        let worker = match &table.mapping.table.versioning {
+            VersioningType::SingleTimeline => {
+                let database = self.database.clone();
+                let journal = self.journal.clone();
+                VerificationWorker::spawn(table_id, job_range, mode, |sender| {
+                    verification::verify_single_versioned(
+                        database, journal, table, range, mode, sender,
+                    )
+                })
+            }
+            VersioningType::DualTimeline => {
+                if mode != VerificationMode::Verify {
+                    return Err(Error::NotYetImplented(
+                        "repair of dual-versioned tables".to_string(),
+                    ));
+                }
+                let database = self.database.clone();
+                VerificationWorker::spawn(table_id, job_range, mode, |sender| {
+                    verification::verify_dual_versioned(database, table, range, sender)
+                })
+            }
+            VersioningType::Timestamped => {
+                return Err(Error::NoTimeline("verify_table_data", table_id));
+            }
+        };
+
+        self.verification
+            .write()
+            .insert(verification_id, Arc::new(worker));
+        Ok(verification_id)
+    }
+
+    /// Register a table definition, creating, updating or reindexing
+    /// the table as needed. Tables are always reindexed when a
+    /// migration is given.
+    async fn register(
         &self,
-        table_id: dbschema::DbTableId,
-        definition: dbschema::DbTable,
-    ) -> Result<(), Self::Error> {
+        table_id: DbTableId,
+        definition: DbTable,
+        migration: Option<Vec<MigrationStep>>,
+    ) -> Result<(), Error> {
         let (schemas, mut table) = self
             .state
             .write_table(
                 &table_id,
                 "register_table",
                 TableNonOperationalState::Registering,
-                true,
+                migration.is_none(),
             )
             .await?;
 
-        let updated = match table.as_mut() {
-            Some(table) if table.mapping.table == definition => None,
-            Some(table) => match definition.verify_compatibility(&table.mapping.table)? {
+        let updated = match (table.as_mut(), migration) {
+            (Some(table), Some(steps)) => {
+                /* Migrated documents are checked against the new value
+                 * schema before the reindexed table is put in place. */
+                let migration = Migration::new(steps.clone(), &definition);
+                let transform: &Transform = &|doc| {
+                    migration
+                        .apply_doc(doc)
+                        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+                };
+                self.database
+                    .reindex_table(
+                        &table_id,
+                        &table.mapping.table,
+                        &definition,
+                        Some(transform),
+                    )
+                    .await?;
+
+                /* Values have changed, so reload the data. The
+                 * changes are not reported as events, so the change
+                 * log is replaced and the old one expired, so that
+                 * watching clients resynchronize. */
+                let loaded =
+                    TableOperationalState::load(self.database.as_ref(), &table_id, definition)
+                        .await?;
+                table.mapping = loaded.mapping;
+                table.data = loaded.data;
+                table.changes.expire();
+                table.changes = loaded.changes;
+                let change = SchemaChange {
+                    reindexed: true,
+                    migration: Some(steps),
+                };
+                Some((table, change))
+            }
+            (Some(table), None) if table.mapping.table == definition => None,
+            (Some(table), None) => match definition.verify_compatibility(&table.mapping.table)? {
                 Compatibility::Compatible => {
-                    self.elastic.update_table(&table_id, &definition).await?;
+                    self.database.update_table(&table_id, &definition).await?;
                     table.mapping = TableMapping::new(definition);
-                    Some(table)
+                    let change = SchemaChange {
+                        reindexed: false,
+                        migration: None,
+                    };
+                    Some((table, change))
                 }
                 Compatibility::NeedsReindex => {
-                    self.elastic
-                        .reindex_table(&table_id, &table.mapping.table, &definition)
+                    self.database
+                        .reindex_table(&table_id, &table.mapping.table, &definition, None)
                         .await?;
                     table.mapping = TableMapping::new(definition);
-                    Some(table)
+                    let change = SchemaChange {
+                        reindexed: true,
+                        migration: None,
+                    };
+                    Some((table, change))
                 }
             },
-            None => {
-                if let Err(e) = self.elastic.create_table(&table_id, &definition).await {
+            (None, Some(_)) => return Err(Error::TableNotFound(table_id.clone())),
+            (None, None) => {
+                if let Err(e) = self.database.create_table(&table_id, &definition).await {
                     log::warn!("Failed to create table for new schema: {e}");
                 }
 
                 let state =
-                    TableOperationalState::load(&self.elastic, &table_id, definition).await?;
+                    TableOperationalState::load(self.database.as_ref(), &table_id, definition)
+                        .await?;
 
-                Some(table.or_insert_with(|| state))
+                let change = SchemaChange {
+                    reindexed: false,
+                    migration: None,
+                };
+                Some((table.or_insert_with(|| state), change))
             }
         };
 
-        if let Some(updated) = updated {
+        if let Some((updated, change)) = updated {
             let object_id = ObjectId::from(table_id.to_string());
             let new_value = serde_json::to_value(TableInfo {
                 schema: updated.mapping.table.clone(),
+                change: Some(change),
             })?;
 
+            let writer = schemas.lock_writes().await;
             let updates = {
-                let mut data = schemas.write_data_single_versioned(Utc::now())?;
+                let mut data = writer.write_data_single_versioned(Utc::now())?;
                 data.insert(&object_id, new_value);
                 data.commit()
             };
 
-            updates.run(&self.elastic).await?;
+            updates.run(self.database.as_ref(), &self.journal).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The registered definitions of a table within a time range,
+    /// oldest first.
+    async fn schema_history(
+        &self,
+        table_id: &DbTableId,
+        range: TimeRange,
+    ) -> Result<Vec<SchemaDocument>, Error> {
+        let schemas = self
+            .state
+            .read_table(SCHEMA_TABLE, "get_table_history")
+            .await?;
+        let filter = FilterPath::new()
+            .field("object_id")
+            .eq(json!(table_id.to_string()))
+            .and(history_filter_single(range));
+        let mut revisions = self
+            .database
+            .query_objects::<SchemaDocument>(
+                SCHEMA_TABLE,
+                &schemas.mapping.table_schema,
+                &filter,
+                &schemas.mapping.sort_fields,
+                None,
+            )
+            .await?
+            .into_iter()
+            .map(|(_id, _version, doc)| doc)
+            .collect::<Vec<_>>();
+        revisions.sort_by_key(|doc| doc.value.version.active.from);
+        Ok(revisions)
+    }
+
+    /// The name of the database backend in use.
+    pub fn whoami(&self) -> String {
+        self.database.whoami()
+    }
+
+    /// Open a cursor over the documents matching the filter and
+    /// return the first page.
+    async fn open_cursor<T: CursorItem>(
+        &self,
+        table_id: &DbTableId,
+        mapping: &TableMapping,
+        filter: Filter,
+        sort: Value,
+        options: PageOptions,
+    ) -> Result<Page<T>, Error> {
+        /* Forget about cursors that were abandoned by their clients. */
+        self.cursors.write().retain(|_, cursor| {
+            cursor
+                .try_lock()
+                .map_or(true, |cursor| !cursor.is_finished())
+        });
+
+        let cursor_id = CursorId::new();
+        let mut cursor = Cursor::<T>::start(
+            self.database.clone(),
+            table_id.clone(),
+            mapping.table_schema.clone(),
+            filter,
+            sort,
+            options,
+        );
+
+        let (items, more) = cursor
+            .next()
+            .await
+            .ok_or(Error::NoSuchCursor(cursor_id))??;
+        if more {
+            self.cursors
+                .write()
+                .insert(cursor_id, Arc::new(AsyncMutex::new(T::wrap(cursor))));
+        }
+
+        Ok(Page {
+            items,
+            cursor: more.then_some(cursor_id),
+        })
+    }
+
+    /// Retrieve the next page from a cursor.
+    async fn next_page<T: CursorItem>(&self, cursor_id: CursorId) -> Result<Page<T>, Error> {
+        let cursor = self
+            .cursors
+            .read()
+            .get(&cursor_id)
+            .ok_or(Error::NoSuchCursor(cursor_id))?
+            .clone();
+        let mut cursor = cursor.lock().await;
+        let page = T::unwrap(&mut cursor)
+            .ok_or(Error::CursorType(cursor_id))?
+            .next()
+            .await;
+
+        match page {
+            Some(Ok((items, true))) => Ok(Page {
+                items,
+                cursor: Some(cursor_id),
+            }),
+            page => {
+                self.cursors.write().remove(&cursor_id);
+                let (items, _) = page.ok_or(Error::NoSuchCursor(cursor_id))??;
+                Ok(Page {
+                    items,
+                    cursor: None,
+                })
+            }
         }
+    }
+}
+
+impl<D: Database<Id = ElasticId> + 'static> BackendDbService for DbDaemon<D> {
+    type Error = Error;
+
+    /* Setup and configuration. */
+
+    #[instrument(skip(self))]
+    async fn wait_for_databases(&self) -> Result<(), Error> {
+        self.database.wait_for_database().await?;
+        Ok(())
+    }
 
+    #[instrument(skip(self))]
+    async fn verify_databases(&self) -> Result<(), Error> {
+        self.database.verify_database().await?;
         Ok(())
     }
 
+    /* Schema manipulation. */
+
+    #[instrument(skip(self))]
+    async fn register_table(
+        &self,
+        table_id: dbschema::DbTableId,
+        definition: dbschema::DbTable,
+    ) -> Result<(), Self::Error> {
+        self.register(table_id, definition, None).await
+    }
+
+    #[instrument(skip(self))]
+    async fn check_table_registration(
+        &self,
+        table_id: DbTableId,
+        definition: DbTable,
+        migration: Option<Vec<MigrationStep>>,
+    ) -> Result<RegistrationReport, Self::Error> {
+        registration::check(
+            self.database.as_ref(),
+            &self.state,
+            &table_id,
+            &definition,
+            migration,
+        )
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn migrate_table(
+        &self,
+        table_id: DbTableId,
+        definition: DbTable,
+        migration: Vec<MigrationStep>,
+    ) -> Result<(), Self::Error> {
+        if !self.state.0.read().contains_key(&table_id) {
+            return Err(Error::TableNotFound(table_id));
+        }
+        self.register(table_id, definition, Some(migration)).await
+    }
+
     /// Warning: this removes all table data!
     /* TODO: Remove in release builds? */
     #[instrument(skip(self))]
@@ -153,19 +464,169 @@ impl BackendDbService for DbDaemon {
             .await?;
         if table.as_mut().is_some() {
             let object_id = ObjectId::from(table_id.to_string());
+            let writer = schemas.lock_writes().await;
             let updates = {
-                let mut data = schemas.write_data_single_versioned(Utc::now())?;
+                let mut data = writer.write_data_single_versioned(Utc::now())?;
                 data.remove(&object_id);
                 data.commit()
             };
 
-            updates.run(&self.elastic).await?;
-            self.elastic.remove_table(&table_id).await?;
+            updates.run(self.database.as_ref(), &self.journal).await?;
+            self.database.remove_table(&table_id).await?;
             table.remove();
         }
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    async fn export_table(
+        &self,
+        table_id: DbTableId,
+        name: String,
+        format: ArchiveFormat,
+    ) -> Result<ArchiveSummary, Self::Error> {
+        let path = archive::resolve(self.archive_dir.as_deref(), &name)?;
+        archive::export(
+            self.database.as_ref(),
+            &self.state,
+            &table_id,
+            &path,
+            format,
+        )
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn import_table(
+        &self,
+        name: String,
+        format: ArchiveFormat,
+    ) -> Result<ArchiveSummary, Self::Error> {
+        const BATCH_SIZE: usize = 1000;
+
+        let path = archive::resolve(self.archive_dir.as_deref(), &name)?;
+        let (header, mut reader) = ArchiveReader::open(&path, format).await?;
+        let table_id = header.table_id;
+        let definition = header.definition;
+
+        if !self.state.0.read().contains_key(&table_id) {
+            self.register(table_id.clone(), definition.clone(), None)
+                .await?;
+        }
+
+        let (_schemas, mut table) = self
+            .state
+            .write_table(
+                &table_id,
+                "import_table",
+                TableNonOperationalState::Importing,
+                false,
+            )
+            .await?;
+        let table = table
+            .as_mut()
+            .ok_or_else(|| Error::TableNotFound(table_id.clone()))?;
+
+        if table.mapping.table != definition {
+            return Err(Error::DefinitionMismatch(table_id.clone()));
+        }
+
+        /* Make recent writes visible to the emptiness check. */
+        self.database.refresh_table(&table_id).await?;
+        let existing = self
+            .database
+            .query_objects::<Value>(
+                &table_id,
+                &table.mapping.table_schema,
+                &Filter::All(vec![]),
+                &table.mapping.sort_fields,
+                Some(1),
+            )
+            .await?;
+        if !existing.is_empty() {
+            return Err(Error::TableNotEmpty(table_id.clone()));
+        }
+
+        /* Documents are written with their original ids and external
+         * versions, bypassing the in-memory state, which is reloaded
+         * afterwards. The archive is read in batches, so that memory
+         * use does not depend on the size of the table. */
+        let mut documents = 0;
+        let imported = async {
+            loop {
+                let batch = reader.next_batch(BATCH_SIZE).await?;
+                if batch.is_empty() {
+                    break;
+                }
+                let len = batch.len() as u64;
+                self.database
+                    .bulk_update(&table_id, &table.mapping.table_schema, batch)
+                    .await?;
+                documents += len;
+            }
+            Ok::<_, Error>(())
+        }
+        .await;
+
+        /* The table is reloaded even if the import failed midway, so
+         * that the in-memory state includes the documents that were
+         * written. */
+        if let Err(e) = &imported {
+            log::warn!("import into table {table_id} failed after {documents} document(s): {e}");
+        }
+
+        let loaded = TableOperationalState::load(
+            self.database.as_ref(),
+            &table_id,
+            table.mapping.table.clone(),
+        )
+        .await?;
+        let changes = table
+            .data
+            .get_mut()
+            .replace(loaded.data.into_inner(), Utc::now());
+        table.mapping = loaded.mapping;
+        table.changes.publish(changes);
+
+        imported.map_err(|e| Error::PartialImport(table_id.clone(), documents, Box::new(e)))?;
+        Ok(ArchiveSummary {
+            table_id: table_id.clone(),
+            documents,
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn reload_table(&self, table_id: DbTableId) -> Result<(), Self::Error> {
+        let (_schemas, mut table) = self
+            .state
+            .write_table(
+                &table_id,
+                "reload_table",
+                TableNonOperationalState::Reloading,
+                false,
+            )
+            .await?;
+        let table = table
+            .as_mut()
+            .ok_or_else(|| Error::TableNotFound(table_id.clone()))?;
+        let loaded = TableOperationalState::load(
+            self.database.as_ref(),
+            &table_id,
+            table.mapping.table.clone(),
+        )
+        .await?;
+
+        /* Keep the change log, so that watches continue, and report
+         * the differences with the previous state as changes. */
+        let changes = table
+            .data
+            .get_mut()
+            .replace(loaded.data.into_inner(), Utc::now());
+        table.mapping = loaded.mapping;
+        table.changes.publish(changes);
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     async fn get_table_ids(
         &self,
@@ -198,123 +659,70 @@ impl BackendDbService for DbDaemon {
         Ok(table.mapping.table.clone())
     }
 
-    async fn verify_table_data_start(
+    #[instrument(skip(self))]
+    async fn get_table_definition_at(
         &self,
-        table_id: DbTableId,
-        range: Option<TimeRange>,
-    ) -> Result<VerificationId, Self::Error> {
-        let verification_id = VerificationId::new();
-        let (sender, receiver) = tokio::sync::mpsc::channel(10);
-
-        let table = self
-            .state
-            .read_table_owned(table_id.clone(), "verify_table_data")
-            .await?;
-
-        tokio::spawn({
-            macro_rules! send_msg {
-                ($sender:ident, $msg:expr) => {
-                    if $sender.send($msg).await.is_err() {
-                        return;
-                    }
-                };
-            }
-
-            macro_rules! handle_err {
-                ($sender:ident, $expr:expr) => {
-                    match $expr {
-                        Ok(r) => r,
-                        Err(e) => {
-                            send_msg!($sender, VerificationMsg::Error(e.to_string()));
-                            return;
-                        }
-                    }
-                };
-            }
+        id: DbTableId,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Option<DbTable>, Self::Error> {
+        Ok(self
+            .schema_history(&id, TimeRange::at(timestamp))
+            .await?
+            .pop()
+            .map(|doc| doc.value.value.schema))
+    }
 
-            let elastic = self.elastic.clone();
-
-            async move {
-                let mut objects = HashMap::<ObjectId, (String, Option<DateTime<Utc>>)>::new();
-                let sort = json!([{ "@active.from": { "order": "asc"} }]);
-                let filter = match range {
-                    Some(range) => FilterPath::new()
-                        .field("value")
-                        .field("version")
-                        .field("active")
-                        .filter(range_filter(range)),
-                    None => Filter::All(Vec::new()),
+    #[instrument(skip(self))]
+    async fn get_table_history(&self, id: DbTableId) -> Result<Vec<TableRevision>, Self::Error> {
+        Ok(self
+            .schema_history(&id, TimeRange::new(None, None))
+            .await?
+            .into_iter()
+            .map(|doc| {
+                let info = doc.value.value;
+                let (reindexed, migration) = match info.change {
+                    Some(change) => (Some(change.reindexed), change.migration),
+                    None => (None, None),
                 };
+                TableRevision {
+                    from: doc.value.version.active.from,
+                    to: doc.value.version.active.to,
+                    definition: info.schema,
+                    reindexed,
+                    migration,
+                }
+            })
+            .collect())
+    }
 
-                let mut n = 0;
-                let (mut docs, mut next) = handle_err!(
-                    sender,
-                    elastic
-                        .query_objects_first::<Identified<SingleVersionedValue>>(
-                            &table_id,
-                            &table.mapping.table_schema,
-                            &filter,
-                            &sort,
-                            std::time::Duration::from_secs(60),
-                            Some(1000),
-                        )
-                        .await
-                );
-
-                loop {
-                    n += docs.len();
-                    for (elastic_id, _version, doc) in docs {
-                        let Anchor { from, to, .. } = doc.value.version.active;
-                        match objects.entry(doc.object_id.clone()) {
-                            Entry::Occupied(mut ent) => {
-                                let (prev_elastic_id, prev_to) = ent.get();
-                                let version_problem = || VersionProblem {
-                                    object_id: doc.object_id.clone(),
-                                    prev_version_id: prev_elastic_id.to_string(),
-                                    cur_version_id: elastic_id.to_string(),
-                                    prev_to: *prev_to,
-                                    cur_from: from,
-                                };
-                                if prev_to.as_ref().is_none_or(|prev_to| prev_to > &from) {
-                                    send_msg!(
-                                        sender,
-                                        VerificationMsg::Overlap(Box::new(version_problem()))
-                                    );
-                                } else if prev_to
-                                    .as_ref()
-                                    .is_some_and(|prev_to| prev_to < &doc.value.version.active.from)
-                                {
-                                    send_msg!(
-                                        sender,
-                                        VerificationMsg::Gap(Box::new(version_problem()))
-                                    );
-                                }
-
-                                ent.insert((elastic_id.to_string(), to));
-                            }
-                            Entry::Vacant(ent) => {
-                                ent.insert((elastic_id.to_string(), to));
-                            }
-                        }
-                    }
-
-                    send_msg!(sender, VerificationMsg::Progress(n as u64));
+    async fn diff_table_definitions(
+        &self,
+        old: DbTable,
+        new: DbTable,
+    ) -> Result<SchemaDiff, Self::Error> {
+        schema_diff::diff(&old, &new)
+    }
 
-                    match next {
-                        Some(query_state) => {
-                            (docs, next) =
-                                handle_err!(sender, elastic.query_objects_next(query_state).await);
-                        }
-                        None => break,
-                    }
-                }
-            }
-        });
+    async fn verify_table_data_start(
+        &self,
+        table_id: DbTableId,
+        range: Option<TimeRange>,
+    ) -> Result<VerificationId, Self::Error> {
+        self.start_verification(table_id, range, VerificationMode::Verify)
+            .await
+    }
 
-        self.verification
-            .write()
-            .insert(verification_id, Arc::new(AsyncMutex::new(receiver)));
-        Ok(verification_id)
+    async fn repair_table_data_start(
+        &self,
+        table_id: DbTableId,
+        range: Option<TimeRange>,
+        dry_run: bool,
+    ) -> Result<VerificationId, Self::Error> {
+        let mode = match dry_run {
+            true => VerificationMode::DryRun,
+            false => VerificationMode::Repair,
+        };
+        self.start_verification(table_id, range, mode).await
     }
 
     async fn verify_table_data_next(
@@ -322,15 +730,165 @@ impl BackendDbService for DbDaemon {
         verification_id: VerificationId,
     ) -> Result<Option<Vec<VerificationMsg>>, Self::Error> {
         const BATCH_SIZE: usize = 10;
-        let receiver = self
+        let worker = self
             .verification
             .read()
             .get(&verification_id)
             .ok_or_else(|| Error::NoSuchVerificationWorker(verification_id))?
             .clone();
-        let mut msgs = Vec::with_capacity(BATCH_SIZE);
-        let n = receiver.lock().await.recv_many(&mut msgs, BATCH_SIZE).await;
-        Ok((n > 0).then_some(msgs))
+        let msgs = worker.next(BATCH_SIZE).await;
+        Ok((!msgs.is_empty()).then_some(msgs))
+    }
+
+    async fn list_verifications(&self) -> Result<Vec<VerificationJob>, Self::Error> {
+        let mut verification = self.verification.write();
+        verification.retain(|_, worker| !worker.expire());
+        Ok(verification
+            .iter()
+            .map(|(verification_id, worker)| worker.info(*verification_id))
+            .collect())
+    }
+
+    async fn cancel_verification(
+        &self,
+        verification_id: VerificationId,
+    ) -> Result<(), Self::Error> {
+        self.verification
+            .read()
+            .get(&verification_id)
+            .ok_or_else(|| Error::NoSuchVerificationWorker(verification_id))?
+            .cancel();
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn check_table_drift(
+        &self,
+        table_id: DbTableId,
+        fix: bool,
+    ) -> Result<DriftReport, Self::Error> {
+        drift::check(self.database.as_ref(), &self.state, &table_id, fix).await
+    }
+
+    #[instrument(skip(self))]
+    async fn read_snapshot(
+        &self,
+        tables: Vec<SnapshotQuery>,
+        timestamp: Option<DateTime<Utc>>,
+    ) -> Result<HashMap<DbTableId, SnapshotTable>, Self::Error> {
+        snapshot::read(self.database.as_ref(), &self.state, tables, timestamp).await
+    }
+
+    /* Change feeds. */
+
+    #[instrument(skip(self))]
+    async fn watch_table_start(
+        &self,
+        table_id: DbTableId,
+        resume: Option<ChangeToken>,
+    ) -> Result<WatchId, Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "watch_table_start")
+            .await?;
+        let watch = Watch::new(table_id.clone(), table.changes.clone(), resume)?;
+
+        /* Forget about watches that were abandoned by their clients. */
+        self.watches
+            .write()
+            .retain(|_, watch| watch.try_lock().map_or(true, |watch| !watch.is_idle()));
+
+        let watch_id = WatchId::new();
+        self.watches
+            .write()
+            .insert(watch_id, Arc::new(AsyncMutex::new(watch)));
+        Ok(watch_id)
+    }
+
+    #[instrument(skip(self))]
+    async fn watch_table_next(&self, watch_id: WatchId) -> Result<Vec<ChangeEvent>, Self::Error> {
+        const BATCH_SIZE: usize = 1000;
+        const TIMEOUT: Duration = Duration::from_secs(30);
+        let watch = self
+            .watches
+            .read()
+            .get(&watch_id)
+            .ok_or(Error::NoSuchWatch(watch_id))?
+            .clone();
+        let mut watch = watch.lock().await;
+        watch.next(BATCH_SIZE, TIMEOUT).await
+    }
+
+    #[instrument(skip(self))]
+    async fn watch_table_stop(&self, watch_id: WatchId) -> Result<(), Self::Error> {
+        self.watches
+            .write()
+            .remove(&watch_id)
+            .ok_or(Error::NoSuchWatch(watch_id))?;
+        Ok(())
+    }
+
+    /* Transactions. */
+
+    #[instrument(skip(self))]
+    async fn run_transaction(&self, transaction: Vec<TableTransaction>) -> Result<(), Self::Error> {
+        let mut transactions = BTreeMap::new();
+        for table in transaction {
+            let table_id = table.table_id().clone();
+            if transactions.insert(table_id.clone(), table).is_some() {
+                return Err(Error::DuplicateTable(table_id));
+            }
+        }
+
+        let table_ids = transactions.keys().cloned().collect::<BTreeSet<_>>();
+        let tables = self
+            .state
+            .read_tables(&table_ids, "run_transaction")
+            .await?;
+
+        tables
+            .iter()
+            .zip(transactions.values())
+            .try_for_each(|(table, transaction)| {
+                table
+                    .mapping
+                    .verify_operations(transaction.updates().values())
+            })?;
+
+        /* Lock all tables for writing (in order) before committing,
+         * so that no changes are made unless all operations apply. */
+        let mut writers = Vec::with_capacity(tables.len());
+        for table in &tables {
+            writers.push(table.lock_writes().await);
+        }
+
+        let updates = {
+            let now = Utc::now();
+            let data = writers
+                .iter()
+                .zip(transactions.into_values())
+                .map(|(table, transaction)| match transaction {
+                    TableTransaction::Discovery { table_id, updates } => {
+                        let mut data = table.write_data_single_versioned(now)?;
+                        data.apply(&table_id, updates)?;
+                        Ok(AnyDataWriteGuard::SingleVersioned(data))
+                    }
+                    TableTransaction::Config {
+                        table_id,
+                        updates,
+                        commit,
+                        activate,
+                    } => {
+                        let mut data = table.write_data_dual_versioned(now)?;
+                        data.apply(&table_id, updates, commit, activate)?;
+                        Ok(AnyDataWriteGuard::DualVersioned(data))
+                    }
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            MultiUpdateGuard::new(data.into_iter().map(AnyDataWriteGuard::commit).collect())
+        };
+
+        updates.run(self.database.as_ref(), &self.journal).await
     }
 
     /* Metric (timestamped) data manipulation. */
@@ -354,7 +912,7 @@ impl BackendDbService for DbDaemon {
 
         /* Prepare bulk request */
 
-        self.elastic
+        self.database
             .bulk_update(
                 &table_id,
                 &table.mapping.table_schema,
@@ -365,6 +923,76 @@ impl BackendDbService for DbDaemon {
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    async fn query_timestamped_objects(
+        &self,
+        table_id: DbTableId,
+        filter: Filter,
+        range: TimeRange,
+        sort: Vec<SortField>,
+        options: PageOptions,
+    ) -> Result<Page<Value>, Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "query_timestamped_objects")
+            .await?;
+        if !matches!(table.mapping.table.versioning, VersioningType::Timestamped) {
+            return Err(Error::NotATimestampedTable(
+                "query_timestamped_objects",
+                table_id,
+            ));
+        }
+
+        let sort = match sort.is_empty() {
+            true => json!([{ TIMESTAMP_FIELD: { "order": "asc" } }]),
+            false => table.mapping.sort_spec(&table_id, &sort)?,
+        };
+        let filter = filter.and(timestamp_filter(range));
+        self.open_cursor(&table_id, &table.mapping, filter, sort, options)
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn read_timestamped_objects_page(
+        &self,
+        cursor: CursorId,
+    ) -> Result<Page<Value>, Self::Error> {
+        self.next_page(cursor).await
+    }
+
+    #[instrument(skip(self))]
+    async fn aggregate_timestamped_objects(
+        &self,
+        table_id: DbTableId,
+        filter: Filter,
+        range: TimeRange,
+        aggregation: Aggregation,
+    ) -> Result<Vec<AggregationBucket>, Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "aggregate_timestamped_objects")
+            .await?;
+        if !matches!(table.mapping.table.versioning, VersioningType::Timestamped) {
+            return Err(Error::NotATimestampedTable(
+                "aggregate_timestamped_objects",
+                table_id,
+            ));
+        }
+
+        let aggregation = table.mapping.aggregation(&table_id, aggregation)?;
+        let filter = filter.and(timestamp_filter(range));
+        Ok(self
+            .database
+            .aggregate(
+                &table_id,
+                &table.mapping.table_schema,
+                &filter,
+                TIMESTAMP_FIELD,
+                &aggregation,
+            )
+            .await?)
+    }
+
     /* Discovery object (single-versioned) manipulation. */
 
     #[instrument(skip(self))]
@@ -381,13 +1009,14 @@ impl BackendDbService for DbDaemon {
         table.mapping.verify_value(&value)?;
 
         let object_id = ObjectId::new();
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_single_versioned(Utc::now())?;
+            let mut data = writer.write_data_single_versioned(Utc::now())?;
             data.create(&object_id, value);
             data.commit()
         };
 
-        updates.run(&self.elastic).await?;
+        updates.run(self.database.as_ref(), &self.journal).await?;
         Ok(object_id)
     }
 
@@ -405,15 +1034,16 @@ impl BackendDbService for DbDaemon {
 
         table.mapping.verify_value(&value)?;
 
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_single_versioned(Utc::now())?;
+            let mut data = writer.write_data_single_versioned(Utc::now())?;
             data.create(&object_id, value)
                 .then_some(())
                 .ok_or_else(|| Error::ObjectIdAlreadyExists(table_id.clone(), object_id.clone()))?;
             data.commit()
         };
 
-        updates.run(&self.elastic).await
+        updates.run(self.database.as_ref(), &self.journal).await
     }
 
     #[instrument(skip(self))]
@@ -430,13 +1060,14 @@ impl BackendDbService for DbDaemon {
 
         table.mapping.verify_value(&value)?;
 
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_single_versioned(Utc::now())?;
+            let mut data = writer.write_data_single_versioned(Utc::now())?;
             data.insert(&object_id, value);
             data.commit()
         };
 
-        updates.run(&self.elastic).await
+        updates.run(self.database.as_ref(), &self.journal).await
     }
 
     #[instrument(skip(self))]
@@ -453,15 +1084,16 @@ impl BackendDbService for DbDaemon {
 
         table.mapping.verify_value(&value)?;
 
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_single_versioned(Utc::now())?;
+            let mut data = writer.write_data_single_versioned(Utc::now())?;
             data.update(&object_id, value)
                 .then_some(())
                 .ok_or_else(|| Error::ObjectDoesNotExist(table_id.clone(), object_id.clone()))?;
             data.commit()
         };
 
-        updates.run(&self.elastic).await
+        updates.run(self.database.as_ref(), &self.journal).await
     }
 
     #[instrument(skip(self))]
@@ -475,15 +1107,65 @@ impl BackendDbService for DbDaemon {
             .read_table(&table_id, "remove_discovery_object")
             .await?;
 
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_single_versioned(Utc::now())?;
+            let mut data = writer.write_data_single_versioned(Utc::now())?;
             data.remove(&object_id)
                 .then_some(())
                 .ok_or_else(|| Error::ObjectDoesNotExist(table_id.clone(), object_id.clone()))?;
             data.commit()
         };
 
-        updates.run(&self.elastic).await
+        updates.run(self.database.as_ref(), &self.journal).await
+    }
+
+    #[instrument(skip(self))]
+    async fn update_discovery_object_if(
+        &self,
+        table_id: DbTableId,
+        object_id: ObjectId,
+        value: Value,
+        version: VersionKey,
+    ) -> Result<(), Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "update_discovery_object_if")
+            .await?;
+
+        table.mapping.verify_value(&value)?;
+
+        let writer = table.lock_writes().await;
+        let updates = {
+            let mut data = writer.write_data_single_versioned(Utc::now())?;
+            check_version(&table_id, &object_id, version, data.version(&object_id))?;
+            data.update(&object_id, value);
+            data.commit()
+        };
+
+        updates.run(self.database.as_ref(), &self.journal).await
+    }
+
+    #[instrument(skip(self))]
+    async fn remove_discovery_object_if(
+        &self,
+        table_id: DbTableId,
+        object_id: ObjectId,
+        version: VersionKey,
+    ) -> Result<(), Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "remove_discovery_object_if")
+            .await?;
+
+        let writer = table.lock_writes().await;
+        let updates = {
+            let mut data = writer.write_data_single_versioned(Utc::now())?;
+            check_version(&table_id, &object_id, version, data.version(&object_id))?;
+            data.remove(&object_id);
+            data.commit()
+        };
+
+        updates.run(self.database.as_ref(), &self.journal).await
     }
 
     #[instrument(skip(self))]
@@ -497,42 +1179,18 @@ impl BackendDbService for DbDaemon {
             .read_table(&table_id, "bulk_update_discovery_objects")
             .await?;
 
-        updates.values().try_for_each(|op| match op {
-            Operation::Create(v) | Operation::Update(v) | Operation::CreateOrUpdate(v) => {
-                table.mapping.verify_value(v)
-            }
-            Operation::Remove => Ok(()),
-        })?;
+        table.mapping.verify_operations(updates.values())?;
 
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_single_versioned(Utc::now())?;
-            updates
-                .into_iter()
-                .try_for_each(|(object_id, op)| match op {
-                    Operation::Create(value) => {
-                        data.create(&object_id, value).then_some(()).ok_or_else(|| {
-                            Error::ObjectIdAlreadyExists(table_id.clone(), object_id.clone())
-                        })
-                    }
-                    Operation::Update(value) => {
-                        data.update(&object_id, value).then_some(()).ok_or_else(|| {
-                            Error::ObjectDoesNotExist(table_id.clone(), object_id.clone())
-                        })
-                    }
-                    Operation::CreateOrUpdate(value) => {
-                        data.insert(&object_id, value);
-                        Ok(())
-                    }
-                    Operation::Remove => data.remove(&object_id).then_some(()).ok_or_else(|| {
-                        Error::ObjectDoesNotExist(table_id.clone(), object_id.clone())
-                    }),
-                })?;
+            let mut data = writer.write_data_single_versioned(Utc::now())?;
+            data.apply(&table_id, updates)?;
             data.commit()
         };
 
-        updates.run(&self.elastic).await
+        updates.run(self.database.as_ref(), &self.journal).await
 
-        // self.elastic
+        // self.database
         //     .bulk_update(&table_id, &table.table_schema, req)
         //     .await?;
         // Ok(())
@@ -570,6 +1228,22 @@ impl BackendDbService for DbDaemon {
         Ok(data.get(&object_id).cloned())
     }
 
+    #[instrument(skip(self))]
+    async fn read_discovery_object_version(
+        &self,
+        table_id: DbTableId,
+        object_id: ObjectId,
+    ) -> Result<VersionKey, Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "read_discovery_object_version")
+            .await?;
+
+        let data = table.read_data_single_versioned()?;
+        data.version(&object_id)
+            .ok_or_else(|| Error::ObjectDoesNotExist(table_id.clone(), object_id.clone()))
+    }
+
     #[instrument(skip(self))]
     async fn read_discovery_objects(
         &self,
@@ -606,15 +1280,9 @@ impl BackendDbService for DbDaemon {
         let filter = FilterPath::new()
             .field("object_id")
             .eq(json!(object_id))
-            .and(Filter::at(
-                FilterPath::new()
-                    .field("value")
-                    .field("version")
-                    .field("active"),
-                range_filter(range),
-            ));
+            .and(history_filter_single(range));
         Ok(self
-            .elastic
+            .database
             .query_objects::<Identified<SingleVersionedValue>>(
                 &table_id,
                 &table.mapping.table_schema,
@@ -643,15 +1311,9 @@ impl BackendDbService for DbDaemon {
         let filter = FilterPath::new()
             .field("object_id")
             .eq_any(object_ids.into_iter().map(|id| json!(id)).collect())
-            .and(Filter::at(
-                FilterPath::new()
-                    .field("value")
-                    .field("version")
-                    .field("active"),
-                range_filter(range),
-            ));
+            .and(history_filter_single(range));
         Ok(self
-            .elastic
+            .database
             .query_objects::<Identified<SingleVersionedValue>>(
                 &table_id,
                 &table.mapping.table_schema,
@@ -746,7 +1408,7 @@ impl BackendDbService for DbDaemon {
             );
 
         Ok(self
-            .elastic
+            .database
             .query_objects::<Identified<SingleVersionedValue>>(
                 &table_id,
                 &table.mapping.table_schema,
@@ -765,6 +1427,69 @@ impl BackendDbService for DbDaemon {
             ))
     }
 
+    #[instrument(skip(self))]
+    async fn read_discovery_objects_history_paged(
+        &self,
+        table_id: DbTableId,
+        object_ids: HashSet<ObjectId>,
+        range: TimeRange,
+        options: PageOptions,
+    ) -> Result<Page<Identified<SingleVersionedValue>>, Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "read_discovery_objects_history_paged")
+            .await?;
+
+        let filter = FilterPath::new()
+            .field("object_id")
+            .eq_any(object_ids.into_iter().map(|id| json!(id)).collect())
+            .and(history_filter_single(range));
+        self.open_cursor(
+            &table_id,
+            &table.mapping,
+            filter,
+            table.mapping.sort_fields.clone(),
+            options,
+        )
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn query_discovery_objects_history_paged(
+        &self,
+        table_id: DbTableId,
+        filter: Filter,
+        range: TimeRange,
+        options: PageOptions,
+    ) -> Result<Page<Identified<SingleVersionedValue>>, Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "query_discovery_objects_history_paged")
+            .await?;
+
+        let filter = FilterPath::new()
+            .field("value")
+            .field("value")
+            .filter(filter)
+            .and(history_filter_single(range));
+        self.open_cursor(
+            &table_id,
+            &table.mapping,
+            filter,
+            table.mapping.sort_fields.clone(),
+            options,
+        )
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn read_discovery_objects_page(
+        &self,
+        cursor: CursorId,
+    ) -> Result<Page<Identified<SingleVersionedValue>>, Self::Error> {
+        self.next_page(cursor).await
+    }
+
     #[instrument(skip(self))]
     async fn query_discovery_objects_at(
         &self,
@@ -796,13 +1521,14 @@ impl BackendDbService for DbDaemon {
         table.mapping.verify_value(&value)?;
 
         let object_id = ObjectId::new();
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_dual_versioned(Utc::now())?;
+            let mut data = writer.write_data_dual_versioned(Utc::now())?;
             data.create(object_id.clone(), value, commit);
             data.commit()
         };
 
-        updates.run(&self.elastic).await?;
+        updates.run(self.database.as_ref(), &self.journal).await?;
         Ok(object_id)
     }
 
@@ -820,15 +1546,16 @@ impl BackendDbService for DbDaemon {
 
         table.mapping.verify_value(&value)?;
 
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_dual_versioned(Utc::now())?;
+            let mut data = writer.write_data_dual_versioned(Utc::now())?;
             data.create(object_id.clone(), value, commit)
                 .then_some(())
                 .ok_or_else(|| Error::ObjectIdAlreadyExists(table_id.clone(), object_id.clone()))?;
             data.commit()
         };
 
-        updates.run(&self.elastic).await
+        updates.run(self.database.as_ref(), &self.journal).await
     }
 
     async fn create_or_update_config_object(
@@ -845,13 +1572,14 @@ impl BackendDbService for DbDaemon {
 
         table.mapping.verify_value(&value)?;
 
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_dual_versioned(Utc::now())?;
+            let mut data = writer.write_data_dual_versioned(Utc::now())?;
             data.insert(object_id, value, commit);
             data.commit()
         };
 
-        updates.run(&self.elastic).await
+        updates.run(self.database.as_ref(), &self.journal).await
     }
 
     async fn update_config_object(
@@ -868,15 +1596,16 @@ impl BackendDbService for DbDaemon {
 
         table.mapping.verify_value(&value)?;
 
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_dual_versioned(Utc::now())?;
+            let mut data = writer.write_data_dual_versioned(Utc::now())?;
             data.update(object_id.clone(), value, commit)
                 .then_some(())
                 .ok_or_else(|| Error::ObjectDoesNotExist(table_id.clone(), object_id.clone()))?;
             data.commit()
         };
 
-        updates.run(&self.elastic).await
+        updates.run(self.database.as_ref(), &self.journal).await
     }
 
     async fn remove_config_object(
@@ -889,15 +1618,64 @@ impl BackendDbService for DbDaemon {
             .read_table(&table_id, "remove_config_object")
             .await?;
 
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_dual_versioned(Utc::now())?;
+            let mut data = writer.write_data_dual_versioned(Utc::now())?;
             data.remove(object_id.clone())
                 .then_some(())
                 .ok_or_else(|| Error::ObjectDoesNotExist(table_id.clone(), object_id.clone()))?;
             data.commit()
         };
 
-        updates.run(&self.elastic).await
+        updates.run(self.database.as_ref(), &self.journal).await
+    }
+
+    async fn update_config_object_if(
+        &self,
+        table_id: DbTableId,
+        object_id: ObjectId,
+        value: Value,
+        commit: bool,
+        version: VersionKey,
+    ) -> Result<(), Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "update_config_object_if")
+            .await?;
+
+        table.mapping.verify_value(&value)?;
+
+        let writer = table.lock_writes().await;
+        let updates = {
+            let mut data = writer.write_data_dual_versioned(Utc::now())?;
+            check_version(&table_id, &object_id, version, data.version(&object_id))?;
+            data.update(object_id, value, commit);
+            data.commit()
+        };
+
+        updates.run(self.database.as_ref(), &self.journal).await
+    }
+
+    async fn remove_config_object_if(
+        &self,
+        table_id: DbTableId,
+        object_id: ObjectId,
+        version: VersionKey,
+    ) -> Result<(), Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "remove_config_object_if")
+            .await?;
+
+        let writer = table.lock_writes().await;
+        let updates = {
+            let mut data = writer.write_data_dual_versioned(Utc::now())?;
+            check_version(&table_id, &object_id, version, data.version(&object_id))?;
+            data.remove(object_id);
+            data.commit()
+        };
+
+        updates.run(self.database.as_ref(), &self.journal).await
     }
 
     async fn activate_config_object(
@@ -910,15 +1688,40 @@ impl BackendDbService for DbDaemon {
             .read_table(&table_id, "activate_config_object")
             .await?;
 
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_dual_versioned(Utc::now())?;
+            let mut data = writer.write_data_dual_versioned(Utc::now())?;
             data.activate(object_id.clone())
                 .then_some(())
                 .ok_or_else(|| Error::ObjectDoesNotExist(table_id.clone(), object_id.clone()))?;
             data.commit()
         };
 
-        updates.run(&self.elastic).await
+        updates.run(self.database.as_ref(), &self.journal).await
+    }
+
+    async fn bulk_update_config_objects(
+        &self,
+        table_id: DbTableId,
+        updates: HashMap<ObjectId, Operation>,
+        commit: bool,
+        activate: bool,
+    ) -> Result<(), Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "bulk_update_config_objects")
+            .await?;
+
+        table.mapping.verify_operations(updates.values())?;
+
+        let writer = table.lock_writes().await;
+        let updates = {
+            let mut data = writer.write_data_dual_versioned(Utc::now())?;
+            data.apply(&table_id, updates, commit, activate)?;
+            data.commit()
+        };
+
+        updates.run(self.database.as_ref(), &self.journal).await
     }
 
     async fn read_config_object(
@@ -951,6 +1754,20 @@ impl BackendDbService for DbDaemon {
         Ok(data.get(&object_id, timeline).cloned())
     }
 
+    async fn read_config_object_version(
+        &self,
+        table_id: DbTableId,
+        object_id: ObjectId,
+    ) -> Result<VersionKey, Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "read_config_object_version")
+            .await?;
+        let data = table.read_data_dual_versioned()?;
+        data.version(&object_id)
+            .ok_or_else(|| Error::ObjectDoesNotExist(table_id.clone(), object_id.clone()))
+    }
+
     async fn read_config_objects(
         &self,
         table_id: DbTableId,
@@ -985,22 +1802,9 @@ impl BackendDbService for DbDaemon {
         let filter = FilterPath::new()
             .field("object_id")
             .eq(json!(object_id))
-            .and(Filter::at(
-                match timeline {
-                    Timeline::Current => FilterPath::new()
-                        .field("value")
-                        .field("version")
-                        .field("current"),
-                    Timeline::Active => FilterPath::new()
-                        .field("value")
-                        .field("version")
-                        .field("active")
-                        .some(),
-                },
-                range_filter(range),
-            ));
+            .and(history_filter_dual(timeline, range));
         Ok(self
-            .elastic
+            .database
             .query_objects::<Identified<DualVersionedValue>>(
                 &table_id,
                 &table.mapping.table_schema,
@@ -1028,22 +1832,9 @@ impl BackendDbService for DbDaemon {
         let filter = FilterPath::new()
             .field("object_id")
             .eq_any(object_ids.into_iter().map(|id| json!(id)).collect())
-            .and(Filter::at(
-                match timeline {
-                    Timeline::Current => FilterPath::new()
-                        .field("value")
-                        .field("version")
-                        .field("current"),
-                    Timeline::Active => FilterPath::new()
-                        .field("value")
-                        .field("version")
-                        .field("active")
-                        .some(),
-                },
-                range_filter(range),
-            ));
+            .and(history_filter_dual(timeline, range));
         Ok(self
-            .elastic
+            .database
             .query_objects::<Identified<DualVersionedValue>>(
                 &table_id,
                 &table.mapping.table_schema,
@@ -1125,22 +1916,9 @@ impl BackendDbService for DbDaemon {
             .field("value")
             .field("value")
             .filter(filter)
-            .and(Filter::at(
-                match timeline {
-                    Timeline::Current => FilterPath::new()
-                        .field("value")
-                        .field("version")
-                        .field("current"),
-                    Timeline::Active => FilterPath::new()
-                        .field("value")
-                        .field("version")
-                        .field("active")
-                        .some(),
-                },
-                range_filter(range),
-            ));
+            .and(history_filter_dual(timeline, range));
         Ok(self
-            .elastic
+            .database
             .query_objects::<Identified<DualVersionedValue>>(
                 &table_id,
                 &table.mapping.table_schema,
@@ -1156,6 +1934,80 @@ impl BackendDbService for DbDaemon {
             }))
     }
 
+    #[instrument(skip(self))]
+    async fn read_config_objects_history_paged(
+        &self,
+        table_id: DbTableId,
+        object_ids: HashSet<ObjectId>,
+        timeline: Timeline,
+        range: TimeRange,
+        options: PageOptions,
+    ) -> Result<Page<Identified<DualVersionedValue>>, Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "read_config_objects_history_paged")
+            .await?;
+
+        let filter = FilterPath::new()
+            .field("object_id")
+            .eq_any(object_ids.into_iter().map(|id| json!(id)).collect())
+            .and(history_filter_dual(timeline, range));
+        self.open_cursor(
+            &table_id,
+            &table.mapping,
+            filter,
+            table.mapping.sort_fields.clone(),
+            options,
+        )
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn query_config_objects_history_paged(
+        &self,
+        table_id: DbTableId,
+        filter: Filter,
+        timeline: Timeline,
+        range: TimeRange,
+        options: PageOptions,
+    ) -> Result<Page<Identified<DualVersionedValue>>, Self::Error> {
+        let table = self
+            .state
+            .read_table(&table_id, "query_config_objects_history_paged")
+            .await?;
+
+        let filter = FilterPath::new()
+            .field("value")
+            .field("value")
+            .filter(filter)
+            .and(history_filter_dual(timeline, range));
+        self.open_cursor(
+            &table_id,
+            &table.mapping,
+            filter,
+            table.mapping.sort_fields.clone(),
+            options,
+        )
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn read_config_objects_page(
+        &self,
+        cursor: CursorId,
+    ) -> Result<Page<Identified<DualVersionedValue>>, Self::Error> {
+        self.next_page(cursor).await
+    }
+
+    #[instrument(skip(self))]
+    async fn close_cursor(&self, cursor: CursorId) -> Result<(), Self::Error> {
+        self.cursors
+            .write()
+            .remove(&cursor)
+            .ok_or(Error::NoSuchCursor(cursor))?;
+        Ok(())
+    }
+
     async fn query_config_objects_at(
         &self,
         table_id: DbTableId,
@@ -1171,3 +2023,25 @@ impl BackendDbService for DbDaemon {
             .collect())
     }
 }
+
+/// Verify the version of an object for compare-and-set operations.
+fn check_version(
+    table_id: &DbTableId,
+    object_id: &ObjectId,
+    expected: VersionKey,
+    actual: Option<VersionKey>,
+) -> Result<(), Error> {
+    match actual {
+        Some(actual) if actual == expected => Ok(()),
+        Some(actual) => Err(Error::VersionMismatch(
+            table_id.clone(),
+            object_id.clone(),
+            expected,
+            actual,
+        )),
+        None => Err(Error::ObjectDoesNotExist(
+            table_id.clone(),
+            object_id.clone(),
+        )),
+    }
+}
@@ -0,0 +1,101 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+
+use dbdaemon_api::DriftReport;
+use dbschema::DbTableId;
+
+use crate::database::{elastic::ElasticId, Database};
+
+use super::error::{Error, Result};
+use super::state::State;
+use super::table_data::TableData;
+
+/// Periodically check all tables for drift between the in-memory
+/// state and the database.
+pub(super) async fn run<D: Database<Id = ElasticId>>(
+    database: Arc<D>,
+    state: Arc<State>,
+    interval: Duration,
+    fix: bool,
+) {
+    let mut timer = tokio::time::interval(interval);
+    loop {
+        timer.tick().await;
+        let table_ids = state.0.read().keys().cloned().collect::<Vec<_>>();
+        for table_id in table_ids {
+            match check(database.as_ref(), &state, &table_id, fix).await {
+                Ok(report) if report.differences.is_empty() => {}
+                Ok(report) => {
+                    for drift in &report.differences {
+                        log::warn!(
+                            "drift: {table_id}/{}{}: {:?} in memory, {:?} in the database",
+                            drift.object_id,
+                            drift
+                                .timeline
+                                .map_or_else(String::new, |timeline| format!(" ({timeline:?})")),
+                            drift.memory,
+                            drift.database
+                        );
+                    }
+                    match report.fixed {
+                        true => log::info!("drift: reloaded {table_id}"),
+                        false => log::warn!(
+                            "drift: {table_id} differs from the database in {} version(s)",
+                            report.differences.len()
+                        ),
+                    }
+                }
+                Err(Error::NoTimeline(..) | Error::TableNotReady(..) | Error::TableNotFound(_)) => {
+                }
+                Err(e) => log::warn!("drift: failed to check {table_id}: {e}"),
+            }
+        }
+    }
+}
+
+/// Compare the in-memory state of a table with a fresh query of the
+/// database, and optionally replace the in-memory state.
+pub(super) async fn check<D: Database<Id = ElasticId>>(
+    database: &D,
+    state: &State,
+    table_id: &DbTableId,
+    fix: bool,
+) -> Result<DriftReport> {
+    /* Check under a shared lock first, to avoid blocking the table
+     * in the common case. Writes may race with this check, so
+     * differences are confirmed under an exclusive lock. */
+    {
+        let table = state.read_table(table_id, "check_table_drift").await?;
+        if matches!(*table.data.read(), TableData::Timestamped) {
+            return Err(Error::NoTimeline("check_table_drift", table_id.clone()));
+        }
+        database.refresh_table(table_id).await?;
+        let loaded = TableData::load(database, table_id, &table.mapping).await?;
+        if table.data.read().drift(&loaded).is_empty() {
+            return Ok(DriftReport {
+                differences: Vec::new(),
+                fixed: false,
+            });
+        }
+    }
+
+    /* Writes hold a shared lock until they are written to the
+     * database, so none are in flight now. */
+    let mut table = state.lock_table(table_id).await?;
+    database.refresh_table(table_id).await?;
+    let loaded = TableData::load(database, table_id, &table.mapping).await?;
+    let data = table.data.get_mut();
+    let differences = data.drift(&loaded);
+    let fixed = fix && !differences.is_empty();
+    if fixed {
+        let changes = data.replace(loaded, Utc::now());
+        table.changes.publish(changes);
+    }
+
+    Ok(DriftReport { differences, fixed })
+}
@@ -2,23 +2,24 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 
+use chrono::{DateTime, Utc};
+use dbdaemon_api::{ChangeKind, Drift, VersionKey};
+use dbdaemon_types::Operation;
 use dbschema::{DbSchema, DbTableId, DualVersionedValue, Identified, ObjectId, Timeline};
-use parking_lot::MappedRwLockWriteGuard;
+use parking_lot::MappedRwLockReadGuard;
 use serde_json::Value;
 
-use crate::database::{
-    elastic::{self, ElasticId},
-    Database,
-};
+use crate::database::{elastic::ElasticId, Database};
 
 use super::{
-    data_write::Transaction,
+    changes::Change,
+    data_write::{Staged, Transaction},
     error::{Error, Result},
     filters::{filter_active_dual, filter_current_dual},
     modify::{modify, modify_res},
-    table_data::ElasticDoc,
+    table_data::{ElasticDoc, TableData},
     table_mapping::TableMapping,
 };
 
@@ -27,12 +28,15 @@ pub struct DualVersionedData(HashMap<ObjectId, DualVersionedObj>);
 
 #[derive(Debug)]
 pub struct DualVersionedTransaction<'a> {
-    data: MappedRwLockWriteGuard<'a, DualVersionedData>,
+    data: MappedRwLockReadGuard<'a, DualVersionedData>,
     updates: HashMap<ObjectId, DualVersionedUpdate>,
 }
 
+/// Previous and new state of the objects modified by a transaction.
+pub struct DualVersionedStaged(Vec<(ObjectId, Option<DualVersionedObj>, Option<DualVersionedObj>)>);
+
 /// Current / active state for dual versioned objects.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 enum DualVersionedObj<T = DualVersionedDoc> {
     /// Current but not active.
     Created { current: T, committed: bool },
@@ -70,13 +74,13 @@ impl DualVersionedData {
         Self(HashMap::new())
     }
 
-    pub async fn load(
-        elastic: &elastic::Database,
+    pub async fn load<D: Database<Id = ElasticId>>(
+        database: &D,
         table_id: &DbTableId,
         mapping: &TableMapping,
     ) -> Result<Self> {
         Ok(Self(
-            elastic
+            database
                 .query_objects::<Identified<DualVersionedValue>>(
                     table_id,
                     &mapping.table_schema,
@@ -110,6 +114,63 @@ impl DualVersionedData {
         ))
     }
 
+    /// Compare the current and active versions with freshly loaded
+    /// data.
+    pub fn drift(&self, loaded: &Self) -> Vec<Drift> {
+        self.drifted(loaded)
+            .flat_map(|object_id| {
+                let memory = self.0.get(object_id);
+                let database = loaded.0.get(object_id);
+                [Timeline::Current, Timeline::Active]
+                    .into_iter()
+                    .filter_map(move |timeline| {
+                        let memory = memory.and_then(|obj| obj.get(timeline));
+                        let database = database.and_then(|obj| obj.get(timeline));
+                        (memory.map(DualVersionedDoc::key) != database.map(DualVersionedDoc::key))
+                            .then(|| Drift {
+                                object_id: object_id.clone(),
+                                timeline: Some(timeline),
+                                memory: memory.map(DualVersionedDoc::version_key),
+                                database: database.map(DualVersionedDoc::version_key),
+                            })
+                    })
+            })
+            .collect()
+    }
+
+    /// Replace by freshly loaded data, returning the changes to
+    /// publish.
+    pub fn replace(&mut self, loaded: Self, now: DateTime<Utc>) -> Vec<Change> {
+        let changes = self
+            .drifted(&loaded)
+            .flat_map(|object_id| {
+                DualVersionedObj::changes(
+                    object_id,
+                    self.0.get(object_id),
+                    loaded.0.get(object_id),
+                    now,
+                )
+            })
+            .collect();
+        *self = loaded;
+        changes
+    }
+
+    fn drifted<'a>(&'a self, loaded: &'a Self) -> impl Iterator<Item = &'a ObjectId> {
+        self.0
+            .keys()
+            .chain(
+                loaded
+                    .0
+                    .keys()
+                    .filter(|object_id| !self.0.contains_key(*object_id)),
+            )
+            .filter(|object_id| {
+                self.0.get(*object_id).map(DualVersionedObj::key)
+                    != loaded.0.get(*object_id).map(DualVersionedObj::key)
+            })
+    }
+
     pub fn get(&self, object_id: &ObjectId, timeline: Timeline) -> Option<&DualVersionedValue> {
         match timeline {
             Timeline::Current => self.get_current(object_id),
@@ -125,6 +186,13 @@ impl DualVersionedData {
         Some(&self.0.get(object_id)?.get_active()?.value)
     }
 
+    /// The compare-and-set token of an object: the id and version of
+    /// the document holding its current version. Updates in place
+    /// bump the version, so they are detected as well.
+    pub fn version(&self, object_id: &ObjectId) -> Option<VersionKey> {
+        Some(self.0.get(object_id)?.get_current()?.version_key())
+    }
+
     pub fn iter(
         &self,
         timeline: Timeline,
@@ -261,6 +329,83 @@ impl DualVersionedObj<ElasticDoc<DualVersionedValue>> {
             (false, false) => None,
         }
     }
+
+    fn key(&self) -> Vec<(ElasticId, u64)> {
+        match self {
+            Self::Created { current: doc, .. }
+            | Self::Removed { active: doc }
+            | Self::Activated { active: doc } => vec![doc.key()],
+            Self::Updated {
+                active, current, ..
+            } => vec![active.key(), current.key()],
+        }
+    }
+
+    /// Determine the changes to report for a transition between two
+    /// states of an object. Version bumps that leave the value and
+    /// the underlying document unchanged (e.g. committing) are not
+    /// reported.
+    fn changes(
+        object_id: &ObjectId,
+        prev: Option<&Self>,
+        new: Option<&Self>,
+        now: DateTime<Utc>,
+    ) -> Vec<Change> {
+        let change = |kind, doc: Option<&DualVersionedDoc>| Change {
+            object_id: object_id.clone(),
+            kind,
+            timestamp: now,
+            value: doc.map(|doc| doc.value.value.clone()),
+        };
+
+        let mut changes = Vec::new();
+
+        match (
+            prev.and_then(DualVersionedObj::get_current),
+            new.and_then(DualVersionedObj::get_current),
+        ) {
+            (None, Some(doc)) => changes.push(change(ChangeKind::Created, Some(doc))),
+            (Some(prev), Some(doc))
+                if prev.elastic_id != doc.elastic_id || prev.value.value != doc.value.value =>
+            {
+                changes.push(change(ChangeKind::Updated, Some(doc)))
+            }
+            (Some(_), None) => changes.push(change(ChangeKind::Removed, None)),
+            _ => {}
+        }
+
+        let prev_active = prev.and_then(DualVersionedObj::get_active);
+        let active = new.and_then(DualVersionedObj::get_active);
+        if prev_active.map(|doc| &doc.elastic_id) != active.map(|doc| &doc.elastic_id) {
+            changes.push(change(ChangeKind::Activated, active));
+        }
+
+        changes
+    }
+
+    fn revert(self, touched: &HashSet<ElasticId>) -> Self {
+        match self {
+            Self::Created { current, committed } => Self::Created {
+                current: current.revert(touched),
+                committed,
+            },
+            Self::Removed { active } => Self::Removed {
+                active: active.revert(touched),
+            },
+            Self::Activated { active } => Self::Activated {
+                active: active.revert(touched),
+            },
+            Self::Updated {
+                active,
+                current,
+                committed,
+            } => Self::Updated {
+                active: active.revert(touched),
+                current: current.revert(touched),
+                committed,
+            },
+        }
+    }
 }
 
 impl DualVersionedUpdate {
@@ -347,7 +492,7 @@ impl DualVersionedUpdate {
 }
 
 impl<'a> DualVersionedTransaction<'a> {
-    pub fn new(data: MappedRwLockWriteGuard<'a, DualVersionedData>) -> Self {
+    pub fn new(data: MappedRwLockReadGuard<'a, DualVersionedData>) -> Self {
         Self {
             data,
             updates: HashMap::new(),
@@ -371,8 +516,18 @@ impl<'a> DualVersionedTransaction<'a> {
             .or_else(get_active)
     }
 
+    /// The compare-and-set token of an object in its written state,
+    /// disregarding updates in this transaction. No other transaction
+    /// is in flight while the table is locked for writing.
+    pub fn version(&self, object_id: &ObjectId) -> Option<VersionKey> {
+        self.data.version(object_id)
+    }
+
+    /// Create an object. Returns false, without changes, if the
+    /// object already exists, so that callers can report the
+    /// conflict (as for single-versioned tables).
     pub fn create(&mut self, object_id: ObjectId, value: Value, commit: bool) -> bool {
-        self.get_current(&object_id).is_some() || {
+        self.get_current(&object_id).is_none() && {
             self.insert(object_id, value, commit);
             true
         }
@@ -423,19 +578,62 @@ impl<'a> DualVersionedTransaction<'a> {
             true
         }
     }
+
+    /// Add a set of operations to the transaction, optionally
+    /// activating the affected objects. Values should have been
+    /// verified against the table schema.
+    pub fn apply(
+        &mut self,
+        table_id: &DbTableId,
+        updates: HashMap<ObjectId, Operation>,
+        commit: bool,
+        activate: bool,
+    ) -> Result<()> {
+        updates.into_iter().try_for_each(|(object_id, op)| {
+            match op {
+                Operation::Create(value) => self
+                    .create(object_id.clone(), value, commit)
+                    .then_some(())
+                    .ok_or_else(|| {
+                        Error::ObjectIdAlreadyExists(table_id.clone(), object_id.clone())
+                    })?,
+                Operation::Update(value) => self
+                    .update(object_id.clone(), value, commit)
+                    .then_some(())
+                    .ok_or_else(|| {
+                        Error::ObjectDoesNotExist(table_id.clone(), object_id.clone())
+                    })?,
+                Operation::CreateOrUpdate(value) => self.insert(object_id.clone(), value, commit),
+                Operation::Remove => {
+                    self.remove(object_id.clone())
+                        .then_some(())
+                        .ok_or_else(|| {
+                            Error::ObjectDoesNotExist(table_id.clone(), object_id.clone())
+                        })?
+                }
+            }
+            if activate {
+                self.activate(object_id);
+            }
+            Ok(())
+        })
+    }
 }
 
 impl<'a> Transaction<'a> for DualVersionedTransaction<'a> {
     type Value = DualVersionedValue;
+    type Staged = DualVersionedStaged;
     fn commit(
-        mut self,
+        self,
         now: chrono::DateTime<chrono::Utc>,
         _force_update: bool,
         _value_schema: &DbSchema,
         updates: &mut super::updates::UpdateGuard<'a, Self::Value>,
-    ) {
+    ) -> Self::Staged {
+        let mut staged = Vec::new();
         for (object_id, update) in self.updates {
-            let obj = self.data.0.remove(&object_id);
+            let obj = self.data.0.get(&object_id).cloned();
+            let prev = obj.clone();
             let obj = match update {
                 DualVersionedUpdate::Insert(value, commit) => match obj {
                     Some(DualVersionedObj::Created { current, committed }) => {
@@ -972,8 +1170,37 @@ impl<'a> Transaction<'a> for DualVersionedTransaction<'a> {
                     }
                 },
             };
-            if let Some(obj) = obj {
-                self.data.0.insert(object_id, obj);
+            if obj.as_ref().map(DualVersionedObj::key) != prev.as_ref().map(DualVersionedObj::key) {
+                DualVersionedObj::changes(&object_id, prev.as_ref(), obj.as_ref(), now)
+                    .into_iter()
+                    .for_each(|change| updates.change(change));
+                staged.push((object_id, prev, obj));
+            }
+        }
+        DualVersionedStaged(staged)
+    }
+}
+
+impl Staged for DualVersionedStaged {
+    fn apply(self: Box<Self>, data: &mut TableData) {
+        let Some(data) = data.dual_versioned_mut() else {
+            return;
+        };
+        for (object_id, _, obj) in self.0 {
+            match obj {
+                Some(obj) => data.0.insert(object_id, obj),
+                None => data.0.remove(&object_id),
+            };
+        }
+    }
+
+    fn revert(self: Box<Self>, data: &mut TableData, touched: &HashSet<ElasticId>) {
+        let Some(data) = data.dual_versioned_mut() else {
+            return;
+        };
+        for (object_id, prev, _) in self.0 {
+            if let Some(prev) = prev {
+                data.0.insert(object_id, prev.revert(touched));
             }
         }
     }
@@ -1016,8 +1243,9 @@ mod test {
         let now = Utc::now();
         let table = state.read_table(&table_id, "test").await.unwrap();
         let object_id = ObjectId::new();
+        let writer = table.lock_writes().await;
         let updates = {
-            let mut data = table.write_data_dual_versioned(now).unwrap();
+            let mut data = writer.write_data_dual_versioned(now).unwrap();
             data.insert(object_id.clone(), json!({"field": "test"}), true);
             data.commit()
         };
@@ -1045,4 +1273,120 @@ mod test {
 
         //assert_eq!(updates, expected);
     }
+
+    #[tokio::test]
+    async fn transaction_create_existing() {
+        type Document = Identified<DualVersioned<Object>>;
+        #[derive(HasSchema, Debug)]
+        #[allow(unused)]
+        struct Object {
+            field: String,
+        }
+
+        let state = State::new();
+        let table_id = DbTableId::new("test-table");
+
+        {
+            let (_schemas, mut table) = state
+                .write_table(
+                    &table_id,
+                    "test",
+                    crate::daemon::table_state::TableNonOperationalState::Registering,
+                    true,
+                )
+                .await
+                .unwrap();
+            table.or_insert_with(|| TableOperationalState::new(Document::table_def()));
+        }
+
+        let table = state.read_table(&table_id, "test").await.unwrap();
+        let object_id = ObjectId::new();
+
+        {
+            let writer = table.lock_writes().await;
+            let mut data = writer.write_data_dual_versioned(Utc::now()).unwrap();
+            assert!(data.create(object_id.clone(), json!({"field": "a"}), true));
+            /* Created earlier in the same transaction. */
+            assert!(!data.create(object_id.clone(), json!({"field": "b"}), true));
+            data.commit().apply();
+        }
+
+        let writer = table.lock_writes().await;
+        let mut data = writer.write_data_dual_versioned(Utc::now()).unwrap();
+        assert!(!data.create(object_id.clone(), json!({"field": "c"}), true));
+        assert!(data.create(ObjectId::new(), json!({"field": "d"}), true));
+        let updates = data.commit().extract();
+        assert_eq!(updates.len(), 1);
+        assert!(updates.values().all(|(_, doc)| doc.object_id != object_id));
+    }
+
+    #[tokio::test]
+    async fn version_changes_on_update() {
+        type Document = Identified<DualVersioned<Object>>;
+        #[derive(HasSchema, Debug)]
+        #[allow(unused)]
+        struct Object {
+            field: String,
+        }
+
+        let state = State::new();
+        let table_id = DbTableId::new("test-table");
+
+        {
+            let (_schemas, mut table) = state
+                .write_table(
+                    &table_id,
+                    "test",
+                    crate::daemon::table_state::TableNonOperationalState::Registering,
+                    true,
+                )
+                .await
+                .unwrap();
+            table.or_insert_with(|| TableOperationalState::new(Document::table_def()));
+        }
+
+        let table = state.read_table(&table_id, "test").await.unwrap();
+        let object_id = ObjectId::new();
+
+        {
+            let writer = table.lock_writes().await;
+            let mut data = writer.write_data_dual_versioned(Utc::now()).unwrap();
+            assert!(data.create(object_id.clone(), json!({"field": "a"}), false));
+            data.commit().apply();
+        }
+
+        let created = table
+            .read_data_dual_versioned()
+            .unwrap()
+            .version(&object_id)
+            .unwrap();
+
+        /* An uncommitted version is updated in place; the token must
+         * change nevertheless. */
+        {
+            let writer = table.lock_writes().await;
+            let mut data = writer.write_data_dual_versioned(Utc::now()).unwrap();
+            assert_eq!(data.version(&object_id), Some(created.clone()));
+            assert!(data.update(object_id.clone(), json!({"field": "b"}), false));
+            /* Updates in the transaction are disregarded. */
+            assert_eq!(data.version(&object_id), Some(created.clone()));
+            let updates = data.commit();
+            /* Staged changes are not visible until applied. */
+            assert_eq!(
+                table
+                    .read_data_dual_versioned()
+                    .unwrap()
+                    .version(&object_id),
+                Some(created.clone())
+            );
+            updates.apply();
+        }
+
+        let updated = table
+            .read_data_dual_versioned()
+            .unwrap()
+            .version(&object_id)
+            .unwrap();
+        assert_ne!(created, updated);
+    }
 }
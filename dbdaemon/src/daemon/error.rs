@@ -4,12 +4,15 @@
 
 use std::path::PathBuf;
 
-use dbdaemon_api::VerificationId;
+use dbdaemon_api::{CursorId, VerificationId, VersionKey, WatchId};
 use thiserror::Error;
 
 use dbschema::{DbTableId, ObjectId, VersioningType};
 
-use crate::database::elastic::{self, ElasticId};
+use crate::database::{
+    elastic::{self, ElasticId},
+    DatabaseError,
+};
 
 use super::table_state::TableNonOperationalState;
 
@@ -23,6 +26,8 @@ pub enum Error {
     MappingError(#[from] elastic::MappingError),
     #[error("Unable to Serialize/Deserialize the object: {0:?}")]
     JSONError(#[from] serde_json::Error),
+    #[error("Unable to encode the object as CBOR: {0}")]
+    CBORError(#[from] serde_cbor::Error),
     #[error("Encountered a problem converting the object {0:?}")]
     ConversionError(#[from] elastic::ConversionError),
     #[error("Table not found: {0}")]
@@ -41,9 +46,8 @@ pub enum Error {
     DbSchemaError(#[from] dbschema::Error),
     #[error("encountered a problen with the response of the database: {0}")]
     ResponseError(String),
-    #[cfg(feature = "elastic")]
-    #[error("elastic error: {0}")]
-    Elastic(#[from] elastic::Error),
+    #[error("database error: {0}")]
+    Database(Box<dyn std::error::Error + Send + Sync>),
     #[error(
         "invalid query for {1} index '{0}'; this request is only \
 	     available for {2} indices"
@@ -83,4 +87,57 @@ pub enum Error {
     InconsistentData(DbTableId, ElasticId),
     #[error("no verification with id {0} is currently in progress")]
     NoSuchVerificationWorker(VerificationId),
+    #[error(
+        "object id '{1}' in table '{0}' was modified: expected version \
+         {2}, found version {3}"
+    )]
+    VersionMismatch(DbTableId, ObjectId, VersionKey, VersionKey),
+    #[error("change token for table '{0}' has expired; rescan the table")]
+    ChangeTokenExpired(DbTableId),
+    #[error("no watch with id {0} exists")]
+    NoSuchWatch(WatchId),
+    #[error("no cursor with id {0} exists; it may have expired")]
+    NoSuchCursor(CursorId),
+    #[error("cursor {0} was opened on a table of another versioning type")]
+    CursorType(CursorId),
+    #[error("cannot sort table '{0}' on field '{1}'")]
+    InvalidSortField(DbTableId, String),
+    #[error("invalid aggregation on table '{0}': {1}")]
+    InvalidAggregation(DbTableId, String),
+    #[error("table '{0}' occurs more than once in the transaction")]
+    DuplicateTable(DbTableId),
+    #[error("Failed to write journal '{0}': {1}")]
+    Journal(PathBuf, std::io::Error),
+    #[error("failed to decode journal '{0}': {1}")]
+    JournalFormat(PathBuf, serde_json::Error),
+    #[error("{0}; the rollback could not be journaled: {1}")]
+    RollbackNotJournaled(Box<Error>, Box<Error>),
+    #[error("Failed to read retention config '{0}': {1}")]
+    ReadRetention(PathBuf, std::io::Error),
+    #[error("cannot migrate field '{0}': {1}")]
+    Migration(String, String),
+    #[error("migrated value of {0} is invalid: {1:?}")]
+    InvalidMigration(String, dbschema::Error),
+    #[error("table export and import are disabled: no archive directory is configured")]
+    ArchivesDisabled,
+    #[error("invalid archive name '{0}'")]
+    InvalidArchiveName(String),
+    #[error("Failed to write archive '{0}': {1}")]
+    WriteArchive(PathBuf, std::io::Error),
+    #[error("Failed to read archive '{0}': {1}")]
+    ReadArchive(PathBuf, std::io::Error),
+    #[error("failed to decode archive '{0}': {1}")]
+    ArchiveFormat(PathBuf, String),
+    #[error("cannot import into table '{0}': the table is not empty")]
+    TableNotEmpty(DbTableId),
+    #[error("cannot import into table '{0}': the table has a different definition")]
+    DefinitionMismatch(DbTableId),
+    #[error("import into table '{0}' is partial: failed after {1} document(s): {2}")]
+    PartialImport(DbTableId, u64, Box<Error>),
+}
+
+impl<E: DatabaseError> From<E> for Error {
+    fn from(e: E) -> Self {
+        Self::Database(Box::new(e))
+    }
 }
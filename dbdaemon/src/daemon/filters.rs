@@ -2,9 +2,11 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use dbschema::{Filter, FilterPath, TimeRange};
+use dbschema::{Filter, FilterPath, TimeRange, Timeline};
 use serde_json::{json, Value};
 
+use crate::database::TIMESTAMP_FIELD;
+
 pub fn filter_active_single() -> Filter {
     FilterPath::new()
         .field("value")
@@ -81,3 +83,49 @@ pub fn range_filter(range: TimeRange) -> Filter {
         .collect(),
     )
 }
+
+/// Select single-versioned documents active during the given range.
+pub fn history_filter_single(range: TimeRange) -> Filter {
+    Filter::at(
+        FilterPath::new()
+            .field("value")
+            .field("version")
+            .field("active"),
+        range_filter(range),
+    )
+}
+
+/// Select dual-versioned documents that are part of the given
+/// timeline during the given range.
+pub fn history_filter_dual(timeline: Timeline, range: TimeRange) -> Filter {
+    Filter::at(
+        match timeline {
+            Timeline::Current => FilterPath::new()
+                .field("value")
+                .field("version")
+                .field("current"),
+            Timeline::Active => FilterPath::new()
+                .field("value")
+                .field("version")
+                .field("active")
+                .some(),
+        },
+        range_filter(range),
+    )
+}
+
+/// Select timestamped documents with a timestamp in the given range.
+pub fn timestamp_filter(range: TimeRange) -> Filter {
+    Filter::All(
+        IntoIterator::into_iter([
+            range
+                .from
+                .map(|from| FilterPath::new().field(TIMESTAMP_FIELD).ge(json!(from))),
+            range
+                .to
+                .map(|to| FilterPath::new().field(TIMESTAMP_FIELD).le(json!(to))),
+        ])
+        .flatten()
+        .collect(),
+    )
+}
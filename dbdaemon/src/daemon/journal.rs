@@ -0,0 +1,340 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex as AsyncMutex,
+};
+use uuid::Uuid;
+
+use dbschema::DbTableId;
+
+use crate::database::elastic::ElasticId;
+
+use super::error::{Error, Result};
+
+/// Append-only write-ahead journal for database updates. Every batch
+/// of updates is written (and synced) to the journal before it is
+/// sent to the database and marked as done afterwards. Batches that
+/// were not marked as done are replayed on startup. Since documents
+/// are written with external versions, replaying a batch that was
+/// (partially) written before is harmless.
+pub struct Journal(Option<AsyncMutex<JournalFile>>);
+
+struct JournalFile {
+    path: PathBuf,
+    file: File,
+    pending: HashSet<BatchId>,
+    recovered: Vec<Batch>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct BatchId(Uuid);
+
+/// A transaction, possibly spanning multiple tables.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Batch {
+    pub id: BatchId,
+    pub tables: Vec<TableOps>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TableOps {
+    pub table_id: DbTableId,
+    pub ops: Vec<JournalOp>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalOp {
+    Index {
+        id: ElasticId,
+        version: u64,
+        doc: Value,
+    },
+    Delete {
+        id: ElasticId,
+        version: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Batch(Batch),
+    Done(BatchId),
+}
+
+impl BatchId {
+    fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl Journal {
+    /// A journal that does not record anything.
+    pub fn disabled() -> Self {
+        Self(None)
+    }
+
+    /// Open the journal, reading unfinished batches from a previous
+    /// run. These should be replayed using `take_recovered` and
+    /// `clear` before any new batches are written.
+    pub async fn open(path: &Path) -> Result<Self> {
+        let recovered = match tokio::fs::read(path).await {
+            Ok(data) => Self::parse(path, &data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(Error::Journal(path.to_path_buf(), e)),
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| Error::Journal(path.to_path_buf(), e))?;
+
+        if !recovered.is_empty() {
+            log::info!(
+                "found {} unfinished batch(es) in journal {}",
+                recovered.len(),
+                path.display()
+            );
+        }
+
+        Ok(Self(Some(AsyncMutex::new(JournalFile {
+            path: path.to_path_buf(),
+            file,
+            pending: HashSet::new(),
+            recovered,
+        }))))
+    }
+
+    fn parse(path: &Path, data: &[u8]) -> Result<Vec<Batch>> {
+        let mut batches = Vec::new();
+        let mut lines = data.split(|c| *c == b'\n').peekable();
+
+        while let Some(line) = lines.next() {
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice(line) {
+                Ok(Record::Batch(batch)) => batches.push(batch),
+                Ok(Record::Done(id)) => batches.retain(|batch| batch.id != id),
+                /* A torn write at the end of the journal means the
+                 * batch was never synced, and thus never sent. */
+                Err(e) if lines.peek().is_none() => {
+                    log::warn!(
+                        "ignoring incomplete record in journal {}: {e}",
+                        path.display()
+                    );
+                }
+                Err(e) => return Err(Error::JournalFormat(path.to_path_buf(), e)),
+            }
+        }
+
+        Ok(batches)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Take the unfinished batches read when opening the journal.
+    pub async fn take_recovered(&self) -> Vec<Batch> {
+        match &self.0 {
+            Some(journal) => std::mem::take(&mut journal.lock().await.recovered),
+            None => Vec::new(),
+        }
+    }
+
+    /// Discard the journal contents, after recovered batches have
+    /// been replayed.
+    pub async fn clear(&self) -> Result<()> {
+        if let Some(journal) = &self.0 {
+            let mut journal = journal.lock().await;
+            journal.recovered.clear();
+            journal.truncate().await?;
+        }
+        Ok(())
+    }
+
+    /// Record a batch of updates. The batch is synced to disk before
+    /// this function returns.
+    pub async fn begin(&self, tables: Vec<TableOps>) -> Result<BatchId> {
+        let id = BatchId::new();
+        if let Some(journal) = &self.0 {
+            let mut journal = journal.lock().await;
+            journal.append(&Record::Batch(Batch { id, tables })).await?;
+            journal
+                .file
+                .sync_data()
+                .await
+                .map_err(|e| Error::Journal(journal.path.clone(), e))?;
+            journal.pending.insert(id);
+        }
+        Ok(id)
+    }
+
+    /// Mark a batch as done. Failure to do so is not fatal: the batch
+    /// will be replayed on the next startup.
+    pub async fn done(&self, id: BatchId) {
+        if let Some(journal) = &self.0 {
+            let mut journal = journal.lock().await;
+            journal.pending.remove(&id);
+            let res = match journal.pending.is_empty() && journal.recovered.is_empty() {
+                true => journal.truncate().await,
+                false => journal.append(&Record::Done(id)).await,
+            };
+            if let Err(e) = res {
+                log::warn!("failed to mark journal batch as done: {e}");
+            }
+        }
+    }
+}
+
+impl JournalFile {
+    async fn append(&mut self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .await
+            .map_err(|e| Error::Journal(self.path.clone(), e))
+    }
+
+    async fn truncate(&mut self) -> Result<()> {
+        self.file
+            .set_len(0)
+            .await
+            .map_err(|e| Error::Journal(self.path.clone(), e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use dbschema::DbTableId;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use crate::daemon::error::Error;
+    use crate::database::elastic::ElasticId;
+
+    use super::{Batch, BatchId, Journal, JournalOp, Record, TableOps};
+
+    fn batch() -> Batch {
+        Batch {
+            id: BatchId::new(),
+            tables: vec![TableOps {
+                table_id: DbTableId::new("test-table"),
+                ops: vec![
+                    JournalOp::Index {
+                        id: ElasticId::new(),
+                        version: 1,
+                        doc: json!({"field": "a"}),
+                    },
+                    JournalOp::Delete {
+                        id: ElasticId::new(),
+                        version: 2,
+                    },
+                ],
+            }],
+        }
+    }
+
+    fn line(record: &Record) -> Vec<u8> {
+        let mut line = serde_json::to_vec(record).unwrap();
+        line.push(b'\n');
+        line
+    }
+
+    fn parse(data: &[u8]) -> Vec<BatchId> {
+        Journal::parse(Path::new("journal"), data)
+            .unwrap()
+            .into_iter()
+            .map(|batch| batch.id)
+            .collect()
+    }
+
+    #[test]
+    fn parse_done() {
+        let (a, b) = (batch(), batch());
+        let (a_id, b_id) = (a.id, b.id);
+        let data = [
+            line(&Record::Batch(a)),
+            b"\n".to_vec(),
+            line(&Record::Batch(b)),
+            line(&Record::Done(a_id)),
+        ]
+        .concat();
+        assert_eq!(parse(&data), vec![b_id]);
+    }
+
+    #[test]
+    fn parse_truncated() {
+        let (a, b) = (batch(), batch());
+        let a_id = a.id;
+        let b_line = line(&Record::Batch(b));
+        let data = [line(&Record::Batch(a)), b_line[..b_line.len() / 2].to_vec()].concat();
+        assert_eq!(parse(&data), vec![a_id]);
+    }
+
+    /// Only the last record can be torn; anything else is corruption.
+    #[test]
+    fn parse_corrupt() {
+        let b_line = line(&Record::Batch(batch()));
+        let data = [
+            line(&Record::Batch(batch())),
+            b_line[..b_line.len() / 2].to_vec(),
+            b"\n".to_vec(),
+            line(&Record::Batch(batch())),
+        ]
+        .concat();
+        assert!(matches!(
+            Journal::parse(Path::new("journal"), &data),
+            Err(Error::JournalFormat(_, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn recover_pending() {
+        let path = std::env::temp_dir().join(format!("dbdaemon-journal-{}", Uuid::new_v4()));
+
+        let journal = Journal::open(&path).await.unwrap();
+        let a = journal.begin(batch().tables).await.unwrap();
+        let b = journal.begin(batch().tables).await.unwrap();
+        journal.done(a).await;
+        drop(journal);
+
+        let journal = Journal::open(&path).await.unwrap();
+        let recovered = journal.take_recovered().await;
+        assert_eq!(
+            recovered.iter().map(|batch| batch.id).collect::<Vec<_>>(),
+            vec![b]
+        );
+        assert_eq!(recovered[0].tables[0].ops.len(), 2);
+
+        /* Once recovered batches are replayed, the journal is cleared
+         * and batches that are done leave no trace. */
+        journal.clear().await.unwrap();
+        let c = journal.begin(batch().tables).await.unwrap();
+        journal.done(c).await;
+        drop(journal);
+
+        assert!(tokio::fs::read(&path).await.unwrap().is_empty());
+        let journal = Journal::open(&path).await.unwrap();
+        assert!(journal.take_recovered().await.is_empty());
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
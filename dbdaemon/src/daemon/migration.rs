@@ -0,0 +1,328 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use serde_json::{Map, Value};
+
+use dbdaemon_api::{Conversion, MigrationStep};
+use dbschema::{
+    DbSchema, DbTable, DualVersionedValue, Identified, SingleVersionedValue, VersioningType,
+};
+
+use super::error::{Error, Result};
+
+/// A schema migration, applied to every document during a reindex.
+/// Migrated values are verified against the new value schema, so that
+/// an invalid migration fails the reindex before the new index is
+/// put in place.
+pub struct Migration<'a> {
+    steps: Vec<MigrationStep>,
+    definition: &'a DbTable,
+    value_schema: DbSchema,
+}
+
+impl<'a> Migration<'a> {
+    pub fn new(steps: Vec<MigrationStep>, definition: &'a DbTable) -> Self {
+        Self {
+            steps,
+            definition,
+            value_schema: definition.value_schema(),
+        }
+    }
+
+    /// Migrate a stored document. For versioned tables, only the
+    /// value of the version is migrated.
+    pub fn apply_doc(&self, doc: Value) -> Result<Value> {
+        match &self.definition.versioning {
+            VersioningType::Timestamped => {
+                let mut doc = doc;
+                self.apply(&mut doc, || String::from("document"))?;
+                Ok(doc)
+            }
+            VersioningType::SingleTimeline => {
+                let mut doc = serde_json::from_value::<Identified<SingleVersionedValue>>(doc)?;
+                self.apply(&mut doc.value.value, || {
+                    format!("object '{}'", doc.object_id)
+                })?;
+                Ok(serde_json::to_value(doc)?)
+            }
+            VersioningType::DualTimeline => {
+                let mut doc = serde_json::from_value::<Identified<DualVersionedValue>>(doc)?;
+                self.apply(&mut doc.value.value, || {
+                    format!("object '{}'", doc.object_id)
+                })?;
+                Ok(serde_json::to_value(doc)?)
+            }
+        }
+    }
+
+    /// Apply the migration steps to a value and verify the result.
+    fn apply<F>(&self, value: &mut Value, describe: F) -> Result<()>
+    where
+        F: FnOnce() -> String,
+    {
+        for step in &self.steps {
+            match step {
+                MigrationStep::Rename { path, to } => {
+                    if let Some((_, parent)) = path.split_last() {
+                        if let Some(field) = take(value, path) {
+                            let mut new_path = parent.to_vec();
+                            new_path.push(to.clone());
+                            insert(value, &new_path, field);
+                        }
+                    }
+                }
+                MigrationStep::Move { from, to } => {
+                    if let Some(field) = take(value, from) {
+                        insert(value, to, field);
+                    }
+                }
+                MigrationStep::Default {
+                    path,
+                    value: default,
+                } => {
+                    if get_mut(value, path).is_none_or(|field| field.is_null()) {
+                        insert(value, path, default.clone());
+                    }
+                }
+                MigrationStep::Convert { path, to } => {
+                    if let Some(field) = get_mut(value, path) {
+                        if !field.is_null() {
+                            *field = convert(field, *to).ok_or_else(|| {
+                                Error::Migration(
+                                    path.join("."),
+                                    format!("cannot convert {field} to {to:?}"),
+                                )
+                            })?;
+                        }
+                    }
+                }
+            }
+        }
+        self.value_schema
+            .verify_value(value)
+            .map_err(|e| Error::InvalidMigration(describe(), e))
+    }
+}
+
+fn get_mut<'a>(value: &'a mut Value, path: &[String]) -> Option<&'a mut Value> {
+    path.iter()
+        .try_fold(value, |value, field| value.as_object_mut()?.get_mut(field))
+}
+
+fn take(value: &mut Value, path: &[String]) -> Option<Value> {
+    let (field, parent) = path.split_last()?;
+    get_mut(value, parent)?.as_object_mut()?.remove(field)
+}
+
+/// Insert a field, creating missing objects on the path. Non-object
+/// values on the path are replaced.
+fn insert(value: &mut Value, path: &[String], field: Value) {
+    let Some((last, parent)) = path.split_last() else {
+        *value = field;
+        return;
+    };
+    let object = parent.iter().fold(value, |value, name| {
+        if !value.is_object() {
+            *value = Value::Object(Map::new());
+        }
+        value
+            .as_object_mut()
+            .unwrap() // checked above
+            .entry(name.clone())
+            .or_insert(Value::Null)
+    });
+    if !object.is_object() {
+        *object = Value::Object(Map::new());
+    }
+    object
+        .as_object_mut()
+        .unwrap() // checked above
+        .insert(last.clone(), field);
+}
+
+fn convert(value: &Value, to: Conversion) -> Option<Value> {
+    match (to, value) {
+        (Conversion::String, Value::String(_)) => Some(value.clone()),
+        (Conversion::String, Value::Number(n)) => Some(Value::String(n.to_string())),
+        (Conversion::String, Value::Bool(b)) => Some(Value::String(b.to_string())),
+        (Conversion::Integer, Value::Number(n)) => match n.as_i64() {
+            Some(i) => Some(Value::from(i)),
+            None => n
+                .as_f64()
+                .filter(|f| f.fract() == 0.0)
+                .map(|f| Value::from(f as i64)),
+        },
+        (Conversion::Integer, Value::String(s)) => s.trim().parse::<i64>().ok().map(Value::from),
+        (Conversion::Integer, Value::Bool(b)) => Some(Value::from(i64::from(*b))),
+        (Conversion::Float, Value::Number(n)) => n.as_f64().map(Value::from),
+        (Conversion::Float, Value::String(s)) => s.trim().parse::<f64>().ok().map(Value::from),
+        (Conversion::Boolean, Value::Bool(_)) => Some(value.clone()),
+        (Conversion::Boolean, Value::String(s)) => s.trim().parse::<bool>().ok().map(Value::from),
+        (Conversion::Boolean, Value::Number(n)) => match n.as_i64() {
+            Some(0) => Some(Value::Bool(false)),
+            Some(1) => Some(Value::Bool(true)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use chrono::Utc;
+    use dbdaemon_api::{Conversion, MigrationStep};
+    use dbschema::{
+        DbTable, HasSchema, HasTableDef, Identified, ObjectId, SingleVersioned,
+        SingleVersionedValue,
+    };
+    use serde_json::{json, Value};
+
+    use crate::daemon::error::Error;
+
+    use super::{insert, take, Migration};
+
+    #[derive(HasSchema, Debug)]
+    #[allow(unused)]
+    struct Object {
+        name: String,
+        count: i64,
+    }
+
+    fn definition() -> DbTable {
+        Identified::<SingleVersioned<Object>>::table_def()
+    }
+
+    fn path(path: &[&str]) -> Vec<String> {
+        path.iter().map(|field| field.to_string()).collect()
+    }
+
+    fn migrate(steps: Vec<MigrationStep>, mut value: Value) -> Result<Value, Error> {
+        let definition = definition();
+        Migration::new(steps, &definition).apply(&mut value, || String::from("test"))?;
+        Ok(value)
+    }
+
+    #[test]
+    fn rename() {
+        let steps = vec![MigrationStep::Rename {
+            path: path(&["label"]),
+            to: String::from("name"),
+        }];
+        assert_eq!(
+            migrate(steps, json!({ "label": "a", "count": 1 })).unwrap(),
+            json!({ "name": "a", "count": 1 })
+        );
+    }
+
+    #[test]
+    fn rename_versioned() {
+        let definition = definition();
+        let migration = Migration::new(
+            vec![MigrationStep::Rename {
+                path: path(&["label"]),
+                to: String::from("name"),
+            }],
+            &definition,
+        );
+        let object_id = ObjectId::new();
+        let doc = serde_json::to_value(Identified {
+            object_id: object_id.clone(),
+            value: SingleVersionedValue::new(Utc::now(), json!({ "label": "a", "count": 1 })),
+        })
+        .unwrap();
+        let doc = serde_json::from_value::<Identified<SingleVersionedValue>>(
+            migration.apply_doc(doc).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(doc.object_id, object_id);
+        assert_eq!(doc.value.value, json!({ "name": "a", "count": 1 }));
+    }
+
+    #[test]
+    fn move_field() {
+        let steps = vec![MigrationStep::Move {
+            from: path(&["total"]),
+            to: path(&["count"]),
+        }];
+        assert_eq!(
+            migrate(steps, json!({ "name": "a", "total": 2 })).unwrap(),
+            json!({ "name": "a", "count": 2 })
+        );
+
+        /* Missing objects on the new path are created. */
+        let mut value = json!({ "a": { "b": 1 } });
+        let field = take(&mut value, &path(&["a", "b"])).unwrap();
+        insert(&mut value, &path(&["c", "d"]), field);
+        assert_eq!(value, json!({ "a": {}, "c": { "d": 1 } }));
+    }
+
+    #[test]
+    fn default() {
+        let steps = || {
+            vec![MigrationStep::Default {
+                path: path(&["count"]),
+                value: json!(0),
+            }]
+        };
+        assert_eq!(
+            migrate(steps(), json!({ "name": "a" })).unwrap(),
+            json!({ "name": "a", "count": 0 })
+        );
+        assert_eq!(
+            migrate(steps(), json!({ "name": "a", "count": null })).unwrap(),
+            json!({ "name": "a", "count": 0 })
+        );
+        assert_eq!(
+            migrate(steps(), json!({ "name": "a", "count": 3 })).unwrap(),
+            json!({ "name": "a", "count": 3 })
+        );
+    }
+
+    #[test]
+    fn convert() {
+        let steps = || {
+            vec![
+                MigrationStep::Convert {
+                    path: path(&["name"]),
+                    to: Conversion::String,
+                },
+                MigrationStep::Convert {
+                    path: path(&["count"]),
+                    to: Conversion::Integer,
+                },
+            ]
+        };
+        assert_eq!(
+            migrate(steps(), json!({ "name": 5, "count": "12" })).unwrap(),
+            json!({ "name": "5", "count": 12 })
+        );
+        assert_eq!(
+            migrate(steps(), json!({ "name": "a", "count": 4.0 })).unwrap(),
+            json!({ "name": "a", "count": 4 })
+        );
+        assert!(matches!(
+            migrate(steps(), json!({ "name": "a", "count": "many" })),
+            Err(Error::Migration(field, _)) if field == "count"
+        ));
+    }
+
+    #[test]
+    fn invalid_value() {
+        assert!(matches!(
+            migrate(Vec::new(), json!({ "name": "a", "count": "many" })),
+            Err(Error::InvalidMigration(..))
+        ));
+        assert!(matches!(
+            migrate(
+                vec![MigrationStep::Default {
+                    path: path(&["count"]),
+                    value: json!("none"),
+                }],
+                json!({ "name": "a" })
+            ),
+            Err(Error::InvalidMigration(..))
+        ));
+    }
+}
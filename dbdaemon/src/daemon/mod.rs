@@ -2,15 +2,25 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
+mod archive;
+mod changes;
+mod cursors;
 mod data_read;
 mod data_write;
 mod dbdaemon;
+mod drift;
 mod dual_versioned_data;
 mod error;
 mod filters;
+mod journal;
+mod migration;
 mod modify;
+mod registration;
+mod retention;
+mod schema_diff;
 mod schema_table;
 mod single_versioned_data;
+mod snapshot;
 mod state;
 mod table_data;
 mod table_mapping;
@@ -18,6 +28,9 @@ mod table_read;
 mod table_state;
 mod table_write;
 mod updates;
+mod verification;
 
 pub use dbdaemon::DbDaemon;
 pub use error::{Error, Result};
+pub use journal::Journal;
+pub use retention::{RetentionConfig, RetentionPolicy};
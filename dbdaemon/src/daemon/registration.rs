@@ -0,0 +1,190 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::time::Duration;
+
+use serde_json::Value;
+
+use dbdaemon_api::{InvalidValue, MigrationStep, RegistrationReport, RegistrationVerdict};
+use dbschema::{
+    Compatibility, DbSchema, DbTable, DbTableId, DualVersionedValue, Filter, Identified, ObjectId,
+    SingleVersionedValue, VersioningType,
+};
+
+use crate::database::{elastic::ElasticId, Database};
+
+use super::error::{Error, Result};
+use super::migration::Migration;
+use super::schema_diff;
+use super::state::State;
+
+/// The maximum number of invalid values reported in detail.
+const MAX_INVALID_VALUES: usize = 100;
+
+/// Report what registering a definition would do, or migrating the
+/// table if migration steps are given. The table is read under a
+/// shared lock, so that it stays available.
+pub(super) async fn check<D: Database<Id = ElasticId>>(
+    database: &D,
+    state: &State,
+    table_id: &DbTableId,
+    definition: &DbTable,
+    migration: Option<Vec<MigrationStep>>,
+) -> Result<RegistrationReport> {
+    if !state.0.read().contains_key(table_id) {
+        if migration.is_some() {
+            return Err(Error::TableNotFound(table_id.clone()));
+        }
+        return Ok(RegistrationReport {
+            verdict: RegistrationVerdict::New,
+            error: None,
+            fields: Vec::new(),
+            documents: 0,
+            invalid: 0,
+            invalid_values: Vec::new(),
+        });
+    }
+
+    let table = state
+        .read_table(table_id, "check_table_registration")
+        .await?;
+    let current = &table.mapping.table;
+
+    /* Migrations always reindex, whether or not the definitions are
+     * compatible. */
+    let (verdict, error) = if migration.is_some() {
+        (RegistrationVerdict::NeedsReindex, None)
+    } else if current == definition {
+        (RegistrationVerdict::Unchanged, None)
+    } else {
+        match definition.verify_compatibility(current) {
+            Ok(Compatibility::Compatible) => (RegistrationVerdict::Compatible, None),
+            Ok(Compatibility::NeedsReindex) => (RegistrationVerdict::NeedsReindex, None),
+            Err(e) => (RegistrationVerdict::Incompatible, Some(e.to_string())),
+        }
+    };
+
+    let fields = match verdict {
+        RegistrationVerdict::NeedsReindex => {
+            let reindex = schema_diff::reindex_fields(current, definition)?;
+            let diff = schema_diff::diff(current, definition)?;
+            diff.changed
+                .into_iter()
+                .chain(diff.removed)
+                .chain(diff.added)
+                .filter(|field| {
+                    let name = field.field.split('.').next().unwrap_or(&field.field);
+                    reindex.contains(name)
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+
+    let mut report = RegistrationReport {
+        verdict,
+        error,
+        fields,
+        documents: database.count_objects(table_id).await?,
+        invalid: 0,
+        invalid_values: Vec::new(),
+    };
+
+    /* Values can only become invalid if the definition changes in a
+     * way that is not compatible with the stored documents. Values
+     * are migrated before they are checked, as they would be by the
+     * reindex. */
+    if matches!(
+        report.verdict,
+        RegistrationVerdict::NeedsReindex | RegistrationVerdict::Incompatible
+    ) {
+        let value_schema = definition.value_schema();
+        let migration = migration.map(|steps| Migration::new(steps, definition));
+        let filter = Filter::All(vec![]);
+        let (mut docs, mut next) = database
+            .query_objects_first::<Value>(
+                table_id,
+                &table.mapping.table_schema,
+                &filter,
+                &table.mapping.sort_fields,
+                Duration::from_secs(60),
+                None,
+            )
+            .await?;
+
+        loop {
+            for (_id, _version, doc) in docs {
+                let result = match &migration {
+                    Some(migration) => migrate(&current.versioning, migration, doc),
+                    None => validate(&current.versioning, &value_schema, doc),
+                };
+                if let Err(e) = result {
+                    report.invalid += 1;
+                    if report.invalid_values.len() < MAX_INVALID_VALUES {
+                        report.invalid_values.push(e);
+                    }
+                }
+            }
+            match next {
+                Some(query_state) => {
+                    (docs, next) = database.query_objects_next(query_state).await?
+                }
+                None => break,
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Validate the value of a stored document against the value schema
+/// of the new definition.
+fn validate(
+    versioning: &VersioningType,
+    value_schema: &DbSchema,
+    doc: Value,
+) -> std::result::Result<(), InvalidValue> {
+    let (object_id, value) = match versioning {
+        VersioningType::Timestamped => (None, doc),
+        VersioningType::SingleTimeline => {
+            let doc = serde_json::from_value::<Identified<SingleVersionedValue>>(doc)
+                .map_err(|e| invalid(None, e))?;
+            (Some(doc.object_id), doc.value.value)
+        }
+        VersioningType::DualTimeline => {
+            let doc = serde_json::from_value::<Identified<DualVersionedValue>>(doc)
+                .map_err(|e| invalid(None, e))?;
+            (Some(doc.object_id), doc.value.value)
+        }
+    };
+    value_schema
+        .verify_value(&value)
+        .map_err(|e| invalid(object_id, format!("{e:?}")))
+}
+
+/// Migrate a stored document. The migrated value is verified against
+/// the value schema of the new definition.
+fn migrate(
+    versioning: &VersioningType,
+    migration: &Migration,
+    doc: Value,
+) -> std::result::Result<(), InvalidValue> {
+    let object_id = match versioning {
+        VersioningType::Timestamped => None,
+        VersioningType::SingleTimeline | VersioningType::DualTimeline => doc
+            .get("object_id")
+            .and_then(|id| serde_json::from_value(id.clone()).ok()),
+    };
+    migration
+        .apply_doc(doc)
+        .map(|_| ())
+        .map_err(|e| invalid(object_id, e))
+}
+
+fn invalid<E: ToString>(object_id: Option<ObjectId>, error: E) -> InvalidValue {
+    InvalidValue {
+        object_id,
+        error: error.to_string(),
+    }
+}
@@ -0,0 +1,148 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use dbschema::{DbTableId, Filter, FilterPath, VersioningType};
+
+use crate::database::{elastic::ElasticId, Database, TIMESTAMP_FIELD};
+
+use super::error::{Error, Result};
+use super::state::State;
+
+/// Retention policies per table, read from a yaml file mapping table
+/// ids to policies.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(transparent)]
+pub struct RetentionConfig(pub HashMap<DbTableId, RetentionPolicy>);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct RetentionPolicy {
+    /// Remove versions closed (for single- and dual-versioned tables)
+    /// or documents timestamped (for timestamped tables) more than
+    /// this number of days ago. Active and current versions are never
+    /// removed.
+    pub keep_days: u32,
+}
+
+impl RetentionConfig {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let data = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| Error::ReadRetention(path.to_path_buf(), e))?;
+        Ok(serde_yaml::from_str(&data)?)
+    }
+}
+
+/// Periodically enforce the retention policies.
+pub(super) async fn run<D: Database<Id = ElasticId>>(
+    database: Arc<D>,
+    state: Arc<State>,
+    config: RetentionConfig,
+    interval: Duration,
+) {
+    let mut timer = tokio::time::interval(interval);
+    loop {
+        timer.tick().await;
+        for (table_id, policy) in &config.0 {
+            match apply(database.as_ref(), &state, table_id, policy).await {
+                Ok(0) => {}
+                Ok(n) => log::info!("retention: removed {n} document(s) from {table_id}"),
+                Err(e) => log::warn!("retention: failed to apply policy for {table_id}: {e}"),
+            }
+        }
+    }
+}
+
+/// Remove expired documents from a table. Returns the number of
+/// removed documents, excluding those in dropped partitions.
+async fn apply<D: Database<Id = ElasticId>>(
+    database: &D,
+    state: &State,
+    table_id: &DbTableId,
+    policy: &RetentionPolicy,
+) -> Result<usize> {
+    let cutoff = Utc::now() - TimeDelta::days(policy.keep_days.into());
+
+    /* The read lock keeps the table from being unregistered or
+     * reindexed while we delete from it. */
+    let table = state.read_table(table_id, "retention").await?;
+    let filter = match &table.mapping.table.versioning {
+        VersioningType::SingleTimeline => closed_before(
+            FilterPath::new()
+                .field("value")
+                .field("version")
+                .field("active"),
+            cutoff,
+        ),
+        VersioningType::DualTimeline => Filter::All(vec![
+            closed_before(
+                FilterPath::new()
+                    .field("value")
+                    .field("version")
+                    .field("current"),
+                cutoff,
+            ),
+            FilterPath::new()
+                .field("value")
+                .field("version")
+                .field("active")
+                .eq(Value::Null)
+                .or(closed_before(
+                    FilterPath::new()
+                        .field("value")
+                        .field("version")
+                        .field("active")
+                        .some(),
+                    cutoff,
+                )),
+        ]),
+        VersioningType::Timestamped => {
+            database.drop_partitions(table_id, cutoff).await?;
+            FilterPath::new().field(TIMESTAMP_FIELD).lt(json!(cutoff))
+        }
+    };
+
+    let schema = &table.mapping.table_schema;
+    let mut removed = 0;
+    let (mut docs, mut next) = database
+        .query_objects_first::<Value>(
+            table_id,
+            schema,
+            &filter,
+            &table.mapping.sort_fields,
+            Duration::from_secs(60),
+            None,
+        )
+        .await?;
+
+    loop {
+        removed += docs.len();
+        /* Deletes need a higher external version to take effect. */
+        database
+            .bulk_delete(
+                table_id,
+                docs.into_iter()
+                    .map(|(id, version, _)| (id, version + 1))
+                    .collect::<Vec<_>>(),
+            )
+            .await?;
+        match next {
+            Some(query_state) => (docs, next) = database.query_objects_next(query_state).await?,
+            None => break,
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Select versions on the anchor at `path` that were closed before
+/// the cutoff.
+fn closed_before(path: FilterPath, cutoff: DateTime<Utc>) -> Filter {
+    Filter::at(path, FilterPath::new().field("to").some().lt(json!(cutoff)))
+}
@@ -0,0 +1,236 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_json::{Map, Value};
+
+use dbdaemon_api::{FieldDiff, SchemaDiff};
+use dbschema::{Compatibility, DbTable};
+
+use crate::database::elastic::ElasticMapping;
+
+use super::error::Result;
+
+/// Compare the value fields of two table definitions. Fields are
+/// compared on their elasticsearch mapping, which is what decides
+/// whether documents need to be reindexed.
+pub fn diff(old: &DbTable, new: &DbTable) -> Result<SchemaDiff> {
+    let old_fields = fields(old)?;
+    let new_fields = fields(new)?;
+    let mut diff = SchemaDiff {
+        reindex: needs_reindex(old, new),
+        ..SchemaDiff::default()
+    };
+
+    for (field, (old_type, old_mapping)) in &old_fields {
+        match new_fields.get(field) {
+            None => diff.removed.push(FieldDiff {
+                field: field.clone(),
+                old: Some(old_type.clone()),
+                new: None,
+            }),
+            Some((new_type, new_mapping)) if new_mapping != old_mapping => {
+                diff.changed.push(FieldDiff {
+                    field: field.clone(),
+                    old: Some(old_type.clone()),
+                    new: Some(new_type.clone()),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+
+    for (field, (new_type, _)) in &new_fields {
+        if !old_fields.contains_key(field) {
+            diff.added.push(FieldDiff {
+                field: field.clone(),
+                old: None,
+                new: Some(new_type.clone()),
+            });
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Whether registering the new definition over the old one requires
+/// a reindex. Definitions that cannot be registered over each other
+/// (but can be migrated) are reindexed as well.
+pub fn needs_reindex(old: &DbTable, new: &DbTable) -> bool {
+    old != new && !matches!(new.verify_compatibility(old), Ok(Compatibility::Compatible))
+}
+
+/// The top-level value fields whose change requires a reindex. Each
+/// field is checked with the same compatibility check as a full
+/// definition, by applying only its change to the old definition.
+pub fn reindex_fields(old: &DbTable, new: &DbTable) -> Result<BTreeSet<String>> {
+    let old_def = serde_json::to_value(old)?;
+    let new_def = serde_json::to_value(new)?;
+    let old_fields = value_fields(&old_def);
+    let new_fields = value_fields(&new_def);
+
+    let names = old_fields
+        .keys()
+        .chain(new_fields.keys())
+        .collect::<BTreeSet<_>>();
+    let mut fields = BTreeSet::new();
+    for name in names {
+        let new_field = new_fields.get(name);
+        if old_fields.get(name) == new_field {
+            continue;
+        }
+        let mut changed = old_def.clone();
+        let Some(changed_fields) = changed
+            .pointer_mut("/schema/fields")
+            .and_then(Value::as_object_mut)
+        else {
+            continue;
+        };
+        match new_field {
+            Some(field) => changed_fields.insert(name.clone(), field.clone()),
+            None => changed_fields.remove(name),
+        };
+        if needs_reindex(old, &serde_json::from_value(changed)?) {
+            fields.insert(name.clone());
+        }
+    }
+
+    Ok(fields)
+}
+
+/// The fields of the value schema of a serialized definition.
+fn value_fields(definition: &Value) -> Map<String, Value> {
+    definition
+        .pointer("/schema/fields")
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// The fields of the value of a table, with their type and mapping.
+/// Nested fields are listed separately, so they are left out of the
+/// mapping of their parent.
+fn fields(definition: &DbTable) -> Result<BTreeMap<String, (String, Value)>> {
+    let mapping = serde_json::to_value(ElasticMapping::new(&definition.value_schema())?)?;
+    let mut fields = BTreeMap::new();
+    collect(&mut fields, None, &mapping);
+    Ok(fields)
+}
+
+fn collect(fields: &mut BTreeMap<String, (String, Value)>, prefix: Option<&str>, mapping: &Value) {
+    let Some(props) = mapping.get("properties").and_then(Value::as_object) else {
+        return;
+    };
+    for (name, mapping) in props {
+        let path = match prefix {
+            Some(prefix) => format!("{prefix}.{name}"),
+            None => name.to_string(),
+        };
+        let typ = mapping
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or("object")
+            .to_string();
+        let mut own = mapping.clone();
+        if let Some(own) = own.as_object_mut() {
+            own.remove("properties");
+        }
+        fields.insert(path.clone(), (typ, own));
+        collect(fields, Some(&path), mapping);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use dbdaemon_api::FieldDiff;
+    use dbschema::{HasSchema, HasTableDef, Identified, SingleVersioned};
+
+    use super::{diff, reindex_fields};
+
+    #[derive(HasSchema, Debug)]
+    #[allow(unused)]
+    struct Old {
+        name: String,
+        count: i64,
+        removed: String,
+        inner: OldInner,
+    }
+
+    #[derive(HasSchema, Debug)]
+    #[allow(unused)]
+    struct OldInner {
+        field: String,
+    }
+
+    #[derive(HasSchema, Debug)]
+    #[allow(unused)]
+    struct New {
+        name: String,
+        count: String,
+        added: bool,
+        inner: NewInner,
+    }
+
+    #[derive(HasSchema, Debug)]
+    #[allow(unused)]
+    struct NewInner {
+        field: String,
+        added: i64,
+    }
+
+    fn fields(diffs: &[FieldDiff]) -> Vec<(&str, bool, bool)> {
+        diffs
+            .iter()
+            .map(|diff| (diff.field.as_str(), diff.old.is_some(), diff.new.is_some()))
+            .collect()
+    }
+
+    #[test]
+    fn unchanged() {
+        let def = Identified::<SingleVersioned<Old>>::table_def();
+        let unchanged = diff(&def, &def).unwrap();
+        assert!(unchanged.added.is_empty());
+        assert!(unchanged.removed.is_empty());
+        assert!(unchanged.changed.is_empty());
+        assert!(!unchanged.reindex);
+    }
+
+    #[test]
+    fn changed_added_removed() {
+        let old = Identified::<SingleVersioned<Old>>::table_def();
+        let new = Identified::<SingleVersioned<New>>::table_def();
+        let forward = diff(&old, &new).unwrap();
+
+        assert_eq!(fields(&forward.changed), vec![("count", true, true)]);
+        assert_ne!(forward.changed[0].old, forward.changed[0].new);
+        /* Nested fields are reported separately; the parent is not
+         * changed by a new nested field. */
+        assert_eq!(
+            fields(&forward.added),
+            vec![("added", false, true), ("inner.added", false, true)]
+        );
+        assert_eq!(fields(&forward.removed), vec![("removed", true, false)]);
+        assert!(forward.reindex);
+
+        /* The reverse diff swaps added and removed fields. */
+        let reverse = diff(&new, &old).unwrap();
+        assert_eq!(fields(&reverse.changed), vec![("count", true, true)]);
+        assert_eq!(fields(&reverse.added), vec![("removed", false, true)]);
+        assert_eq!(
+            fields(&reverse.removed),
+            vec![("added", true, false), ("inner.added", true, false)]
+        );
+    }
+
+    #[test]
+    fn reindex_fields_changed() {
+        let old = Identified::<SingleVersioned<Old>>::table_def();
+        let new = Identified::<SingleVersioned<New>>::table_def();
+        let fields = reindex_fields(&old, &new).unwrap();
+        assert!(fields.contains("count"));
+        assert!(!fields.contains("name"));
+        assert!(reindex_fields(&old, &old).unwrap().is_empty());
+    }
+}
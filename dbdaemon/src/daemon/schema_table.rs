@@ -2,6 +2,7 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
+use dbdaemon_api::MigrationStep;
 use dbschema::{DbTable, DbTableId, HasSchema, Identified, SingleVersioned};
 use serde::{Deserialize, Serialize};
 
@@ -12,4 +13,17 @@ pub type SchemaDocument = Identified<SingleVersioned<TableInfo>>;
 pub struct TableInfo {
     #[dbschema(json)]
     pub schema: DbTable,
+    /// How the table was changed to register the definition. Not
+    /// recorded for definitions registered by older versions.
+    #[dbschema(json)]
+    #[serde(default)]
+    pub change: Option<SchemaChange>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SchemaChange {
+    /// Whether the table was reindexed.
+    pub reindexed: bool,
+    /// The migration applied to the documents, if any.
+    pub migration: Option<Vec<MigrationStep>>,
 }
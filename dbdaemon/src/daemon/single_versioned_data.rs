@@ -2,28 +2,61 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 
 use chrono::{DateTime, Utc};
+use dbdaemon_api::{ChangeKind, Drift, VersionKey};
+use dbdaemon_types::Operation;
 use dbschema::{DbSchema, DbTableId, Identified, ObjectId, SingleVersionedValue};
-use parking_lot::MappedRwLockWriteGuard;
+use parking_lot::MappedRwLockReadGuard;
 use serde_json::Value;
 
-use crate::database::{elastic, Database};
+use crate::database::{elastic::ElasticId, Database};
 
 use super::{
-    data_write::Transaction, error::Result, filters::filter_active_single, modify::modify,
-    table_data::ElasticDoc, table_mapping::TableMapping, updates::UpdateGuard,
+    changes::Change,
+    data_write::{Staged, Transaction},
+    error::{Error, Result},
+    filters::filter_active_single,
+    table_data::{ElasticDoc, TableData},
+    table_mapping::TableMapping,
+    updates::UpdateGuard,
 };
 
 #[derive(Debug)]
 pub struct SingleVersionedData(HashMap<ObjectId, SingleVersionedDoc>);
 
 pub struct SingleVersionedTransaction<'a> {
-    data: MappedRwLockWriteGuard<'a, SingleVersionedData>,
+    data: MappedRwLockReadGuard<'a, SingleVersionedData>,
     updates: HashMap<ObjectId, Option<Value>>,
 }
 
+/// Repairs to the history of single-versioned objects, found by
+/// verification.
+pub struct SingleVersionedRepair<'a> {
+    data: MappedRwLockReadGuard<'a, SingleVersionedData>,
+    closes: Vec<SingleVersionedClose>,
+}
+
+/// Close a version at the start of the overlapping version following
+/// it.
+pub struct SingleVersionedClose {
+    pub object_id: ObjectId,
+    pub doc: SingleVersionedDoc,
+    pub to: DateTime<Utc>,
+    pub next: SingleVersionedDoc,
+}
+
+/// Previous and new active versions of the objects modified by a
+/// transaction.
+pub struct SingleVersionedStaged(
+    Vec<(
+        ObjectId,
+        Option<SingleVersionedDoc>,
+        Option<SingleVersionedDoc>,
+    )>,
+);
+
 type SingleVersionedDoc = ElasticDoc<SingleVersionedValue>;
 
 impl SingleVersionedData {
@@ -32,13 +65,13 @@ impl SingleVersionedData {
         Self(HashMap::new())
     }
 
-    pub async fn load(
-        elastic: &elastic::Database,
+    pub async fn load<D: Database<Id = ElasticId>>(
+        database: &D,
         table_id: &DbTableId,
         mapping: &TableMapping,
     ) -> Result<Self> {
         Ok(Self(
-            elastic
+            database
                 .query_objects::<Identified<SingleVersionedValue>>(
                     table_id,
                     &mapping.table_schema,
@@ -66,13 +99,69 @@ impl SingleVersionedData {
         Some(&self.0.get(object_id)?.value)
     }
 
+    /// The compare-and-set token of an object: the id and version of
+    /// the document holding its active version.
+    pub fn version(&self, object_id: &ObjectId) -> Option<VersionKey> {
+        Some(self.0.get(object_id)?.version_key())
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (&ObjectId, &SingleVersionedValue)> {
         self.0.iter().map(|(k, v)| (k, &v.value))
     }
+
+    /// Compare the active versions with freshly loaded data.
+    pub fn drift(&self, loaded: &Self) -> Vec<Drift> {
+        self.drifted(loaded)
+            .map(|object_id| Drift {
+                object_id: object_id.clone(),
+                timeline: None,
+                memory: self.0.get(object_id).map(SingleVersionedDoc::version_key),
+                database: loaded.0.get(object_id).map(SingleVersionedDoc::version_key),
+            })
+            .collect()
+    }
+
+    /// Replace by freshly loaded data, returning the changes to
+    /// publish.
+    pub fn replace(&mut self, loaded: Self, now: DateTime<Utc>) -> Vec<Change> {
+        let changes = self
+            .drifted(&loaded)
+            .map(|object_id| {
+                let value = loaded.0.get(object_id).map(|doc| doc.value.value.clone());
+                Change {
+                    object_id: object_id.clone(),
+                    kind: match (self.0.contains_key(object_id), &value) {
+                        (false, _) => ChangeKind::Created,
+                        (true, Some(_)) => ChangeKind::Updated,
+                        (true, None) => ChangeKind::Removed,
+                    },
+                    timestamp: now,
+                    value,
+                }
+            })
+            .collect();
+        *self = loaded;
+        changes
+    }
+
+    fn drifted<'a>(&'a self, loaded: &'a Self) -> impl Iterator<Item = &'a ObjectId> {
+        self.0
+            .keys()
+            .chain(
+                loaded
+                    .0
+                    .keys()
+                    .filter(|object_id| !self.0.contains_key(*object_id)),
+            )
+            .filter(|object_id| {
+                self.0.get(*object_id).map(SingleVersionedDoc::key)
+                    != loaded.0.get(*object_id).map(SingleVersionedDoc::key)
+            })
+    }
 }
 
 impl<'a> SingleVersionedTransaction<'a> {
-    pub fn new(data: MappedRwLockWriteGuard<'a, SingleVersionedData>) -> Self {
+    pub fn new(data: MappedRwLockReadGuard<'a, SingleVersionedData>) -> Self {
         Self {
             data,
             updates: HashMap::new(),
@@ -86,6 +175,13 @@ impl<'a> SingleVersionedTransaction<'a> {
     //     }
     // }
 
+    /// The compare-and-set token of an object in its written state,
+    /// disregarding updates in this transaction. No other transaction
+    /// is in flight while the table is locked for writing.
+    pub fn version(&self, object_id: &ObjectId) -> Option<VersionKey> {
+        self.data.version(object_id)
+    }
+
     pub fn create(&mut self, object_id: &ObjectId, value: Value) -> bool {
         match self.updates.entry(object_id.clone()) {
             Entry::Vacant(ent) if !self.data.0.contains_key(object_id) => {
@@ -127,23 +223,55 @@ impl<'a> SingleVersionedTransaction<'a> {
             _ => false,
         }
     }
+
+    /// Add a set of operations to the transaction. Values should
+    /// have been verified against the table schema.
+    pub fn apply(
+        &mut self,
+        table_id: &DbTableId,
+        updates: HashMap<ObjectId, Operation>,
+    ) -> Result<()> {
+        updates
+            .into_iter()
+            .try_for_each(|(object_id, op)| match op {
+                Operation::Create(value) => self
+                    .create(&object_id, value)
+                    .then_some(())
+                    .ok_or_else(|| Error::ObjectIdAlreadyExists(table_id.clone(), object_id)),
+                Operation::Update(value) => self
+                    .update(&object_id, value)
+                    .then_some(())
+                    .ok_or_else(|| Error::ObjectDoesNotExist(table_id.clone(), object_id)),
+                Operation::CreateOrUpdate(value) => {
+                    self.insert(&object_id, value);
+                    Ok(())
+                }
+                Operation::Remove => self
+                    .remove(&object_id)
+                    .then_some(())
+                    .ok_or_else(|| Error::ObjectDoesNotExist(table_id.clone(), object_id)),
+            })
+    }
 }
 
 impl<'a> Transaction<'a> for SingleVersionedTransaction<'a> {
     type Value = SingleVersionedValue;
+    type Staged = SingleVersionedStaged;
     fn commit(
-        mut self,
+        self,
         now: DateTime<Utc>,
         force_update: bool,
         value_schema: &DbSchema,
         updates: &mut UpdateGuard<'a, Self::Value>,
-    ) {
+    ) -> Self::Staged {
+        let mut staged = Vec::new();
         for (object_id, update) in self.updates {
-            match (self.data.0.entry(object_id), update) {
-                (Entry::Occupied(mut active), Some(value))
+            let prev = self.data.0.get(&object_id).cloned();
+            let installed = match (prev.clone(), update) {
+                (Some(active), Some(value))
                     if force_update
                         || !value_schema
-                            .value_eq(&active.get().value.value, &value)
+                            .value_eq(&active.value.value, &value)
                             .unwrap_or_else(|e| {
                                 log::warn!(
                                     "value_eq failed during transaction \
@@ -152,26 +280,242 @@ impl<'a> Transaction<'a> for SingleVersionedTransaction<'a> {
                                 false /* values should have been checked */
                             }) =>
                 {
-                    let object_id = active.key().clone();
-                    modify(active.get_mut(), |active| {
-                        updates.replace(
-                            object_id,
-                            active,
-                            |v| v.remove(now),
-                            |v| v.update(now, value),
-                        )
-                    });
+                    Some(updates.replace(
+                        object_id.clone(),
+                        active,
+                        |v| v.remove(now),
+                        |v| v.update(now, value),
+                    ))
                 }
-                (Entry::Occupied(active), None) => {
-                    let object_id = active.key().clone();
-                    updates.remove(object_id, active.remove(), |v| v.remove(now));
+                (Some(active), None) => {
+                    updates.remove(object_id.clone(), active, |v| v.remove(now));
+                    None
                 }
-                (Entry::Vacant(active), Some(value)) => {
-                    let object_id = active.key().clone();
-                    active.insert(updates.create(object_id, SingleVersionedValue::new(now, value)));
+                (None, Some(value)) => {
+                    Some(updates.create(object_id.clone(), SingleVersionedValue::new(now, value)))
                 }
-                _ => {}
+                (active, _) => active,
+            };
+            if installed.as_ref().map(SingleVersionedDoc::key)
+                != prev.as_ref().map(SingleVersionedDoc::key)
+            {
+                let value = installed.as_ref().map(|doc| doc.value.value.clone());
+                let kind = match (&prev, &value) {
+                    (None, _) => ChangeKind::Created,
+                    (Some(_), Some(_)) => ChangeKind::Updated,
+                    (Some(_), None) => ChangeKind::Removed,
+                };
+                updates.change(Change {
+                    object_id: object_id.clone(),
+                    kind,
+                    timestamp: now,
+                    value,
+                });
+                staged.push((object_id, prev, installed));
             }
         }
+        SingleVersionedStaged(staged)
+    }
+}
+
+impl<'a> SingleVersionedRepair<'a> {
+    pub fn new(
+        data: MappedRwLockReadGuard<'a, SingleVersionedData>,
+        closes: Vec<SingleVersionedClose>,
+    ) -> Self {
+        Self { data, closes }
+    }
+}
+
+impl<'a> Transaction<'a> for SingleVersionedRepair<'a> {
+    type Value = SingleVersionedValue;
+    type Staged = SingleVersionedStaged;
+    fn commit(
+        self,
+        now: DateTime<Utc>,
+        _force_update: bool,
+        _value_schema: &DbSchema,
+        updates: &mut UpdateGuard<'a, Self::Value>,
+    ) -> Self::Staged {
+        let mut staged = Vec::new();
+        for SingleVersionedClose {
+            object_id,
+            doc,
+            to,
+            next,
+        } in self.closes
+        {
+            /* If the closed version is the active version in memory,
+             * the in-memory copy is the most recent one. */
+            let prev = self.data.0.get(&object_id).cloned();
+            let in_memory = prev
+                .as_ref()
+                .filter(|active| active.elastic_id == doc.elastic_id);
+            let doc = in_memory.cloned().unwrap_or(doc);
+            updates.update(object_id.clone(), doc, |mut v| {
+                v.version.active.to = Some(to);
+                v
+            });
+
+            if in_memory.is_none() {
+                continue;
+            }
+
+            /* The closed version no longer is the active version;
+             * replace it with the version following it, if that one
+             * is still open. */
+            let installed = match next.value.version.active.to {
+                None => Some(next),
+                Some(_) => None,
+            };
+            let value = installed.as_ref().map(|doc| doc.value.value.clone());
+            updates.change(Change {
+                object_id: object_id.clone(),
+                kind: match &value {
+                    Some(_) => ChangeKind::Updated,
+                    None => ChangeKind::Removed,
+                },
+                timestamp: now,
+                value,
+            });
+            staged.push((object_id, prev, installed));
+        }
+        SingleVersionedStaged(staged)
+    }
+}
+
+impl Staged for SingleVersionedStaged {
+    fn apply(self: Box<Self>, data: &mut TableData) {
+        let Some(data) = data.single_versioned_mut() else {
+            return;
+        };
+        for (object_id, _, installed) in self.0 {
+            match installed {
+                Some(doc) => data.0.insert(object_id, doc),
+                None => data.0.remove(&object_id),
+            };
+        }
+    }
+
+    fn revert(self: Box<Self>, data: &mut TableData, touched: &HashSet<ElasticId>) {
+        let Some(data) = data.single_versioned_mut() else {
+            return;
+        };
+        for (object_id, prev, _) in self.0 {
+            if let Some(prev) = prev {
+                data.0.insert(object_id, prev.revert(touched));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeDelta, Utc};
+    use dbschema::{
+        DbTableId, HasSchema, HasTableDef, Identified, ObjectId, SingleVersioned,
+        SingleVersionedValue,
+    };
+    use serde_json::json;
+
+    use crate::daemon::{
+        state::State,
+        table_data::ElasticDoc,
+        table_state::{TableNonOperationalState, TableOperationalState},
+    };
+
+    use super::SingleVersionedClose;
+
+    #[tokio::test]
+    async fn repair_closes_overlapping_version() {
+        type Document = Identified<SingleVersioned<Object>>;
+        #[derive(HasSchema, Debug)]
+        #[allow(unused)]
+        struct Object {
+            field: String,
+        }
+
+        let state = State::new();
+        let table_id = DbTableId::new("test-table");
+
+        {
+            let (_schemas, mut table) = state
+                .write_table(
+                    &table_id,
+                    "test",
+                    TableNonOperationalState::Registering,
+                    true,
+                )
+                .await
+                .unwrap();
+            table.or_insert_with(|| TableOperationalState::new(Document::table_def()));
+        }
+
+        let table = state.read_table(&table_id, "test").await.unwrap();
+        let object_id = ObjectId::new();
+
+        {
+            let writer = table.lock_writes().await;
+            let mut data = writer.write_data_single_versioned(Utc::now()).unwrap();
+            assert!(data.create(&object_id, json!({"field": "a"})));
+            data.commit().apply();
+        }
+
+        /* An open version overlapping the active one. */
+        let active = table
+            .read_data_single_versioned()
+            .unwrap()
+            .0
+            .get(&object_id)
+            .cloned()
+            .unwrap();
+        let from = active.value.version.active.from + TimeDelta::seconds(1);
+        let next = ElasticDoc::new(SingleVersionedValue::new(from, json!({"field": "b"})));
+        let close = |doc: &ElasticDoc<SingleVersionedValue>| {
+            vec![SingleVersionedClose {
+                object_id: object_id.clone(),
+                doc: doc.clone(),
+                to: from,
+                next: next.clone(),
+            }]
+        };
+
+        /* The closed version is written with a new version. */
+        {
+            let writer = table.lock_writes().await;
+            let data = writer
+                .repair_data_single_versioned(Utc::now(), close(&active))
+                .unwrap();
+            let updates = data.commit().extract();
+            let (version, doc) = updates.get(&active.elastic_id).unwrap();
+            assert_eq!(updates.len(), 1);
+            assert_eq!(*version, active.version + 1);
+            assert_eq!(doc.value.version.active.to, Some(from));
+        }
+
+        /* A stale copy of the closed version does not affect the
+         * in-memory state. */
+        {
+            let stale = ElasticDoc::new(active.value.clone());
+            let writer = table.lock_writes().await;
+            let data = writer
+                .repair_data_single_versioned(Utc::now(), close(&stale))
+                .unwrap();
+            data.commit().apply();
+            let data = table.read_data_single_versioned().unwrap();
+            assert_eq!(data.version(&object_id), Some(active.version_key()));
+        }
+
+        /* When the closed version is the active one, the version
+         * following it becomes active. */
+        {
+            let writer = table.lock_writes().await;
+            let data = writer
+                .repair_data_single_versioned(Utc::now(), close(&active))
+                .unwrap();
+            data.commit().apply();
+            let data = table.read_data_single_versioned().unwrap();
+            assert_eq!(data.version(&object_id), Some(next.version_key()));
+        }
     }
 }
@@ -0,0 +1,163 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use dbdaemon_api::{SnapshotQuery, SnapshotTable};
+use dbschema::{
+    DbTableId, DualVersionedValue, Filter, FilterPath, Identified, ObjectId, SingleVersionedValue,
+    TimeRange, Timeline, VersioningType,
+};
+
+use crate::database::{elastic::ElasticId, Database};
+
+use super::error::{Error, Result};
+use super::filters::{history_filter_dual, history_filter_single};
+use super::state::State;
+use super::table_read::TableReadGuard;
+
+/// Read a snapshot of multiple tables. All tables are read-locked
+/// (in order of their id) for the duration of the snapshot, so that
+/// none of them is reindexed or unregistered in between.
+pub(super) async fn read<D: Database<Id = ElasticId>>(
+    database: &D,
+    state: &State,
+    queries: Vec<SnapshotQuery>,
+    timestamp: Option<DateTime<Utc>>,
+) -> Result<HashMap<DbTableId, SnapshotTable>> {
+    let mut queries_by_table = BTreeMap::new();
+    for query in queries {
+        let table_id = query.table_id.clone();
+        if queries_by_table.insert(table_id.clone(), query).is_some() {
+            return Err(Error::DuplicateTable(table_id));
+        }
+    }
+
+    let table_ids = queries_by_table.keys().cloned().collect::<BTreeSet<_>>();
+    let tables = state.read_tables(&table_ids, "read_snapshot").await?;
+    let queries = tables.iter().zip(queries_by_table.into_values());
+
+    match timestamp {
+        None => current(queries),
+        Some(timestamp) => {
+            let mut snapshot = HashMap::new();
+            for (table, query) in queries {
+                snapshot.insert(
+                    query.table_id.clone(),
+                    at(database, table, query, timestamp).await?,
+                );
+            }
+            Ok(snapshot)
+        }
+    }
+}
+
+/// Read the current state from memory. The data of all tables is
+/// locked before any of it is read, in the same order as in
+/// transactions, so that the snapshot is mutually consistent.
+fn current<'a, 'b: 'a, I>(queries: I) -> Result<HashMap<DbTableId, SnapshotTable>>
+where
+    I: Iterator<Item = (&'a TableReadGuard<'b>, SnapshotQuery)>,
+{
+    let locked = queries
+        .map(|(table, query)| (table, table.data.read(), query))
+        .collect::<Vec<_>>();
+
+    locked
+        .iter()
+        .map(|(table, data, query)| {
+            let matches = |value: &Value| match &query.filter {
+                Some(filter) => filter.matches(&table.mapping.value_schema, value),
+                None => Ok(true),
+            };
+            let result = if let Some(data) = data.single_versioned() {
+                let mut objects = HashMap::new();
+                for (object_id, value) in data.iter() {
+                    if matches(&value.value)? {
+                        objects.insert(object_id.clone(), value.clone());
+                    }
+                }
+                SnapshotTable::Discovery(objects)
+            } else if let Some(data) = data.dual_versioned() {
+                let timeline = query.timeline.unwrap_or(Timeline::Active);
+                let mut objects = HashMap::new();
+                for (object_id, value) in data.iter(timeline) {
+                    if matches(&value.value)? {
+                        objects.insert(object_id.clone(), value.clone());
+                    }
+                }
+                SnapshotTable::Config(objects)
+            } else {
+                return Err(Error::NoTimeline("read_snapshot", query.table_id.clone()));
+            };
+            Ok((query.table_id.clone(), result))
+        })
+        .collect()
+}
+
+/// Read the state of a table at a past instant from the database.
+async fn at<D: Database<Id = ElasticId>>(
+    database: &D,
+    table: &TableReadGuard<'_>,
+    query: SnapshotQuery,
+    timestamp: DateTime<Utc>,
+) -> Result<SnapshotTable> {
+    let range = TimeRange::at(timestamp);
+    let value_filter = FilterPath::new()
+        .field("value")
+        .field("value")
+        .filter(query.filter.unwrap_or(Filter::All(vec![])));
+    match &table.mapping.table.versioning {
+        VersioningType::SingleTimeline => Ok(SnapshotTable::Discovery(
+            query_at::<D, SingleVersionedValue>(
+                database,
+                table,
+                &query.table_id,
+                value_filter.and(history_filter_single(range)),
+            )
+            .await?,
+        )),
+        VersioningType::DualTimeline => {
+            let timeline = query.timeline.unwrap_or(Timeline::Active);
+            Ok(SnapshotTable::Config(
+                query_at::<D, DualVersionedValue>(
+                    database,
+                    table,
+                    &query.table_id,
+                    value_filter.and(history_filter_dual(timeline, range)),
+                )
+                .await?,
+            ))
+        }
+        VersioningType::Timestamped => Err(Error::NoTimeline("read_snapshot", query.table_id)),
+    }
+}
+
+async fn query_at<D, T>(
+    database: &D,
+    table: &TableReadGuard<'_>,
+    table_id: &DbTableId,
+    filter: Filter,
+) -> Result<HashMap<ObjectId, T>>
+where
+    D: Database<Id = ElasticId>,
+    T: DeserializeOwned + Send + Sync,
+{
+    Ok(database
+        .query_objects::<Identified<T>>(
+            table_id,
+            &table.mapping.table_schema,
+            &filter,
+            &table.mapping.sort_fields,
+            None,
+        )
+        .await?
+        .into_iter()
+        .map(|(_id, _version, obj)| (obj.object_id, obj.value))
+        .collect())
+}
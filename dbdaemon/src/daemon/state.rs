@@ -3,17 +3,23 @@
  ******************************************************************************/
 
 use std::collections::hash_map::Entry;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
 
 use parking_lot::RwLock;
-use tokio::sync::{OwnedRwLockReadGuard, RwLock as AsyncRwLock};
+use tokio::sync::{
+    OwnedRwLockMappedWriteGuard, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock as AsyncRwLock,
+};
 
-use dbschema::{DbTableId, HasTableDef};
+use dbschema::{DbSchema, DbTableId, HasTableDef};
 use tracing::instrument;
 
-use crate::database::{elastic, Database};
+use crate::database::{elastic::ElasticId, Database};
 
 use super::error::{Error, Result};
+use super::journal::{Journal, JournalOp, TableOps};
 use super::schema_table::{SchemaDocument, TableInfo, SCHEMA_TABLE};
 use super::table_read::TableReadGuard;
 use super::table_state::{TableNonOperationalState, TableOperationalState, TableState};
@@ -32,8 +38,21 @@ impl State {
         )])))
     }
 
-    pub async fn load(elastic: &elastic::Database) -> Result<Self> {
-        elastic.wait_for_database().await?;
+    pub async fn load<D: Database<Id = ElasticId>>(
+        database: &D,
+        journal: &Journal,
+    ) -> Result<Self> {
+        database.wait_for_database().await?;
+
+        let mut recovered = HashMap::<DbTableId, Vec<TableOps>>::new();
+        for batch in journal.take_recovered().await {
+            for table in batch.tables {
+                recovered
+                    .entry(table.table_id.clone())
+                    .or_default()
+                    .push(table);
+            }
+        }
 
         // Load schema table.
 
@@ -41,15 +60,24 @@ impl State {
 
         let schema_table_def = SchemaDocument::table_def();
 
-        if !elastic.has_table(SCHEMA_TABLE).await? {
-            elastic
+        if !database.has_table(SCHEMA_TABLE).await? {
+            database
                 .create_table(SCHEMA_TABLE, &schema_table_def)
                 .await?;
+        } else {
+            /* Add fields introduced since the table was created. */
+            database
+                .update_table(SCHEMA_TABLE, &schema_table_def)
+                .await?;
+        }
+
+        if let Some(batches) = recovered.remove(SCHEMA_TABLE) {
+            Self::replay(database, SCHEMA_TABLE, &schema_table_def.schema(), batches).await?;
         }
 
         log::info!("Loading schemas...");
         let mut schema_info =
-            TableOperationalState::load(elastic, SCHEMA_TABLE, schema_table_def).await?;
+            TableOperationalState::load(database, SCHEMA_TABLE, schema_table_def).await?;
 
         let schemas = schema_info
             .get_data_single_versioned()
@@ -69,17 +97,60 @@ impl State {
             Arc::new(AsyncRwLock::new(TableState::new(schema_info))),
         );
 
+        let table_ids = schemas
+            .keys()
+            .chain([SCHEMA_TABLE])
+            .cloned()
+            .collect::<Vec<_>>();
+        database.recover(&table_ids).await?;
+
         // Load other tables
 
         for (table_id, table_def) in schemas {
+            if let Some(batches) = recovered.remove(&table_id) {
+                Self::replay(database, &table_id, &table_def.schema(), batches).await?;
+            }
             log::info!("Loading {table_id}...");
-            let state = TableOperationalState::load(elastic, &table_id, table_def).await?;
+            let state = TableOperationalState::load(database, &table_id, table_def).await?;
             tables.insert(table_id, Arc::new(AsyncRwLock::new(TableState::new(state))));
         }
 
+        for table_id in recovered.keys() {
+            log::warn!("discarding journal entries for unknown table {table_id}");
+        }
+
+        journal.clear().await?;
+
         Ok(Self(RwLock::new(tables)))
     }
 
+    /// Complete updates that were journaled but possibly not (fully)
+    /// written before a crash.
+    async fn replay<D: Database<Id = ElasticId>>(
+        database: &D,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        batches: Vec<TableOps>,
+    ) -> Result<()> {
+        log::info!(
+            "Replaying {} journal batch(es) for {table_id}...",
+            batches.len()
+        );
+        for batch in batches {
+            let mut updates = Vec::new();
+            let mut deletes = Vec::new();
+            for op in batch.ops {
+                match op {
+                    JournalOp::Index { id, version, doc } => updates.push((id, version, doc)),
+                    JournalOp::Delete { id, version } => deletes.push((id, version)),
+                }
+            }
+            database.bulk_update(table_id, schema, updates).await?;
+            database.bulk_delete(table_id, deletes).await?;
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     pub async fn read_table<'a>(
         &'a self,
@@ -100,6 +171,22 @@ impl State {
         Ok(TableReadGuard::new(table_id, method, oper_state))
     }
 
+    /// Lock multiple tables for reading. Tables are locked in order
+    /// of their id, to avoid deadlocks between concurrent multi-table
+    /// operations.
+    #[instrument(skip(self))]
+    pub async fn read_tables<'a>(
+        &'a self,
+        table_ids: &'a BTreeSet<DbTableId>,
+        method: &'static str,
+    ) -> Result<Vec<TableReadGuard<'a>>> {
+        let mut tables = Vec::with_capacity(table_ids.len());
+        for table_id in table_ids {
+            tables.push(self.read_table(table_id, method).await?);
+        }
+        Ok(tables)
+    }
+
     #[instrument(skip(self))]
     pub async fn read_table_owned<'a>(
         &'a self,
@@ -120,6 +207,37 @@ impl State {
         Ok(TableReadGuard::new_owned(table_id, method, oper_state))
     }
 
+    /// Lock a table exclusively, without taking it out of operation.
+    /// Requests on the table wait until the lock is released.
+    #[instrument(skip(self))]
+    pub async fn lock_table(
+        &self,
+        table_id: &DbTableId,
+    ) -> Result<OwnedRwLockMappedWriteGuard<TableState, TableOperationalState>> {
+        let table_state = self.lock_table_state(table_id).await?;
+        Ok(OwnedRwLockWriteGuard::map(table_state, |s| {
+            s.write(table_id).unwrap() // checked by lock_table_state
+        }))
+    }
+
+    /// Lock a table exclusively, like `lock_table`, but return the
+    /// unmapped guard, so that it can be downgraded to a shared lock.
+    #[instrument(skip(self))]
+    pub async fn lock_table_state(
+        &self,
+        table_id: &DbTableId,
+    ) -> Result<OwnedRwLockWriteGuard<TableState>> {
+        let table_state = self
+            .0
+            .read()
+            .get(table_id)
+            .ok_or_else(|| Error::TableNotFound(table_id.clone()))?
+            .clone();
+        let table_state = table_state.write_owned().await;
+        let _ = table_state.read(table_id)?;
+        Ok(table_state)
+    }
+
     #[instrument(skip(self))]
     pub async fn write_table<'a>(
         &'a self,
@@ -182,3 +300,100 @@ impl State {
         debug_assert!(prev.is_some());
     }
 }
+
+#[cfg(all(test, feature = "memory"))]
+mod test {
+    use chrono::Utc;
+    use dbschema::{
+        DbTableId, HasSchema, HasTableDef, Identified, ObjectId, SingleVersioned,
+        SingleVersionedValue,
+    };
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+
+    use crate::daemon::journal::{Journal, JournalOp, TableOps};
+    use crate::daemon::schema_table::{TableInfo, SCHEMA_TABLE};
+    use crate::database::{
+        elastic::ElasticId,
+        memory::{Database, DatabaseConfig},
+        Database as _,
+    };
+
+    use super::State;
+
+    #[tokio::test]
+    async fn load_replays_journal() {
+        type Document = Identified<SingleVersioned<Object>>;
+        #[derive(HasSchema, Debug)]
+        #[allow(unused)]
+        struct Object {
+            field: String,
+        }
+
+        let path = std::env::temp_dir().join(format!("dbdaemon-journal-{}", Uuid::new_v4()));
+        let db = Database::new(DatabaseConfig::default()).await.unwrap();
+        let table_id = DbTableId::new("test-table");
+        let table_def = Document::table_def();
+        db.create_table(&table_id, &table_def).await.unwrap();
+
+        let doc = |object_id: &ObjectId, value: Value| {
+            serde_json::to_value(Identified {
+                object_id: object_id.clone(),
+                value: SingleVersionedValue::new(Utc::now(), value),
+            })
+            .unwrap()
+        };
+        let index = |table_id: &DbTableId, doc: Value| TableOps {
+            table_id: table_id.clone(),
+            ops: vec![JournalOp::Index {
+                id: ElasticId::new(),
+                version: 1,
+                doc,
+            }],
+        };
+
+        /* The table is registered and written in the same batch, as
+         * the schema table is replayed before the schemas are loaded. */
+        let schema_id = ObjectId::from(table_id.to_string());
+        let schema = serde_json::to_value(TableInfo {
+            schema: table_def.clone(),
+            change: None,
+        })
+        .unwrap();
+        let pending = ObjectId::new();
+        let done = ObjectId::new();
+
+        let journal = Journal::open(&path).await.unwrap();
+        journal
+            .begin(vec![
+                index(SCHEMA_TABLE, doc(&schema_id, schema)),
+                index(&table_id, doc(&pending, json!({"field": "a"}))),
+            ])
+            .await
+            .unwrap();
+        let batch = journal
+            .begin(vec![index(&table_id, doc(&done, json!({"field": "b"})))])
+            .await
+            .unwrap();
+        journal.done(batch).await;
+        drop(journal);
+
+        let journal = Journal::open(&path).await.unwrap();
+        let state = State::load(&db, &journal).await.unwrap();
+
+        {
+            let table = state.read_table(&table_id, "test").await.unwrap();
+            let data = table.read_data_single_versioned().unwrap();
+            assert_eq!(
+                data.get(&pending).map(|v| v.value.clone()),
+                Some(json!({"field": "a"}))
+            );
+            assert!(data.get(&done).is_none());
+        }
+
+        /* Replayed batches are cleared from the journal. */
+        assert!(journal.take_recovered().await.is_empty());
+        assert!(tokio::fs::read(&path).await.unwrap().is_empty());
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -2,12 +2,18 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-#[cfg(test)]
-use dbschema::VersioningType;
+use std::collections::HashSet;
 
-use crate::database::elastic::ElasticId;
+use chrono::{DateTime, Utc};
+use dbdaemon_api::{Drift, VersionKey};
+use dbschema::{DbTableId, VersioningType};
 
-use super::{dual_versioned_data::DualVersionedData, single_versioned_data::SingleVersionedData};
+use crate::database::{elastic::ElasticId, Database};
+
+use super::{
+    changes::Change, dual_versioned_data::DualVersionedData, error::Result,
+    single_versioned_data::SingleVersionedData, table_mapping::TableMapping,
+};
 
 #[derive(Debug)]
 pub enum TableData {
@@ -53,6 +59,30 @@ impl<T> ElasticDoc<T> {
         let prev = self.update(prev);
         (prev, new)
     }
+
+    pub fn key(&self) -> (ElasticId, u64) {
+        (self.elastic_id.clone(), self.version)
+    }
+
+    pub fn version_key(&self) -> VersionKey {
+        VersionKey {
+            version_id: self.elastic_id.to_string(),
+            version: self.version,
+        }
+    }
+
+    /// Restore a document after a failed write. If the document was
+    /// part of the write, its version is bumped past the version of
+    /// the compensating write.
+    pub fn revert(self, touched: &HashSet<ElasticId>) -> Self {
+        match touched.contains(&self.elastic_id) {
+            true => Self {
+                version: self.version + 2,
+                ..self
+            },
+            false => self,
+        }
+    }
 }
 
 impl TableData {
@@ -65,6 +95,42 @@ impl TableData {
         }
     }
 
+    /// Load the active (and current) versions of a table.
+    pub async fn load<D: Database<Id = ElasticId>>(
+        database: &D,
+        table_id: &DbTableId,
+        mapping: &TableMapping,
+    ) -> Result<Self> {
+        Ok(match &mapping.table.versioning {
+            VersioningType::Timestamped => Self::Timestamped,
+            VersioningType::SingleTimeline => {
+                Self::SingleTimeline(SingleVersionedData::load(database, table_id, mapping).await?)
+            }
+            VersioningType::DualTimeline => {
+                Self::DualTimeline(DualVersionedData::load(database, table_id, mapping).await?)
+            }
+        })
+    }
+
+    /// Compare with freshly loaded data.
+    pub fn drift(&self, loaded: &Self) -> Vec<Drift> {
+        match (self, loaded) {
+            (Self::SingleTimeline(data), Self::SingleTimeline(loaded)) => data.drift(loaded),
+            (Self::DualTimeline(data), Self::DualTimeline(loaded)) => data.drift(loaded),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Replace by freshly loaded data, returning the changes to
+    /// publish.
+    pub fn replace(&mut self, loaded: Self, now: DateTime<Utc>) -> Vec<Change> {
+        match (self, loaded) {
+            (Self::SingleTimeline(data), Self::SingleTimeline(loaded)) => data.replace(loaded, now),
+            (Self::DualTimeline(data), Self::DualTimeline(loaded)) => data.replace(loaded, now),
+            _ => Vec::new(),
+        }
+    }
+
     pub fn single_versioned(&self) -> Option<&SingleVersionedData> {
         match self {
             Self::SingleTimeline(data) => Some(data),
@@ -2,10 +2,14 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use dbschema::{DbSchema, DbTable};
-use serde_json::Value;
+use dbdaemon_api::{Aggregation, MetricFunction, SortField, SortOrder};
+use dbdaemon_types::Operation;
+use dbschema::{DbSchema, DbTable, DbTableId};
+use serde_json::{json, Value};
 
-use super::error::Result;
+use crate::database::elastic::ElasticMapping;
+
+use super::error::{Error, Result};
 
 #[derive(Debug)]
 pub struct TableMapping {
@@ -28,4 +32,115 @@ impl TableMapping {
     pub fn verify_value(&self, value: &Value) -> Result<()> {
         Ok(self.value_schema.verify_value(value)?)
     }
+
+    pub fn verify_operations<'a, I>(&self, ops: I) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a Operation>,
+    {
+        ops.into_iter().try_for_each(|op| match op {
+            Operation::Create(v) | Operation::Update(v) | Operation::CreateOrUpdate(v) => {
+                self.verify_value(v)
+            }
+            Operation::Remove => Ok(()),
+        })
+    }
+
+    /// Build a sort specification from the requested sort fields.
+    /// Fields must be mapped to a sortable type.
+    pub fn sort_spec(&self, table_id: &DbTableId, sort: &[SortField]) -> Result<Value> {
+        let mapping = serde_json::to_value(ElasticMapping::new(&self.table_schema)?)?;
+        sort.iter()
+            .map(|sort| {
+                let field = keyword_field(&mapping, &sort.field)
+                    .ok_or_else(|| Error::InvalidSortField(table_id.clone(), sort.field.clone()))?;
+                let order = match sort.order {
+                    SortOrder::Asc => "asc",
+                    SortOrder::Desc => "desc",
+                };
+                Ok(json!({ field: { "order": order } }))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Value::Array)
+    }
+
+    /// Validate an aggregation against the value schema. Metrics
+    /// other than counts require numeric fields. Field names are
+    /// resolved to their aggregatable form.
+    pub fn aggregation(
+        &self,
+        table_id: &DbTableId,
+        mut aggregation: Aggregation,
+    ) -> Result<Aggregation> {
+        let mapping = serde_json::to_value(ElasticMapping::new(&self.value_schema)?)?;
+        let invalid = |msg: String| Error::InvalidAggregation(table_id.clone(), msg);
+
+        if aggregation.interval == 0 {
+            return Err(invalid(String::from("the interval must be positive")));
+        }
+
+        for metric in &mut aggregation.metrics {
+            let typ = field_type(&mapping, &metric.field)
+                .ok_or_else(|| invalid(format!("unknown field '{}'", metric.field)))?;
+            match metric.function {
+                MetricFunction::Count => {
+                    metric.field = keyword_field(&mapping, &metric.field).ok_or_else(|| {
+                        invalid(format!("cannot count values of field '{}'", metric.field))
+                    })?;
+                }
+                MetricFunction::Percentile(p) if !(0.0..=100.0).contains(&p) => {
+                    return Err(invalid(format!("invalid percentile {p}")));
+                }
+                _ if !NUMERIC_TYPES.contains(&typ) => {
+                    return Err(invalid(format!("field '{}' is not numeric", metric.field)));
+                }
+                _ => {}
+            }
+        }
+
+        for field in &mut aggregation.group_by {
+            *field = keyword_field(&mapping, field)
+                .ok_or_else(|| invalid(format!("cannot group on field '{field}'")))?;
+        }
+
+        Ok(aggregation)
+    }
+}
+
+/// Elasticsearch field types holding numbers.
+const NUMERIC_TYPES: &[&str] = &[
+    "long",
+    "integer",
+    "short",
+    "byte",
+    "double",
+    "float",
+    "half_float",
+    "scaled_float",
+    "unsigned_long",
+];
+
+/// The mapped type of a (dotted) field path.
+fn field_type<'a>(mapping: &'a Value, field: &str) -> Option<&'a str> {
+    field
+        .split('.')
+        .try_fold(mapping, |mapping, name| {
+            mapping.get("properties")?.get(name)
+        })?
+        .get("type")?
+        .as_str()
+}
+
+/// The name under which a field can be sorted or aggregated on. Text
+/// fields are only usable through their keyword sub-field.
+fn keyword_field(mapping: &Value, field: &str) -> Option<String> {
+    let props = field.split('.').try_fold(mapping, |mapping, name| {
+        mapping.get("properties")?.get(name)
+    })?;
+    match props.get("type")?.as_str()? {
+        "text" => props
+            .get("fields")?
+            .get("keyword")
+            .map(|_| format!("{field}.keyword")),
+        _ => Some(field.to_string()),
+    }
 }
@@ -6,19 +6,29 @@ use std::{borrow::Cow, ops::Deref};
 
 use chrono::{DateTime, Utc};
 use dbschema::DbTableId;
-use parking_lot::{RwLockReadGuard, RwLockWriteGuard};
-use tokio::sync::OwnedRwLockReadGuard;
+use parking_lot::RwLockReadGuard;
+use tokio::sync::{MutexGuard, OwnedRwLockReadGuard};
 
 use super::{
     data_read::DataReadGuard,
     data_write::DataWriteGuard,
     dual_versioned_data::{DualVersionedData, DualVersionedTransaction},
     error::{Error, Result},
-    single_versioned_data::{SingleVersionedData, SingleVersionedTransaction},
+    single_versioned_data::{
+        SingleVersionedClose, SingleVersionedData, SingleVersionedRepair,
+        SingleVersionedTransaction,
+    },
     table_data::TableData,
     table_state::{TableOperationalState, TableState},
 };
 
+/// Exclusive right to write to a table, obtained through
+/// `TableReadGuard::lock_writes`.
+pub struct TableWriteLock<'a> {
+    table: &'a TableReadGuard<'a>,
+    _writer: MutexGuard<'a, ()>,
+}
+
 pub struct TableReadGuard<'a> {
     pub table_id: Cow<'a, DbTableId>,
     pub method: &'static str,
@@ -50,42 +60,73 @@ impl<'a> TableReadGuard<'a> {
         }
     }
 
+    /// Lock the table for writing. Transactions on the table are
+    /// serialized: the lock is held until the database write for the
+    /// transaction has been resolved, so that a transaction never
+    /// builds on changes that may yet be rolled back.
+    pub async fn lock_writes(&'a self) -> TableWriteLock<'a> {
+        TableWriteLock {
+            table: self,
+            _writer: self.writer.lock().await,
+        }
+    }
+
     pub fn read_data_single_versioned(&self) -> Result<DataReadGuard<'_, SingleVersionedData>> {
         let data = RwLockReadGuard::try_map(self.data.read(), TableData::single_versioned)
             .map_err(|_| Error::NoTimeline(self.method, (*self.table_id).clone()))?;
         Ok(DataReadGuard::new(self, data))
     }
 
+    pub fn read_data_dual_versioned(&self) -> Result<DataReadGuard<'_, DualVersionedData>> {
+        let data = RwLockReadGuard::try_map(self.data.read(), TableData::dual_versioned)
+            .map_err(|_| Error::NoTimeline(self.method, (*self.table_id).clone()))?;
+        Ok(DataReadGuard::new(self, data))
+    }
+}
+
+impl Deref for TableReadGuard<'_> {
+    type Target = TableOperationalState;
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl<'a> TableWriteLock<'a> {
     pub fn write_data_single_versioned(
         &'a self,
         now: DateTime<Utc>,
     ) -> Result<DataWriteGuard<'a, SingleVersionedTransaction<'a>>> {
-        let data = RwLockWriteGuard::try_map(self.data.write(), TableData::single_versioned_mut)
+        let data = RwLockReadGuard::try_map(self.table.data.read(), TableData::single_versioned)
             .map_err(|_| Error::NoTimeline(self.method, (*self.table_id).clone()))?;
         let transaction = SingleVersionedTransaction::new(data);
-        Ok(DataWriteGuard::new(self, now, transaction))
+        Ok(DataWriteGuard::new(self.table, now, transaction))
     }
 
-    pub fn read_data_dual_versioned(&self) -> Result<DataReadGuard<'_, DualVersionedData>> {
-        let data = RwLockReadGuard::try_map(self.data.read(), TableData::dual_versioned)
+    pub fn repair_data_single_versioned(
+        &'a self,
+        now: DateTime<Utc>,
+        closes: Vec<SingleVersionedClose>,
+    ) -> Result<DataWriteGuard<'a, SingleVersionedRepair<'a>>> {
+        let data = RwLockReadGuard::try_map(self.table.data.read(), TableData::single_versioned)
             .map_err(|_| Error::NoTimeline(self.method, (*self.table_id).clone()))?;
-        Ok(DataReadGuard::new(self, data))
+        let transaction = SingleVersionedRepair::new(data, closes);
+        Ok(DataWriteGuard::new(self.table, now, transaction))
     }
 
     pub fn write_data_dual_versioned(
         &'a self,
         now: DateTime<Utc>,
     ) -> Result<DataWriteGuard<'a, DualVersionedTransaction<'a>>> {
-        let data = RwLockWriteGuard::try_map(self.data.write(), TableData::dual_versioned_mut)
+        let data = RwLockReadGuard::try_map(self.table.data.read(), TableData::dual_versioned)
             .map_err(|_| Error::NoTimeline(self.method, (*self.table_id).clone()))?;
         let transaction = DualVersionedTransaction::new(data);
-        Ok(DataWriteGuard::new(self, now, transaction))
+        Ok(DataWriteGuard::new(self.table, now, transaction))
     }
 }
 
-impl Deref for TableReadGuard<'_> {
-    type Target = TableOperationalState;
+impl<'a> Deref for TableWriteLock<'a> {
+    type Target = TableReadGuard<'a>;
     fn deref(&self) -> &Self::Target {
-        &self.state
+        self.table
     }
 }
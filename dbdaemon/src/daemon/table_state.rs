@@ -2,15 +2,16 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::fmt::Display;
+use std::{fmt::Display, sync::Arc};
 
-use dbschema::{DbTable, DbTableId, VersioningType};
+use dbschema::{DbTable, DbTableId};
 use parking_lot::RwLock;
+use tokio::sync::Mutex as AsyncMutex;
 
-use crate::database::elastic;
+use crate::database::{elastic::ElasticId, Database};
 
 use super::{
-    dual_versioned_data::DualVersionedData,
+    changes::ChangeLog,
     error::{Error, Result},
     single_versioned_data::SingleVersionedData,
     table_data::TableData,
@@ -29,6 +30,7 @@ pub enum TableNonOperationalState {
     Updating,
     Reloading,
     Reindexing,
+    Importing,
     Unregistering,
 }
 
@@ -36,6 +38,10 @@ pub enum TableNonOperationalState {
 pub struct TableOperationalState {
     pub mapping: TableMapping,
     pub data: RwLock<TableData>,
+    pub changes: Arc<ChangeLog>,
+    /// Held by transactions from commit until their database write
+    /// has been resolved.
+    pub writer: AsyncMutex<()>,
 }
 
 impl TableState {
@@ -63,6 +69,13 @@ impl TableState {
             Self::NonOperational(state) => Err(Error::TableNotReady(table_id.clone(), *state)),
         }
     }
+
+    pub fn write(&mut self, table_id: &DbTableId) -> Result<&mut TableOperationalState> {
+        match self {
+            Self::Operational(state) => Ok(state),
+            Self::NonOperational(state) => Err(Error::TableNotReady(table_id.clone(), *state)),
+        }
+    }
 }
 
 impl TableOperationalState {
@@ -71,29 +84,25 @@ impl TableOperationalState {
         Self {
             data: RwLock::new(TableData::new(table.versioning)),
             mapping: TableMapping::new(table),
+            changes: Arc::new(ChangeLog::new()),
+            writer: AsyncMutex::new(()),
         }
     }
 
-    pub async fn load(
-        elastic: &elastic::Database,
+    pub async fn load<D: Database<Id = ElasticId>>(
+        database: &D,
         table_id: &DbTableId,
         table_def: DbTable,
     ) -> Result<Self> {
         let mapping = TableMapping::new(table_def);
-        elastic.refresh_table(table_id).await?;
-
-        let data = match &mapping.table.versioning {
-            VersioningType::Timestamped => TableData::Timestamped,
-            VersioningType::SingleTimeline => TableData::SingleTimeline(
-                SingleVersionedData::load(elastic, table_id, &mapping).await?,
-            ),
-            VersioningType::DualTimeline => {
-                TableData::DualTimeline(DualVersionedData::load(elastic, table_id, &mapping).await?)
-            }
-        };
+        database.refresh_table(table_id).await?;
+
+        let data = TableData::load(database, table_id, &mapping).await?;
         Ok(Self {
             mapping,
             data: RwLock::new(data),
+            changes: Arc::new(ChangeLog::new()),
+            writer: AsyncMutex::new(()),
         })
     }
 
@@ -109,6 +118,7 @@ impl Display for TableNonOperationalState {
             Self::Updating => write!(f, "updating"),
             Self::Reloading => write!(f, "reloading"),
             Self::Reindexing => write!(f, "reindexing"),
+            Self::Importing => write!(f, "importing"),
             Self::Unregistering => write!(f, "unregistering"),
         }
     }
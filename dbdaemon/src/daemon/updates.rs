@@ -2,30 +2,67 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use dbschema::{Identified, ObjectId};
+use dbschema::{DbTableId, DualVersionedValue, Identified, ObjectId, SingleVersionedValue};
 use serde::Serialize;
 
-use crate::database::{
-    elastic::{self, ElasticId},
-    Database,
-};
+use crate::database::{elastic::ElasticId, Database};
 
-use super::{error::Result, table_data::ElasticDoc, table_read::TableReadGuard};
+use super::{
+    changes::{Change, PendingChanges},
+    data_write::Staged,
+    error::{Error, Result},
+    journal::{BatchId, Journal, JournalOp, TableOps},
+    table_data::ElasticDoc,
+    table_read::TableReadGuard,
+};
 
-pub struct UpdateGuard<'a, T>(
-    &'a TableReadGuard<'a>,
-    HashMap<ElasticId, (u64, Identified<T>)>,
-);
+pub struct UpdateGuard<'a, T> {
+    state: &'a TableReadGuard<'a>,
+    updates: HashMap<ElasticId, (u64, Identified<T>)>,
+    /// Previous values of the updated documents, to revert them if
+    /// the write fails.
+    previous: HashMap<ElasticId, T>,
+    /// Changes to the in-memory state, applied when the write
+    /// succeeds.
+    staged: Option<Box<dyn Staged + Send + Sync>>,
+    /// Changes to publish when the write succeeds.
+    changes: PendingChanges,
+}
 
 impl<'a, T> UpdateGuard<'a, T> {
     pub fn new(state: &'a TableReadGuard<'a>) -> Self {
-        Self(state, HashMap::new())
+        Self {
+            state,
+            updates: HashMap::new(),
+            previous: HashMap::new(),
+            staged: None,
+            changes: PendingChanges::new(state.changes.clone()),
+        }
+    }
+
+    pub fn stage<S: Staged + Send + Sync + 'static>(&mut self, staged: S) {
+        self.staged = Some(Box::new(staged));
+    }
+
+    /// Record a change to the in-memory state, to be published with
+    /// the staged changes.
+    pub fn change(&mut self, change: Change) {
+        self.changes.push(change);
+    }
+
+    /// Apply the staged changes to the in-memory state and publish
+    /// them. Called once the updates have been written.
+    fn publish(self) {
+        if let Some(staged) = self.staged {
+            staged.apply(&mut self.state.data.write());
+        }
+        self.changes.publish();
     }
 
     pub fn insert(&mut self, object_id: ObjectId, elastic_id: ElasticId, version: u64, value: T) {
-        self.1
+        self.updates
             .insert(elastic_id, (version, Identified::new_id(object_id, value)));
     }
 
@@ -38,6 +75,15 @@ impl<'a, T> UpdateGuard<'a, T> {
         self.insert(object_id, elastic_id, version, value);
     }
 
+    fn save_previous(&mut self, doc: &ElasticDoc<T>)
+    where
+        T: Clone,
+    {
+        self.previous
+            .entry(doc.elastic_id.clone())
+            .or_insert_with(|| doc.value.clone());
+    }
+
     pub fn create(&mut self, object_id: ObjectId, value: T) -> ElasticDoc<T>
     where
         T: Clone,
@@ -52,6 +98,7 @@ impl<'a, T> UpdateGuard<'a, T> {
         F: FnOnce(T) -> T,
         T: Clone,
     {
+        self.save_previous(&doc);
         let doc = doc.update(f);
         self.insert_doc(object_id, doc.clone());
         doc
@@ -69,6 +116,7 @@ impl<'a, T> UpdateGuard<'a, T> {
         G: FnOnce(T) -> T,
         T: Clone,
     {
+        self.save_previous(&doc);
         let (prev, new) = doc.update_new(prev, new);
         self.insert_doc(object_id.clone(), prev);
         self.insert_doc(object_id, new.clone());
@@ -87,6 +135,7 @@ impl<'a, T> UpdateGuard<'a, T> {
         G: FnOnce(T) -> T,
         T: Clone,
     {
+        self.save_previous(&doc);
         let (prev, new) = doc.update_new(prev, new);
         self.insert_doc(object_id.clone(), prev.clone());
         self.insert_doc(object_id, new.clone());
@@ -98,42 +147,91 @@ impl<'a, T> UpdateGuard<'a, T> {
         F: FnOnce(T) -> T,
         T: Clone,
     {
+        self.save_previous(&doc);
         let doc = doc.update(f);
         self.insert_doc(object_id, doc);
     }
 
     #[cfg(test)]
     pub fn extract(self) -> HashMap<ElasticId, (u64, Identified<T>)> {
-        self.1
+        self.updates
+    }
+
+    /// Apply the staged changes without writing the updates.
+    #[cfg(test)]
+    pub fn apply(self) {
+        self.publish();
     }
 }
 
-impl<T: Send + Sync + Serialize> UpdateGuard<'_, T> {
-    pub async fn run(self, elastic: &elastic::Database) -> Result<()> {
-        // TODO: error handling!
+impl<'a, T> UpdateGuard<'a, T>
+where
+    AnyUpdateGuard<'a>: From<Self>,
+{
+    /// Write the updates to the database. The updates are recorded in
+    /// the journal first, so that they can be completed after a
+    /// crash. The in-memory state is only changed once the write
+    /// succeeds. If it fails, the documents that may have been
+    /// written are reverted, so that the transaction leaves no trace.
+    pub async fn run<D: Database<Id = ElasticId>>(
+        self,
+        database: &D,
+        journal: &Journal,
+    ) -> Result<()> {
+        MultiUpdateGuard::new(vec![self.into()])
+            .run(database, journal)
+            .await
+    }
+}
+
+impl<'a, T: Send + Sync + Serialize> UpdateGuard<'a, T> {
+    fn journal_ops(&self, journal: &Journal) -> Result<TableOps> {
+        let ops = match journal.is_enabled() {
+            true => self
+                .updates
+                .iter()
+                .map(|(elastic_id, (version, value))| {
+                    Ok(JournalOp::Index {
+                        id: elastic_id.clone(),
+                        version: *version,
+                        doc: serde_json::to_value(value)?,
+                    })
+                })
+                .collect::<Result<_>>()?,
+            false => Vec::new(),
+        };
+        Ok(TableOps {
+            table_id: self.state.table_id.as_ref().clone(),
+            ops,
+        })
+    }
+
+    async fn write<D: Database<Id = ElasticId>>(&self, database: &D) -> Result<()> {
         const CHUNK_SIZE: usize = 1000;
 
-        let mut updates = self.1.into_iter();
+        let updates = self.updates.iter().collect::<Vec<_>>();
 
-        while updates.len() > 0 {
-            if updates.len() > 1 {
-                let chunk = (&mut updates).take(CHUNK_SIZE);
-                elastic
-                    .bulk_update(
-                        self.0.table_id.as_ref(),
-                        &self.0.mapping.table_schema,
-                        chunk.map(|(id, (version, value))| (id, version, value)),
+        match updates.as_slice() {
+            [(elastic_id, (version, value))] => {
+                database
+                    .update_object(
+                        self.state.table_id.as_ref(),
+                        &self.state.mapping.table_schema,
+                        elastic_id,
+                        *version,
+                        value,
                     )
                     .await?;
-            } else {
-                for (elastic_id, (version, value)) in &mut updates {
-                    elastic
-                        .update_object(
-                            self.0.table_id.as_ref(),
-                            &self.0.mapping.table_schema,
-                            &elastic_id,
-                            version,
-                            &value,
+            }
+            updates => {
+                for chunk in updates.chunks(CHUNK_SIZE) {
+                    database
+                        .bulk_update(
+                            self.state.table_id.as_ref(),
+                            &self.state.mapping.table_schema,
+                            chunk.iter().map(|(elastic_id, (version, value))| {
+                                (ElasticId::clone(elastic_id), *version, value)
+                            }),
                         )
                         .await?;
                 }
@@ -142,4 +240,492 @@ impl<T: Send + Sync + Serialize> UpdateGuard<'_, T> {
 
         Ok(())
     }
+
+    /// Discard the staged changes after a failed write.
+    fn cancel(&mut self) {
+        self.changes.cancel();
+        self.staged = None;
+    }
+
+    /// Discard the staged changes and determine the writes needed to
+    /// revert the documents that may have been written.
+    fn compensate(mut self) -> Compensation<'a, T> {
+        self.changes.cancel();
+        if let Some(staged) = self.staged.take() {
+            let touched = self.updates.keys().cloned().collect::<HashSet<_>>();
+            staged.revert(&mut self.state.data.write(), &touched);
+        }
+
+        /* Revert updated documents to their previous value and
+         * remove created documents, with versions superseding the
+         * failed write. The table is locked for writing until the
+         * rollback completes, so no later transaction has modified
+         * them. */
+
+        let mut reverts = Vec::new();
+        let mut removes = Vec::new();
+
+        for (elastic_id, (version, doc)) in self.updates {
+            match self.previous.remove(&elastic_id) {
+                Some(prev) => reverts.push((
+                    elastic_id,
+                    version + 1,
+                    Identified::new_id(doc.object_id, prev),
+                )),
+                None => removes.push((elastic_id, version + 1)),
+            }
+        }
+
+        Compensation {
+            state: self.state,
+            reverts,
+            removes,
+        }
+    }
+}
+
+/// Writes reverting a failed transaction on a single table.
+struct Compensation<'a, T> {
+    state: &'a TableReadGuard<'a>,
+    reverts: Vec<(ElasticId, u64, Identified<T>)>,
+    removes: Vec<(ElasticId, u64)>,
+}
+
+impl<T: Send + Sync + Serialize> Compensation<'_, T> {
+    fn journal_ops(&self, journal: &Journal) -> Result<TableOps> {
+        let ops = match journal.is_enabled() {
+            true => self
+                .reverts
+                .iter()
+                .map(|(id, version, value)| {
+                    Ok(JournalOp::Index {
+                        id: id.clone(),
+                        version: *version,
+                        doc: serde_json::to_value(value)?,
+                    })
+                })
+                .chain(self.removes.iter().map(|(id, version)| {
+                    Ok(JournalOp::Delete {
+                        id: id.clone(),
+                        version: *version,
+                    })
+                }))
+                .collect::<Result<_>>()?,
+            false => Vec::new(),
+        };
+        Ok(TableOps {
+            table_id: self.state.table_id.as_ref().clone(),
+            ops,
+        })
+    }
+
+    /// Returns true if all writes succeeded.
+    async fn write<D: Database<Id = ElasticId>>(self, database: &D) -> bool {
+        let mut ok = true;
+
+        if let Err(e) = database
+            .bulk_update(
+                self.state.table_id.as_ref(),
+                &self.state.mapping.table_schema,
+                self.reverts,
+            )
+            .await
+        {
+            ok = false;
+            log::error!(
+                "failed to revert updated documents in table {}: {e}",
+                self.state.table_id
+            );
+        }
+
+        if let Err(e) = database
+            .bulk_delete(self.state.table_id.as_ref(), self.removes)
+            .await
+        {
+            ok = false;
+            log::error!(
+                "failed to remove created documents in table {}: {e}",
+                self.state.table_id
+            );
+        }
+
+        ok
+    }
+}
+
+/// Updates for a table of any versioning type.
+pub enum AnyUpdateGuard<'a> {
+    SingleVersioned(UpdateGuard<'a, SingleVersionedValue>),
+    DualVersioned(UpdateGuard<'a, DualVersionedValue>),
+}
+
+enum AnyCompensation<'a> {
+    SingleVersioned(Compensation<'a, SingleVersionedValue>),
+    DualVersioned(Compensation<'a, DualVersionedValue>),
+}
+
+macro_rules! dispatch {
+    ($enum:ident, $value:expr, $var:ident => $expr:expr) => {
+        match $value {
+            $enum::SingleVersioned($var) => $expr,
+            $enum::DualVersioned($var) => $expr,
+        }
+    };
+}
+
+impl<'a> From<UpdateGuard<'a, SingleVersionedValue>> for AnyUpdateGuard<'a> {
+    fn from(updates: UpdateGuard<'a, SingleVersionedValue>) -> Self {
+        Self::SingleVersioned(updates)
+    }
+}
+
+impl<'a> From<UpdateGuard<'a, DualVersionedValue>> for AnyUpdateGuard<'a> {
+    fn from(updates: UpdateGuard<'a, DualVersionedValue>) -> Self {
+        Self::DualVersioned(updates)
+    }
+}
+
+impl<'a> AnyUpdateGuard<'a> {
+    fn journal_ops(&self, journal: &Journal) -> Result<TableOps> {
+        dispatch!(Self, self, updates => updates.journal_ops(journal))
+    }
+
+    async fn write<D: Database<Id = ElasticId>>(&self, database: &D) -> Result<()> {
+        dispatch!(Self, self, updates => updates.write(database).await)
+    }
+
+    fn publish(self) {
+        dispatch!(Self, self, updates => updates.publish())
+    }
+
+    fn cancel(&mut self) {
+        dispatch!(Self, self, updates => updates.cancel())
+    }
+
+    fn table_id(&self) -> &DbTableId {
+        dispatch!(Self, self, updates => updates.state.table_id.as_ref())
+    }
+
+    fn compensate(self) -> AnyCompensation<'a> {
+        match self {
+            Self::SingleVersioned(updates) => {
+                AnyCompensation::SingleVersioned(updates.compensate())
+            }
+            Self::DualVersioned(updates) => AnyCompensation::DualVersioned(updates.compensate()),
+        }
+    }
+}
+
+impl AnyCompensation<'_> {
+    fn journal_ops(&self, journal: &Journal) -> Result<TableOps> {
+        dispatch!(Self, self, comp => comp.journal_ops(journal))
+    }
+
+    async fn write<D: Database<Id = ElasticId>>(self, database: &D) -> bool {
+        dispatch!(Self, self, comp => comp.write(database).await)
+    }
+}
+
+/// Updates for one or more tables, written to the database as a
+/// single transaction.
+pub struct MultiUpdateGuard<'a>(Vec<AnyUpdateGuard<'a>>);
+
+impl<'a> MultiUpdateGuard<'a> {
+    pub fn new(updates: Vec<AnyUpdateGuard<'a>>) -> Self {
+        Self(updates)
+    }
+
+    /// Write the updates for all tables. The updates are journaled
+    /// as one batch, so that either all or none of them are
+    /// completed after a crash. If the write for any table fails,
+    /// all tables are rolled back.
+    pub async fn run<D: Database<Id = ElasticId>>(
+        self,
+        database: &D,
+        journal: &Journal,
+    ) -> Result<()> {
+        let batch = match self.begin(journal).await {
+            Ok(batch) => batch,
+            Err(e) => {
+                log::warn!(
+                    "failed to journal updates for {}; rolling back: {e}",
+                    self.tables()
+                );
+                self.0.into_iter().for_each(|mut updates| updates.cancel());
+                return Err(e);
+            }
+        };
+
+        match self.write(database).await {
+            Ok(()) => {
+                journal.done(batch).await;
+                self.0.into_iter().for_each(AnyUpdateGuard::publish);
+                Ok(())
+            }
+            Err(e) => {
+                log::warn!(
+                    "failed to write updates for {}; rolling back: {e}",
+                    self.tables()
+                );
+                match self.rollback(database, journal, batch).await {
+                    Ok(()) => Err(e),
+                    Err(rollback) => {
+                        Err(Error::RollbackNotJournaled(Box::new(e), Box::new(rollback)))
+                    }
+                }
+            }
+        }
+    }
+
+    async fn begin(&self, journal: &Journal) -> Result<BatchId> {
+        let tables = self
+            .0
+            .iter()
+            .map(|updates| updates.journal_ops(journal))
+            .collect::<Result<_>>()?;
+        journal.begin(tables).await
+    }
+
+    async fn write<D: Database<Id = ElasticId>>(&self, database: &D) -> Result<()> {
+        for updates in &self.0 {
+            updates.write(database).await?;
+        }
+        Ok(())
+    }
+
+    /// Discard the staged changes and revert the documents that may
+    /// have been written. Returns an error if the compensating writes
+    /// could not be journaled; they are attempted nevertheless, but
+    /// are not retried after a crash.
+    async fn rollback<D: Database<Id = ElasticId>>(
+        self,
+        database: &D,
+        journal: &Journal,
+        batch: BatchId,
+    ) -> Result<()> {
+        let tables = self.tables();
+        let comps = self
+            .0
+            .into_iter()
+            .map(AnyUpdateGuard::compensate)
+            .collect::<Vec<_>>();
+
+        /* Journal the compensating writes before discarding the
+         * original batch, so that a crash in between does not leave
+         * the transaction half-written. The original batch is
+         * discarded even if this fails: the in-memory state does not
+         * include it, so replaying it on startup would resurrect a
+         * transaction that was reported as failed. */
+
+        let compensation = match comps
+            .iter()
+            .map(|comp| comp.journal_ops(journal))
+            .collect::<Result<_>>()
+        {
+            Ok(ops) => journal.begin(ops).await,
+            Err(e) => Err(e),
+        };
+
+        journal.done(batch).await;
+
+        let mut ok = true;
+        for comp in comps {
+            ok &= comp.write(database).await;
+        }
+
+        match compensation {
+            /* On failure, the compensating batch remains in the
+             * journal and is retried on the next startup. */
+            Ok(compensation) => {
+                if ok {
+                    journal.done(compensation).await;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                log::error!(
+                    "failed to journal rollback for {tables}; it will not be \
+                     retried after a crash (rollback succeeded: {ok}): {e}"
+                );
+                Err(e)
+            }
+        }
+    }
+
+    fn tables(&self) -> String {
+        self.0
+            .iter()
+            .map(|updates| format!("table {}", updates.table_id()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod test {
+    use chrono::Utc;
+    use dbschema::{
+        DbTableId, Filter, HasSchema, HasTableDef, Identified, ObjectId, SingleVersioned,
+        SingleVersionedValue,
+    };
+    use serde_json::{json, Value};
+
+    use crate::daemon::{
+        journal::Journal,
+        state::State,
+        table_state::{TableNonOperationalState, TableOperationalState},
+    };
+    use crate::database::{
+        memory::{Database, DatabaseConfig},
+        Database as _,
+    };
+
+    #[tokio::test]
+    async fn failed_write_leaves_no_trace() {
+        type Document = Identified<SingleVersioned<Object>>;
+        #[derive(HasSchema, Debug)]
+        #[allow(unused)]
+        struct Object {
+            field: String,
+        }
+
+        let db = Database::new(DatabaseConfig::default()).await.unwrap();
+        let journal = Journal::disabled();
+        let state = State::new();
+        let table_id = DbTableId::new("test-table");
+        let table_def = Document::table_def();
+        let schema = table_def.schema();
+        db.create_table(&table_id, &table_def).await.unwrap();
+
+        {
+            let (_schemas, mut table) = state
+                .write_table(
+                    &table_id,
+                    "test",
+                    TableNonOperationalState::Registering,
+                    true,
+                )
+                .await
+                .unwrap();
+            table.or_insert_with(|| TableOperationalState::new(table_def));
+        }
+
+        let table = state.read_table(&table_id, "test").await.unwrap();
+        let object_id = ObjectId::new();
+
+        let memory = || {
+            table
+                .read_data_single_versioned()
+                .unwrap()
+                .get(&object_id)
+                .map(|v| v.value.clone())
+        };
+        let stored = || async {
+            db.query_objects::<Identified<SingleVersionedValue>>(
+                &table_id,
+                &schema,
+                &Filter::All(Vec::new()),
+                &Value::Null,
+                None,
+            )
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|(_, _, doc)| doc.value.version.active.to.is_none())
+            .map(|(_, _, doc)| doc.value.value)
+            .collect::<Vec<_>>()
+        };
+
+        {
+            let writer = table.lock_writes().await;
+            let mut data = writer.write_data_single_versioned(Utc::now()).unwrap();
+            assert!(data.create(&object_id, json!({"field": "a"})));
+            data.commit().run(&db, &journal).await.unwrap();
+        }
+
+        {
+            let writer = table.lock_writes().await;
+            let mut data = writer.write_data_single_versioned(Utc::now()).unwrap();
+            data.update(&object_id, json!({"field": "b"}));
+            let updates = data.commit();
+            /* Not visible until written. */
+            assert_eq!(memory(), Some(json!({"field": "a"})));
+            db.fail_writes(1);
+            assert!(updates.run(&db, &journal).await.is_err());
+        }
+        assert_eq!(memory(), Some(json!({"field": "a"})));
+        assert_eq!(stored().await, vec![json!({"field": "a"})]);
+
+        /* The reverted documents supersede the failed write, so that
+         * later writes are not ignored. */
+        {
+            let writer = table.lock_writes().await;
+            let mut data = writer.write_data_single_versioned(Utc::now()).unwrap();
+            data.update(&object_id, json!({"field": "c"}));
+            data.commit().run(&db, &journal).await.unwrap();
+        }
+        assert_eq!(memory(), Some(json!({"field": "c"})));
+        assert_eq!(stored().await, vec![json!({"field": "c"})]);
+    }
+
+    #[tokio::test]
+    async fn version_follows_written_state() {
+        type Document = Identified<SingleVersioned<Object>>;
+        #[derive(HasSchema, Debug)]
+        #[allow(unused)]
+        struct Object {
+            field: String,
+        }
+
+        let db = Database::new(DatabaseConfig::default()).await.unwrap();
+        let journal = Journal::disabled();
+        let state = State::new();
+        let table_id = DbTableId::new("test-table");
+        let table_def = Document::table_def();
+        db.create_table(&table_id, &table_def).await.unwrap();
+
+        {
+            let (_schemas, mut table) = state
+                .write_table(
+                    &table_id,
+                    "test",
+                    TableNonOperationalState::Registering,
+                    true,
+                )
+                .await
+                .unwrap();
+            table.or_insert_with(|| TableOperationalState::new(table_def));
+        }
+
+        let table = state.read_table(&table_id, "test").await.unwrap();
+        let object_id = ObjectId::new();
+        let version = || {
+            table
+                .read_data_single_versioned()
+                .unwrap()
+                .version(&object_id)
+        };
+
+        {
+            let writer = table.lock_writes().await;
+            let mut data = writer.write_data_single_versioned(Utc::now()).unwrap();
+            assert!(data.create(&object_id, json!({"field": "a"})));
+            data.commit().run(&db, &journal).await.unwrap();
+        }
+
+        let written = version().unwrap();
+
+        {
+            let writer = table.lock_writes().await;
+            let mut data = writer.write_data_single_versioned(Utc::now()).unwrap();
+            data.update(&object_id, json!({"field": "b"}));
+            let updates = data.commit();
+            /* Compare-and-set operations see the written version
+             * until the update has been written. */
+            assert_eq!(version(), Some(written.clone()));
+            updates.run(&db, &journal).await.unwrap();
+        }
+
+        assert!(version().is_some_and(|version| version != written));
+    }
 }
@@ -0,0 +1,514 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde_json::json;
+use tokio::{
+    sync::{mpsc, mpsc::error::SendError, Mutex as AsyncMutex},
+    task::AbortHandle,
+};
+
+use dbdaemon_api::{
+    OpenVersions, VerificationId, VerificationJob, VerificationMode, VerificationMsg,
+    VerificationState, VersionProblem, VersionRef, VersionRepair,
+};
+use dbschema::{
+    Anchor, DbTableId, DualVersionedValue, Filter, FilterPath, Identified, ObjectId,
+    SingleVersionedValue, TimeRange, Timeline,
+};
+
+use crate::database::{elastic::ElasticId, Database};
+
+use super::{
+    error::Result,
+    filters::{history_filter_dual, range_filter},
+    journal::Journal,
+    single_versioned_data::SingleVersionedClose,
+    table_data::ElasticDoc,
+    table_read::TableReadGuard,
+};
+
+/// Verification jobs expire when their messages are not retrieved
+/// for this long.
+const EXPIRY: Duration = Duration::from_secs(3600);
+
+/// A running or finished verification job.
+pub(super) struct VerificationWorker {
+    table_id: DbTableId,
+    range: Option<TimeRange>,
+    mode: VerificationMode,
+    started: DateTime<Utc>,
+    status: Arc<Mutex<WorkerStatus>>,
+    receiver: AsyncMutex<mpsc::Receiver<VerificationMsg>>,
+    task: AbortHandle,
+}
+
+/// The sending side of a verification job. Keeps the job status up
+/// to date with the messages sent to the client.
+pub(super) struct VerificationSender {
+    sender: mpsc::Sender<VerificationMsg>,
+    status: Arc<Mutex<WorkerStatus>>,
+}
+
+struct WorkerStatus {
+    state: VerificationState,
+    finished: Option<DateTime<Utc>>,
+    last_access: Instant,
+    processed: u64,
+    problems: u64,
+    repairs: u64,
+    error: Option<String>,
+}
+
+impl VerificationWorker {
+    pub(super) fn spawn<F, Fut>(
+        table_id: DbTableId,
+        range: Option<TimeRange>,
+        mode: VerificationMode,
+        worker: F,
+    ) -> Self
+    where
+        F: FnOnce(VerificationSender) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(10);
+        let status = Arc::new(Mutex::new(WorkerStatus {
+            state: VerificationState::Running,
+            finished: None,
+            last_access: Instant::now(),
+            processed: 0,
+            problems: 0,
+            repairs: 0,
+            error: None,
+        }));
+        let task = tokio::spawn(worker(VerificationSender {
+            sender,
+            status: status.clone(),
+        }))
+        .abort_handle();
+        Self {
+            table_id,
+            range,
+            mode,
+            started: Utc::now(),
+            status,
+            receiver: AsyncMutex::new(receiver),
+            task,
+        }
+    }
+
+    /// Retrieve the next batch of messages. Returns an empty batch
+    /// when the job has finished.
+    pub(super) async fn next(&self, n: usize) -> Vec<VerificationMsg> {
+        self.status.lock().last_access = Instant::now();
+        let mut msgs = Vec::with_capacity(n);
+        self.receiver.lock().await.recv_many(&mut msgs, n).await;
+        self.status.lock().last_access = Instant::now();
+        msgs
+    }
+
+    pub(super) fn cancel(&self) {
+        let mut status = self.status.lock();
+        if status.state == VerificationState::Running {
+            status.state = VerificationState::Cancelled;
+            self.task.abort();
+        }
+    }
+
+    /// Check whether the job expired, stopping it if it is still
+    /// running.
+    pub(super) fn expire(&self) -> bool {
+        let expired = self.status.lock().last_access.elapsed() > EXPIRY;
+        if expired {
+            self.task.abort();
+        }
+        expired
+    }
+
+    pub(super) fn info(&self, verification_id: VerificationId) -> VerificationJob {
+        let status = self.status.lock();
+        VerificationJob {
+            verification_id,
+            table_id: self.table_id.clone(),
+            range: self
+                .range
+                .as_ref()
+                .map(|range| TimeRange::new(range.from, range.to)),
+            mode: self.mode,
+            state: status.state,
+            started: self.started,
+            finished: status.finished,
+            processed: status.processed,
+            problems: status.problems,
+            repairs: status.repairs,
+            error: status.error.clone(),
+        }
+    }
+}
+
+impl VerificationSender {
+    async fn send(
+        &self,
+        msg: VerificationMsg,
+    ) -> std::result::Result<(), SendError<VerificationMsg>> {
+        {
+            let mut status = self.status.lock();
+            match &msg {
+                VerificationMsg::Overlap(_)
+                | VerificationMsg::Gap(_)
+                | VerificationMsg::UncommittedActivation(_)
+                | VerificationMsg::MultipleOpen(_) => status.problems += 1,
+                VerificationMsg::Repair(repair) if !repair.conflict => status.repairs += 1,
+                VerificationMsg::Repair(_) => {}
+                VerificationMsg::Progress(n) => status.processed = *n,
+                VerificationMsg::Error(e) => {
+                    status.state = VerificationState::Failed;
+                    status.error = Some(e.clone());
+                }
+            }
+        }
+        self.sender.send(msg).await
+    }
+}
+
+/* The worker drops its sender when it returns or is aborted. */
+impl Drop for VerificationSender {
+    fn drop(&mut self) {
+        let mut status = self.status.lock();
+        if status.state == VerificationState::Running {
+            status.state = VerificationState::Finished;
+        }
+        status.finished = Some(Utc::now());
+    }
+}
+
+macro_rules! send_msg {
+    ($sender:ident, $msg:expr) => {
+        if $sender.send($msg).await.is_err() {
+            return;
+        }
+    };
+}
+
+macro_rules! handle_err {
+    ($sender:ident, $expr:expr) => {
+        match $expr {
+            Ok(r) => r,
+            Err(e) => {
+                send_msg!($sender, VerificationMsg::Error(e.to_string()));
+                return;
+            }
+        }
+    };
+}
+
+/// Check the active timeline of a single-versioned table for
+/// overlapping and missing versions. In repair mode, overlaps are
+/// resolved by closing the earlier version at the start of the later
+/// one.
+pub(super) async fn verify_single_versioned<D: Database<Id = ElasticId>>(
+    database: Arc<D>,
+    journal: Arc<Journal>,
+    table: TableReadGuard<'static>,
+    range: Option<TimeRange>,
+    mode: VerificationMode,
+    sender: VerificationSender,
+) {
+    let mut objects = HashMap::<ObjectId, ElasticDoc<SingleVersionedValue>>::new();
+    let sort = json!([{ "@active.from": { "order": "asc"} }]);
+    let filter = match range {
+        Some(range) => FilterPath::new()
+            .field("value")
+            .field("version")
+            .field("active")
+            .filter(range_filter(range)),
+        None => Filter::All(Vec::new()),
+    };
+
+    let mut n = 0;
+    let (mut docs, mut next) = handle_err!(
+        sender,
+        database
+            .query_objects_first::<Identified<SingleVersionedValue>>(
+                &table.table_id,
+                &table.mapping.table_schema,
+                &filter,
+                &sort,
+                Duration::from_secs(60),
+                Some(1000),
+            )
+            .await
+    );
+
+    loop {
+        n += docs.len();
+        let mut closes = Vec::new();
+
+        for (elastic_id, version, doc) in docs {
+            let Anchor { from, .. } = doc.value.version.active;
+            let cur = ElasticDoc {
+                elastic_id,
+                version,
+                value: doc.value,
+            };
+            match objects.entry(doc.object_id.clone()) {
+                Entry::Occupied(mut ent) => {
+                    let prev = ent.get();
+                    let prev_to = prev.value.version.active.to;
+                    let version_problem = || VersionProblem {
+                        object_id: doc.object_id.clone(),
+                        prev_version_id: prev.elastic_id.to_string(),
+                        cur_version_id: cur.elastic_id.to_string(),
+                        prev_to,
+                        cur_from: from,
+                        timeline: None,
+                    };
+                    if prev_to.as_ref().is_none_or(|prev_to| prev_to > &from) {
+                        send_msg!(
+                            sender,
+                            VerificationMsg::Overlap(Box::new(version_problem()))
+                        );
+                        let repair = VersionRepair {
+                            object_id: doc.object_id.clone(),
+                            version_id: prev.elastic_id.to_string(),
+                            prev_to,
+                            new_to: from,
+                            applied: false,
+                            conflict: false,
+                        };
+                        match mode {
+                            VerificationMode::Verify => {}
+                            VerificationMode::DryRun => {
+                                send_msg!(sender, VerificationMsg::Repair(Box::new(repair)));
+                            }
+                            VerificationMode::Repair => closes.push((
+                                repair,
+                                SingleVersionedClose {
+                                    object_id: doc.object_id.clone(),
+                                    doc: prev.clone(),
+                                    to: from,
+                                    next: cur.clone(),
+                                },
+                            )),
+                        }
+                    } else if prev_to.as_ref().is_some_and(|prev_to| prev_to < &from) {
+                        send_msg!(sender, VerificationMsg::Gap(Box::new(version_problem())));
+                    }
+
+                    ent.insert(cur);
+                }
+                Entry::Vacant(ent) => {
+                    ent.insert(cur);
+                }
+            }
+        }
+
+        if !closes.is_empty() {
+            let repairs = handle_err!(
+                sender,
+                repair_single_versioned(&*database, &journal, &table, closes).await
+            );
+            for repair in repairs {
+                send_msg!(sender, VerificationMsg::Repair(Box::new(repair)));
+            }
+        }
+
+        send_msg!(sender, VerificationMsg::Progress(n as u64));
+
+        match next {
+            Some(query_state) => {
+                (docs, next) = handle_err!(sender, database.query_objects_next(query_state).await);
+            }
+            None => break,
+        }
+    }
+}
+
+/// Check both timelines of a dual-versioned table for overlapping and
+/// missing versions and for objects with more than one open version.
+/// Activated versions that were never committed are reported as well.
+pub(super) async fn verify_dual_versioned<D: Database<Id = ElasticId>>(
+    database: Arc<D>,
+    table: TableReadGuard<'static>,
+    range: Option<TimeRange>,
+    sender: VerificationSender,
+) {
+    let mut n = 0;
+
+    for timeline in [Timeline::Current, Timeline::Active] {
+        /* Previous version and open versions per object. */
+        let mut objects = HashMap::<ObjectId, (ElasticId, Option<DateTime<Utc>>)>::new();
+        let mut open = HashMap::<ObjectId, Vec<String>>::new();
+
+        let sort = match timeline {
+            Timeline::Current => json!([{ "@current.from": { "order": "asc"} }]),
+            Timeline::Active => json!([{ "@active.from": { "order": "asc"} }]),
+        };
+        let filter = history_filter_dual(
+            timeline,
+            match &range {
+                Some(range) => TimeRange::new(range.from, range.to),
+                None => TimeRange::new(None, None),
+            },
+        );
+
+        let (mut docs, mut next) = handle_err!(
+            sender,
+            database
+                .query_objects_first::<Identified<DualVersionedValue>>(
+                    &table.table_id,
+                    &table.mapping.table_schema,
+                    &filter,
+                    &sort,
+                    Duration::from_secs(60),
+                    Some(1000),
+                )
+                .await
+        );
+
+        loop {
+            n += docs.len();
+
+            for (elastic_id, _, doc) in docs {
+                let version = &doc.value.version;
+                let Some(Anchor { from, to }) = (match timeline {
+                    Timeline::Current => Some(&version.current),
+                    Timeline::Active => version.active.as_ref(),
+                }) else {
+                    continue;
+                };
+
+                if matches!(timeline, Timeline::Active) && version.committed.is_none() {
+                    send_msg!(
+                        sender,
+                        VerificationMsg::UncommittedActivation(Box::new(VersionRef {
+                            object_id: doc.object_id.clone(),
+                            version_id: elastic_id.to_string(),
+                        }))
+                    );
+                }
+
+                if to.is_none() {
+                    open.entry(doc.object_id.clone())
+                        .or_default()
+                        .push(elastic_id.to_string());
+                }
+
+                match objects.entry(doc.object_id.clone()) {
+                    Entry::Occupied(mut ent) => {
+                        let (prev_id, prev_to) = ent.get();
+                        let version_problem = || VersionProblem {
+                            object_id: doc.object_id.clone(),
+                            prev_version_id: prev_id.to_string(),
+                            cur_version_id: elastic_id.to_string(),
+                            prev_to: *prev_to,
+                            cur_from: *from,
+                            timeline: Some(timeline),
+                        };
+                        if prev_to.as_ref().is_none_or(|prev_to| prev_to > from) {
+                            send_msg!(
+                                sender,
+                                VerificationMsg::Overlap(Box::new(version_problem()))
+                            );
+                        } else if prev_to.as_ref().is_some_and(|prev_to| prev_to < from) {
+                            send_msg!(sender, VerificationMsg::Gap(Box::new(version_problem())));
+                        }
+                        ent.insert((elastic_id, *to));
+                    }
+                    Entry::Vacant(ent) => {
+                        ent.insert((elastic_id, *to));
+                    }
+                }
+            }
+
+            send_msg!(sender, VerificationMsg::Progress(n as u64));
+
+            match next {
+                Some(query_state) => {
+                    (docs, next) =
+                        handle_err!(sender, database.query_objects_next(query_state).await);
+                }
+                None => break,
+            }
+        }
+
+        for (object_id, version_ids) in open {
+            if version_ids.len() > 1 {
+                send_msg!(
+                    sender,
+                    VerificationMsg::MultipleOpen(Box::new(OpenVersions {
+                        object_id,
+                        timeline,
+                        version_ids,
+                    }))
+                );
+            }
+        }
+    }
+}
+
+/// Close versions through the normal update path, so that the
+/// repairs are journaled and the in-memory state is kept in sync.
+/// The versions were read from a point in time; those modified since
+/// are reported as conflicts and left alone.
+async fn repair_single_versioned<D: Database<Id = ElasticId>>(
+    database: &D,
+    journal: &Journal,
+    table: &TableReadGuard<'_>,
+    closes: Vec<(VersionRepair, SingleVersionedClose)>,
+) -> Result<Vec<VersionRepair>> {
+    /* No writes can intervene between the check and the repair
+     * while the table is locked for writing. */
+    let writer = table.lock_writes().await;
+    database.refresh_table(&table.table_id).await?;
+
+    let object_ids = closes
+        .iter()
+        .map(|(_, close)| json!(close.object_id))
+        .collect();
+    let current = database
+        .query_objects::<Identified<SingleVersionedValue>>(
+            &table.table_id,
+            &table.mapping.table_schema,
+            &FilterPath::new().field("object_id").eq_any(object_ids),
+            &table.mapping.sort_fields,
+            None,
+        )
+        .await?
+        .into_iter()
+        .map(|(elastic_id, version, _)| (elastic_id, version))
+        .collect::<HashMap<_, _>>();
+    let unchanged =
+        |doc: &ElasticDoc<SingleVersionedValue>| current.get(&doc.elastic_id) == Some(&doc.version);
+
+    let mut repairs = Vec::with_capacity(closes.len());
+    let mut applied = Vec::new();
+    for (mut repair, close) in closes {
+        match unchanged(&close.doc) && unchanged(&close.next) {
+            true => {
+                repair.applied = true;
+                applied.push(close);
+            }
+            false => repair.conflict = true,
+        }
+        repairs.push(repair);
+    }
+
+    if !applied.is_empty() {
+        let updates = writer
+            .repair_data_single_versioned(Utc::now(), applied)?
+            .commit();
+        updates.run(database, journal).await?;
+    }
+
+    Ok(repairs)
+}
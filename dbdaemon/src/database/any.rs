@@ -0,0 +1,314 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use clap::{Args, ValueEnum};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use dbdaemon_api::{Aggregation, AggregationBucket};
+use dbschema::{DbSchema, DbTable, DbTableId, Filter};
+
+use super::backend::{Database, DatabaseError, Transform};
+use super::elastic::{self, ElasticId};
+#[cfg(feature = "mariadb")]
+use super::mariadb;
+#[cfg(feature = "memory")]
+use super::memory;
+
+/// The database backend selected at startup.
+#[derive(Debug)]
+pub enum AnyDatabase {
+    Elastic(elastic::Database),
+    #[cfg(feature = "mariadb")]
+    MariaDb(mariadb::Database),
+    #[cfg(feature = "memory")]
+    Memory(memory::Database),
+}
+
+pub enum AnyQueryState<'a> {
+    Elastic(<elastic::Database as Database>::QueryState<'a>),
+    #[cfg(feature = "mariadb")]
+    MariaDb(<mariadb::Database as Database>::QueryState<'a>),
+    #[cfg(feature = "memory")]
+    Memory(<memory::Database as Database>::QueryState<'a>),
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("elastic error: {0}")]
+    Elastic(#[from] elastic::Error),
+    #[cfg(feature = "mariadb")]
+    #[error("mariadb error: {0}")]
+    MariaDb(#[from] mariadb::Error),
+    #[cfg(feature = "memory")]
+    #[error("embedded database error: {0}")]
+    Memory(#[from] memory::Error),
+    #[error("missing configuration for the {0} backend")]
+    MissingConfig(&'static str),
+    #[error("query state does not belong to this backend")]
+    QueryState,
+}
+
+impl DatabaseError for Error {}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DatabaseType {
+    Elastic,
+    #[cfg(feature = "mariadb")]
+    Mariadb,
+    #[cfg(feature = "memory")]
+    Memory,
+}
+
+/// Database options.
+#[derive(Args, Debug, Clone)]
+#[group(id = "database")]
+pub struct DatabaseConfig {
+    /// The database backend to use.
+    #[clap(
+        env = "DB_DATABASE",
+        long = "database",
+        value_enum,
+        default_value = "elastic"
+    )]
+    pub database: DatabaseType,
+    #[clap(flatten, next_help_heading = "Elasticsearch")]
+    pub elastic: Option<elastic::DatabaseConfig>,
+    #[cfg(feature = "mariadb")]
+    #[clap(flatten, next_help_heading = "MariaDB")]
+    pub mariadb: Option<mariadb::DatabaseConfig>,
+    #[cfg(feature = "memory")]
+    #[clap(flatten, next_help_heading = "Embedded database")]
+    pub memory: Option<memory::DatabaseConfig>,
+}
+
+macro_rules! dispatch {
+    ($self:expr, $db:ident => $body:expr) => {
+        match $self {
+            AnyDatabase::Elastic($db) => Ok($body?),
+            #[cfg(feature = "mariadb")]
+            AnyDatabase::MariaDb($db) => Ok($body?),
+            #[cfg(feature = "memory")]
+            AnyDatabase::Memory($db) => Ok($body?),
+        }
+    };
+}
+
+macro_rules! wrap_query {
+    ($variant:ident, $res:expr) => {{
+        let (docs, state) = $res?;
+        Ok((docs, state.map(AnyQueryState::$variant)))
+    }};
+}
+
+impl AnyDatabase {
+    pub async fn new(config: DatabaseConfig) -> Result<Self, Error> {
+        match config.database {
+            DatabaseType::Elastic => Ok(Self::Elastic(
+                elastic::Database::new(config.elastic.ok_or(Error::MissingConfig("elastic"))?)
+                    .await?,
+            )),
+            #[cfg(feature = "mariadb")]
+            DatabaseType::Mariadb => Ok(Self::MariaDb(mariadb::Database::new(
+                config.mariadb.ok_or(Error::MissingConfig("mariadb"))?,
+            )?)),
+            #[cfg(feature = "memory")]
+            DatabaseType::Memory => Ok(Self::Memory(
+                memory::Database::new(config.memory.unwrap_or_default()).await?,
+            )),
+        }
+    }
+}
+
+impl Database for AnyDatabase {
+    type Error = Error;
+    type Id = ElasticId;
+    type QueryState<'a> = AnyQueryState<'a>;
+
+    fn whoami(&self) -> String {
+        match self {
+            Self::Elastic(db) => db.whoami(),
+            #[cfg(feature = "mariadb")]
+            Self::MariaDb(db) => db.whoami(),
+            #[cfg(feature = "memory")]
+            Self::Memory(db) => db.whoami(),
+        }
+    }
+
+    async fn wait_for_database(&self) -> Result<(), Error> {
+        dispatch!(self, db => db.wait_for_database().await)
+    }
+
+    async fn verify_database(&self) -> Result<(), Error> {
+        dispatch!(self, db => db.verify_database().await)
+    }
+
+    async fn has_table(&self, id: &DbTableId) -> Result<bool, Error> {
+        dispatch!(self, db => db.has_table(id).await)
+    }
+
+    async fn create_table(&self, id: &DbTableId, definition: &DbTable) -> Result<(), Error> {
+        dispatch!(self, db => db.create_table(id, definition).await)
+    }
+
+    async fn update_table(&self, id: &DbTableId, definition: &DbTable) -> Result<(), Error> {
+        dispatch!(self, db => db.update_table(id, definition).await)
+    }
+
+    async fn reindex_table(
+        &self,
+        id: &DbTableId,
+        old_definition: &DbTable,
+        new_definition: &DbTable,
+        transform: Option<&Transform<'_>>,
+    ) -> Result<(), Error> {
+        dispatch!(self, db => db.reindex_table(id, old_definition, new_definition, transform).await)
+    }
+
+    async fn remove_table(&self, id: &DbTableId) -> Result<(), Error> {
+        dispatch!(self, db => db.remove_table(id).await)
+    }
+
+    async fn drop_partitions(&self, id: &DbTableId, before: DateTime<Utc>) -> Result<(), Error> {
+        dispatch!(self, db => db.drop_partitions(id, before).await)
+    }
+
+    async fn recover(&self, tables: &[DbTableId]) -> Result<(), Error> {
+        dispatch!(self, db => db.recover(tables).await)
+    }
+
+    async fn refresh_table(&self, id: &DbTableId) -> Result<(), Error> {
+        dispatch!(self, db => db.refresh_table(id).await)
+    }
+
+    async fn count_objects(&self, table_id: &DbTableId) -> Result<u64, Error> {
+        dispatch!(self, db => db.count_objects(table_id).await)
+    }
+
+    async fn bulk_update<T, I>(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        updates: I,
+    ) -> Result<(), Error>
+    where
+        T: Serialize + Send + Sync,
+        I: IntoIterator<Item = (ElasticId, u64, T)> + Send + Sync,
+    {
+        dispatch!(self, db => db.bulk_update(table_id, schema, updates).await)
+    }
+
+    async fn bulk_delete<I>(&self, table_id: &DbTableId, deletes: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (ElasticId, u64)> + Send + Sync,
+    {
+        dispatch!(self, db => db.bulk_delete(table_id, deletes).await)
+    }
+
+    async fn update_object<T: Serialize + Send + Sync>(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        doc_id: &ElasticId,
+        version: u64,
+        value: T,
+    ) -> Result<(), Error> {
+        dispatch!(self, db => db.update_object(table_id, schema, doc_id, version, value).await)
+    }
+
+    async fn query_objects<T: DeserializeOwned + Send + Sync>(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        filter: &Filter,
+        sort: &Value,
+        limit: Option<usize>,
+    ) -> Result<Vec<(ElasticId, u64, T)>, Error> {
+        dispatch!(self, db => db.query_objects(table_id, schema, filter, sort, limit).await)
+    }
+
+    async fn query_objects_first<'a, T: DeserializeOwned + Send + Sync>(
+        &self,
+        table_id: &DbTableId,
+        schema: &'a DbSchema,
+        filter: &'a Filter,
+        sort: &'a Value,
+        keep_alive: Duration,
+        limit: Option<usize>,
+    ) -> Result<(Vec<(ElasticId, u64, T)>, Option<AnyQueryState<'a>>), Error> {
+        match self {
+            Self::Elastic(db) => wrap_query!(
+                Elastic,
+                db.query_objects_first(table_id, schema, filter, sort, keep_alive, limit)
+                    .await
+            ),
+            #[cfg(feature = "mariadb")]
+            Self::MariaDb(db) => wrap_query!(
+                MariaDb,
+                db.query_objects_first(table_id, schema, filter, sort, keep_alive, limit)
+                    .await
+            ),
+            #[cfg(feature = "memory")]
+            Self::Memory(db) => wrap_query!(
+                Memory,
+                db.query_objects_first(table_id, schema, filter, sort, keep_alive, limit)
+                    .await
+            ),
+        }
+    }
+
+    async fn query_objects_next<'a, T: DeserializeOwned + Send + Sync>(
+        &self,
+        query_state: AnyQueryState<'a>,
+    ) -> Result<(Vec<(ElasticId, u64, T)>, Option<AnyQueryState<'a>>), Error> {
+        match (self, query_state) {
+            (Self::Elastic(db), AnyQueryState::Elastic(state)) => {
+                wrap_query!(Elastic, db.query_objects_next(state).await)
+            }
+            #[cfg(feature = "mariadb")]
+            (Self::MariaDb(db), AnyQueryState::MariaDb(state)) => {
+                wrap_query!(MariaDb, db.query_objects_next(state).await)
+            }
+            #[cfg(feature = "memory")]
+            (Self::Memory(db), AnyQueryState::Memory(state)) => {
+                wrap_query!(Memory, db.query_objects_next(state).await)
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::QueryState),
+        }
+    }
+
+    async fn query_objects_close(&self, query_state: AnyQueryState<'_>) -> Result<(), Error> {
+        match (self, query_state) {
+            (Self::Elastic(db), AnyQueryState::Elastic(state)) => {
+                Ok(db.query_objects_close(state).await?)
+            }
+            #[cfg(feature = "mariadb")]
+            (Self::MariaDb(db), AnyQueryState::MariaDb(state)) => {
+                Ok(db.query_objects_close(state).await?)
+            }
+            #[cfg(feature = "memory")]
+            (Self::Memory(db), AnyQueryState::Memory(state)) => {
+                Ok(db.query_objects_close(state).await?)
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::QueryState),
+        }
+    }
+
+    async fn aggregate(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        filter: &Filter,
+        timestamp_field: &str,
+        aggregation: &Aggregation,
+    ) -> Result<Vec<AggregationBucket>, Error> {
+        dispatch!(self, db => db.aggregate(table_id, schema, filter, timestamp_field, aggregation).await)
+    }
+}
@@ -4,40 +4,98 @@
 
 use std::{
     fmt::{Debug, Display},
+    future::Future,
     time::Duration,
 };
 
+use chrono::{DateTime, Utc};
 use serde::{de::DeserializeOwned, Serialize};
 
+use dbdaemon_api::{Aggregation, AggregationBucket};
 use dbschema::{DbSchema, DbTable, DbTableId, Filter};
 use serde_json::Value;
 
-pub(crate) trait Database: Sized {
-    type Error: std::error::Error;
+/// The field holding the timestamp of documents in timestamped
+/// tables.
+pub const TIMESTAMP_FIELD: &str = "timestamp";
+
+/// A transformation applied to every document copied by a reindex,
+/// such as a schema migration.
+pub type Transform<'a> =
+    dyn Fn(Value) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> + Send + Sync + 'a;
+
+/// Marker for errors returned by a database backend. Allows the
+/// daemon to convert backend errors without knowing the backend.
+pub trait DatabaseError: std::error::Error + Send + Sync + 'static {}
+
+/// A database backend for the daemon. Futures returned by the
+/// backend must be `Send`, so that they can be awaited from spawned
+/// tasks.
+pub trait Database: Sized + Send + Sync {
+    type Error: DatabaseError;
     type Id: Display + Debug;
-    type QueryState<'a>;
+    type QueryState<'a>: Send;
+
+    fn whoami(&self) -> String;
 
     /* Setup and configuration. */
 
-    async fn wait_for_database(&self) -> Result<(), Self::Error>;
-    async fn verify_database(&self) -> Result<(), Self::Error>;
+    fn wait_for_database(&self) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    fn verify_database(&self) -> impl Future<Output = Result<(), Self::Error>> + Send;
 
     /* Schema manipulation. */
 
-    async fn has_table(&self, id: &DbTableId) -> Result<bool, Self::Error>;
+    fn has_table(&self, id: &DbTableId) -> impl Future<Output = Result<bool, Self::Error>> + Send;
 
-    async fn create_table(&self, id: &DbTableId, definition: &DbTable) -> Result<(), Self::Error>;
+    fn create_table(
+        &self,
+        id: &DbTableId,
+        definition: &DbTable,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
 
-    async fn update_table(&self, id: &DbTableId, definition: &DbTable) -> Result<(), Self::Error>;
+    fn update_table(
+        &self,
+        id: &DbTableId,
+        definition: &DbTable,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
 
-    async fn reindex_table(
+    /// Move the data of a table to storage for a new definition,
+    /// transforming every document if requested.
+    fn reindex_table(
         &self,
         id: &DbTableId,
         old_definition: &DbTable,
         new_definition: &DbTable,
-    ) -> Result<(), Self::Error>;
+        transform: Option<&Transform<'_>>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn remove_table(&self, id: &DbTableId) -> impl Future<Output = Result<(), Self::Error>> + Send;
 
-    async fn remove_table(&self, id: &DbTableId) -> Result<(), Self::Error>;
+    /// Drop whole partitions of a timestamped table that only hold
+    /// documents from before the given time. Backends that do not
+    /// partition tables leave the data alone.
+    fn drop_partitions(
+        &self,
+        _id: &DbTableId,
+        _before: DateTime<Utc>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Clean up after schema operations on the given tables that were
+    /// interrupted by a crash. Called once at startup, before the
+    /// tables are loaded. Other tables must be left alone, since they
+    /// may belong to another instance sharing the database.
+    fn recover(
+        &self,
+        _tables: &[DbTableId],
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Make all previous writes to the table visible to queries.
+    fn refresh_table(&self, id: &DbTableId)
+        -> impl Future<Output = Result<(), Self::Error>> + Send;
 
     /* Data manipulation. */
 
@@ -49,16 +107,24 @@ pub(crate) trait Database: Sized {
     //     value: T,
     // ) -> Result<(), Self::Error>;
 
-    async fn bulk_update<T, I>(
+    fn bulk_update<T, I>(
         &self,
         table_id: &DbTableId,
         schema: &DbSchema,
         updates: I,
-    ) -> Result<(), Self::Error>
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send
     where
         T: Serialize + Send + Sync,
         I: IntoIterator<Item = (Self::Id, u64, T)> + Send + Sync;
 
+    fn bulk_delete<I>(
+        &self,
+        table_id: &DbTableId,
+        deletes: I,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send
+    where
+        I: IntoIterator<Item = (Self::Id, u64)> + Send + Sync;
+
     // async fn query_object<T: DeserializeOwned + Send + Sync>(
     //     &self,
     //     table_id: &DbTableId,
@@ -67,25 +133,25 @@ pub(crate) trait Database: Sized {
     //     sort: &Value,
     // ) -> Result<(Self::Id, u64, T), Self::Error>;
 
-    async fn update_object<T: Serialize + Send + Sync>(
+    fn update_object<T: Serialize + Send + Sync>(
         &self,
         table_id: &DbTableId,
         schema: &DbSchema,
         doc_id: &Self::Id,
         version: u64,
         value: T,
-    ) -> Result<(), Self::Error>;
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
 
-    async fn query_objects<T: DeserializeOwned + Send + Sync>(
+    fn query_objects<T: DeserializeOwned + Send + Sync>(
         &self,
         table_id: &DbTableId,
         schema: &DbSchema,
         filter: &Filter,
         sort: &Value,
         limit: Option<usize>,
-    ) -> Result<Vec<(Self::Id, u64, T)>, Self::Error>;
+    ) -> impl Future<Output = Result<Vec<(Self::Id, u64, T)>, Self::Error>> + Send;
 
-    async fn query_objects_first<'a, T: DeserializeOwned + Send + Sync>(
+    fn query_objects_first<'a, T: DeserializeOwned + Send + Sync>(
         &self,
         table_id: &DbTableId,
         schema: &'a DbSchema,
@@ -93,10 +159,43 @@ pub(crate) trait Database: Sized {
         sort: &'a Value, /* should be its own type */
         keep_alive: Duration,
         limit: Option<usize>,
-    ) -> Result<(Vec<(Self::Id, u64, T)>, Option<Self::QueryState<'a>>), Self::Error>;
+    ) -> impl Future<
+        Output = Result<(Vec<(Self::Id, u64, T)>, Option<Self::QueryState<'a>>), Self::Error>,
+    > + Send;
 
-    async fn query_objects_next<'a, T: DeserializeOwned + Send + Sync>(
+    fn query_objects_next<'a, T: DeserializeOwned + Send + Sync>(
         &self,
         query_state: Self::QueryState<'a>,
-    ) -> Result<(Vec<(Self::Id, u64, T)>, Option<Self::QueryState<'a>>), Self::Error>;
+    ) -> impl Future<
+        Output = Result<(Vec<(Self::Id, u64, T)>, Option<Self::QueryState<'a>>), Self::Error>,
+    > + Send;
+
+    /// Count the documents stored in a table.
+    fn count_objects(
+        &self,
+        table_id: &DbTableId,
+    ) -> impl Future<Output = Result<u64, Self::Error>> + Send;
+
+    /// Release the resources of a query that is not read to the end.
+    /// Queries that are not closed expire after their keep-alive time.
+    fn query_objects_close(
+        &self,
+        _query_state: Self::QueryState<'_>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async { Ok(()) }
+    }
+
+    /* Aggregation. */
+
+    /// Aggregate the documents matching the filter into time buckets
+    /// on the timestamp field. Field names are given as in sort
+    /// specifications.
+    fn aggregate(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        filter: &Filter,
+        timestamp_field: &str,
+        aggregation: &Aggregation,
+    ) -> impl Future<Output = Result<Vec<AggregationBucket>, Self::Error>> + Send;
 }
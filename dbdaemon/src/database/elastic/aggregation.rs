@@ -0,0 +1,135 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+
+use dbdaemon_api::{Aggregation, AggregationBucket, MetricFunction};
+use dbschema_elastic::ElasticFilter;
+
+use super::error::{Error, Result};
+
+/// The maximum number of groups per group-by field and time bucket.
+const TERMS_SIZE: usize = 1000;
+
+/// Compile an aggregation to an elasticsearch search request. Time
+/// buckets are calculated with a `date_histogram`, containing a
+/// nested `terms` aggregation for every group-by field.
+pub(super) fn request(
+    esfilter: &ElasticFilter,
+    timestamp_field: &str,
+    aggregation: &Aggregation,
+) -> Value {
+    let metrics = aggregation
+        .metrics
+        .iter()
+        .enumerate()
+        .map(|(i, metric)| {
+            let field = &metric.field;
+            let agg = match metric.function {
+                MetricFunction::Avg => json!({ "avg": { "field": field } }),
+                MetricFunction::Min => json!({ "min": { "field": field } }),
+                MetricFunction::Max => json!({ "max": { "field": field } }),
+                MetricFunction::Sum => json!({ "sum": { "field": field } }),
+                MetricFunction::Count => json!({ "value_count": { "field": field } }),
+                MetricFunction::Percentile(p) => json!({
+                    "percentiles": { "field": field, "percents": [p] }
+                }),
+            };
+            (format!("m{i}"), agg)
+        })
+        .collect::<Map<_, _>>();
+
+    let aggs = aggregation.group_by.iter().enumerate().rev().fold(
+        Value::Object(metrics),
+        |aggs, (i, field)| {
+            let name = format!("g{i}");
+            json!({
+                name: {
+                    "terms": { "field": field, "size": TERMS_SIZE },
+                    "aggs": aggs
+                }
+            })
+        },
+    );
+
+    json!({
+        "size": 0,
+        "query": esfilter,
+        "aggs": {
+            "time": {
+                "date_histogram": {
+                    "field": timestamp_field,
+                    "fixed_interval": format!("{}s", aggregation.interval),
+                    "min_doc_count": 1
+                },
+                "aggs": aggs
+            }
+        }
+    })
+}
+
+/// Flatten the aggregation response into buckets.
+pub(super) fn buckets(aggregation: &Aggregation, aggs: &Value) -> Result<Vec<AggregationBucket>> {
+    let mut result = Vec::new();
+    for bucket in get_buckets(aggs.get("time"))? {
+        let timestamp = bucket
+            .get("key")
+            .and_then(Value::as_i64)
+            .and_then(DateTime::from_timestamp_millis)
+            .ok_or(Error::InvalidAggregation)?;
+        collect(aggregation, timestamp, &mut Vec::new(), bucket, &mut result)?;
+    }
+    Ok(result)
+}
+
+fn collect(
+    aggregation: &Aggregation,
+    timestamp: DateTime<Utc>,
+    key: &mut Vec<Value>,
+    bucket: &Value,
+    result: &mut Vec<AggregationBucket>,
+) -> Result<()> {
+    let level = key.len();
+    if level < aggregation.group_by.len() {
+        for sub in get_buckets(bucket.get(format!("g{level}")))? {
+            key.push(sub.get("key").cloned().ok_or(Error::InvalidAggregation)?);
+            collect(aggregation, timestamp, key, sub, result)?;
+            key.pop();
+        }
+        return Ok(());
+    }
+
+    let values = aggregation
+        .metrics
+        .iter()
+        .enumerate()
+        .map(|(i, metric)| {
+            let agg = bucket.get(format!("m{i}"));
+            match metric.function {
+                MetricFunction::Percentile(_) => agg
+                    .and_then(|agg| agg.get("values")?.as_object()?.values().next())
+                    .and_then(Value::as_f64),
+                _ => agg.and_then(|agg| agg.get("value")).and_then(Value::as_f64),
+            }
+        })
+        .collect();
+
+    result.push(AggregationBucket {
+        timestamp,
+        key: key.clone(),
+        count: bucket
+            .get("doc_count")
+            .and_then(Value::as_u64)
+            .ok_or(Error::InvalidAggregation)?,
+        values,
+    });
+    Ok(())
+}
+
+fn get_buckets(agg: Option<&Value>) -> Result<&Vec<Value>> {
+    agg.and_then(|agg| agg.get("buckets"))
+        .and_then(Value::as_array)
+        .ok_or(Error::InvalidAggregation)
+}
@@ -10,28 +10,37 @@ use std::str::FromStr;
 use std::sync::atomic::AtomicBool;
 use std::time::Duration;
 
+use chrono::{DateTime, Utc};
 use clap::Args;
 use futures::TryFutureExt;
 use http::Method;
 use log::{debug, info};
+use parking_lot::RwLock;
 use reqwest::{Body, Certificate, Client, Identity, RequestBuilder, Response, StatusCode, Url};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Value};
 use tokio::fs;
 
-use dbschema::{DbSchema, DbTable, DbTableId, Filter};
+use dbdaemon_api::{Aggregation, AggregationBucket};
+use dbschema::{DbSchema, DbTable, DbTableId, Filter, VersioningType};
 use uuid::Uuid;
 
 use dbschema_elastic::{ElasticFilter, ElasticMapping, ElasticValue};
 
-use crate::database::backend::Database as DatabaseTrait;
+use crate::database::backend::{Database as DatabaseTrait, Transform};
 
+use super::aggregation;
 use super::bulk_op::BulkOp;
 use super::error::{Error, InitializationError, Result};
-use super::requests::{CreateIndex, IndexSettings, Pit, SearchRequest};
+use super::partitioning::Partitioning;
+use super::requests::{
+    CreateIndex, IndexSettings, IndexTemplate, IndexTemplateMeta, Pit, SearchRequest,
+};
 use super::responses::{
-    BulkReponse, ClusterDistribution, ClusterInfoResponse, DocumentResponse, IndexResponse,
-    PitResponse, QueryResponse, RefreshResponse, UpdateByQueryResponse,
+    AcknowledgedResponse, AggregationResponse, BulkReponse, ClusterDistribution,
+    ClusterInfoResponse, CountResponse, DocumentResponse, IndexAliases, IndexResponse,
+    IndexTemplatesResponse, LocateResponse, PitResponse, QueryResponse, RefreshResponse,
+    UpdateByQueryResponse,
 };
 
 #[derive(Debug)]
@@ -40,6 +49,8 @@ pub struct Database {
     client: Client,
     pub base_url: Url,
     opensearch: AtomicBool,
+    /// The partitioning of tables and the version of their partitions.
+    partitioning: RwLock<HashMap<DbTableId, (Partitioning, u32)>>,
 }
 
 #[derive(Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
@@ -51,6 +62,15 @@ impl ElasticId {
     pub fn new() -> Self {
         Self(Uuid::new_v4().to_string())
     }
+
+    /// Document id as stored by a (non-elastic) backend.
+    pub fn from_string(id: String) -> Self {
+        Self(id)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
 impl Database {
@@ -117,6 +137,7 @@ impl Database {
             client: client.build().map_err(InitializationError::BuildClient)?,
             base_url: url,
             opensearch: AtomicBool::new(false),
+            partitioning: RwLock::new(HashMap::new()),
         })
     }
 
@@ -134,6 +155,124 @@ impl Database {
         format!("{prefix}-{table_id}")
     }
 
+    /// The name of a version of a table's index. Unpartitioned tables
+    /// are stored in a versioned index behind an alias with the name
+    /// of the table's index, so that reindexing can switch to a new
+    /// index atomically.
+    fn versioned_index(index: &str, version: u32) -> String {
+        format!("{index}.v-{version}")
+    }
+
+    /// The physical index behind a table's alias and its version.
+    /// Tables created before indices were versioned are stored in an
+    /// index with the name of the alias, with version 0.
+    async fn current_index(&self, index: &str) -> Result<(String, u32)> {
+        let indices: HashMap<String, IndexAliases> = self.get(&format!("{index}/_alias")).await?;
+        Ok(indices
+            .into_keys()
+            .map(|name| {
+                let version = name
+                    .strip_prefix(index)
+                    .and_then(|suffix| suffix.strip_prefix(".v-"))
+                    .and_then(|version| version.parse().ok())
+                    .unwrap_or(0);
+                (name, version)
+            })
+            .max_by_key(|(_, version)| *version)
+            .unwrap_or_else(|| (index.to_string(), 0)))
+    }
+
+    /// Look up the partitioning of a table and the version of its
+    /// partitions. Partitioned tables are recognized by their index
+    /// template.
+    async fn table_layout(&self, table_id: &DbTableId) -> Result<(Partitioning, u32)> {
+        if let Some(layout) = self.partitioning.read().get(table_id) {
+            return Ok(*layout);
+        }
+        let index = self.get_index_name(table_id);
+        let layout = match self
+            .get::<IndexTemplatesResponse>(&format!("_index_template/{index}"))
+            .await
+        {
+            Ok(res) => res
+                .index_templates
+                .into_iter()
+                .find(|template| template.name == index)
+                .and_then(|template| template.index_template.meta)
+                .map_or((Partitioning::None, 0), |meta| {
+                    (meta.partitioning, meta.version)
+                }),
+            Err(Error::EsError(e)) if e.status == 404 => (Partitioning::None, 0),
+            Err(e) => return Err(e),
+        };
+        self.partitioning.write().insert(table_id.clone(), layout);
+        Ok(layout)
+    }
+
+    async fn table_partitioning(&self, table_id: &DbTableId) -> Result<Partitioning> {
+        Ok(self.table_layout(table_id).await?.0)
+    }
+
+    /// The partitioning of a table and the name from which its
+    /// partitions are named. For unpartitioned tables, this is the
+    /// name of the index.
+    async fn partition_base(&self, table_id: &DbTableId) -> Result<(Partitioning, String)> {
+        let index = self.get_index_name(table_id);
+        let (partitioning, version) = self.table_layout(table_id).await?;
+        Ok((partitioning, Partitioning::base(&index, version)))
+    }
+
+    /// The partitions of all versions of a partitioned table.
+    async fn partitions(&self, index: &str) -> Result<Vec<Partition>> {
+        let indices: HashMap<String, IndexAliases> = self
+            .get(&format!(
+                "{},{index}.v-*/_alias",
+                Partitioning::pattern(index)
+            ))
+            .await?;
+        Ok(indices
+            .into_iter()
+            .filter_map(|(name, aliases)| {
+                Some(Partition {
+                    version: Partitioning::version(index, &name)?,
+                    aliased: aliases.aliases.contains_key(index),
+                    name,
+                })
+            })
+            .collect())
+    }
+
+    /// Create or update the index template for the partitions of a
+    /// version of a table. The template adds every partition to an
+    /// alias with the name of the unpartitioned index.
+    async fn put_index_template(
+        &self,
+        index: &str,
+        definition: &DbTable,
+        partitioning: Partitioning,
+        version: u32,
+    ) -> Result<()> {
+        let req = IndexTemplate {
+            index_patterns: vec![Partitioning::pattern(&Partitioning::base(index, version))],
+            template: CreateIndex {
+                aliasses: Some(HashMap::from_iter([(index.to_string(), json!({}))])),
+                mappings: Some(ElasticMapping::new(&definition.schema())?),
+                settings: Some(IndexSettings {
+                    total_fields_limit: Some(10000),
+                    ..IndexSettings::default()
+                }),
+            },
+            priority: Some(100),
+            meta: Some(IndexTemplateMeta {
+                partitioning,
+                version,
+            }),
+        };
+        let _res: AcknowledgedResponse =
+            self.put(&format!("_index_template/{index}"), &req).await?;
+        Ok(())
+    }
+
     async fn refresh_index(&self, index: &str) -> Result<()> {
         let _ = self
             .get::<RefreshResponse>(&format!("{index}/_refresh"))
@@ -141,11 +280,6 @@ impl Database {
         Ok(())
     }
 
-    pub async fn refresh_table(&self, table_id: &DbTableId) -> Result<()> {
-        let index = self.get_index_name(table_id);
-        self.refresh_index(&index).await
-    }
-
     /// This is not included in the trait because the
     /// script parameter is ES-specific.
     pub async fn partial_update_by_query(
@@ -336,12 +470,182 @@ impl Database {
         res
     }
 
+    /// Index documents into an index, or into the partitions of an
+    /// index.
+    async fn bulk_index<T, I>(
+        &self,
+        index: &str,
+        partitioning: Partitioning,
+        schema: &DbSchema,
+        updates: I,
+    ) -> Result<()>
+    where
+        T: Serialize + Send + Sync,
+        I: IntoIterator<Item = (ElasticId, u64, T)> + Send + Sync,
+    {
+        let mut req = Vec::new();
+        updates
+            .into_iter()
+            .try_for_each::<_, Result<()>>(|(id, version, value)| {
+                let value = serde_json::to_value(&value)?;
+                let partition = partitioning
+                    .is_partitioned()
+                    .then(|| partitioning.partition_for(index, &value));
+                let dbvalue = ElasticValue::save(schema, value)?;
+                BulkOp::Index {
+                    index: partition.as_deref(),
+                    id: id.0.as_str(),
+                    value: &dbvalue,
+                    version,
+                }
+                .write(&mut req)?;
+                Ok(())
+            })?;
+        if !req.is_empty() {
+            /* Partitions are named in the operations; the base name
+             * of a new version of the partitions is not an index. */
+            let path = match partitioning.is_partitioned() {
+                true => String::from("_bulk"),
+                false => format!("{index}/_bulk"),
+            };
+            let res: BulkReponse = self.post_ndjson(&path, req).await?;
+            if res.errors
+                && res
+                    .items
+                    .iter()
+                    .any(|h| h.status() != 200 && h.status() != 409)
+            {
+                return Err(
+                    match res
+                        .items
+                        .iter()
+                        .all(|h| h.status() != 200 && h.status() != 409)
+                    {
+                        true => Error::BulkUpdateComplete,
+                        false => Error::BulkUpdatePartial,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Reindex a partitioned table into a new version of its
+    /// partitions. The new partitions are created from a separate
+    /// template without the alias, and put behind the alias once
+    /// they are complete. The table's template is updated before
+    /// the alias is switched, so that an interrupted switch can be
+    /// completed at startup.
+    async fn reindex_partitioned(
+        &self,
+        id: &DbTableId,
+        old_definition: &DbTable,
+        new_definition: &DbTable,
+        transform: Option<&Transform<'_>>,
+    ) -> Result<()> {
+        let index = self.get_index_name(id);
+        let (partitioning, version) = self.table_layout(id).await?;
+        let new_base = Partitioning::base(&index, version + 1);
+        info!("reindexing partitioned table '{id}' to '{new_base}'");
+
+        let req = IndexTemplate {
+            index_patterns: vec![Partitioning::pattern(&new_base)],
+            template: CreateIndex {
+                mappings: Some(ElasticMapping::new(&new_definition.schema())?),
+                settings: Some(IndexSettings {
+                    total_fields_limit: Some(10000),
+                    ..IndexSettings::default()
+                }),
+                ..CreateIndex::default()
+            },
+            priority: Some(100),
+            meta: None,
+        };
+        let _res: AcknowledgedResponse = self
+            .put(&format!("_index_template/{new_base}"), &req)
+            .await?;
+
+        let copy = async {
+            /* Create the current partition, so that the alias does
+             * not end up without indices. */
+            let partition = partitioning.partition(&new_base, Utc::now());
+            let _res: IndexResponse = self.put(&partition, &CreateIndex::default()).await?;
+            self.refresh_index(&index).await?;
+            self.reindex(
+                id,
+                &old_definition.schema(),
+                &new_base,
+                partitioning,
+                &new_definition.schema(),
+                transform,
+            )
+            .await?;
+            /* The table's template takes over the pattern. */
+            let _res: AcknowledgedResponse =
+                self.delete(&format!("_index_template/{new_base}")).await?;
+            Ok(())
+        };
+        if let Err(e) = copy.await {
+            self.discard_partitions(&index, version + 1).await;
+            return Err(e);
+        }
+
+        self.put_index_template(&index, new_definition, partitioning, version + 1)
+            .await?;
+        self.partitioning
+            .write()
+            .insert(id.clone(), (partitioning, version + 1));
+        self.switch_partitions(&index, version + 1).await
+    }
+
+    /// Put the partitions of a version of a partitioned table behind
+    /// its alias, in place of those of other versions, and delete the
+    /// latter.
+    async fn switch_partitions(&self, index: &str, version: u32) -> Result<()> {
+        let partitions = self.partitions(index).await?;
+        if !partitions.iter().any(|p| p.version == version) {
+            log::warn!("no partitions found for version {version} of '{index}'");
+            return Ok(());
+        }
+
+        let actions = partitions
+            .iter()
+            .filter_map(|p| match (p.version == version, p.aliased) {
+                (true, false) => Some(json!({ "add": { "index": &p.name, "alias": index } })),
+                (false, true) => Some(json!({ "remove": { "index": &p.name, "alias": index } })),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if !actions.is_empty() {
+            info!("switching '{index}' to version {version} of its partitions");
+            let _res: AcknowledgedResponse = self
+                .post("_aliases", &json!({ "actions": actions }))
+                .await?;
+        }
+
+        let old = partitions
+            .into_iter()
+            .filter(|p| p.version != version)
+            .map(|p| p.name)
+            .collect::<Vec<_>>();
+        if !old.is_empty() {
+            let names = old.join(",");
+            info!("deleting old partitions of '{index}': {names}");
+            let _res: Value = self.delete(&names).await?;
+        }
+        Ok(())
+    }
+
+    /// Copy all documents of a table to another index, or to the
+    /// partitions of another index.
     async fn reindex(
         &self,
         old: &DbTableId,
-        new: &DbTableId,
         old_schema: &DbSchema,
+        new_index: &str,
+        new_partitioning: Partitioning,
         new_schema: &DbSchema,
+        transform: Option<&Transform<'_>>,
     ) -> Result<()> {
         let filter = Filter::All(Vec::new());
         let sort = json!([
@@ -361,7 +665,26 @@ impl Database {
             .await?;
 
         loop {
-            self.bulk_update(new, new_schema, docs).await?;
+            let copy = async {
+                if let Some(transform) = transform {
+                    docs = docs
+                        .into_iter()
+                        .map(|(id, version, doc)| {
+                            Ok((id, version, transform(doc).map_err(Error::Transform)?))
+                        })
+                        .collect::<Result<_>>()?;
+                }
+                self.bulk_index(new_index, new_partitioning, new_schema, docs)
+                    .await
+            };
+            if let Err(e) = copy.await {
+                if let Some(state) = query_state {
+                    if let Err(e) = self.close_pit(&state.pit_id).await {
+                        log::warn!("failed to close point in time: {e}");
+                    }
+                }
+                return Err(e);
+            }
             match query_state {
                 Some(state) => {
                     (docs, query_state) = self.query_objects_next::<Value>(state).await?;
@@ -373,8 +696,143 @@ impl Database {
         Ok(())
     }
 
-    pub fn whoami(&self) -> String {
-        String::from("Elastic")
+    /// Find the partitions holding documents of a partitioned table.
+    async fn locate(
+        &self,
+        index: &str,
+        docs: Vec<(ElasticId, u64)>,
+    ) -> Result<Vec<(ElasticId, u64, Option<String>)>> {
+        const CHUNK_SIZE: usize = 10000;
+
+        if docs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        /* Searches only see refreshed documents. */
+        self.refresh_index(index).await?;
+
+        let mut located = Vec::new();
+        for chunk in docs.chunks(CHUNK_SIZE) {
+            let versions = chunk.iter().cloned().collect::<HashMap<_, _>>();
+            let ids = versions.keys().collect::<Vec<_>>();
+            let res: LocateResponse = self
+                .post(
+                    &format!("{index}/_search"),
+                    &json!({
+                        "query": { "ids": { "values": ids } },
+                        "_source": false,
+                        "size": chunk.len()
+                    }),
+                )
+                .await?;
+            located.extend(res.hits.hits.into_iter().filter_map(|hit| {
+                let version = *versions.get(&hit.id)?;
+                Some((hit.id, version, Some(hit.index)))
+            }));
+        }
+        Ok(located)
+    }
+
+    /// Delete an index that was not put in place, after a failed
+    /// reindex. Failures are only logged; the index is removed at
+    /// startup otherwise.
+    async fn discard_index(&self, index: &str) {
+        info!("deleting index '{index}'");
+        if let Err(e) = self.delete::<Value>(index).await {
+            log::warn!("failed to delete index '{index}': {e}");
+        }
+    }
+
+    /// Delete the partitions of a version of a partitioned table that
+    /// was not put in place, and the template they were created from,
+    /// after a failed reindex. Failures are only logged; the
+    /// partitions are removed at startup otherwise.
+    async fn discard_partitions(&self, index: &str, version: u32) {
+        let base = Partitioning::base(index, version);
+        match self
+            .delete::<Value>(&format!("_index_template/{base}"))
+            .await
+        {
+            Ok(_) => {}
+            Err(Error::EsError(e)) if e.status == 404 => {}
+            Err(e) => log::warn!("failed to delete index template '{base}': {e}"),
+        }
+        let partitions = match self.partitions(index).await {
+            Ok(partitions) => partitions,
+            Err(e) => {
+                log::warn!("failed to list partitions of '{index}': {e}");
+                return;
+            }
+        };
+        let names = partitions
+            .into_iter()
+            .filter(|p| p.version == version && !p.aliased)
+            .map(|p| p.name)
+            .collect::<Vec<_>>();
+        if !names.is_empty() {
+            self.discard_index(&names.join(",")).await;
+        }
+    }
+
+    /// Read the next page of a query.
+    async fn query_page<'a, T: DeserializeOwned + Send + Sync>(
+        &self,
+        query_state: QueryState<'a>,
+    ) -> Result<(Vec<(ElasticId, u64, T)>, Option<QueryState<'a>>)> {
+        let res: QueryResponse = self
+            .post_with_query(
+                "_search",
+                &json!({ "version": true }),
+                &SearchRequest {
+                    query: query_state.esfilter.clone(),
+                    pit: Some(Pit {
+                        id: query_state.pit_id.clone(),
+                        keep_alive: format!("{}s", query_state.keep_alive.as_secs()),
+                    }),
+                    sort: Some(query_state.sort.clone()),
+                    search_after: query_state.last.clone(),
+                    size: Some(query_state.limit.map_or(10000, |n| n.min(10000))),
+                },
+            )
+            .await?;
+        let mut last = None;
+        let docs: Vec<(ElasticId, u64, T)> = res
+            .hits
+            .hits
+            .into_iter()
+            .map(|hit| {
+                last = Some(hit.sort.ok_or(Error::MissingSortField)?);
+                let val = hit.source.load(query_state.schema)?;
+                Ok(
+                    match &query_state.filter.matches(query_state.schema, &val)? {
+                        true => Some((hit.id, hit.version, serde_json::from_value(val)?)),
+                        false => None,
+                    },
+                )
+            })
+            .filter_map(Result::transpose)
+            .collect::<Result<_>>()?;
+        let query_state = match docs.is_empty() {
+            true => None,
+            false => Some(QueryState {
+                pit_id: res.pit_id.ok_or(Error::MissingPitId)?,
+                limit: query_state.limit.map(|n| 0.max(n - docs.len())),
+                last,
+                ..query_state
+            }),
+        };
+        Ok((docs, query_state))
+    }
+
+    async fn close_pit(&self, pit_id: &str) -> Result<()> {
+        let (path, req) = match self.opensearch.load(std::sync::atomic::Ordering::Acquire) {
+            true => ("_search/point_in_time", json!({ "pit_id": [pit_id] })),
+            false => ("_pit", json!({ "id": pit_id })),
+        };
+        debug!("elasticsearch DELETE {path}");
+        let res = self.request(Method::DELETE, path).json(&req).send().await?;
+        self.response::<Value>(res).await?;
+        Ok(())
     }
 }
 
@@ -382,6 +840,10 @@ impl DatabaseTrait for Database {
     type Error = Error;
     type Id = ElasticId;
 
+    fn whoami(&self) -> String {
+        String::from("Elastic")
+    }
+
     /* Setup and configuration. */
 
     async fn wait_for_database(&self) -> Result<()> {
@@ -422,23 +884,64 @@ impl DatabaseTrait for Database {
     //https://www.elastic.co/guide/en/elasticsearch/reference/current/indices-create-index.html
     async fn create_table(&self, id: &DbTableId, definition: &DbTable) -> Result<()> {
         let index = self.get_index_name(id);
+        let partitioning = match definition.versioning {
+            VersioningType::Timestamped => self.config.partitioning,
+            _ => Partitioning::None,
+        };
+
+        if partitioning.is_partitioned() {
+            info!("creating partitioned table: {index}");
+            self.put_index_template(&index, definition, partitioning, 0)
+                .await?;
+            /* Create the current partition, so that the alias exists. */
+            let partition = partitioning.partition(&index, Utc::now());
+            let _res: IndexResponse = self.put(&partition, &CreateIndex::default()).await?;
+            self.partitioning
+                .write()
+                .insert(id.clone(), (partitioning, 0));
+            return Ok(());
+        }
+
         info!("creating table: {index}");
-        // Note: this is only true if the table does not yet exist!
+        /* Creating the first version would add a second index to the
+         * alias of an existing table. */
+        if self.head(&index).await?.is_success() {
+            return Err(Error::IndexExists(index));
+        }
         let req = CreateIndex {
+            aliasses: Some(HashMap::from_iter([(index.clone(), json!({}))])),
             mappings: Some(ElasticMapping::new(&definition.schema())?),
             settings: Some(IndexSettings {
                 total_fields_limit: Some(10000),
                 ..IndexSettings::default()
             }),
-            ..CreateIndex::default()
         };
-        let _res: IndexResponse = self.put(&index, &req).await?;
+        let _res: IndexResponse = self.put(&Self::versioned_index(&index, 1), &req).await?;
         // assert!(res.acknowledged && res.index == table_id);
+        self.partitioning
+            .write()
+            .insert(id.clone(), (partitioning, 0));
         Ok(())
     }
 
     async fn update_table(&self, id: &DbTableId, definition: &DbTable) -> Result<()> {
         let index = self.get_index_name(id);
+        let (partitioning, version) = self.table_layout(id).await?;
+
+        if partitioning.is_partitioned() {
+            info!("updating mapping for partitioned table '{index}'");
+            self.put_index_template(&index, definition, partitioning, version)
+                .await?;
+            let base = Partitioning::base(&index, version);
+            let _res: AcknowledgedResponse = self
+                .put(
+                    &format!("{}/_mapping", Partitioning::pattern(&base)),
+                    &ElasticMapping::new(&definition.schema())?,
+                )
+                .await?;
+            return Ok(());
+        }
+
         info!("updating mapping for table '{index}'");
         let _res: HashMap<String, ElasticMapping> = self
             .put(
@@ -454,50 +957,183 @@ impl DatabaseTrait for Database {
         id: &DbTableId,
         old_definition: &DbTable,
         new_definition: &DbTable,
+        transform: Option<&Transform<'_>>,
     ) -> Result<()> {
-        let reindexed = DbTableId::from_string(format!("{id}-reindex"));
-        info!("reindexing table '{id}' to '{reindexed}'");
-
-        let old_schema = old_definition.schema();
-        let new_schema = new_definition.schema();
-
-        // let create_req = CreateIndex {
-        //     mappings: Some(ElasticMapping::new(&new_schema)?),
-        //     settings: Some(IndexSettings {
-        //         total_fields_limit: Some(10000),
-        //         ..IndexSettings::default()
-        //     }),
-        //     ..CreateIndex::default()
-        // };
-
-        /* Reindex from table to table-reindex. */
-
-        //let _res: IndexResponse = self.put(&reindexed, &create_req).await?;
-        self.create_table(&reindexed, new_definition).await?;
-        self.reindex(id, &reindexed, &old_schema, &new_schema)
-            .await?;
+        if self.table_partitioning(id).await?.is_partitioned() {
+            return self
+                .reindex_partitioned(id, old_definition, new_definition, transform)
+                .await;
+        }
 
-        /* Reindex from table-reindex to table. */
+        let index = self.get_index_name(id);
+        let (current, version) = self.current_index(&index).await?;
+        let new_index = Self::versioned_index(&index, version + 1);
+        info!("reindexing table '{id}' from '{current}' to '{new_index}'");
+
+        /* The new index is added to the alias only once it is
+         * complete. After a crash before that, the new index is
+         * removed at startup. */
+        let req = CreateIndex {
+            mappings: Some(ElasticMapping::new(&new_definition.schema())?),
+            settings: Some(IndexSettings {
+                total_fields_limit: Some(10000),
+                ..IndexSettings::default()
+            }),
+            ..CreateIndex::default()
+        };
+        let _res: IndexResponse = self.put(&new_index, &req).await?;
+        let copy = async {
+            self.refresh_index(&index).await?;
+            self.reindex(
+                id,
+                &old_definition.schema(),
+                &new_index,
+                Partitioning::None,
+                &new_definition.schema(),
+                transform,
+            )
+            .await
+        };
+        if let Err(e) = copy.await {
+            self.discard_index(&new_index).await;
+            return Err(e);
+        }
 
-        // let _res: Value = self.delete(id.to_str()).await?;
-        // let _res: IndexResponse = self.put(id.to_str(), &create_req).await?;
-        self.remove_table(id).await?;
-        self.create_table(id, new_definition).await?;
-        self.reindex(&reindexed, id, &new_schema, &new_schema)
+        /* Switch the alias atomically. Tables created before indices
+         * were versioned have an index with the name of the alias,
+         * which is removed in the same operation. */
+        let actions = match version {
+            0 => json!([
+                { "remove_index": { "index": &current } },
+                { "add": { "index": &new_index, "alias": &index } }
+            ]),
+            _ => json!([
+                { "remove": { "index": &current, "alias": &index } },
+                { "add": { "index": &new_index, "alias": &index } }
+            ]),
+        };
+        let _res: AcknowledgedResponse = self
+            .post("_aliases", &json!({ "actions": actions }))
             .await?;
-        self.remove_table(&reindexed).await?;
-        // let _res: Value = self.delete(&reindexed).await?;
+
+        /* After a crash before this, the old index is removed at
+         * startup. */
+        if version > 0 {
+            info!("deleting index '{current}'");
+            let _res: Value = self.delete(&current).await?;
+        }
 
         Ok(())
     }
 
     async fn remove_table(&self, id: &DbTableId) -> Result<()> {
         let index = self.get_index_name(id);
-        info!("deleting index '{index}'");
-        let _res: Value = self.delete(&index).await?;
+        let partitioning = self.table_partitioning(id).await?;
+
+        if partitioning.is_partitioned() {
+            /* Wildcard deletes may be disabled on the cluster
+             * (action.destructive_requires_name), so delete the
+             * partitions by name. */
+            let partitions = self.partitions(&index).await?;
+            if !partitions.is_empty() {
+                let names = partitions
+                    .into_iter()
+                    .map(|p| p.name)
+                    .collect::<Vec<_>>()
+                    .join(",");
+                info!("deleting partitions of '{index}': {names}");
+                let _res: Value = self.delete(&names).await?;
+            }
+            info!("deleting index template '{index}'");
+            let _res: AcknowledgedResponse =
+                self.delete(&format!("_index_template/{index}")).await?;
+        } else {
+            /* Delete the index behind the alias (or the index itself,
+             * for tables created before indices were versioned). */
+            let indices: HashMap<String, IndexAliases> =
+                self.get(&format!("{index}/_alias")).await?;
+            let names = indices.into_keys().collect::<Vec<_>>().join(",");
+            info!("deleting index '{index}': {names}");
+            let _res: Value = self.delete(&names).await?;
+        }
+
+        self.partitioning.write().remove(id);
         Ok(())
     }
 
+    async fn drop_partitions(&self, id: &DbTableId, before: DateTime<Utc>) -> Result<()> {
+        let partitioning = self.table_partitioning(id).await?;
+        if !partitioning.is_partitioned() {
+            return Ok(());
+        }
+
+        let (_, base) = self.partition_base(id).await?;
+        let partitions: HashMap<String, Value> = self
+            .get(&format!("{}/_alias", Partitioning::pattern(&base)))
+            .await?;
+        let expired = partitions
+            .into_keys()
+            .filter(|partition| {
+                partitioning
+                    .period_end(&base, partition)
+                    .is_some_and(|end| end <= before)
+            })
+            .collect::<Vec<_>>();
+        if !expired.is_empty() {
+            let names = expired.join(",");
+            let index = self.get_index_name(id);
+            info!("dropping expired partitions of '{index}': {names}");
+            let _res: Value = self.delete(&names).await?;
+        }
+        Ok(())
+    }
+
+    async fn recover(&self, tables: &[DbTableId]) -> Result<()> {
+        for id in tables {
+            let index = self.get_index_name(id);
+            match self.table_layout(id).await? {
+                (Partitioning::None, _) => {
+                    /* Versions of the index without alias were left
+                     * behind by a reindex that was interrupted before
+                     * or after switching the alias. */
+                    let versions: HashMap<String, IndexAliases> =
+                        self.get(&format!("{index}.v-*/_alias")).await?;
+                    let orphans = versions
+                        .into_iter()
+                        .filter(|(name, aliases)| {
+                            aliases.aliases.is_empty()
+                                && name
+                                    .strip_prefix(&index)
+                                    .and_then(|suffix| suffix.strip_prefix(".v-"))
+                                    .is_some_and(|version| version.parse::<u32>().is_ok())
+                        })
+                        .map(|(name, _)| name)
+                        .collect::<Vec<_>>();
+                    if !orphans.is_empty() {
+                        let names = orphans.join(",");
+                        info!("deleting indices left behind by an interrupted reindex: {names}");
+                        let _res: Value = self.delete(&names).await?;
+                    }
+                }
+                (_, version) => {
+                    /* The table's template is updated once the new
+                     * partitions are complete. Partitions of another
+                     * version were left behind by a reindex that was
+                     * interrupted before that, or before the old
+                     * partitions were replaced. */
+                    self.discard_partitions(&index, version + 1).await;
+                    self.switch_partitions(&index, version).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn refresh_table(&self, id: &DbTableId) -> Result<()> {
+        let index = self.get_index_name(id);
+        self.refresh_index(&index).await
+    }
+
     // https://www.elastic.co/guide/en/elasticsearch/reference/current/indices-exists.html
     async fn has_table(&self, id: &DbTableId) -> Result<bool> {
         let index = self.get_index_name(id);
@@ -528,8 +1164,10 @@ impl DatabaseTrait for Database {
         version: u64,
         value: T,
     ) -> Result<()> {
-        let index = self.get_index_name(table_id);
-        let value = ElasticValue::save(schema, serde_json::to_value(value)?)?;
+        let (partitioning, base) = self.partition_base(table_id).await?;
+        let value = serde_json::to_value(value)?;
+        let index = partitioning.partition_for(&base, &value);
+        let value = ElasticValue::save(schema, value)?;
         match self
             .post_with_query::<_, _, DocumentResponse>(
                 &format!("{index}/_doc/{doc_id}"),
@@ -557,40 +1195,50 @@ impl DatabaseTrait for Database {
     where
         T: Serialize + Send + Sync,
         I: IntoIterator<Item = (Self::Id, u64, T)> + Send + Sync,
+    {
+        let (partitioning, base) = self.partition_base(table_id).await?;
+        self.bulk_index(&base, partitioning, schema, updates).await
+    }
+
+    async fn bulk_delete<I>(&self, table_id: &DbTableId, deletes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (Self::Id, u64)> + Send + Sync,
     {
         let index = self.get_index_name(table_id);
+        let deletes = deletes.into_iter().collect::<Vec<_>>();
+
+        /* Deletes on a partitioned table must name the partition
+         * holding the document, so that they are versioned like
+         * other writes. Documents that are not found are already
+         * gone. */
+        let deletes = match self.table_partitioning(table_id).await?.is_partitioned() {
+            true => self.locate(&index, deletes).await?,
+            false => deletes
+                .into_iter()
+                .map(|(id, version)| (id, version, None))
+                .collect(),
+        };
+
         let mut req = Vec::new();
-        updates
-            .into_iter()
-            .try_for_each::<_, Result<()>>(|(id, version, value)| {
-                let dbvalue = ElasticValue::save(schema, serde_json::to_value(&value)?)?;
-                BulkOp::Index {
-                    index: None,
+        deletes
+            .iter()
+            .try_for_each::<_, Result<()>>(|(id, version, partition)| {
+                BulkOp::<Value>::Delete {
+                    index: partition.as_deref(),
                     id: id.0.as_str(),
-                    value: &dbvalue,
-                    version,
+                    version: *version,
                 }
                 .write(&mut req)?;
                 Ok(())
             })?;
         if !req.is_empty() {
             let res: BulkReponse = self.post_ndjson(&format!("{index}/_bulk"), req).await?;
-            if res.errors
-                && res
-                    .items
-                    .iter()
-                    .any(|h| h.status() != 200 && h.status() != 409)
-            {
-                return Err(
-                    match res
-                        .items
-                        .iter()
-                        .all(|h| h.status() != 200 && h.status() != 409)
-                    {
-                        true => Error::BulkUpdateComplete,
-                        false => Error::BulkUpdatePartial,
-                    },
-                );
+            let ok = |status| matches!(status, 200 | 404 | 409);
+            if res.errors && res.items.iter().any(|h| !ok(h.status())) {
+                return Err(match res.items.iter().all(|h| !ok(h.status())) {
+                    true => Error::BulkUpdateComplete,
+                    false => Error::BulkUpdatePartial,
+                });
             }
         }
         Ok(())
@@ -719,58 +1367,58 @@ impl DatabaseTrait for Database {
         self.query_objects_next(query_state).await
     }
 
+    /// The point in time is closed when the last page is read, or
+    /// when reading a page fails.
     async fn query_objects_next<'a, T: DeserializeOwned + Send + Sync>(
         &self,
         query_state: Self::QueryState<'a>,
     ) -> Result<(Vec<(Self::Id, u64, T)>, Option<Self::QueryState<'a>>)> {
-        let res: QueryResponse = self
+        let pit_id = query_state.pit_id.clone();
+        let res = self.query_page(query_state).await;
+        if !matches!(res, Ok((_, Some(_)))) {
+            if let Err(e) = self.close_pit(&pit_id).await {
+                log::warn!("failed to close point in time: {e}");
+            }
+        }
+        res
+    }
+
+    async fn count_objects(&self, table_id: &DbTableId) -> Result<u64> {
+        let index = self.get_index_name(table_id);
+        let res: CountResponse = self.get(&format!("{index}/_count")).await?;
+        Ok(res.count)
+    }
+
+    async fn query_objects_close(&self, query_state: Self::QueryState<'_>) -> Result<()> {
+        self.close_pit(&query_state.pit_id).await
+    }
+
+    async fn aggregate(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        filter: &Filter,
+        timestamp_field: &str,
+        aggregation: &Aggregation,
+    ) -> Result<Vec<AggregationBucket>> {
+        let index = self.get_index_name(table_id);
+        /* Todo: if the filter is inexact, documents that do not match
+         * it are included in the aggregation. */
+        let esfilter = ElasticFilter::new(schema, filter)?;
+        let res: AggregationResponse = self
             .post_with_query(
-                "_search",
-                &json!({ "version": true }),
-                &SearchRequest {
-                    query: query_state.esfilter.clone(),
-                    pit: Some(Pit {
-                        id: query_state.pit_id.clone(),
-                        keep_alive: format!("{}s", query_state.keep_alive.as_secs()),
-                    }),
-                    sort: Some(query_state.sort.clone()),
-                    search_after: query_state.last.clone(),
-                    size: Some(query_state.limit.map_or(10000, |n| n.min(10000))),
-                },
+                &format!("{index}/_search"),
+                &json!({}),
+                &aggregation::request(&esfilter, timestamp_field, aggregation),
             )
             .await?;
-        let mut last = None;
-        let docs: Vec<(ElasticId, u64, T)> = res
-            .hits
-            .hits
-            .into_iter()
-            .map(|hit| {
-                last = Some(hit.sort.ok_or(Error::MissingSortField)?);
-                let val = hit.source.load(query_state.schema)?;
-                Ok(
-                    match &query_state.filter.matches(query_state.schema, &val)? {
-                        true => Some((hit.id, hit.version, serde_json::from_value(val)?)),
-                        false => None,
-                    },
-                )
-            })
-            .filter_map(Result::transpose)
-            .collect::<Result<_>>()?;
-        let query_state = match docs.is_empty() {
-            true => None,
-            false => Some(QueryState {
-                pit_id: res.pit_id.ok_or(Error::MissingPitId)?,
-                limit: query_state.limit.map(|n| 0.max(n - docs.len())),
-                last,
-                ..query_state
-            }),
-        };
-        Ok((docs, query_state))
+        aggregation::buckets(aggregation, &res.aggregations)
     }
 }
 
 /// Elasticsearch options.
 #[derive(Serialize, Deserialize, Args, Debug, Clone)]
+#[group(id = "elastic")]
 pub struct DatabaseConfig {
     #[clap(env = "DB_ELASTIC_URL", long = "elastic-url")]
     pub url: String,
@@ -788,6 +1436,24 @@ pub struct DatabaseConfig {
     /// multiple instances on the same elasticsearch cluster.
     #[clap(env = "DB_ELASTIC_INDEX_PREFIX", long = "elastic-index-prefix")]
     pub index_prefix: String,
+    /// Time partitioning of newly created timestamped tables.
+    /// Existing tables keep the partitioning they were created with.
+    #[clap(
+        env = "DB_ELASTIC_PARTITIONING",
+        long = "elastic-partitioning",
+        value_enum,
+        default_value = "none"
+    )]
+    #[serde(default)]
+    pub partitioning: Partitioning,
+}
+
+/// A partition of a partitioned table.
+struct Partition {
+    name: String,
+    version: u32,
+    /// Whether the partition is behind the table's alias.
+    aliased: bool,
 }
 
 #[derive(Debug, Clone)]
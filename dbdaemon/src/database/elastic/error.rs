@@ -9,6 +9,8 @@ use thiserror::Error;
 
 use dbschema_elastic::{ConversionError, FilterError, MappingError};
 
+use crate::database::backend::DatabaseError;
+
 use super::responses::ErrorResponse;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -47,16 +49,24 @@ pub enum Error {
     MissingPitId,
     #[error("Missing sort field in query response")]
     MissingSortField,
+    #[error("Invalid aggregation in query response")]
+    InvalidAggregation,
     #[error("Query did not return any hits")]
     ZeroHits,
     #[error("Query returned multiple hits (expected one)")]
     ManyHits,
+    #[error("failed to transform document: {0}")]
+    Transform(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Index '{0}' already exists")]
+    IndexExists(String),
     #[error("Timeout")]
     Timeout,
     #[error("Not implemented!")]
     Unimplemented,
 }
 
+impl DatabaseError for Error {}
+
 #[derive(Error, Debug)]
 pub enum InitializationError {
     #[error("Failed to read config file '{0}': {1}")]
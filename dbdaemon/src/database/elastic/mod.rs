@@ -2,9 +2,11 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
+mod aggregation;
 mod backend;
 mod bulk_op;
 mod error;
+mod partitioning;
 //mod refresh;
 mod requests;
 mod responses;
@@ -16,4 +18,5 @@ pub use dbschema_elastic::{
     ConversionError, ElasticFilter, ElasticMapping, ElasticValue, FilterError, MappingError,
 };
 pub use error::{Error, InitializationError, Result};
+pub use partitioning::Partitioning;
 pub use utils::sanitize_index_id;
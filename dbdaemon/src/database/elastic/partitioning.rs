@@ -0,0 +1,197 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use chrono::{DateTime, Days, Months, NaiveDate, Utc, Weekday};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::database::TIMESTAMP_FIELD;
+
+/// Time partitioning of the indices of timestamped tables. A
+/// partitioned table is stored in one index per period, created on
+/// demand from an index template. Reads go through an alias with the
+/// name of the unpartitioned index.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Partitioning {
+    #[default]
+    None,
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl Partitioning {
+    pub fn is_partitioned(self) -> bool {
+        !matches!(self, Self::None)
+    }
+
+    /// The name from which the partitions of a version of an index
+    /// are named. Partitions of tables that were never reindexed are
+    /// named from the index itself.
+    pub fn base(index: &str, version: u32) -> String {
+        match version {
+            0 => index.to_string(),
+            _ => format!("{index}.v-{version}"),
+        }
+    }
+
+    /// The version of a partition of an index, or `None` if the name
+    /// is not that of a partition of (any version of) the index.
+    pub fn version(index: &str, partition: &str) -> Option<u32> {
+        let suffix = partition.strip_prefix(index)?;
+        if suffix.starts_with(".p-") {
+            return Some(0);
+        }
+        let (version, _) = suffix.strip_prefix(".v-")?.split_once(".p-")?;
+        version.parse().ok().filter(|version| *version > 0)
+    }
+
+    /// The index pattern matching all partitions of an index.
+    pub fn pattern(index: &str) -> String {
+        format!("{index}.p-*")
+    }
+
+    /// The name of the partition holding documents with the given
+    /// timestamp.
+    pub fn partition(self, index: &str, timestamp: DateTime<Utc>) -> String {
+        let suffix = match self {
+            Self::None => return index.to_string(),
+            Self::Daily => timestamp.format("%Y.%m.%d"),
+            Self::Weekly => timestamp.format("%G.w%V"),
+            Self::Monthly => timestamp.format("%Y.%m"),
+        };
+        format!("{index}.p-{suffix}")
+    }
+
+    /// The name of the partition for a document. Documents without a
+    /// (valid) timestamp go into the current partition.
+    pub fn partition_for(self, index: &str, doc: &Value) -> String {
+        let timestamp = doc
+            .get(TIMESTAMP_FIELD)
+            .and_then(Value::as_str)
+            .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+            .map_or_else(Utc::now, |t| t.with_timezone(&Utc));
+        self.partition(index, timestamp)
+    }
+
+    /// The end of the period covered by a partition, or `None` if the
+    /// name is not that of a partition of the index.
+    pub fn period_end(self, index: &str, partition: &str) -> Option<DateTime<Utc>> {
+        let suffix = partition.strip_prefix(index)?.strip_prefix(".p-")?;
+        let end = match self {
+            Self::None => return None,
+            Self::Daily => NaiveDate::parse_from_str(suffix, "%Y.%m.%d")
+                .ok()?
+                .checked_add_days(Days::new(1))?,
+            Self::Weekly => {
+                let (year, week) = suffix.split_once(".w")?;
+                NaiveDate::from_isoywd_opt(year.parse().ok()?, week.parse().ok()?, Weekday::Mon)?
+                    .checked_add_days(Days::new(7))?
+            }
+            Self::Monthly => NaiveDate::parse_from_str(&format!("{suffix}.01"), "%Y.%m.%d")
+                .ok()?
+                .checked_add_months(Months::new(1))?,
+        };
+        Some(end.and_hms_opt(0, 0, 0)?.and_utc())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{DateTime, NaiveDate, Utc};
+
+    use super::Partitioning;
+
+    fn date(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(year, month, day)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    #[test]
+    fn period_end_daily() {
+        let p = Partitioning::Daily;
+        assert_eq!(
+            p.period_end("idx", "idx.p-2024.02.28"),
+            Some(date(2024, 2, 29))
+        );
+        assert_eq!(
+            p.period_end("idx", "idx.p-2024.12.31"),
+            Some(date(2025, 1, 1))
+        );
+    }
+
+    #[test]
+    fn period_end_weekly() {
+        let p = Partitioning::Weekly;
+        assert_eq!(
+            p.period_end("idx", "idx.p-2024.w01"),
+            Some(date(2024, 1, 8))
+        );
+        /* ISO weeks may start in the previous year or end in the
+         * next one. */
+        assert_eq!(
+            p.period_end("idx", "idx.p-2025.w01"),
+            Some(date(2025, 1, 6))
+        );
+        assert_eq!(
+            p.period_end("idx", "idx.p-2020.w53"),
+            Some(date(2021, 1, 4))
+        );
+        assert_eq!(p.period_end("idx", "idx.p-2021.w53"), None);
+        assert_eq!(p.period_end("idx", "idx.p-2021.w00"), None);
+    }
+
+    #[test]
+    fn period_end_monthly() {
+        let p = Partitioning::Monthly;
+        assert_eq!(p.period_end("idx", "idx.p-2024.01"), Some(date(2024, 2, 1)));
+        assert_eq!(p.period_end("idx", "idx.p-2024.12"), Some(date(2025, 1, 1)));
+        assert_eq!(p.period_end("idx", "idx.p-2024.13"), None);
+    }
+
+    #[test]
+    fn period_end_of_partition() {
+        /* 2021-01-03 is a Sunday in the last week of 2020. */
+        let timestamp = date(2021, 1, 3);
+        for (p, end) in [
+            (Partitioning::Daily, date(2021, 1, 4)),
+            (Partitioning::Weekly, date(2021, 1, 4)),
+            (Partitioning::Monthly, date(2021, 2, 1)),
+        ] {
+            let partition = p.partition("idx", timestamp);
+            assert_eq!(p.period_end("idx", &partition), Some(end));
+        }
+    }
+
+    #[test]
+    fn period_end_non_matching() {
+        assert_eq!(
+            Partitioning::Daily.period_end("idx", "other.p-2024.01.01"),
+            None
+        );
+        assert_eq!(Partitioning::Daily.period_end("idx", "idx.p-2024.01"), None);
+        assert_eq!(
+            Partitioning::Monthly.period_end("idx", "idx.p-2024.w01"),
+            None
+        );
+        assert_eq!(
+            Partitioning::None.period_end("idx", "idx.p-2024.01.01"),
+            None
+        );
+        /* Partitions of a reindexed version are named from its base. */
+        assert_eq!(
+            Partitioning::Daily.period_end("idx", "idx.v-2.p-2024.01.01"),
+            None
+        );
+        assert_eq!(
+            Partitioning::Daily.period_end(&Partitioning::base("idx", 2), "idx.v-2.p-2024.01.01"),
+            Some(date(2024, 1, 2))
+        );
+    }
+}
@@ -7,11 +7,11 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use super::{ElasticFilter, ElasticMapping};
+use super::{ElasticFilter, ElasticMapping, Partitioning};
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct CreateIndex {
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "aliases", skip_serializing_if = "Option::is_none")]
     pub aliasses: Option<HashMap<String, Value>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mappings: Option<ElasticMapping>,
@@ -29,6 +29,25 @@ pub struct IndexSettings {
     pub nested_fields_limit: Option<u64>,
 }
 
+/// A composable index template.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexTemplate {
+    pub index_patterns: Vec<String>,
+    pub template: CreateIndex,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u64>,
+    #[serde(rename = "_meta", skip_serializing_if = "Option::is_none")]
+    pub meta: Option<IndexTemplateMeta>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexTemplateMeta {
+    pub partitioning: Partitioning,
+    /// The version of the partitions, increased by every reindex.
+    #[serde(default)]
+    pub version: u32,
+}
+
 #[derive(Serialize, Debug)]
 pub struct SearchRequest {
     pub query: ElasticFilter,
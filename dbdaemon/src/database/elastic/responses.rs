@@ -2,6 +2,7 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
+use std::collections::HashMap;
 use std::fmt::Display;
 
 use chrono::{DateTime, Utc};
@@ -11,6 +12,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use super::backend::ElasticId;
+use super::requests::IndexTemplateMeta;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorResponse {
@@ -67,6 +69,37 @@ pub struct IndexResponse {
     pub shards_acknowledged: bool,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AcknowledgedResponse {
+    pub acknowledged: bool,
+}
+
+/// The aliases of an index, as returned per index by `_alias`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexAliases {
+    #[serde(default)]
+    pub aliases: HashMap<String, Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexTemplatesResponse {
+    pub index_templates: Vec<NamedIndexTemplate>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NamedIndexTemplate {
+    pub name: String,
+    pub index_template: IndexTemplateInfo,
+}
+
+/* Only the metadata is needed; mappings and settings are returned in
+ * a different format than they are set in. */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexTemplateInfo {
+    #[serde(rename = "_meta")]
+    pub meta: Option<IndexTemplateMeta>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RefreshResponse {
     #[serde(rename = "_shards")]
@@ -93,6 +126,32 @@ pub struct DocumentResponse {
 pub enum DocumentResult {
     Created,
     Updated,
+    Deleted,
+    NotFound,
+}
+
+/// The indices holding documents, from a search without source.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LocateResponse {
+    pub hits: LocateHits,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LocateHits {
+    pub hits: Vec<DocumentLocation>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentLocation {
+    #[serde(rename = "_id")]
+    pub id: ElasticId,
+    #[serde(rename = "_index")]
+    pub index: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CountResponse {
+    pub count: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -105,6 +164,11 @@ pub struct QueryResponse {
     pub pit_id: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AggregationResponse {
+    pub aggregations: Value,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UpdateByQueryResponse {
     pub took: u64,
@@ -180,8 +244,11 @@ pub struct BulkReponse {
 #[serde(rename_all = "snake_case")]
 pub enum BulkItem {
     Index(BulkItemResult),
+    Delete(BulkItemResult),
 }
 
+/* Failed items (e.g. version conflicts) only carry an id, index,
+ * status and error. */
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BulkItemResult {
     #[serde(rename = "_id")]
@@ -189,21 +256,22 @@ pub struct BulkItemResult {
     #[serde(rename = "_index")]
     index: String,
     #[serde(rename = "_primary_term")]
-    primary_term: u64,
+    primary_term: Option<u64>,
     #[serde(rename = "_seq_no")]
-    seq_no: u64,
+    seq_no: Option<u64>,
     #[serde(rename = "_shards")]
-    shards: Shards,
+    shards: Option<Shards>,
     #[serde(rename = "_version")]
-    version: u64,
-    result: DocumentResult,
+    version: Option<u64>,
+    result: Option<DocumentResult>,
     status: u64,
+    error: Option<Value>,
 }
 
 impl BulkItem {
     pub fn status(&self) -> u64 {
         match self {
-            BulkItem::Index(res) => res.status,
+            BulkItem::Index(res) | BulkItem::Delete(res) => res.status,
         }
     }
 }
@@ -0,0 +1,621 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::time::Duration;
+
+use clap::Args;
+use log::info;
+use mysql_async::{prelude::*, Opts, OptsBuilder, Pool, Row, TxOpts, Value as SqlValue};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use dbdaemon_api::{Aggregation, AggregationBucket};
+use dbschema::{DbSchema, DbTable, DbTableId, Filter};
+use dbschema_elastic::{ElasticFilter, ElasticValue};
+
+use crate::database::backend::{Database as DatabaseTrait, Transform};
+use crate::database::elastic::ElasticId;
+
+use super::error::{Error, InitializationError, Result};
+use super::fields::Fields;
+use super::query::{condition, Cursor, Sort};
+
+/// Relational backend. Every table is stored as an SQL table holding
+/// the documents (in elasticsearch form) as JSON, keyed by document
+/// id. Writes use external versioning: a document is only replaced
+/// by a higher version. Deleted documents are kept as tombstones, so
+/// that late writes with an older version are still ignored.
+#[derive(Debug)]
+pub struct Database {
+    config: DatabaseConfig,
+    pool: Pool,
+}
+
+/// Maximum number of rows returned per page.
+const PAGE_SIZE: usize = 10000;
+
+impl Database {
+    pub fn new(config: DatabaseConfig) -> Result<Database> {
+        let mut opts = OptsBuilder::from_opts(
+            Opts::from_url(&config.url).map_err(InitializationError::InvalidUrl)?,
+        );
+        if let Some(username) = &config.username {
+            opts = opts.user(Some(username));
+        }
+        if let Some(password) = &config.password {
+            opts = opts.pass(Some(password));
+        }
+        Ok(Database {
+            pool: Pool::new(opts),
+            config,
+        })
+    }
+
+    /// Calculate the SQL table name.
+    pub fn get_table_name(&self, table_id: &DbTableId) -> Result<String> {
+        let prefix = &self.config.table_prefix;
+        let name = format!("{prefix}_{table_id}");
+        match name.len() > 64 || name.contains('`') {
+            true => Err(Error::InvalidTableName(name)),
+            false => Ok(name),
+        }
+    }
+
+    fn quoted_table_name(&self, table_id: &DbTableId) -> Result<String> {
+        Ok(format!("`{}`", self.get_table_name(table_id)?))
+    }
+
+    /// Write documents, or tombstones for deleted documents, given as
+    /// (id, version, doc, deleted) parameters. Older versions are
+    /// silently ignored, like version conflicts in elasticsearch.
+    /// Assignments are evaluated in order, so `version` still refers
+    /// to the old version when updating `doc` and `deleted`.
+    async fn write(&self, table_id: &DbTableId, params: Vec<Vec<SqlValue>>) -> Result<()> {
+        if params.is_empty() {
+            return Ok(());
+        }
+        let table = self.quoted_table_name(table_id)?;
+        let mut tx = self.pool.start_transaction(TxOpts::default()).await?;
+        tx.exec_batch(
+            format!(
+                "INSERT INTO {table} (id, version, doc, deleted) VALUES (?, ?, ?, ?) \
+                 ON DUPLICATE KEY UPDATE \
+                 doc = IF(VALUES(version) > version, VALUES(doc), doc), \
+                 deleted = IF(VALUES(version) > version, VALUES(deleted), deleted), \
+                 version = GREATEST(version, VALUES(version))"
+            ),
+            params,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn copy(
+        &self,
+        old: &DbTableId,
+        new: &DbTableId,
+        old_schema: &DbSchema,
+        new_schema: &DbSchema,
+        transform: Option<&Transform<'_>>,
+    ) -> Result<()> {
+        let filter = Filter::All(Vec::new());
+        let (mut docs, mut query_state) = self
+            .query_objects_first::<Value>(
+                old,
+                old_schema,
+                &filter,
+                &Value::Null,
+                Duration::from_secs(300),
+                None,
+            )
+            .await?;
+
+        loop {
+            if let Some(transform) = transform {
+                docs = docs
+                    .into_iter()
+                    .map(|(id, version, doc)| {
+                        Ok((id, version, transform(doc).map_err(Error::Transform)?))
+                    })
+                    .collect::<Result<_>>()?;
+            }
+            self.bulk_update(new, new_schema, docs).await?;
+            match query_state {
+                Some(state) => {
+                    (docs, query_state) = self.query_objects_next::<Value>(state).await?;
+                }
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl DatabaseTrait for Database {
+    type Error = Error;
+    type Id = ElasticId;
+
+    fn whoami(&self) -> String {
+        String::from("MariaDB")
+    }
+
+    /* Setup and configuration. */
+
+    async fn wait_for_database(&self) -> Result<()> {
+        info!("Waiting for mariadb database...");
+        loop {
+            tokio::select! {
+                r = self.verify_database() => match r {
+                    Ok(()) => {
+                        info!("MariaDB database is up and running!");
+                        return Ok(())
+                    },
+                    Err(e) => {
+                        info!("Error contacting mariadb: {}", e);
+                        tokio::time::sleep(
+                            std::time::Duration::from_secs(5)).await
+                    },
+                },
+                _ = tokio::time::sleep(
+                    std::time::Duration::from_secs(300)) => {
+                    info!("Timeout (5min) waiting for mariadb :-(");
+                    return Err(Error::Timeout);
+                }
+            }
+        }
+    }
+
+    async fn verify_database(&self) -> Result<()> {
+        self.pool.get_conn().await?.ping().await?;
+        Ok(())
+    }
+
+    /* Schema manipulation. */
+
+    async fn create_table(&self, id: &DbTableId, definition: &DbTable) -> Result<()> {
+        let table = self.quoted_table_name(id)?;
+        info!("creating table: {table}");
+        let _ = Fields::new(&definition.schema())?;
+        self.pool
+            .get_conn()
+            .await?
+            .query_drop(format!(
+                "CREATE TABLE IF NOT EXISTS {table} (\
+                 id VARCHAR(64) CHARACTER SET ascii COLLATE ascii_bin NOT NULL PRIMARY KEY, \
+                 version BIGINT UNSIGNED NOT NULL, \
+                 doc LONGTEXT CHARACTER SET utf8mb4 COLLATE utf8mb4_bin NOT NULL \
+                 CHECK (JSON_VALID(doc)), \
+                 deleted BOOLEAN NOT NULL DEFAULT FALSE)"
+            ))
+            .await?;
+        Ok(())
+    }
+
+    /// Documents are stored as JSON, so compatible schema updates do
+    /// not require any changes to the table.
+    async fn update_table(&self, id: &DbTableId, definition: &DbTable) -> Result<()> {
+        let table = self.quoted_table_name(id)?;
+        info!("updating definition for table {table}");
+        let _ = Fields::new(&definition.schema())?;
+        Ok(())
+    }
+
+    async fn reindex_table(
+        &self,
+        id: &DbTableId,
+        old_definition: &DbTable,
+        new_definition: &DbTable,
+        transform: Option<&Transform<'_>>,
+    ) -> Result<()> {
+        let reindexed = DbTableId::from_string(format!("{id}-reindex"));
+        let replaced = DbTableId::from_string(format!("{id}-replaced"));
+        info!("reindexing table '{id}' to '{reindexed}'");
+
+        /* Clean up after an interrupted reindex. */
+        self.remove_table(&reindexed).await?;
+        self.remove_table(&replaced).await?;
+
+        self.create_table(&reindexed, new_definition).await?;
+        self.copy(
+            id,
+            &reindexed,
+            &old_definition.schema(),
+            &new_definition.schema(),
+            transform,
+        )
+        .await?;
+
+        /* Swap the tables atomically. */
+        self.pool
+            .get_conn()
+            .await?
+            .query_drop(format!(
+                "RENAME TABLE {table} TO {replaced}, {reindexed} TO {table}",
+                table = self.quoted_table_name(id)?,
+                replaced = self.quoted_table_name(&replaced)?,
+                reindexed = self.quoted_table_name(&reindexed)?,
+            ))
+            .await?;
+        self.remove_table(&replaced).await?;
+
+        Ok(())
+    }
+
+    async fn remove_table(&self, id: &DbTableId) -> Result<()> {
+        let table = self.quoted_table_name(id)?;
+        info!("deleting table {table}");
+        self.pool
+            .get_conn()
+            .await?
+            .query_drop(format!("DROP TABLE IF EXISTS {table}"))
+            .await?;
+        Ok(())
+    }
+
+    /// Committed writes are immediately visible.
+    async fn refresh_table(&self, _id: &DbTableId) -> Result<()> {
+        Ok(())
+    }
+
+    async fn has_table(&self, id: &DbTableId) -> Result<bool> {
+        let table = self.get_table_name(id)?;
+        info!("checking if table '{table}' exists");
+        let count: Option<u64> = self
+            .pool
+            .get_conn()
+            .await?
+            .exec_first(
+                "SELECT COUNT(*) FROM information_schema.tables \
+                 WHERE table_schema = DATABASE() AND table_name = ?",
+                (table,),
+            )
+            .await?;
+        Ok(count.unwrap_or(0) > 0)
+    }
+
+    /* Data manipulation. */
+
+    async fn update_object<T: Serialize + Send + Sync>(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        doc_id: &Self::Id,
+        version: u64,
+        value: T,
+    ) -> Result<()> {
+        self.bulk_update(table_id, schema, [(doc_id.clone(), version, value)])
+            .await
+    }
+
+    async fn bulk_update<T, I>(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        updates: I,
+    ) -> Result<()>
+    where
+        T: Serialize + Send + Sync,
+        I: IntoIterator<Item = (Self::Id, u64, T)> + Send + Sync,
+    {
+        let fields = Fields::new(schema)?;
+        let params = updates
+            .into_iter()
+            .map(|(id, version, value)| {
+                let mut doc = serde_json::to_value(ElasticValue::save(
+                    schema,
+                    serde_json::to_value(&value)?,
+                )?)?;
+                fields.normalize(&mut doc);
+                Ok(vec![
+                    SqlValue::from(id.as_str()),
+                    SqlValue::from(version),
+                    SqlValue::from(serde_json::to_string(&doc)?),
+                    SqlValue::from(false),
+                ])
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.write(table_id, params).await
+    }
+
+    async fn bulk_delete<I>(&self, table_id: &DbTableId, deletes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (Self::Id, u64)> + Send + Sync,
+    {
+        let params = deletes
+            .into_iter()
+            .map(|(id, version)| {
+                vec![
+                    SqlValue::from(id.as_str()),
+                    SqlValue::from(version),
+                    SqlValue::from("null"),
+                    SqlValue::from(true),
+                ]
+            })
+            .collect::<Vec<_>>();
+        self.write(table_id, params).await
+    }
+
+    async fn count_objects(&self, table_id: &DbTableId) -> Result<u64> {
+        let table = self.quoted_table_name(table_id)?;
+        let count: Option<u64> = self
+            .pool
+            .get_conn()
+            .await?
+            .query_first(format!("SELECT COUNT(*) FROM {table} WHERE NOT deleted"))
+            .await?;
+        Ok(count.unwrap_or(0))
+    }
+
+    async fn query_objects<T: DeserializeOwned + Send + Sync>(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        filter: &Filter,
+        sort: &Value,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Self::Id, u64, T)>> {
+        let (mut result, mut state) = self
+            .query_objects_first(
+                table_id,
+                schema,
+                filter,
+                sort,
+                Duration::from_secs(60),
+                limit,
+            )
+            .await?;
+        while let Some(next) = state.take() {
+            let (rows, next) = self.query_objects_next(next).await?;
+            result.extend(rows);
+            state = next;
+        }
+        Ok(result)
+    }
+
+    type QueryState<'a> = QueryState<'a>;
+
+    /// Pages are retrieved using keyset pagination on the sort
+    /// fields and the document id. There is no equivalent to a
+    /// point-in-time, so `keep_alive` is ignored and updates between
+    /// pages may be visible.
+    async fn query_objects_first<'a, T: DeserializeOwned + Send + Sync>(
+        &self,
+        table_id: &DbTableId,
+        schema: &'a DbSchema,
+        filter: &'a Filter,
+        sort: &'a Value,
+        _keep_alive: Duration,
+        limit: Option<usize>,
+    ) -> Result<(Vec<(Self::Id, u64, T)>, Option<Self::QueryState<'a>>)> {
+        let fields = Fields::new(schema)?;
+        let esfilter = serde_json::to_value(ElasticFilter::new(schema, filter)?)?;
+        let mut params = Vec::new();
+        let query_state = QueryState {
+            table: self.quoted_table_name(table_id)?,
+            schema,
+            filter,
+            condition: condition(&fields, &esfilter, &mut params),
+            params,
+            sort: Sort::new(&fields, sort)?,
+            fields,
+            limit,
+            last: None,
+        };
+        self.query_objects_next(query_state).await
+    }
+
+    async fn query_objects_next<'a, T: DeserializeOwned + Send + Sync>(
+        &self,
+        query_state: Self::QueryState<'a>,
+    ) -> Result<(Vec<(Self::Id, u64, T)>, Option<Self::QueryState<'a>>)> {
+        let size = query_state.limit.map_or(PAGE_SIZE, |n| n.min(PAGE_SIZE));
+        if size == 0 {
+            return Ok((Vec::new(), None));
+        }
+
+        let mut params = query_state.params.clone();
+        let mut sql = format!(
+            "SELECT id, version, doc{} FROM {} WHERE NOT deleted AND ({})",
+            query_state.sort.columns(),
+            query_state.table,
+            query_state.condition
+        );
+        if let Some(cursor) = &query_state.last {
+            sql.push_str(" AND ");
+            sql.push_str(&query_state.sort.after(cursor, &mut params));
+        }
+        sql.push_str(&format!(
+            " ORDER BY {} LIMIT {size}",
+            query_state.sort.order_by()
+        ));
+
+        let rows: Vec<Row> = self.pool.get_conn().await?.exec(sql, params).await?;
+        let fetched = rows.len();
+
+        /* Filter rows because the SQL condition can be broader than
+         * the filter. */
+        let mut last = None;
+        let docs: Vec<(ElasticId, u64, T)> = rows
+            .into_iter()
+            .map(|mut row| {
+                let id: String = column(&mut row, 0)?;
+                let version: u64 = column(&mut row, 1)?;
+                let doc: String = column(&mut row, 2)?;
+                let keys = (0..query_state.sort.len())
+                    .map(|i| column(&mut row, 3 + i))
+                    .collect::<Result<_>>()?;
+                last = Some(Cursor {
+                    keys,
+                    id: id.clone(),
+                });
+
+                let mut doc: Value = serde_json::from_str(&doc)?;
+                query_state.fields.denormalize(&mut doc);
+                let val = serde_json::from_value::<ElasticValue>(doc)?.load(query_state.schema)?;
+                Ok(
+                    match query_state.filter.matches(query_state.schema, &val)? {
+                        true => Some((
+                            ElasticId::from_string(id),
+                            version,
+                            serde_json::from_value(val)?,
+                        )),
+                        false => None,
+                    },
+                )
+            })
+            .filter_map(Result::transpose)
+            .collect::<Result<_>>()?;
+
+        let query_state = match fetched < size {
+            true => None,
+            false => Some(QueryState {
+                limit: query_state.limit.map(|n| n.saturating_sub(docs.len())),
+                last,
+                ..query_state
+            }),
+        };
+        Ok((docs, query_state))
+    }
+
+    async fn aggregate(
+        &self,
+        _table_id: &DbTableId,
+        _schema: &DbSchema,
+        _filter: &Filter,
+        _timestamp_field: &str,
+        _aggregation: &Aggregation,
+    ) -> Result<Vec<AggregationBucket>> {
+        Err(Error::Unsupported("aggregations"))
+    }
+}
+
+fn column<T: FromValue>(row: &mut Row, index: usize) -> Result<T> {
+    row.take_opt(index)
+        .ok_or(Error::MissingColumn(index))?
+        .map_err(|e| Error::Column(index, e))
+}
+
+/// MariaDB options.
+#[derive(Serialize, Deserialize, Args, Debug, Clone)]
+#[group(id = "mariadb")]
+pub struct DatabaseConfig {
+    /// Connection url, eg. "mysql://localhost:3306/dbdaemon".
+    #[clap(env = "DB_MARIADB_URL", long = "mariadb-url")]
+    pub url: String,
+    #[clap(env = "DB_MARIADB_USERNAME", long = "mariadb-username")]
+    pub username: Option<String>,
+    #[clap(env = "DB_MARIADB_PASSWORD", long = "mariadb-password")]
+    pub password: Option<String>,
+    /// Prefix to use for all table names, to allow running multiple
+    /// instances on the same database.
+    #[clap(env = "DB_MARIADB_TABLE_PREFIX", long = "mariadb-table-prefix")]
+    pub table_prefix: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryState<'a> {
+    table: String,
+    schema: &'a DbSchema,
+    filter: &'a Filter,
+    fields: Fields,
+    condition: String,
+    params: Vec<SqlValue>,
+    sort: Sort,
+    limit: Option<usize>,
+    last: Option<Cursor>,
+}
+
+/* These tests need a MariaDB server; they are run in CI with
+ * `--include-ignored`. */
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use dbschema::{
+        DbTableId, Filter, HasSchema, HasTableDef, Identified, ObjectId, SingleVersioned,
+        SingleVersionedValue,
+    };
+    use serde_json::{json, Value};
+
+    use crate::database::{backend::Database as _, elastic::ElasticId};
+
+    use super::{Database, DatabaseConfig};
+
+    fn database() -> Database {
+        Database::new(DatabaseConfig {
+            url: std::env::var("DB_MARIADB_URL").expect("DB_MARIADB_URL is not set"),
+            username: None,
+            password: None,
+            table_prefix: String::from("test"),
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MariaDB server (DB_MARIADB_URL)"]
+    async fn deleted_documents_are_not_recreated() {
+        type Document = Identified<SingleVersioned<Object>>;
+        #[derive(HasSchema, Debug)]
+        #[allow(unused)]
+        struct Object {
+            field: String,
+        }
+
+        let db = database();
+        let table_id = DbTableId::new("tombstones");
+        let table_def = Document::table_def();
+        let schema = table_def.schema();
+        db.remove_table(&table_id).await.unwrap();
+        db.create_table(&table_id, &table_def).await.unwrap();
+
+        let id = ElasticId::new();
+        let doc = |field: &str| {
+            Identified::new_id(
+                ObjectId::new(),
+                SingleVersionedValue::new(Utc::now(), json!({ "field": field })),
+            )
+        };
+        let stored = || async {
+            db.query_objects::<Identified<SingleVersionedValue>>(
+                &table_id,
+                &schema,
+                &Filter::All(Vec::new()),
+                &Value::Null,
+                None,
+            )
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, version, doc)| (version, doc.value.value))
+            .collect::<Vec<_>>()
+        };
+
+        db.update_object(&table_id, &schema, &id, 1, doc("a"))
+            .await
+            .unwrap();
+        assert_eq!(stored().await, vec![(1, json!({"field": "a"}))]);
+
+        /* A stale write does not resurrect a deleted document. */
+        db.bulk_delete(&table_id, [(id.clone(), 2)]).await.unwrap();
+        db.update_object(&table_id, &schema, &id, 2, doc("b"))
+            .await
+            .unwrap();
+        assert_eq!(stored().await, Vec::new());
+        assert_eq!(db.count_objects(&table_id).await.unwrap(), 0);
+
+        /* A later write does. */
+        db.update_object(&table_id, &schema, &id, 3, doc("c"))
+            .await
+            .unwrap();
+        assert_eq!(stored().await, vec![(3, json!({"field": "c"}))]);
+        assert_eq!(db.count_objects(&table_id).await.unwrap(), 1);
+
+        /* Deleting with an older version has no effect. */
+        db.bulk_delete(&table_id, [(id.clone(), 3)]).await.unwrap();
+        assert_eq!(stored().await, vec![(3, json!({"field": "c"}))]);
+
+        db.remove_table(&table_id).await.unwrap();
+    }
+}
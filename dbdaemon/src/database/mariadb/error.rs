@@ -0,0 +1,53 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use thiserror::Error;
+
+use dbschema_elastic::{ConversionError, FilterError, MappingError};
+
+use crate::database::backend::DatabaseError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unable connect to the mariadb database: {0}")]
+    Initialization(#[from] InitializationError),
+    #[error("mapping error: {0}")]
+    Mapping(#[from] MappingError),
+    #[error("filter error: {0}")]
+    Filter(#[from] FilterError),
+    #[error("conversion error: {0}")]
+    Conversion(#[from] ConversionError),
+    #[error("schema error: {0}")]
+    Schema(#[from] dbschema::Error),
+    #[error("Json (de)serialization error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("MariaDB error: {0}")]
+    MariaDb(#[from] mysql_async::Error),
+    #[error("Unexpected value in column {0}: {1}")]
+    Column(usize, mysql_async::FromValueError),
+    #[error("Missing column {0} in query result")]
+    MissingColumn(usize),
+    #[error("Invalid table name: {0}")]
+    InvalidTableName(String),
+    #[error("Unsupported field name: {0}")]
+    InvalidField(String),
+    #[error("Invalid sort specification: {0}")]
+    InvalidSort(serde_json::Value),
+    #[error("failed to transform document: {0}")]
+    Transform(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Timeout")]
+    Timeout,
+    #[error("{0} are not supported by the mariadb backend")]
+    Unsupported(&'static str),
+}
+
+impl DatabaseError for Error {}
+
+#[derive(Error, Debug)]
+pub enum InitializationError {
+    #[error("Invalid mariadb url: {0}")]
+    InvalidUrl(mysql_async::UrlError),
+}
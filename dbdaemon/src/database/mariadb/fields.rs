@@ -0,0 +1,194 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use mysql_async::Value as SqlValue;
+use serde_json::Value;
+
+use dbschema::DbSchema;
+use dbschema_elastic::ElasticMapping;
+
+use super::error::{Error, Result};
+
+/// Field types of a table, as derived from the elasticsearch mapping
+/// of its schema. Documents are stored in the same form as in
+/// elasticsearch, so that filters and sort specifications translate
+/// directly to paths in the stored JSON.
+#[derive(Debug, Clone)]
+pub(super) struct Fields(HashMap<String, FieldType>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum FieldType {
+    String,
+    Number,
+    Boolean,
+    Date,
+}
+
+/// Canonical timestamp format: fixed-width, in UTC, so that string
+/// comparison and ordering match chronological order.
+const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.9fZ";
+
+impl Fields {
+    pub fn new(schema: &DbSchema) -> Result<Self> {
+        let mapping = serde_json::to_value(ElasticMapping::new(schema)?)?;
+        let mut fields = HashMap::new();
+        Self::collect(&mut fields, None, &mapping);
+        Ok(Self(fields))
+    }
+
+    #[cfg(test)]
+    pub fn from_types<const N: usize>(types: [(&str, FieldType); N]) -> Self {
+        Self(
+            types
+                .into_iter()
+                .map(|(field, typ)| (field.to_string(), typ))
+                .collect(),
+        )
+    }
+
+    fn collect(fields: &mut HashMap<String, FieldType>, prefix: Option<&str>, mapping: &Value) {
+        let Some(props) = mapping.get("properties").and_then(Value::as_object) else {
+            return;
+        };
+        for (name, mapping) in props {
+            let path = match prefix {
+                Some(prefix) => format!("{prefix}.{name}"),
+                None => name.to_string(),
+            };
+            if let Some(typ) = mapping.get("type").and_then(Value::as_str) {
+                fields.insert(path.clone(), FieldType::from_elastic(typ));
+            }
+            Self::collect(fields, Some(&path), mapping);
+        }
+    }
+
+    /// The type of a field, given as an elasticsearch field name.
+    pub fn get(&self, field: &str) -> FieldType {
+        let field = field.strip_suffix(".keyword").unwrap_or(field);
+        self.0.get(field).copied().unwrap_or(FieldType::String)
+    }
+
+    /// SQL expression for the (scalar) value of a field. Evaluates to
+    /// NULL if the field is missing or the path crosses an array.
+    pub fn expr(&self, field: &str) -> Result<String> {
+        let path = json_path(field)?;
+        Ok(match self.get(field) {
+            FieldType::Number => format!("CAST(JSON_VALUE(doc, '{path}') AS DOUBLE)"),
+            FieldType::String | FieldType::Date | FieldType::Boolean => {
+                format!("CAST(JSON_VALUE(doc, '{path}') AS BINARY)")
+            }
+        })
+    }
+
+    /// SQL expression for the (possibly non-scalar) JSON value at a
+    /// field path.
+    pub fn json_expr(&self, field: &str) -> Result<String> {
+        let path = json_path(field)?;
+        Ok(format!("JSON_EXTRACT(doc, '{path}')"))
+    }
+
+    /// Convert a JSON value to a query parameter to be compared with
+    /// `expr(field)`. Returns `None` if the value cannot be compared.
+    pub fn param(&self, field: &str, value: &Value) -> Option<SqlValue> {
+        match (self.get(field), value) {
+            (FieldType::Number, Value::Number(n)) => Some(SqlValue::Double(n.as_f64()?)),
+            (FieldType::Number, Value::String(s)) => Some(SqlValue::Double(s.parse().ok()?)),
+            (FieldType::Date, Value::String(s)) => Some(SqlValue::Bytes(
+                normalize_date(s).unwrap_or_else(|| s.clone()).into_bytes(),
+            )),
+            (FieldType::Number, _) | (FieldType::Date, _) => None,
+            (_, Value::String(s)) => Some(SqlValue::Bytes(s.clone().into_bytes())),
+            (_, Value::Bool(b)) => Some(SqlValue::Bytes(b.to_string().into_bytes())),
+            (_, Value::Number(n)) => Some(SqlValue::Bytes(n.to_string().into_bytes())),
+            (_, Value::Null | Value::Array(_) | Value::Object(_)) => None,
+        }
+    }
+
+    /// Rewrite timestamps in a document to the canonical format
+    /// before it is stored.
+    pub fn normalize(&self, doc: &mut Value) {
+        self.map_dates(None, doc, &|s| normalize_date(s));
+    }
+
+    /// Rewrite stored timestamps to the format used by chrono's
+    /// serializer.
+    pub fn denormalize(&self, doc: &mut Value) {
+        self.map_dates(None, doc, &|s| {
+            Some(
+                DateTime::parse_from_rfc3339(s)
+                    .ok()?
+                    .with_timezone(&Utc)
+                    .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            )
+        });
+    }
+
+    fn map_dates<F>(&self, path: Option<&str>, value: &mut Value, f: &F)
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        match value {
+            Value::Object(obj) => obj.iter_mut().for_each(|(name, value)| {
+                let path = match path {
+                    Some(path) => format!("{path}.{name}"),
+                    None => name.to_string(),
+                };
+                self.map_dates(Some(&path), value, f)
+            }),
+            Value::Array(elems) => elems
+                .iter_mut()
+                .for_each(|value| self.map_dates(path, value, f)),
+            Value::String(s) => {
+                if let Some(path) = path {
+                    if self.0.get(path) == Some(&FieldType::Date) {
+                        if let Some(date) = f(s) {
+                            *s = date;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl FieldType {
+    fn from_elastic(typ: &str) -> Self {
+        match typ {
+            "long" | "integer" | "short" | "byte" | "double" | "float" | "half_float"
+            | "scaled_float" | "unsigned_long" => Self::Number,
+            "boolean" => Self::Boolean,
+            "date" | "date_nanos" => Self::Date,
+            _ => Self::String,
+        }
+    }
+}
+
+fn normalize_date(s: &str) -> Option<String> {
+    Some(
+        DateTime::parse_from_rfc3339(s)
+            .ok()?
+            .with_timezone(&Utc)
+            .format(DATE_FORMAT)
+            .to_string(),
+    )
+}
+
+/// Convert an elasticsearch field name to a JSON path.
+fn json_path(field: &str) -> Result<String> {
+    let field = field.strip_suffix(".keyword").unwrap_or(field);
+    field
+        .split('.')
+        .map(
+            |elem| match elem.is_empty() || elem.contains(['"', '\'', '\\']) {
+                true => Err(Error::InvalidField(field.to_string())),
+                false => Ok(format!(".\"{elem}\"")),
+            },
+        )
+        .collect::<Result<String>>()
+        .map(|path| format!("${path}"))
+}
@@ -0,0 +1,11 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+mod backend;
+mod error;
+mod fields;
+mod query;
+
+pub use backend::{Database, DatabaseConfig};
+pub use error::{Error, InitializationError, Result};
@@ -0,0 +1,545 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use mysql_async::Value as SqlValue;
+use serde_json::{Map, Value};
+
+use super::error::{Error, Result};
+use super::fields::Fields;
+
+/// Which side to err on when a query clause cannot be translated
+/// exactly. Results are always refiltered with the original filter,
+/// so the translated condition only needs to include every matching
+/// row (`Superset`). Negated clauses are translated as a `Subset`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Bound {
+    Superset,
+    Subset,
+}
+
+/// Translate an elasticsearch query to an SQL condition on the
+/// stored documents.
+pub(super) fn condition(fields: &Fields, query: &Value, params: &mut Vec<SqlValue>) -> String {
+    translate(fields, query, Bound::Superset, params)
+}
+
+fn translate(fields: &Fields, query: &Value, bound: Bound, params: &mut Vec<SqlValue>) -> String {
+    let Some((kind, args)) = query
+        .as_object()
+        .filter(|obj| obj.len() == 1)
+        .and_then(|obj| obj.iter().next())
+    else {
+        return unknown(bound);
+    };
+
+    match kind.as_str() {
+        "match_all" => String::from("TRUE"),
+        "match_none" => String::from("FALSE"),
+        "bool" => match args.as_object() {
+            Some(args) => translate_bool(fields, args, bound, params),
+            None => unknown(bound),
+        },
+        "term" => field_arg(args, "value")
+            .and_then(|(field, value)| {
+                let expr = fields.expr(field).ok()?;
+                let param = fields.param(field, value)?;
+                params.push(param);
+                Some(nullable(format!("{expr} = ?"), &expr, bound))
+            })
+            .unwrap_or_else(|| unknown(bound)),
+        "terms" => args
+            .as_object()
+            .and_then(|args| args.iter().find(|(field, _)| *field != "boost"))
+            .and_then(|(field, values)| {
+                let expr = fields.expr(field).ok()?;
+                let values = values
+                    .as_array()?
+                    .iter()
+                    .map(|value| fields.param(field, value))
+                    .collect::<Option<Vec<_>>>()?;
+                if values.is_empty() {
+                    return Some(String::from("FALSE"));
+                }
+                let placeholders = vec!["?"; values.len()].join(", ");
+                params.extend(values);
+                Some(nullable(
+                    format!("{expr} IN ({placeholders})"),
+                    &expr,
+                    bound,
+                ))
+            })
+            .unwrap_or_else(|| unknown(bound)),
+        "exists" => args
+            .get("field")
+            .and_then(Value::as_str)
+            .and_then(|field| fields.json_expr(field).ok())
+            .map(|expr| match bound {
+                Bound::Superset => String::from("TRUE"),
+                Bound::Subset => format!("({expr} IS NOT NULL AND JSON_TYPE({expr}) <> 'NULL')"),
+            })
+            .unwrap_or_else(|| unknown(bound)),
+        "range" => args
+            .as_object()
+            .filter(|args| args.len() == 1)
+            .and_then(|args| args.iter().next())
+            .and_then(|(field, range)| {
+                let expr = fields.expr(field).ok()?;
+                let conds = range
+                    .as_object()?
+                    .iter()
+                    .filter_map(|(op, value)| {
+                        let op = match op.as_str() {
+                            "gt" => ">",
+                            "gte" => ">=",
+                            "lt" => "<",
+                            "lte" => "<=",
+                            _ => return None,
+                        };
+                        Some((op, fields.param(field, value)))
+                    })
+                    .collect::<Vec<_>>();
+                if conds.is_empty() || conds.iter().any(|(_, param)| param.is_none()) {
+                    return None;
+                }
+                let cond = conds
+                    .into_iter()
+                    .map(|(op, param)| {
+                        params.push(param.unwrap());
+                        format!("{expr} {op} ?")
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" AND ");
+                Some(nullable(cond, &expr, bound))
+            })
+            .unwrap_or_else(|| unknown(bound)),
+        _ => unknown(bound),
+    }
+}
+
+fn translate_bool(
+    fields: &Fields,
+    args: &Map<String, Value>,
+    bound: Bound,
+    params: &mut Vec<SqlValue>,
+) -> String {
+    let clauses = |name: &str| -> Vec<&Value> {
+        match args.get(name) {
+            Some(Value::Array(clauses)) => clauses.iter().collect(),
+            Some(clause) => vec![clause],
+            None => Vec::new(),
+        }
+    };
+
+    let mut conds = Vec::new();
+
+    for clause in clauses("must").into_iter().chain(clauses("filter")) {
+        conds.push(translate(fields, clause, bound, params));
+    }
+
+    let should = clauses("should");
+    let min_should = args
+        .get("minimum_should_match")
+        .and_then(Value::as_u64)
+        .unwrap_or(match conds.is_empty() {
+            true => 1,
+            false => 0,
+        });
+    if !should.is_empty() && min_should > 0 {
+        if min_should == 1 || bound == Bound::Superset {
+            let alts = should
+                .into_iter()
+                .map(|clause| translate(fields, clause, bound, params))
+                .collect::<Vec<_>>();
+            conds.push(format!("({})", alts.join(" OR ")));
+        } else {
+            conds.push(unknown(bound));
+        }
+    }
+
+    for clause in clauses("must_not") {
+        let cond = translate(fields, clause, bound.negate(), params);
+        conds.push(format!("NOT ({cond})"));
+    }
+
+    match conds.is_empty() {
+        true => String::from("TRUE"),
+        false => format!("({})", conds.join(" AND ")),
+    }
+}
+
+/// Parse `{"field": value}` or `{"field": {"<arg>": value}}`.
+fn field_arg<'a>(args: &'a Value, arg: &str) -> Option<(&'a str, &'a Value)> {
+    let (field, value) = args
+        .as_object()?
+        .iter()
+        .find(|(field, _)| *field != "boost")?;
+    match value {
+        Value::Object(obj) => Some((field, obj.get(arg)?)),
+        value => Some((field, value)),
+    }
+}
+
+/// Field values inside arrays cannot be compared in SQL and evaluate
+/// to NULL; these rows are included in a superset and excluded from a
+/// subset.
+fn nullable(cond: String, expr: &str, bound: Bound) -> String {
+    match bound {
+        Bound::Superset => format!("({cond} OR {expr} IS NULL)"),
+        Bound::Subset => format!("COALESCE({cond}, FALSE)"),
+    }
+}
+
+fn unknown(bound: Bound) -> String {
+    match bound {
+        Bound::Superset => String::from("TRUE"),
+        Bound::Subset => String::from("FALSE"),
+    }
+}
+
+impl Bound {
+    fn negate(self) -> Self {
+        match self {
+            Self::Superset => Self::Subset,
+            Self::Subset => Self::Superset,
+        }
+    }
+}
+
+/// A parsed elasticsearch sort specification, with the document id
+/// as implicit tie-breaker to allow keyset pagination.
+#[derive(Debug, Clone)]
+pub(super) struct Sort(Vec<(String, Order)>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Order {
+    Asc,
+    Desc,
+}
+
+/// Position of the last row returned by a query.
+#[derive(Debug, Clone)]
+pub(super) struct Cursor {
+    pub keys: Vec<SqlValue>,
+    pub id: String,
+}
+
+impl Sort {
+    pub fn new(fields: &Fields, sort: &Value) -> Result<Self> {
+        let elems = match sort {
+            Value::Array(elems) => elems.iter().collect(),
+            Value::Null => Vec::new(),
+            elem => vec![elem],
+        };
+        elems
+            .into_iter()
+            .map(|elem| {
+                let (field, order) = match elem {
+                    Value::String(field) => (field.as_str(), Order::Asc),
+                    Value::Object(obj) if obj.len() == 1 => {
+                        let (field, order) = obj.iter().next().unwrap();
+                        let order = match order {
+                            Value::String(order) => order.as_str(),
+                            Value::Object(opts) => {
+                                opts.get("order").and_then(Value::as_str).unwrap_or("asc")
+                            }
+                            _ => return Err(Error::InvalidSort(sort.clone())),
+                        };
+                        match order {
+                            "asc" => (field.as_str(), Order::Asc),
+                            "desc" => (field.as_str(), Order::Desc),
+                            _ => return Err(Error::InvalidSort(sort.clone())),
+                        }
+                    }
+                    _ => return Err(Error::InvalidSort(sort.clone())),
+                };
+                Ok((fields.expr(field)?, order))
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    /// Expressions to select alongside the document, to be used as
+    /// the cursor for the next page.
+    pub fn columns(&self) -> String {
+        self.0.iter().map(|(expr, _)| format!(", {expr}")).collect()
+    }
+
+    pub fn order_by(&self) -> String {
+        self.0
+            .iter()
+            .map(|(expr, order)| format!("{expr} {}, ", order.sql()))
+            .chain(std::iter::once(String::from("id ASC")))
+            .collect()
+    }
+
+    /// Condition selecting the rows following `cursor`. NULL values
+    /// sort first in ascending and last in descending order.
+    pub fn after(&self, cursor: &Cursor, params: &mut Vec<SqlValue>) -> String {
+        let mut alts = Vec::new();
+        let mut prefix: Vec<String> = Vec::new();
+        let mut prefix_params = Vec::new();
+
+        for ((expr, order), key) in self.0.iter().zip(&cursor.keys) {
+            let after = match (order, key) {
+                (Order::Asc, SqlValue::NULL) => Some((format!("{expr} IS NOT NULL"), None)),
+                (Order::Asc, key) => Some((format!("{expr} > ?"), Some(key.clone()))),
+                (Order::Desc, SqlValue::NULL) => None,
+                (Order::Desc, key) => {
+                    Some((format!("({expr} < ? OR {expr} IS NULL)"), Some(key.clone())))
+                }
+            };
+            if let Some((cond, param)) = after {
+                alts.push(
+                    prefix
+                        .iter()
+                        .cloned()
+                        .chain(std::iter::once(cond))
+                        .collect::<Vec<_>>()
+                        .join(" AND "),
+                );
+                params.extend(prefix_params.iter().cloned());
+                params.extend(param);
+            }
+            prefix.push(format!("{expr} <=> ?"));
+            prefix_params.push(key.clone());
+        }
+
+        alts.push(
+            prefix
+                .into_iter()
+                .chain(std::iter::once(String::from("id > ?")))
+                .collect::<Vec<_>>()
+                .join(" AND "),
+        );
+        params.extend(prefix_params);
+        params.push(SqlValue::Bytes(cursor.id.clone().into_bytes()));
+
+        format!("({})", alts.join(") OR ("))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl Order {
+    fn sql(self) -> &'static str {
+        match self {
+            Self::Asc => "ASC",
+            Self::Desc => "DESC",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mysql_async::Value as SqlValue;
+    use serde_json::json;
+
+    use crate::database::mariadb::{
+        error::Error,
+        fields::{FieldType, Fields},
+    };
+
+    use super::{condition, Cursor, Sort};
+
+    fn fields() -> Fields {
+        Fields::from_types([("count", FieldType::Number), ("name", FieldType::String)])
+    }
+
+    fn bytes(s: &str) -> SqlValue {
+        SqlValue::Bytes(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn term() {
+        let fields = fields();
+        let count = fields.expr("count").unwrap();
+        let mut params = Vec::new();
+        assert_eq!(
+            condition(
+                &fields,
+                &json!({"term": {"count": {"value": 5}}}),
+                &mut params
+            ),
+            format!("({count} = ? OR {count} IS NULL)")
+        );
+        assert_eq!(params, vec![SqlValue::Double(5.0)]);
+
+        /* Values that cannot be compared match any row. */
+        let mut params = Vec::new();
+        assert_eq!(
+            condition(&fields, &json!({"term": {"count": "many"}}), &mut params),
+            "TRUE"
+        );
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn terms() {
+        let fields = fields();
+        let name = fields.expr("name.keyword").unwrap();
+        let mut params = Vec::new();
+        assert_eq!(
+            condition(
+                &fields,
+                &json!({"terms": {"name.keyword": ["a", "b"], "boost": 1.0}}),
+                &mut params
+            ),
+            format!("({name} IN (?, ?) OR {name} IS NULL)")
+        );
+        assert_eq!(params, vec![bytes("a"), bytes("b")]);
+
+        let mut params = Vec::new();
+        assert_eq!(
+            condition(&fields, &json!({"terms": {"name": []}}), &mut params),
+            "FALSE"
+        );
+    }
+
+    #[test]
+    fn range() {
+        let fields = fields();
+        let count = fields.expr("count").unwrap();
+        let mut params = Vec::new();
+        assert_eq!(
+            condition(
+                &fields,
+                &json!({"range": {"count": {"gte": 2}}}),
+                &mut params
+            ),
+            format!("({count} >= ? OR {count} IS NULL)")
+        );
+        assert_eq!(params, vec![SqlValue::Double(2.0)]);
+    }
+
+    #[test]
+    fn bool() {
+        let fields = fields();
+        let count = fields.expr("count").unwrap();
+        let name = fields.expr("name").unwrap();
+        let mut params = Vec::new();
+        assert_eq!(
+            condition(
+                &fields,
+                &json!({"bool": {
+                    "filter": [{"term": {"count": 1}}],
+                    "should": [{"term": {"name": "a"}}, {"match": {"name": "b"}}],
+                    "minimum_should_match": 1,
+                    "must_not": {"term": {"name": "c"}},
+                }}),
+                &mut params
+            ),
+            format!(
+                "(({count} = ? OR {count} IS NULL) \
+                 AND (({name} = ? OR {name} IS NULL) OR TRUE) \
+                 AND NOT (COALESCE({name} = ?, FALSE)))"
+            )
+        );
+        assert_eq!(params, vec![SqlValue::Double(1.0), bytes("a"), bytes("c")]);
+    }
+
+    /// Negated clauses are translated as a subset, so that the
+    /// condition still includes every matching row.
+    #[test]
+    fn negated_unknown() {
+        let fields = fields();
+        let mut params = Vec::new();
+        assert_eq!(
+            condition(
+                &fields,
+                &json!({"bool": {"must_not": [
+                    {"match": {"name": "a"}},
+                    {"exists": {"field": "name"}},
+                    {"bool": {
+                        "should": [{"term": {"name": "a"}}, {"term": {"name": "b"}}],
+                        "minimum_should_match": 2,
+                    }},
+                ]}}),
+                &mut params
+            ),
+            "(NOT (FALSE) \
+             AND NOT ((JSON_EXTRACT(doc, '$.\"name\"') IS NOT NULL \
+             AND JSON_TYPE(JSON_EXTRACT(doc, '$.\"name\"')) <> 'NULL')) \
+             AND NOT ((FALSE)))"
+        );
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn sort() {
+        let fields = fields();
+        let count = fields.expr("count").unwrap();
+        let name = fields.expr("name").unwrap();
+        let sort = Sort::new(
+            &fields,
+            &json!([{"count": "asc"}, {"name": {"order": "desc"}}]),
+        )
+        .unwrap();
+        assert_eq!(sort.len(), 2);
+        assert_eq!(sort.columns(), format!(", {count}, {name}"));
+        assert_eq!(sort.order_by(), format!("{count} ASC, {name} DESC, id ASC"));
+
+        assert!(matches!(
+            Sort::new(&fields, &json!([{"count": "up"}])),
+            Err(Error::InvalidSort(_))
+        ));
+    }
+
+    #[test]
+    fn sort_after() {
+        let fields = fields();
+        let count = fields.expr("count").unwrap();
+        let name = fields.expr("name").unwrap();
+        let sort = Sort::new(&fields, &json!([{"count": "asc"}, {"name": "desc"}])).unwrap();
+
+        let mut params = Vec::new();
+        let cursor = Cursor {
+            keys: vec![SqlValue::Double(1.0), bytes("b")],
+            id: String::from("x"),
+        };
+        assert_eq!(
+            sort.after(&cursor, &mut params),
+            format!(
+                "({count} > ?) \
+                 OR ({count} <=> ? AND ({name} < ? OR {name} IS NULL)) \
+                 OR ({count} <=> ? AND {name} <=> ? AND id > ?)"
+            )
+        );
+        assert_eq!(
+            params,
+            vec![
+                SqlValue::Double(1.0),
+                SqlValue::Double(1.0),
+                bytes("b"),
+                SqlValue::Double(1.0),
+                bytes("b"),
+                bytes("x"),
+            ]
+        );
+    }
+
+    /// NULL sorts first in ascending and last in descending order.
+    #[test]
+    fn sort_after_null() {
+        let fields = fields();
+        let count = fields.expr("count").unwrap();
+        let name = fields.expr("name").unwrap();
+        let sort = Sort::new(&fields, &json!([{"count": "asc"}, {"name": "desc"}])).unwrap();
+
+        let mut params = Vec::new();
+        let cursor = Cursor {
+            keys: vec![SqlValue::NULL, SqlValue::NULL],
+            id: String::from("x"),
+        };
+        assert_eq!(
+            sort.after(&cursor, &mut params),
+            format!(
+                "({count} IS NOT NULL) \
+                 OR ({count} <=> ? AND {name} <=> ? AND id > ?)"
+            )
+        );
+        assert_eq!(params, vec![SqlValue::NULL, SqlValue::NULL, bytes("x")]);
+    }
+}
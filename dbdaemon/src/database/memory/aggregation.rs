@@ -0,0 +1,124 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use dbdaemon_api::{Aggregation, AggregationBucket, MetricFunction};
+
+use super::backend::collect_values;
+
+/// Values collected for one time bucket and group.
+struct Bucket {
+    key: Vec<Value>,
+    count: u64,
+    values: Vec<Vec<f64>>,
+}
+
+/// Aggregate stored documents, following elasticsearch semantics:
+/// time buckets are aligned to the epoch, documents without a
+/// timestamp or group-by value are skipped and empty buckets are
+/// omitted.
+pub(super) fn aggregate<'a, I>(
+    docs: I,
+    timestamp_field: &str,
+    aggregation: &Aggregation,
+) -> Vec<AggregationBucket>
+where
+    I: IntoIterator<Item = &'a Value>,
+{
+    let interval = (aggregation.interval.max(1) * 1000) as i64;
+    let timestamp_path = path(timestamp_field);
+    let group_paths = aggregation
+        .group_by
+        .iter()
+        .map(|field| path(field))
+        .collect::<Vec<_>>();
+    let metric_paths = aggregation
+        .metrics
+        .iter()
+        .map(|metric| path(&metric.field))
+        .collect::<Vec<_>>();
+
+    let mut buckets = BTreeMap::<(i64, String), Bucket>::new();
+    for doc in docs {
+        let Some(timestamp) = first_value(doc, &timestamp_path)
+            .and_then(Value::as_str)
+            .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+        else {
+            continue;
+        };
+        let Some(key) = group_paths
+            .iter()
+            .map(|path| first_value(doc, path).cloned())
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+
+        let start = timestamp.timestamp_millis().div_euclid(interval) * interval;
+        let bucket = buckets
+            .entry((start, Value::Array(key.clone()).to_string()))
+            .or_insert_with(|| Bucket {
+                key,
+                count: 0,
+                values: vec![Vec::new(); metric_paths.len()],
+            });
+        bucket.count += 1;
+        for (values, path) in bucket.values.iter_mut().zip(&metric_paths) {
+            let mut found = Vec::new();
+            collect_values(doc, path, &mut found);
+            values.extend(found.into_iter().filter_map(Value::as_f64));
+        }
+    }
+
+    buckets
+        .into_iter()
+        .filter_map(|((start, _), bucket)| {
+            Some(AggregationBucket {
+                timestamp: DateTime::<Utc>::from_timestamp_millis(start)?,
+                key: bucket.key,
+                count: bucket.count,
+                values: aggregation
+                    .metrics
+                    .iter()
+                    .zip(bucket.values)
+                    .map(|(metric, values)| calculate(metric.function, values))
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+fn calculate(function: MetricFunction, mut values: Vec<f64>) -> Option<f64> {
+    match function {
+        MetricFunction::Count => Some(values.len() as f64),
+        MetricFunction::Sum => Some(values.iter().sum()),
+        MetricFunction::Avg => {
+            (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+        }
+        MetricFunction::Min => values.into_iter().reduce(f64::min),
+        MetricFunction::Max => values.into_iter().reduce(f64::max),
+        MetricFunction::Percentile(p) => {
+            values.sort_by(f64::total_cmp);
+            let rank = (p.clamp(0.0, 100.0) / 100.0) * (values.len() as f64 - 1.0);
+            let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+            let (a, b) = (*values.get(lower)?, *values.get(upper)?);
+            Some(a + (b - a) * (rank - lower as f64))
+        }
+    }
+}
+
+fn path(field: &str) -> Vec<String> {
+    let field = field.strip_suffix(".keyword").unwrap_or(field);
+    field.split('.').map(String::from).collect()
+}
+
+fn first_value<'a>(doc: &'a Value, path: &[String]) -> Option<&'a Value> {
+    let mut values = Vec::new();
+    collect_values(doc, path, &mut values);
+    values.into_iter().next()
+}
@@ -0,0 +1,966 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::cmp::Ordering;
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::DateTime;
+use clap::Args;
+use log::{info, warn};
+use parking_lot::RwLock;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{io::AsyncWriteExt, sync::Mutex as AsyncMutex};
+
+use dbdaemon_api::{Aggregation, AggregationBucket};
+use dbschema::{DbSchema, DbTable, DbTableId, Filter};
+use dbschema_elastic::{ElasticMapping, ElasticValue};
+
+use crate::database::backend::{Database as DatabaseTrait, Transform};
+use crate::database::elastic::ElasticId;
+
+use super::aggregation;
+use super::error::{Error, Result};
+
+/// Embedded backend, keeping all documents in memory. Documents are
+/// stored in elasticsearch form and written with the same external
+/// versioning semantics. When a path is configured, every write is
+/// appended to a journal file, which is compacted on startup and
+/// after a number of writes.
+#[derive(Debug)]
+pub struct Database {
+    tables: RwLock<HashMap<DbTableId, Table>>,
+    /// Serializes writes, so that the journal records them in the
+    /// order they were applied.
+    journal: AsyncMutex<Option<Journal>>,
+    /// The number of upcoming writes to fail, to test error handling.
+    #[cfg(test)]
+    fail_writes: std::sync::atomic::AtomicUsize,
+}
+
+type Table = HashMap<ElasticId, Doc>;
+
+/// A stored document. Deleted documents are kept as tombstones, so
+/// that late writes with an older version are still ignored.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Doc {
+    version: u64,
+    source: Option<Value>,
+}
+
+/// The journal file, to which writes are appended.
+#[derive(Debug)]
+struct Journal {
+    path: PathBuf,
+    file: tokio::fs::File,
+    /// The number of entries appended since the last compaction.
+    entries: usize,
+    /// Set when a write failed, leaving the file in an unknown state.
+    failed: bool,
+}
+
+/// An entry in the journal file. Entries are stored as one json
+/// object per line.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum JournalEntry {
+    CreateTable {
+        table_id: DbTableId,
+    },
+    RemoveTable {
+        table_id: DbTableId,
+    },
+    /// The full contents of a table, written on compaction and
+    /// after a reindex.
+    Table {
+        table_id: DbTableId,
+        docs: Table,
+    },
+    Write {
+        table_id: DbTableId,
+        docs: Vec<(ElasticId, Doc)>,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum JournalEntryRef<'a> {
+    CreateTable {
+        table_id: &'a DbTableId,
+    },
+    RemoveTable {
+        table_id: &'a DbTableId,
+    },
+    Table {
+        table_id: &'a DbTableId,
+        docs: &'a Table,
+    },
+    Write {
+        table_id: &'a DbTableId,
+        docs: &'a [(ElasticId, Doc)],
+    },
+}
+
+/// Database file written before the journal was introduced.
+#[derive(Deserialize)]
+struct TableFile {
+    table_id: DbTableId,
+    docs: Table,
+}
+
+/// Maximum number of documents returned per page.
+const PAGE_SIZE: usize = 10000;
+
+/// Number of journal entries after which the journal is compacted.
+const COMPACT_ENTRIES: usize = 10000;
+
+impl Database {
+    pub async fn new(config: DatabaseConfig) -> Result<Database> {
+        let (tables, journal) = match config.path {
+            Some(path) => {
+                let tables = match tokio::fs::read(&path).await {
+                    Ok(data) => Self::load(&path, &data)?,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                    Err(e) => return Err(Error::ReadFile(path, e)),
+                };
+                let journal = Journal::create(path, &tables).await?;
+                (tables, Some(journal))
+            }
+            None => (HashMap::new(), None),
+        };
+        Ok(Database {
+            tables: RwLock::new(tables),
+            journal: AsyncMutex::new(journal),
+            #[cfg(test)]
+            fail_writes: std::sync::atomic::AtomicUsize::new(0),
+        })
+    }
+
+    /// Replay the journal. An incomplete last line, left by a crash
+    /// during a write, is ignored.
+    fn load(path: &Path, data: &[u8]) -> Result<HashMap<DbTableId, Table>> {
+        if data.first() == Some(&b'[') {
+            return Ok(serde_json::from_slice::<Vec<TableFile>>(data)
+                .map_err(|e| Error::ParseFile(path.to_path_buf(), e))?
+                .into_iter()
+                .map(|table| (table.table_id, table.docs))
+                .collect());
+        }
+
+        let mut tables = HashMap::new();
+        let mut lines = data.split(|c| *c == b'\n').peekable();
+        while let Some(line) = lines.next() {
+            if line.is_empty() {
+                continue;
+            }
+            let entry = match serde_json::from_slice::<JournalEntry>(line) {
+                Ok(entry) => entry,
+                Err(e) if lines.peek().is_none() => {
+                    warn!(
+                        "ignoring incomplete entry at the end of '{}': {e}",
+                        path.display()
+                    );
+                    break;
+                }
+                Err(e) => return Err(Error::ParseFile(path.to_path_buf(), e)),
+            };
+            match entry {
+                JournalEntry::CreateTable { table_id } => {
+                    tables.insert(table_id, HashMap::new());
+                }
+                JournalEntry::RemoveTable { table_id } => {
+                    tables.remove(&table_id);
+                }
+                JournalEntry::Table { table_id, docs } => {
+                    tables.insert(table_id, docs);
+                }
+                JournalEntry::Write { table_id, docs } => {
+                    let table = tables
+                        .get_mut(&table_id)
+                        .ok_or_else(|| Error::NoSuchTable(table_id.clone()))?;
+                    docs.into_iter()
+                        .for_each(|(id, doc)| Self::write_doc(table, id, doc.version, doc.source));
+                }
+            }
+        }
+        Ok(tables)
+    }
+
+    /// Fail the next `n` writes.
+    #[cfg(test)]
+    pub fn fail_writes(&self, n: usize) {
+        self.fail_writes
+            .store(n, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Fail the write if requested by a test.
+    fn check_write(&self) -> Result<()> {
+        #[cfg(test)]
+        if self
+            .fail_writes
+            .fetch_update(
+                std::sync::atomic::Ordering::SeqCst,
+                std::sync::atomic::Ordering::SeqCst,
+                |n| n.checked_sub(1),
+            )
+            .is_ok()
+        {
+            return Err(Error::InjectedFailure);
+        }
+        Ok(())
+    }
+
+    /// Apply a change to the tables and append it to the journal, if
+    /// configured. The change returns the journal entry to append,
+    /// serialized before the change is applied.
+    async fn modify<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut HashMap<DbTableId, Table>) -> Result<Option<Vec<u8>>>,
+    {
+        let mut journal = self.journal.lock().await;
+        let entry = f(&mut *self.tables.write())?;
+        match (journal.as_mut(), entry) {
+            (Some(journal), Some(entry)) => journal.append(&entry, &self.tables).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Serialize a journal entry, as a single line.
+    fn entry(entry: JournalEntryRef) -> Result<Vec<u8>> {
+        let mut data = serde_json::to_vec(&entry)?;
+        data.push(b'\n');
+        Ok(data)
+    }
+
+    /// Write documents, or tombstones for documents without source.
+    async fn write_docs(&self, table_id: &DbTableId, docs: Vec<(ElasticId, Doc)>) -> Result<()> {
+        self.modify(|tables| {
+            let table = tables
+                .get_mut(table_id)
+                .ok_or_else(|| Error::NoSuchTable(table_id.clone()))?;
+            let entry = Self::entry(JournalEntryRef::Write {
+                table_id,
+                docs: &docs,
+            })?;
+            docs.into_iter()
+                .for_each(|(id, doc)| Self::write_doc(table, id, doc.version, doc.source));
+            Ok(Some(entry))
+        })
+        .await
+    }
+
+    fn with_table<F, R>(&self, table_id: &DbTableId, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Table) -> R,
+    {
+        let mut tables = self.tables.write();
+        let table = tables
+            .get_mut(table_id)
+            .ok_or_else(|| Error::NoSuchTable(table_id.clone()))?;
+        Ok(f(table))
+    }
+
+    /// Store a document, or a tombstone if `source` is `None`. Writes
+    /// with a version that is not greater than the stored version are
+    /// ignored, like version conflicts in elasticsearch.
+    fn write_doc(table: &mut Table, id: ElasticId, version: u64, source: Option<Value>) {
+        match table.entry(id) {
+            Entry::Occupied(mut ent) => {
+                if version > ent.get().version {
+                    ent.insert(Doc { version, source });
+                }
+            }
+            Entry::Vacant(ent) => {
+                ent.insert(Doc { version, source });
+            }
+        }
+    }
+
+    /// Find the documents matching `filter`, in the requested order.
+    fn query(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        filter: &Filter,
+        sort: &Value,
+        limit: Option<usize>,
+    ) -> Result<Vec<(ElasticId, u64, Value)>> {
+        let sort = Sort::new(sort);
+        let tables = self.tables.read();
+        let table = tables
+            .get(table_id)
+            .ok_or_else(|| Error::NoSuchTable(table_id.clone()))?;
+
+        let mut rows = table
+            .iter()
+            .filter_map(|(id, doc)| Some((id, doc.version, doc.source.as_ref()?)))
+            .map(|(id, version, source)| {
+                let value = serde_json::from_value::<ElasticValue>(source.clone())?.load(schema)?;
+                Ok(match filter.matches(schema, &value)? {
+                    true => Some((sort.keys(source), id.clone(), version, value)),
+                    false => None,
+                })
+            })
+            .filter_map(Result::transpose)
+            .collect::<Result<Vec<_>>>()?;
+
+        rows.sort_by(|(a_keys, a_id, _, _), (b_keys, b_id, _, _)| {
+            sort.compare(a_keys, b_keys).then_with(|| a_id.cmp(b_id))
+        });
+        if let Some(limit) = limit {
+            rows.truncate(limit);
+        }
+
+        Ok(rows
+            .into_iter()
+            .map(|(_, id, version, value)| (id, version, value))
+            .collect())
+    }
+}
+
+impl Journal {
+    /// Write the current state to a new journal file.
+    async fn create(path: PathBuf, tables: &HashMap<DbTableId, Table>) -> Result<Self> {
+        let data = Self::snapshot(tables)?;
+        let file = Self::replace(&path, &data)
+            .await
+            .map_err(|e| Error::WriteFile(path.clone(), e))?;
+        Ok(Self {
+            path,
+            file,
+            entries: 0,
+            failed: false,
+        })
+    }
+
+    /// Append an entry to the journal and compact it if it has grown
+    /// too large. If a previous append failed, the journal may end in
+    /// an incomplete entry, so it is compacted instead.
+    async fn append(
+        &mut self,
+        entry: &[u8],
+        tables: &RwLock<HashMap<DbTableId, Table>>,
+    ) -> Result<()> {
+        if self.failed || self.entries >= COMPACT_ENTRIES {
+            return self.compact(tables).await;
+        }
+        let write = async {
+            self.file.write_all(entry).await?;
+            self.file.sync_data().await
+        };
+        if let Err(e) = write.await {
+            self.failed = true;
+            return Err(Error::WriteFile(self.path.clone(), e));
+        }
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Replace the journal by a snapshot of the current state.
+    async fn compact(&mut self, tables: &RwLock<HashMap<DbTableId, Table>>) -> Result<()> {
+        let data = Self::snapshot(&tables.read())?;
+        match Self::replace(&self.path, &data).await {
+            Ok(file) => {
+                self.file = file;
+                self.entries = 0;
+                self.failed = false;
+                Ok(())
+            }
+            Err(e) => {
+                self.failed = true;
+                Err(Error::WriteFile(self.path.clone(), e))
+            }
+        }
+    }
+
+    fn snapshot(tables: &HashMap<DbTableId, Table>) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for (table_id, docs) in tables {
+            data.extend(Database::entry(JournalEntryRef::Table { table_id, docs })?);
+        }
+        Ok(data)
+    }
+
+    /// Atomically replace the file and open it for appending.
+    async fn replace(path: &Path, data: &[u8]) -> std::io::Result<tokio::fs::File> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(data).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, path).await?;
+        tokio::fs::OpenOptions::new().append(true).open(path).await
+    }
+}
+
+impl DatabaseTrait for Database {
+    type Error = Error;
+    type Id = ElasticId;
+
+    fn whoami(&self) -> String {
+        String::from("Memory")
+    }
+
+    /* Setup and configuration. */
+
+    async fn wait_for_database(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn verify_database(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /* Schema manipulation. */
+
+    async fn create_table(&self, id: &DbTableId, definition: &DbTable) -> Result<()> {
+        info!("creating table: {id}");
+        let _ = ElasticMapping::new(&definition.schema())?;
+        self.modify(|tables| match tables.entry(id.clone()) {
+            Entry::Occupied(_) => Err(Error::TableExists(id.clone())),
+            Entry::Vacant(ent) => {
+                ent.insert(HashMap::new());
+                Ok(Some(Self::entry(JournalEntryRef::CreateTable {
+                    table_id: id,
+                })?))
+            }
+        })
+        .await
+    }
+
+    async fn update_table(&self, id: &DbTableId, definition: &DbTable) -> Result<()> {
+        info!("updating mapping for table '{id}'");
+        let _ = ElasticMapping::new(&definition.schema())?;
+        self.with_table(id, |_| ())
+    }
+
+    async fn reindex_table(
+        &self,
+        id: &DbTableId,
+        old_definition: &DbTable,
+        new_definition: &DbTable,
+        transform: Option<&Transform<'_>>,
+    ) -> Result<()> {
+        info!("reindexing table '{id}'");
+        let old_schema = old_definition.schema();
+        let new_schema = new_definition.schema();
+        let _ = ElasticMapping::new(&new_schema)?;
+
+        self.modify(|tables| {
+            let table = tables
+                .get_mut(id)
+                .ok_or_else(|| Error::NoSuchTable(id.clone()))?;
+            let reindexed = table
+                .iter()
+                .map(|(id, doc)| {
+                    let source = match &doc.source {
+                        Some(source) => {
+                            let value = serde_json::from_value::<ElasticValue>(source.clone())?
+                                .load(&old_schema)?;
+                            let value = match transform {
+                                Some(transform) => transform(value).map_err(Error::Transform)?,
+                                None => value,
+                            };
+                            Some(serde_json::to_value(ElasticValue::save(
+                                &new_schema,
+                                value,
+                            )?)?)
+                        }
+                        None => None,
+                    };
+                    Ok((
+                        id.clone(),
+                        Doc {
+                            version: doc.version,
+                            source,
+                        },
+                    ))
+                })
+                .collect::<Result<_>>()?;
+            let entry = Self::entry(JournalEntryRef::Table {
+                table_id: id,
+                docs: &reindexed,
+            })?;
+            *table = reindexed;
+            Ok(Some(entry))
+        })
+        .await
+    }
+
+    async fn remove_table(&self, id: &DbTableId) -> Result<()> {
+        info!("deleting table '{id}'");
+        self.modify(|tables| {
+            tables
+                .remove(id)
+                .ok_or_else(|| Error::NoSuchTable(id.clone()))?;
+            Ok(Some(Self::entry(JournalEntryRef::RemoveTable {
+                table_id: id,
+            })?))
+        })
+        .await
+    }
+
+    async fn has_table(&self, id: &DbTableId) -> Result<bool> {
+        Ok(self.tables.read().contains_key(id))
+    }
+
+    async fn refresh_table(&self, id: &DbTableId) -> Result<()> {
+        self.with_table(id, |_| ())
+    }
+
+    /* Data manipulation. */
+
+    async fn update_object<T: Serialize + Send + Sync>(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        doc_id: &Self::Id,
+        version: u64,
+        value: T,
+    ) -> Result<()> {
+        self.check_write()?;
+        let source =
+            serde_json::to_value(ElasticValue::save(schema, serde_json::to_value(value)?)?)?;
+        let doc = Doc {
+            version,
+            source: Some(source),
+        };
+        self.write_docs(table_id, vec![(doc_id.clone(), doc)]).await
+    }
+
+    async fn bulk_update<T, I>(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        updates: I,
+    ) -> Result<()>
+    where
+        T: Serialize + Send + Sync,
+        I: IntoIterator<Item = (Self::Id, u64, T)> + Send + Sync,
+    {
+        self.check_write()?;
+        let docs = updates
+            .into_iter()
+            .map(|(id, version, value)| {
+                let source = serde_json::to_value(ElasticValue::save(
+                    schema,
+                    serde_json::to_value(&value)?,
+                )?)?;
+                let doc = Doc {
+                    version,
+                    source: Some(source),
+                };
+                Ok((id, doc))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if !docs.is_empty() {
+            self.write_docs(table_id, docs).await?;
+        }
+        Ok(())
+    }
+
+    async fn bulk_delete<I>(&self, table_id: &DbTableId, deletes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (Self::Id, u64)> + Send + Sync,
+    {
+        self.check_write()?;
+        let deletes = deletes
+            .into_iter()
+            .map(|(id, version)| {
+                let doc = Doc {
+                    version,
+                    source: None,
+                };
+                (id, doc)
+            })
+            .collect::<Vec<_>>();
+        if !deletes.is_empty() {
+            self.write_docs(table_id, deletes).await?;
+        }
+        Ok(())
+    }
+
+    async fn count_objects(&self, table_id: &DbTableId) -> Result<u64> {
+        self.with_table(table_id, |table| {
+            table.values().filter(|doc| doc.source.is_some()).count() as u64
+        })
+    }
+
+    async fn query_objects<T: DeserializeOwned + Send + Sync>(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        filter: &Filter,
+        sort: &Value,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Self::Id, u64, T)>> {
+        self.query(table_id, schema, filter, sort, limit)?
+            .into_iter()
+            .map(|(id, version, value)| Ok((id, version, serde_json::from_value(value)?)))
+            .collect()
+    }
+
+    type QueryState<'a> = QueryState;
+
+    /// The matching documents are collected on the first call, which
+    /// gives the same consistency as an elasticsearch point-in-time.
+    async fn query_objects_first<'a, T: DeserializeOwned + Send + Sync>(
+        &self,
+        table_id: &DbTableId,
+        schema: &'a DbSchema,
+        filter: &'a Filter,
+        sort: &'a Value,
+        _keep_alive: Duration,
+        limit: Option<usize>,
+    ) -> Result<(Vec<(Self::Id, u64, T)>, Option<Self::QueryState<'a>>)> {
+        let rows = self.query(table_id, schema, filter, sort, limit)?;
+        self.query_objects_next(QueryState {
+            rows: VecDeque::from(rows),
+        })
+        .await
+    }
+
+    async fn query_objects_next<'a, T: DeserializeOwned + Send + Sync>(
+        &self,
+        mut query_state: Self::QueryState<'a>,
+    ) -> Result<(Vec<(Self::Id, u64, T)>, Option<Self::QueryState<'a>>)> {
+        let n = query_state.rows.len().min(PAGE_SIZE);
+        let docs = query_state
+            .rows
+            .drain(..n)
+            .map(|(id, version, value)| Ok((id, version, serde_json::from_value(value)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let query_state = match query_state.rows.is_empty() {
+            true => None,
+            false => Some(query_state),
+        };
+        Ok((docs, query_state))
+    }
+
+    async fn aggregate(
+        &self,
+        table_id: &DbTableId,
+        schema: &DbSchema,
+        filter: &Filter,
+        timestamp_field: &str,
+        aggregation: &Aggregation,
+    ) -> Result<Vec<AggregationBucket>> {
+        let tables = self.tables.read();
+        let table = tables
+            .get(table_id)
+            .ok_or_else(|| Error::NoSuchTable(table_id.clone()))?;
+
+        let docs = table
+            .values()
+            .filter_map(|doc| doc.source.as_ref())
+            .map(|source| {
+                let value = serde_json::from_value::<ElasticValue>(source.clone())?.load(schema)?;
+                Ok(filter.matches(schema, &value)?.then_some(source))
+            })
+            .filter_map(Result::transpose)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(aggregation::aggregate(docs, timestamp_field, aggregation))
+    }
+}
+
+/// Embedded database options.
+#[derive(Serialize, Deserialize, Args, Default, Debug, Clone)]
+#[group(id = "memory")]
+pub struct DatabaseConfig {
+    /// File in which to persist the embedded database. If not set,
+    /// all data is lost when the daemon exits.
+    #[clap(env = "DB_MEMORY_PATH", long = "memory-path")]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct QueryState {
+    rows: VecDeque<(ElasticId, u64, Value)>,
+}
+
+/// An elasticsearch sort specification, evaluated on stored documents.
+struct Sort(Vec<(Vec<String>, bool)>);
+
+impl Sort {
+    fn new(sort: &Value) -> Self {
+        let elems = match sort {
+            Value::Array(elems) => elems.iter().collect(),
+            Value::Null => Vec::new(),
+            elem => vec![elem],
+        };
+        Self(
+            elems
+                .into_iter()
+                .filter_map(|elem| {
+                    let (field, desc) = match elem {
+                        Value::String(field) => (field.as_str(), false),
+                        Value::Object(obj) => {
+                            let (field, order) = obj.iter().next()?;
+                            let order = match order {
+                                Value::Object(opts) => opts.get("order").and_then(Value::as_str),
+                                order => order.as_str(),
+                            };
+                            (field.as_str(), order == Some("desc"))
+                        }
+                        _ => return None,
+                    };
+                    let field = field.strip_suffix(".keyword").unwrap_or(field);
+                    (!field.starts_with('_'))
+                        .then(|| (field.split('.').map(String::from).collect(), desc))
+                })
+                .collect(),
+        )
+    }
+
+    /// Extract the sort values from a document. For multi-valued
+    /// fields, the lowest (ascending) or highest (descending) value
+    /// is used.
+    fn keys(&self, source: &Value) -> Vec<Option<Value>> {
+        self.0
+            .iter()
+            .map(|(path, desc)| {
+                let mut values = Vec::new();
+                collect_values(source, path, &mut values);
+                values
+                    .into_iter()
+                    .reduce(
+                        |a, b| match (compare_values(a, b) == Ordering::Greater) == *desc {
+                            true => a,
+                            false => b,
+                        },
+                    )
+                    .cloned()
+            })
+            .collect()
+    }
+
+    /// Compare sort values. Missing values sort last.
+    fn compare(&self, a: &[Option<Value>], b: &[Option<Value>]) -> Ordering {
+        self.0
+            .iter()
+            .zip(a.iter().zip(b))
+            .map(|((_, desc), (a, b))| match (a, b) {
+                (Some(a), Some(b)) => match desc {
+                    true => compare_values(b, a),
+                    false => compare_values(a, b),
+                },
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            })
+            .find(|ord| ord.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+pub(super) fn collect_values<'a>(value: &'a Value, path: &[String], values: &mut Vec<&'a Value>) {
+    match (value, path.split_first()) {
+        (Value::Array(elems), _) => elems
+            .iter()
+            .for_each(|elem| collect_values(elem, path, values)),
+        (Value::Object(obj), Some((field, path))) => {
+            if let Some(value) = obj.get(field) {
+                collect_values(value, path, values)
+            }
+        }
+        (Value::Null | Value::Object(_), None) => {}
+        (value, None) => values.push(value),
+        (_, Some(_)) => {}
+    }
+}
+
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => {
+            match (
+                DateTime::parse_from_rfc3339(a),
+                DateTime::parse_from_rfc3339(b),
+            ) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => a.cmp(b),
+            }
+        }
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+fn type_rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use chrono::Utc;
+    use dbschema::{
+        DbSchema, DbTableId, Filter, HasSchema, HasTableDef, Identified, ObjectId, SingleVersioned,
+        SingleVersionedValue,
+    };
+    use serde_json::{json, Value};
+
+    use crate::database::{elastic::ElasticId, Database as _};
+
+    use super::{Database, DatabaseConfig};
+
+    #[tokio::test]
+    async fn external_versions() {
+        type Document = Identified<SingleVersioned<Object>>;
+        #[derive(HasSchema, Debug)]
+        #[allow(unused)]
+        struct Object {
+            field: String,
+        }
+
+        let db = Database::new(DatabaseConfig::default()).await.unwrap();
+        let table_id = DbTableId::new("test-table");
+        let table_def = Document::table_def();
+        let schema = table_def.schema();
+        db.create_table(&table_id, &table_def).await.unwrap();
+
+        let object_id = ObjectId::new();
+        let doc_id = ElasticId::new();
+        let doc = |field: &str| Identified {
+            object_id: object_id.clone(),
+            value: SingleVersionedValue::new(Utc::now(), json!({ "field": field })),
+        };
+        let fields = || async {
+            db.query_objects::<Identified<SingleVersionedValue>>(
+                &table_id,
+                &schema,
+                &Filter::All(Vec::new()),
+                &Value::Null,
+                None,
+            )
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, version, doc)| (version, doc.value.value["field"].clone()))
+            .collect::<Vec<_>>()
+        };
+
+        db.bulk_update(&table_id, &schema, [(doc_id.clone(), 1, doc("a"))])
+            .await
+            .unwrap();
+        db.bulk_update(&table_id, &schema, [(doc_id.clone(), 1, doc("b"))])
+            .await
+            .unwrap();
+        assert_eq!(fields().await, vec![(1, json!("a"))]);
+
+        db.update_object(&table_id, &schema, &doc_id, 3, doc("c"))
+            .await
+            .unwrap();
+        db.update_object(&table_id, &schema, &doc_id, 2, doc("d"))
+            .await
+            .unwrap();
+        assert_eq!(fields().await, vec![(3, json!("c"))]);
+
+        db.bulk_delete(&table_id, [(doc_id.clone(), 3)])
+            .await
+            .unwrap();
+        assert_eq!(fields().await, vec![(3, json!("c"))]);
+
+        db.bulk_delete(&table_id, [(doc_id.clone(), 4)])
+            .await
+            .unwrap();
+        db.bulk_update(&table_id, &schema, [(doc_id.clone(), 4, doc("e"))])
+            .await
+            .unwrap();
+        assert_eq!(fields().await, vec![]);
+    }
+
+    #[tokio::test]
+    async fn journal() {
+        type Document = Identified<SingleVersioned<Object>>;
+        #[derive(HasSchema, Debug)]
+        #[allow(unused)]
+        struct Object {
+            field: String,
+        }
+
+        let path = std::env::temp_dir().join(format!("dbdaemon-{}.json", uuid::Uuid::new_v4()));
+        let config = DatabaseConfig {
+            path: Some(path.clone()),
+        };
+        let table_id = DbTableId::new("test-table");
+        let table_def = Document::table_def();
+        let schema = table_def.schema();
+
+        let object_id = ObjectId::new();
+        let doc = |field: &str| Identified {
+            object_id: object_id.clone(),
+            value: SingleVersionedValue::new(Utc::now(), json!({ "field": field })),
+        };
+        async fn fields(
+            db: &Database,
+            table_id: &DbTableId,
+            schema: &DbSchema,
+        ) -> Vec<(u64, Value)> {
+            db.query_objects::<Identified<SingleVersionedValue>>(
+                table_id,
+                schema,
+                &Filter::All(Vec::new()),
+                &Value::Null,
+                None,
+            )
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, version, doc)| (version, doc.value.value["field"].clone()))
+            .collect()
+        }
+
+        let (kept, deleted) = (ElasticId::new(), ElasticId::new());
+        {
+            let db = Database::new(config.clone()).await.unwrap();
+            db.create_table(&table_id, &table_def).await.unwrap();
+            db.bulk_update(
+                &table_id,
+                &schema,
+                [(kept.clone(), 1, doc("a")), (deleted.clone(), 2, doc("b"))],
+            )
+            .await
+            .unwrap();
+            db.bulk_delete(&table_id, [(deleted.clone(), 3)])
+                .await
+                .unwrap();
+        }
+
+        /* Simulate a crash while appending an entry. */
+        let mut data = tokio::fs::read(&path).await.unwrap();
+        data.extend(b"{\"op\":\"write\",\"table_id\":");
+        tokio::fs::write(&path, data).await.unwrap();
+
+        let db = Database::new(config.clone()).await.unwrap();
+        assert_eq!(fields(&db, &table_id, &schema).await, vec![(1, json!("a"))]);
+        db.bulk_update(&table_id, &schema, [(deleted.clone(), 3, doc("c"))])
+            .await
+            .unwrap();
+        db.update_object(&table_id, &schema, &kept, 4, doc("d"))
+            .await
+            .unwrap();
+        drop(db);
+
+        let db = Database::new(config).await.unwrap();
+        assert_eq!(fields(&db, &table_id, &schema).await, vec![(4, json!("d"))]);
+        drop(db);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
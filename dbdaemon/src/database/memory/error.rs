@@ -0,0 +1,43 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use dbschema::DbTableId;
+use dbschema_elastic::{ConversionError, MappingError};
+
+use crate::database::backend::DatabaseError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("mapping error: {0}")]
+    Mapping(#[from] MappingError),
+    #[error("conversion error: {0}")]
+    Conversion(#[from] ConversionError),
+    #[error("schema error: {0}")]
+    Schema(#[from] dbschema::Error),
+    #[error("Json (de)serialization error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("failed to transform document: {0}")]
+    Transform(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Table does not exist: {0}")]
+    NoSuchTable(DbTableId),
+    #[error("Table already exists: {0}")]
+    TableExists(DbTableId),
+    #[error("Failed to read database file '{0}': {1}")]
+    ReadFile(PathBuf, std::io::Error),
+    #[error("Failed to parse database file '{0}': {1}")]
+    ParseFile(PathBuf, serde_json::Error),
+    #[error("Failed to write database file '{0}': {1}")]
+    WriteFile(PathBuf, std::io::Error),
+    #[cfg(test)]
+    #[error("Injected write failure")]
+    InjectedFailure,
+}
+
+impl DatabaseError for Error {}
@@ -0,0 +1,10 @@
+/******************************************************************************
+ * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
+ ******************************************************************************/
+
+mod aggregation;
+mod backend;
+mod error;
+
+pub use backend::{Database, DatabaseConfig};
+pub use error::{Error, Result};
@@ -2,9 +2,14 @@
  * Copyright ContinuousC. Licensed under the "Elastic License 2.0".           *
  ******************************************************************************/
 
+mod any;
 pub mod backend;
 
 pub mod elastic;
+#[cfg(feature = "mariadb")]
 pub mod mariadb;
+#[cfg(feature = "memory")]
+pub mod memory;
 
-pub(crate) use backend::Database;
+pub use any::{AnyDatabase, DatabaseConfig, DatabaseType, Error};
+pub use backend::{Database, DatabaseError, Transform, TIMESTAMP_FIELD};
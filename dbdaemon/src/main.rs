@@ -4,6 +4,7 @@
 
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::Parser;
 use log::info;
@@ -14,7 +15,10 @@ use tokio::{
     signal::unix::{signal, SignalKind},
 };
 
-use dbdaemon::{daemon::DbDaemon, database::elastic};
+use dbdaemon::{
+    daemon::{DbDaemon, RetentionConfig},
+    database::{AnyDatabase, DatabaseConfig},
+};
 use dbdaemon_api::BackendDbHandler;
 use opentelemetry::trace::TracerProvider;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -77,11 +81,41 @@ struct Args {
     /// The path to the server key.
     #[clap(env = "DB_KEY", long, default_value = "dbdaemon.key")]
     key: PathBuf,
+    /// Path to the write-ahead journal for database updates. When
+    /// set, interrupted transactions are completed on startup.
+    #[clap(env = "DB_JOURNAL", long)]
+    journal: Option<PathBuf>,
+    /// Path to a yaml file with retention policies per table.
+    #[clap(env = "DB_RETENTION", long)]
+    retention: Option<PathBuf>,
+    /// Interval, in seconds, at which retention policies are enforced.
+    #[clap(
+        env = "DB_RETENTION_INTERVAL",
+        long,
+        default_value = "3600",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    retention_interval: u64,
+    /// Interval, in seconds, at which tables are checked for drift
+    /// between the in-memory state and the database.
+    #[clap(
+        env = "DB_DRIFT_CHECK_INTERVAL",
+        long,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    drift_check_interval: Option<u64>,
+    /// Reload tables from the database when drift is detected.
+    #[clap(env = "DB_DRIFT_FIX", long)]
+    drift_fix: bool,
+    /// Directory holding table archives for export and import. Export
+    /// and import are disabled when not set.
+    #[clap(env = "DB_ARCHIVE_DIR", long)]
+    archive_dir: Option<PathBuf>,
     /// Increase log verbosity.
     #[clap(env = "DB_VERBOSE", long, short, action = clap::ArgAction::Count)]
     verbose: u8,
-    #[clap(flatten, next_help_heading = "Elasticsearch")]
-    elastic: elastic::DatabaseConfig,
+    #[clap(flatten)]
+    database: DatabaseConfig,
 }
 
 #[tokio::main]
@@ -164,13 +198,29 @@ async fn main() -> Result<()> {
 
 async fn run(args: Args) -> Result<()> {
     // create daemon
-    let daemon = DbDaemon::new(args.elastic).await?;
+    let database = AnyDatabase::new(args.database).await?;
+    let mut daemon = DbDaemon::new(database, args.journal.as_deref()).await?;
+
+    if let Some(dir) = &args.archive_dir {
+        daemon.set_archive_dir(dir.clone());
+    }
+
+    if let Some(path) = &args.retention {
+        let retention = RetentionConfig::load(path).await?;
+        info!(
+            "enforcing retention policies for {} table(s)",
+            retention.0.len()
+        );
+        daemon.start_retention(retention, Duration::from_secs(args.retention_interval));
+    }
+
+    if let Some(interval) = args.drift_check_interval {
+        info!("checking for drift every {interval}s");
+        daemon.start_drift_check(Duration::from_secs(interval), args.drift_fix);
+    }
 
     info!("daemon started");
-    info!(
-        "using objectdb: {}",
-        "elastic" /* &daemon.elastic.whoami() */
-    );
+    info!("using objectdb: {}", daemon.whoami());
     // debug!("daemon: {:?}", &daemon);
 
     // signal handling
@@ -244,6 +294,8 @@ enum Error {
     // Broker(#[from] AgentError),
     // #[error("Failed to send termination signal to connection handlers: {0}")]
     // SendTerm(watch::error::SendError<bool>),
+    #[error("Failed to initialize database: {0}")]
+    Database(#[from] dbdaemon::database::Error),
     #[error(transparent)]
     Daemon(#[from] dbdaemon::daemon::Error),
     #[error("rpc error: {0}")]